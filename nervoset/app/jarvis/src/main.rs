@@ -5,7 +5,8 @@ use std::sync::Arc;
 use clap::Parser;
 use nervo_bot_core::config::common::NervoConfig;
 use nervo_bot_core::config::jarvis::{InitialParams, JarvisAppState};
-use nervo_sdk::agent_type::NervoAgentType;
+use nervo_bot_core::context::memory_consolidation;
+use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use tracing::{debug_span, info, Instrument, Level};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
@@ -60,6 +61,10 @@ pub async fn start_jarvis(nervo_agent_type: NervoAgentType) -> anyhow::Result<()
     };
 
     let app_state = Arc::from(JarvisAppState::create_from(initial_params).await?);
+    // Only Kevin keeps the memory of the users, so the other agents don't consolidate it again
+    if agent_type == AgentType::Kevin {
+        tokio::spawn(memory_consolidation::schedule(app_state.clone()));
+    }
     jarvis::start(telegram_agent_params, app_state, nervo_agent_type).await?;
 
     Ok(())
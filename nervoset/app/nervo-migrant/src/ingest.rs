@@ -0,0 +1,509 @@
+use crate::models::migration_model::{DataSample, MigrationModel, VectorData};
+use anyhow::anyhow;
+use nervo_bot_core::utils::context_builder::count_tokens;
+use nervo_sdk::utils::cryptography::UuidGenerator;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::panic;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+pub const DEFAULT_MAX_TOKENS: usize = 400;
+pub const DEFAULT_OVERLAP_TOKENS: usize = 50;
+/// html2text wraps the lines, the chunks are better without the wrapping
+const HTML_LINE_WIDTH: usize = 10_000;
+/// The headings never take more than this part of a chunk
+const MAX_HEADINGS_SHARE: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkingParams {
+    pub max_tokens: usize,
+    /// Tokens of the end of a chunk repeated at the beginning of the next one
+    pub overlap_tokens: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    pub documents: usize,
+    pub chunks: usize,
+    /// Files of unknown types
+    pub skipped: usize,
+}
+
+/// Splits every document of the `source_dir` into chunks and writes a dataset json per chunk
+/// into `agent_dir/<document path without extension>/`. Earlier samples of the document
+/// are replaced, the embeddings of the unchanged chunks are kept and the dropped chunks
+/// are listed in the `delete` section of the first sample.
+pub fn ingest_documents(
+    source_dir: &Path,
+    agent_dir: &Path,
+    params: ChunkingParams,
+) -> anyhow::Result<IngestReport> {
+    let mut report = IngestReport::default();
+
+    for path in find_documents(source_dir)? {
+        let Some(text) = read_document(&path)? else {
+            info!("Skip the document of unknown type: {:?}", path);
+            report.skipped += 1;
+            continue;
+        };
+
+        let relative_path = path.strip_prefix(source_dir)?;
+        let chunks = chunk_document(&text, params);
+        info!("Document {:?} has {} chunks", relative_path, chunks.len());
+
+        let samples_dir = agent_dir.join(relative_path.with_extension(""));
+        let source = relative_path.to_string_lossy().to_string();
+        report.chunks += write_samples(&samples_dir, &source, chunks)?;
+        report.documents += 1;
+    }
+
+    Ok(report)
+}
+
+fn find_documents(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut documents = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            documents.append(&mut find_documents(&path)?);
+        } else {
+            documents.push(path);
+        }
+    }
+    documents.sort();
+    Ok(documents)
+}
+
+/// Text of the document, markdown headings are kept
+fn read_document(path: &Path) -> anyhow::Result<Option<String>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+
+    let text = match extension.as_deref() {
+        Some("md" | "markdown" | "txt") => std::fs::read_to_string(path)?,
+        Some("html" | "htm") => html2text::from_read(File::open(path)?, HTML_LINE_WIDTH),
+        // pdf-extract panics on some malformed files
+        Some("pdf") => panic::catch_unwind(|| pdf_extract::extract_text(path))
+            .map_err(|_| anyhow!("Can't extract the text of {:?}", path))??,
+        _ => return Ok(None),
+    };
+    Ok(Some(text.replace("\r\n", "\n")))
+}
+
+/// Part of the document under the same headings
+#[derive(Debug, PartialEq)]
+struct Section {
+    headings: Vec<String>,
+    body: String,
+}
+
+/// Chunks of at most `max_tokens` (roughly, the separators are not counted), every chunk
+/// starts with the headings of its section, i.e. `Office > Parking`
+pub fn chunk_document(text: &str, params: ChunkingParams) -> Vec<String> {
+    let mut chunks = vec![];
+
+    for section in split_sections(text) {
+        let headings = section.headings.join(" > ");
+        let headings_tokens = if headings.is_empty() {
+            0
+        } else {
+            count_tokens(&headings).min(params.max_tokens / MAX_HEADINGS_SHARE)
+        };
+        let body_tokens = params.max_tokens - headings_tokens;
+
+        let units = split_units(&section.body, body_tokens);
+        for body in pack_units(units, body_tokens, params.overlap_tokens) {
+            let chunk = if headings.is_empty() {
+                body
+            } else {
+                format!("{}\n\n{}", headings, body)
+            };
+            chunks.push(chunk);
+        }
+    }
+
+    chunks
+}
+
+fn split_sections(text: &str) -> Vec<Section> {
+    let mut sections = vec![];
+    let mut headings: Vec<(usize, String)> = vec![];
+    let mut body = String::new();
+    let mut in_code_block = false;
+
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        let heading = if in_code_block {
+            None
+        } else {
+            markdown_heading(line)
+        };
+
+        let Some((level, title)) = heading else {
+            body.push_str(line);
+            body.push('\n');
+            continue;
+        };
+        push_section(&mut sections, &headings, &mut body);
+        headings.retain(|(parent_level, _)| *parent_level < level);
+        headings.push((level, title));
+    }
+    push_section(&mut sections, &headings, &mut body);
+
+    sections
+}
+
+fn push_section(sections: &mut Vec<Section>, headings: &[(usize, String)], body: &mut String) {
+    if !body.trim().is_empty() {
+        sections.push(Section {
+            headings: headings.iter().map(|(_, title)| title.clone()).collect(),
+            body: body.trim().to_string(),
+        });
+    }
+    body.clear();
+}
+
+/// `## Title` => (2, "Title")
+fn markdown_heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|ch| *ch == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let title = line[level..]
+        .strip_prefix(' ')?
+        .trim()
+        .trim_end_matches('#')
+        .trim();
+    (!title.is_empty()).then(|| (level, title.to_string()))
+}
+
+/// Paragraphs, the ones over the limit are split into sentences and then into words
+fn split_units(body: &str, max_tokens: usize) -> Vec<String> {
+    let mut units = vec![];
+    for paragraph in body.split("\n\n").map(str::trim) {
+        if paragraph.is_empty() {
+            continue;
+        }
+        if count_tokens(paragraph) <= max_tokens {
+            units.push(paragraph.to_string());
+            continue;
+        }
+
+        for sentence in split_sentences(paragraph) {
+            if count_tokens(&sentence) <= max_tokens {
+                units.push(sentence);
+            } else {
+                units.extend(split_words(&sentence, max_tokens));
+            }
+        }
+    }
+    units
+}
+
+fn split_sentences(paragraph: &str) -> Vec<String> {
+    let mut sentences = vec![];
+    let mut sentence = String::new();
+    let mut chars = paragraph.chars().peekable();
+    while let Some(ch) = chars.next() {
+        sentence.push(ch);
+        let at_end =
+            matches!(ch, '.' | '!' | '?') && chars.peek().is_none_or(|next| next.is_whitespace());
+        if at_end {
+            sentences.push(sentence.trim().to_string());
+            sentence.clear();
+        }
+    }
+    if !sentence.trim().is_empty() {
+        sentences.push(sentence.trim().to_string());
+    }
+    sentences
+}
+
+fn split_words(sentence: &str, max_tokens: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part: Vec<&str> = vec![];
+    let mut tokens = 0;
+    for word in sentence.split_whitespace() {
+        let word_tokens = count_tokens(word);
+        if tokens + word_tokens > max_tokens && !part.is_empty() {
+            parts.push(part.join(" "));
+            part.clear();
+            tokens = 0;
+        }
+        part.push(word);
+        tokens += word_tokens;
+    }
+    if !part.is_empty() {
+        parts.push(part.join(" "));
+    }
+    parts
+}
+
+/// Packs the units into chunks, a new chunk repeats the last units of the previous one
+fn pack_units(units: Vec<String>, max_tokens: usize, overlap_tokens: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk: Vec<(String, usize)> = vec![];
+    let mut tokens = 0;
+
+    for unit in units {
+        let unit_tokens = count_tokens(&unit);
+        if tokens + unit_tokens > max_tokens && !chunk.is_empty() {
+            chunks.push(join_units(&chunk));
+
+            let mut overlap = vec![];
+            let mut overlap_size = 0;
+            for (text, size) in chunk.iter().rev() {
+                if overlap_size + size > overlap_tokens
+                    || overlap_size + size + unit_tokens > max_tokens
+                {
+                    break;
+                }
+                overlap_size += size;
+                overlap.insert(0, (text.clone(), *size));
+            }
+            chunk = overlap;
+            tokens = overlap_size;
+        }
+
+        tokens += unit_tokens;
+        chunk.push((unit, unit_tokens));
+    }
+    if !chunk.is_empty() {
+        chunks.push(join_units(&chunk));
+    }
+
+    chunks
+}
+
+fn join_units(units: &[(String, usize)]) -> String {
+    let texts: Vec<&str> = units.iter().map(|(text, _)| text.as_str()).collect();
+    texts.join("\n\n")
+}
+
+/// Id of the chunk, the same text may be a chunk of several documents or of one twice.
+/// `occurrence` is the number of the same chunks earlier in the document, so the ids
+/// don't depend on the chunk positions and survive the edits of the other chunks
+fn chunk_id(source: &str, occurrence: usize, text: &str) -> String {
+    UuidGenerator::from(format!("{}#{}\n{}", source, occurrence, text).as_str()).to_string()
+}
+
+/// Ids of the chunks of the document in their order
+fn chunk_ids(source: &str, chunks: &[String]) -> Vec<String> {
+    let mut occurrences: HashMap<&str, usize> = HashMap::new();
+    chunks
+        .iter()
+        .map(|text| {
+            let occurrence = occurrences.entry(text.as_str()).or_default();
+            let id = chunk_id(source, *occurrence, text);
+            *occurrence += 1;
+            id
+        })
+        .collect()
+}
+
+fn write_samples(samples_dir: &Path, source: &str, chunks: Vec<String>) -> anyhow::Result<usize> {
+    let mut earlier = read_samples(samples_dir)?;
+    let ids = chunk_ids(source, &chunks);
+    let current_ids: HashSet<&String> = ids.iter().collect();
+    let mut deleted: Vec<DataSample> = earlier
+        .ids
+        .into_iter()
+        .filter(|(id, _)| !current_ids.contains(id))
+        .map(|(id, text)| DataSample {
+            id: Some(id),
+            text,
+            vector: None,
+            source: Some(source.to_string()),
+            tags: vec![],
+            language: None,
+        })
+        .collect();
+    if chunks.is_empty() && !deleted.is_empty() {
+        warn!(
+            "Document {} has no chunks anymore, delete its {} points from the collection by hand",
+            source,
+            deleted.len()
+        );
+    }
+
+    if samples_dir.exists() {
+        std::fs::remove_dir_all(samples_dir)?;
+    }
+    std::fs::create_dir_all(samples_dir)?;
+
+    for (index, (text, id)) in chunks.iter().zip(ids).enumerate() {
+        let model = MigrationModel {
+            delete: std::mem::take(&mut deleted),
+            create: DataSample {
+                vector: earlier.vectors.remove(text),
+                id: Some(id),
+                text: text.clone(),
+                source: Some(source.to_string()),
+                tags: vec![],
+                language: None,
+            },
+        };
+
+        let json_path = samples_dir.join(format!("{:04}.json", index));
+        let writer = BufWriter::new(File::create(json_path)?);
+        serde_json::to_writer_pretty(writer, &model)?;
+    }
+
+    Ok(chunks.len())
+}
+
+/// What the samples written earlier keep
+#[derive(Default)]
+struct EarlierSamples {
+    /// Embeddings by text, so the moved chunks keep them
+    vectors: HashMap<String, VectorData>,
+    /// Texts of the points made of the document and not deleted yet, by id
+    ids: BTreeMap<String, String>,
+}
+
+fn read_samples(samples_dir: &Path) -> anyhow::Result<EarlierSamples> {
+    let mut samples = EarlierSamples::default();
+    if !samples_dir.is_dir() {
+        return Ok(samples);
+    }
+
+    for entry in std::fs::read_dir(samples_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("json") {
+            continue;
+        }
+        let model: MigrationModel = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        // The deletes that have not been migrated yet are carried over
+        for sample in model.delete.into_iter().chain([model.create.clone()]) {
+            if let Some(id) = sample.id {
+                samples.ids.insert(id, sample.text);
+            }
+        }
+        if let Some(vector) = model.create.vector {
+            samples.vectors.insert(model.create.text, vector);
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ingest::{chunk_document, chunk_ids, ingest_documents, ChunkingParams};
+    use crate::models::migration_model::MigrationModel;
+    use nervo_bot_core::utils::context_builder::count_tokens;
+    use uuid::Uuid;
+
+    const DOCUMENT: &str = "# Office\n\nWe are open from 9 to 18.\n\n\
+        ## Parking\n\nParking is free. It is behind the building. \
+        Ask the guard for a pass at the entrance.\n\n\
+        ```\n# not a heading\n```\n\n# Contacts\n\nWrite to hello@nervoset.com";
+
+    #[test]
+    fn test_chunks_keep_headings_and_overlap() {
+        let params = ChunkingParams {
+            max_tokens: 20,
+            overlap_tokens: 8,
+        };
+        let chunks = chunk_document(DOCUMENT, params);
+
+        assert_eq!(chunks[0], "Office\n\nWe are open from 9 to 18.");
+        let parking: Vec<&String> = chunks
+            .iter()
+            .filter(|chunk| chunk.starts_with("Office > Parking\n\n"))
+            .collect();
+        assert!(parking.len() > 1);
+        assert!(parking[1].contains("It is behind the building."));
+        assert!(parking
+            .iter()
+            .any(|chunk| chunk.contains("# not a heading")));
+        assert_eq!(
+            chunks.last().unwrap(),
+            "Contacts\n\nWrite to hello@nervoset.com"
+        );
+        assert!(chunks.iter().all(|chunk| count_tokens(chunk) <= 24));
+    }
+
+    #[test]
+    fn test_ingest_writes_samples() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("nervo_ingest_{}", Uuid::new_v4()));
+        let docs_dir = root.join("docs");
+        std::fs::create_dir_all(docs_dir.join("handbook"))?;
+        std::fs::write(docs_dir.join("handbook/office.md"), DOCUMENT)?;
+        std::fs::write(
+            docs_dir.join("faq.html"),
+            "<h1>FAQ</h1><p>Coffee is free.</p>",
+        )?;
+        std::fs::write(docs_dir.join("logo.png"), [0u8; 4])?;
+
+        let params = ChunkingParams {
+            max_tokens: 400,
+            overlap_tokens: 50,
+        };
+        let agent_dir = root.join("dataset/nervoznyak");
+        let report = ingest_documents(&docs_dir, &agent_dir, params)?;
+        assert_eq!((report.documents, report.skipped), (2, 1));
+
+        let json = std::fs::read_to_string(agent_dir.join("faq/0000.json"))?;
+        let model: MigrationModel = serde_json::from_str(&json)?;
+        assert_eq!(model.create.text, "FAQ\n\nCoffee is free.");
+        assert_eq!(model.create.source.as_deref(), Some("faq.html"));
+        assert!(model.create.id.is_some());
+        assert!(agent_dir.join("handbook/office/0000.json").exists());
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+    #[test]
+    fn test_chunk_ids_survive_inserted_chunks() {
+        let chunks =
+            |texts: &[&str]| -> Vec<String> { texts.iter().map(|text| text.to_string()).collect() };
+        let ids = chunk_ids("faq.md", &chunks(&["Coffee", "Tea", "Coffee"]));
+        assert_ne!(ids[0], ids[2]);
+
+        let edited_ids = chunk_ids("faq.md", &chunks(&["Water", "Coffee", "Tea", "Coffee"]));
+        assert_eq!(edited_ids[1..], ids[..]);
+        assert_ne!(chunk_ids("menu.md", &chunks(&["Coffee"]))[0], ids[0]);
+    }
+
+    #[test]
+    fn test_reingest_deletes_dropped_chunks() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("nervo_ingest_{}", Uuid::new_v4()));
+        let docs_dir = root.join("docs");
+        std::fs::create_dir_all(&docs_dir)?;
+        let params = ChunkingParams {
+            max_tokens: 5,
+            overlap_tokens: 0,
+        };
+        let agent_dir = root.join("dataset/nervoznyak");
+        let read_model = |index: usize| -> anyhow::Result<MigrationModel> {
+            let json_path = agent_dir.join(format!("faq/{:04}.json", index));
+            Ok(serde_json::from_str(&std::fs::read_to_string(json_path)?)?)
+        };
+
+        std::fs::write(
+            docs_dir.join("faq.md"),
+            "Coffee is free.\n\nCoffee is free.",
+        )?;
+        ingest_documents(&docs_dir, &agent_dir, params)?;
+        let (first, second) = (read_model(0)?.create, read_model(1)?.create);
+        assert_eq!(first.text, second.text);
+        assert_ne!(first.id, second.id);
+
+        std::fs::write(docs_dir.join("faq.md"), "Coffee is free.")?;
+        ingest_documents(&docs_dir, &agent_dir, params)?;
+        let model = read_model(0)?;
+        assert_eq!(model.create.id, first.id);
+        let deleted: Vec<Option<String>> =
+            model.delete.into_iter().map(|sample| sample.id).collect();
+        assert_eq!(deleted, vec![second.id]);
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
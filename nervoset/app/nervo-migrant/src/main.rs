@@ -1,9 +1,18 @@
+mod ingest;
+mod migration_diff;
 mod models;
+mod reembed;
 
+use crate::ingest::{ChunkingParams, DEFAULT_MAX_TOKENS, DEFAULT_OVERLAP_TOKENS};
 use crate::models::migration_model::{MigrationModel, VectorData};
 use crate::models::migration_path_model::{MigrationMetaData, MigrationPlan};
+use crate::reembed::DEFAULT_BATCH_SIZE;
+use anyhow::bail;
+use async_openai::types::Embedding;
 use nervo_bot_core::config::common::NervoConfig;
 use nervo_bot_core::config::jarvis::JarvisAppState;
+use nervo_bot_core::context::memory_consolidation::MemoryConsolidation;
+use nervo_bot_core::context::user_memory::conclusions_collection;
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
@@ -20,7 +29,8 @@ use futures::FutureExt;
 use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use nervo_sdk::utils::cryptography::UuidGenerator;
 use tokio::time::Instant;
-use uuid::Uuid;
+
+const DATASET_PATH: &str = "../../dataset";
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -32,7 +42,66 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Dataset,
-    Migration,
+    /// Compares the dataset with the qdrant collections and prints the plan, nothing is changed
+    /// without `--apply`, `--dry-run` states that explicitly. The points without the filter fields (agent type, tags, language,
+    /// creation time) or embedded by another model get updated, and the collections
+    /// get the indexes of the filter fields they miss. New collections are created
+    /// as `<agent>_v1` behind the `<agent>` alias
+    Migration {
+        /// Executes the plan and writes the report
+        #[arg(long)]
+        apply: bool,
+        /// Only prints the plan, the default without `--apply`
+        #[arg(long, conflicts_with = "apply")]
+        dry_run: bool,
+        #[arg(long, default_value = "migration_report.json")]
+        report: PathBuf,
+    },
+    /// Splits the documents (markdown, text, html, pdf) into the dataset samples of the agent
+    Ingest {
+        /// Agent name, i.e. `nervoznyak`
+        #[arg(long)]
+        agent: String,
+        /// Folder with the documents
+        #[arg(long)]
+        source: PathBuf,
+        #[arg(long, default_value_t = DEFAULT_MAX_TOKENS)]
+        max_tokens: usize,
+        #[arg(long, default_value_t = DEFAULT_OVERLAP_TOKENS)]
+        overlap_tokens: usize,
+    },
+    /// Re-embeds the samples of another embedding model and moves the agent
+    /// to a new version of its collection. The collections without the keyword vectors
+    /// are moved too, so their hybrid search works. A collection named as the agent
+    /// needs `collections --cutover` first
+    Reembed {
+        /// Agent name, all the agents by default
+        #[arg(long)]
+        agent: Option<String>,
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
+        /// Builds a new collection even if every sample is up to date
+        #[arg(long)]
+        force: bool,
+    },
+    /// Lists the collections with their aliases
+    Collections {
+        /// Deletes the collection, i.e. an old version left by `reembed`,
+        /// unless an alias points to it
+        #[arg(long)]
+        delete: Option<String>,
+        /// Moves the collection named as the agent, i.e. `nervoznyak`, behind the agent alias,
+        /// as `reembed` requires. The agent misses its collection for the time of the copy,
+        /// so stop the bots first
+        #[arg(long, conflicts_with = "delete")]
+        cutover: Option<String>,
+    },
+    /// Merges similar conclusions about the users and forgets rarely recalled ones
+    Consolidate {
+        /// Only this user, all the users by default
+        #[arg(long)]
+        user_id: Option<u64>,
+    },
 }
 
 #[tokio::main]
@@ -69,20 +138,127 @@ async fn main() -> anyhow::Result<()> {
             // - update json files with embeddings
             // - commit and push changes to GitHub (manually)
             info!("Dataset preparation has been started");
-            let migration_plan = collect_jsons_content("../../dataset").await?;
+            let migration_plan = collect_jsons_content(DATASET_PATH).await?;
             enrich_datasets_with_embeddings(app_state, migration_plan).await?;
             info!("Dataset preparation step has been finished");
         }
-        Commands::Migration => {
+        Commands::Migration {
+            apply,
+            dry_run: _,
+            report,
+        } => {
             let start = Instant::now();
 
-            // Update qdrant collection (remove old records in qdrant if needed)
             info!("Migration preparation has been started");
-            let migration_plan = collect_jsons_content("../../dataset").await?;
-            migrate_qdrant_db(migration_plan, app_state).await?;
+            let migration_plan = collect_jsons_content(DATASET_PATH).await?;
+            let ai_db = &app_state.nervo_ai_db;
+            let diffs = migration_diff::plan_migration(&migration_plan, ai_db).await?;
+            for diff in diffs.iter() {
+                diff.print();
+            }
+
+            if apply {
+                migration_diff::apply_migration(diffs, ai_db.vector_store.as_ref(), &report)
+                    .await?;
+                let duration = start.elapsed();
+                info!("Migration completed for: {:?}", duration);
+            } else {
+                info!("Dry run, nothing has been changed. Run with --apply to migrate");
+            }
+        }
+        Commands::Ingest {
+            agent,
+            source,
+            max_tokens,
+            overlap_tokens,
+        } => {
+            let agent_type = NervoAgentType::try_from(agent.as_str()).agent_type;
+            if agent_type == AgentType::None {
+                bail!("Unknown agent: {}", agent);
+            }
+
+            let params = ChunkingParams {
+                max_tokens,
+                overlap_tokens,
+            };
+            let agent_dir = Path::new(DATASET_PATH).join(NervoAgentType::get_name(agent_type));
+            let report = ingest::ingest_documents(&source, &agent_dir, params)?;
+            info!(
+                "Ingestion has been finished: {:?}, run the dataset command to embed the chunks",
+                report
+            );
+        }
+        Commands::Reembed {
+            agent,
+            batch_size,
+            force,
+        } => {
+            let ai_db = &app_state.nervo_ai_db;
+            let migration_plan = collect_jsons_content(DATASET_PATH).await?;
+            for mut plan in migration_plan {
+                let agent_name = NervoAgentType::get_name(plan.agent_type);
+                if agent.as_ref().is_some_and(|agent| agent != &agent_name) {
+                    continue;
+                }
+
+                let reembedded =
+                    reembed::reembed_dataset(&ai_db.nervo_llm, &mut plan, batch_size).await?;
+                info!("{} samples of {} re-embedded", reembedded, agent_name);
+                // Collections made before the hybrid search get the keyword vectors this way
+                let keyword_indexed = !ai_db.vector_store.collection_exists(&agent_name).await?
+                    || ai_db.vector_store.is_keyword_indexed(&agent_name).await?;
+                if reembedded == 0 && keyword_indexed && !force {
+                    continue;
+                }
+                reembed::switch_collection(ai_db, &plan, batch_size).await?;
+            }
+            info!("Re-embedding has been finished");
+        }
+        Commands::Collections { delete, cutover } => {
+            let vector_store = app_state.nervo_ai_db.vector_store.as_ref();
+            if let Some(alias) = cutover {
+                reembed::cutover(&app_state.nervo_ai_db, &alias).await?;
+            }
+            let aliases = vector_store.list_aliases().await?;
+
+            if let Some(collection_name) = delete {
+                let alias = aliases
+                    .iter()
+                    .find(|alias| alias.collection_name == collection_name);
+                if let Some(alias) = alias {
+                    bail!(
+                        "Alias {} points to {}, keep it",
+                        alias.alias,
+                        collection_name
+                    );
+                }
+                vector_store.delete_collection(&collection_name).await?;
+                info!("Collection {} has been deleted", collection_name);
+            }
 
-            let duration = start.elapsed();
-            info!("Migration completed for: {:?}", duration);
+            let mut collections = vector_store.list_collections().await?;
+            collections.sort();
+            for collection_name in collections {
+                let collection_aliases: Vec<&str> = aliases
+                    .iter()
+                    .filter(|alias| alias.collection_name == collection_name)
+                    .map(|alias| alias.alias.as_str())
+                    .collect();
+                info!("{} aliases: {:?}", collection_name, collection_aliases);
+            }
+        }
+        Commands::Consolidate { user_id } => {
+            let config = &app_state.nervo_config.memory_consolidation;
+            let consolidation = MemoryConsolidation::new(&app_state.nervo_ai_db, config);
+            let report = match user_id {
+                Some(user_id) => {
+                    consolidation
+                        .consolidate(&conclusions_collection(user_id))
+                        .await?
+                }
+                None => consolidation.run().await?,
+            };
+            info!("Memory consolidation has been finished: {:?}", report);
         }
     }
 
@@ -171,110 +347,58 @@ fn find_json_files(
     .boxed()
 }
 
-async fn migrate_qdrant_db(
-    plans: Vec<MigrationPlan>,
-    app_state: Arc<JarvisAppState>,
-) -> anyhow::Result<()> {
-    info!("Start updating Qdrant collection");
-
-    let qdrant_db = &app_state.nervo_ai_db.qdrant;
-
-    // delete old records
-    for migration_plan in plans.iter() {
-        for migration_info in migration_plan.data_models.iter() {
-            let migration_model = &migration_info.migration_model;
-
-            // Need to delete an old version at first
-            delete_action(
-                migration_model,
-                migration_plan.agent_type,
-                app_state.clone(),
-            )
-            .await?;
-
-            let data_sample = migration_model.clone().create;
-            let id = data_sample.id.unwrap();
-            let text = data_sample.text;
-            let embedding = data_sample.vector.unwrap().embedding;
-
-            //check if qdrant already has this record
-            let records = qdrant_db
-                .find_by_id(migration_plan.agent_type, Uuid::parse_str(id.as_str())?)
-                .await?;
-            if records.result.is_empty() {
-                info!(
-                    "Save text of {:?} to qdrant: {:?}",
-                    migration_info.json_path, migration_plan.agent_type
-                );
-                let collection_name = NervoAgentType::get_name(migration_plan.agent_type);
-                qdrant_db
-                    .save(collection_name, text.as_str(), embedding)
-                    .await?;
-            }
-        }
-    }
-
-    Ok(())
-}
-
-async fn delete_action(
-    migration_model: &MigrationModel,
-    agent_type: AgentType,
-    app_state: Arc<JarvisAppState>,
-) -> anyhow::Result<()> {
-    for delete_item in migration_model.delete.iter() {
-        let qdrant_db = &app_state.nervo_ai_db.qdrant;
-
-        let id = delete_item
-            .id
-            .as_ref()
-            .map(|id_val| Uuid::parse_str(id_val.as_str()))
-            .unwrap()?;
-
-        let _ = qdrant_db.delete_by_id(agent_type, id).await?;
-    }
-    Ok(())
+/// Path of the dataset json, the answers cite it
+fn source_of(json_path: &Path) -> String {
+    json_path
+        .strip_prefix(DATASET_PATH)
+        .unwrap_or(json_path)
+        .to_string_lossy()
+        .to_string()
 }
 
 async fn enrich_datasets_with_embeddings(
     app_state: Arc<JarvisAppState>,
     migration_plans: Vec<MigrationPlan>,
 ) -> anyhow::Result<()> {
-    let qdrant_db = &app_state.nervo_ai_db.qdrant;
+    let ai_db = &app_state.nervo_ai_db;
+    let llm_config = &app_state.nervo_config.llm;
+    // every chunk is one round of concurrent requests, its samples are saved right after it,
+    // so an interrupted run keeps the embeddings it has paid for
+    let chunk_size =
+        llm_config.embeddings.batch_size.max(1) * llm_config.embeddings.concurrency.max(1);
 
     for migration in migration_plans.iter() {
-        for data_model in &migration.data_models {
-            let mut need_update = false;
-
-            let mut updated_model = data_model.migration_model.clone();
+        let (unembedded, embedded): (Vec<_>, Vec<_>) = migration
+            .data_models
+            .iter()
+            .partition(|data_model| data_model.migration_model.create.vector.is_none());
 
+        for data_model in embedded {
             if data_model.migration_model.create.id.is_none() {
-                let text = data_model.migration_model.create.text.as_str();
-                let id = UuidGenerator::from(text).to_string();
-
-                updated_model.create.id = Some(id);
-
-                need_update = true;
+                save_enriched_model(data_model, None, &llm_config.embedding_model_name)?;
             }
+        }
 
-            if data_model.migration_model.create.vector.is_none() {
-                // get embeddings and update model
-                let text = data_model.migration_model.create.text.as_str();
-
-                let embedding = qdrant_db.nervo_llm.text_to_embeddings(text).await?.unwrap();
-
-                let model_name = Some(app_state.nervo_config.llm.embedding_model_name.clone());
-                updated_model.create.vector = Some(VectorData {
-                    embedding,
-                    embedding_model_name: model_name,
-                });
-
-                need_update = true;
+        for chunk in unembedded.chunks(chunk_size) {
+            let texts = chunk
+                .iter()
+                .map(|data_model| data_model.migration_model.create.text.clone())
+                .collect();
+            let embeddings = ai_db.nervo_llm.embeddings(texts).await?;
+            if embeddings.len() != chunk.len() {
+                bail!(
+                    "Got {} embeddings for {} samples",
+                    embeddings.len(),
+                    chunk.len()
+                );
             }
 
-            if need_update {
-                save_updated_model_to_json(data_model, &updated_model)?;
-                info!("Dataset has been updated: {:?}", data_model.json_path);
+            for (data_model, embedding) in chunk.iter().zip(embeddings) {
+                save_enriched_model(
+                    data_model,
+                    Some(embedding),
+                    &llm_config.embedding_model_name,
+                )?;
             }
         }
     }
@@ -282,6 +406,30 @@ async fn enrich_datasets_with_embeddings(
     Ok(())
 }
 
+/// Saves the sample with its id and, when given, the embedding
+fn save_enriched_model(
+    data_model: &MigrationMetaData,
+    embedding: Option<Embedding>,
+    embedding_model_name: &str,
+) -> anyhow::Result<()> {
+    let mut updated_model = data_model.migration_model.clone();
+
+    if updated_model.create.id.is_none() {
+        let id = UuidGenerator::from(updated_model.create.text.as_str()).to_string();
+        updated_model.create.id = Some(id);
+    }
+    if let Some(embedding) = embedding {
+        updated_model.create.vector = Some(VectorData {
+            embedding,
+            embedding_model_name: Some(embedding_model_name.to_string()),
+        });
+    }
+
+    save_updated_model_to_json(data_model, &updated_model)?;
+    info!("Dataset has been updated: {:?}", data_model.json_path);
+    Ok(())
+}
+
 fn save_updated_model_to_json(
     data_model: &MigrationMetaData,
     updated_model: &MigrationModel,
@@ -295,19 +443,36 @@ fn save_updated_model_to_json(
 
 #[cfg(test)]
 mod test {
-    use crate::collect_jsons_content;
+    use crate::{collect_jsons_content, source_of, Cli, DATASET_PATH};
+    use clap::Parser;
     use nervo_sdk::agent_type::AgentType;
+    use std::path::Path;
+
+    #[test]
+    fn test_migration_dry_run() {
+        assert!(Cli::try_parse_from(["nervo-migrant", "migration", "--dry-run"]).is_ok());
+        assert!(Cli::try_parse_from(["nervo-migrant", "migration", "--apply"]).is_ok());
+        assert!(
+            Cli::try_parse_from(["nervo-migrant", "migration", "--dry-run", "--apply"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_source_of() {
+        let json_path = Path::new(DATASET_PATH).join("nervoznyak/about.json");
+        assert_eq!(source_of(&json_path), "nervoznyak/about.json");
+    }
 
     #[tokio::test]
     async fn test_collect_jsons_content() -> anyhow::Result<()> {
-        let jsons_content = collect_jsons_content("../../dataset").await?;
+        let jsons_content = collect_jsons_content(DATASET_PATH).await?;
         assert_eq!(jsons_content.len(), 0);
         Ok(())
     }
 
     #[tokio::test]
     async fn test_collect_jsons_content_one() -> anyhow::Result<()> {
-        let jsons_content = collect_jsons_content("../../dataset").await?;
+        let jsons_content = collect_jsons_content(DATASET_PATH).await?;
         let apps: Vec<AgentType> = jsons_content.iter().map(|plan| plan.agent_type).collect();
 
         Ok(())
@@ -0,0 +1,545 @@
+use crate::models::migration_path_model::{MigrationMetaData, MigrationPlan};
+use crate::reembed::next_version;
+use crate::source_of;
+use anyhow::bail;
+use nervo_bot_core::ai::ai_db::NervoAiDb;
+use nervo_bot_core::ai::vector_store::{
+    payload_fields, CollectionParams, PayloadIndex, VectorPayload, VectorPoint, VectorStore,
+};
+use nervo_sdk::agent_type::{AgentType, NervoAgentType};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// The model the vector of the point was made by, the stores may normalize the vectors
+/// so they can't be compared with the dataset ones
+const EMBEDDING_MODEL_FIELD: &str = "embedding_model";
+/// Payload fields the dataset sets, the points with other values get updated.
+/// `created_at` is set once, when the point is added
+const DATASET_FIELDS: [&str; 5] = [
+    payload_fields::SOURCE,
+    payload_fields::AGENT_TYPE,
+    payload_fields::TAGS,
+    payload_fields::LANGUAGE,
+    EMBEDDING_MODEL_FIELD,
+];
+
+/// A dataset sample that goes to the collection
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedPoint {
+    pub id: String,
+    pub json_path: PathBuf,
+    #[serde(skip)]
+    pub point: VectorPoint,
+}
+
+/// Difference between the dataset of the agent and its live collection,
+/// the ids are sorted
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionDiff {
+    pub agent_type: AgentType,
+    pub collection_name: String,
+    /// Dataset samples missing in the collection
+    pub added: Vec<PlannedPoint>,
+    /// Dataset samples whose text, payload fields or embedding model differ from
+    /// the collection ones, the points made before the filter fields existed get them this way
+    pub updated: Vec<PlannedPoint>,
+    /// Points listed in the `delete` sections that the collection still has
+    pub deleted: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Points of the collection that no dataset file mentions, they are never deleted
+    pub orphaned: Vec<String>,
+}
+
+impl CollectionDiff {
+    /// Compares the dataset of the agent with the points of its collection
+    pub fn build(
+        agent_type: AgentType,
+        data_models: &[MigrationMetaData],
+        live_points: Vec<VectorPoint>,
+    ) -> anyhow::Result<Self> {
+        let mut planned: BTreeMap<String, PlannedPoint> = BTreeMap::new();
+        let mut delete_ids = BTreeSet::new();
+        let agent_name = NervoAgentType::get_name(agent_type);
+        let now = unix_now();
+
+        for data_model in data_models {
+            let migration_model = &data_model.migration_model;
+            delete_ids.extend(
+                migration_model
+                    .delete
+                    .iter()
+                    .filter_map(|sample| sample.id.clone()),
+            );
+
+            let sample = &migration_model.create;
+            let (Some(id), Some(vector)) = (&sample.id, &sample.vector) else {
+                bail!(
+                    "Sample {:?} has no id or embedding, run the dataset command first",
+                    data_model.json_path
+                );
+            };
+
+            let source = sample
+                .source
+                .clone()
+                .unwrap_or_else(|| source_of(&data_model.json_path));
+            let mut payload = VectorPayload::from_text(&sample.text);
+            let fields = &mut payload.0;
+            fields.insert(payload_fields::SOURCE.to_string(), Value::from(source));
+            fields.insert(
+                payload_fields::AGENT_TYPE.to_string(),
+                Value::from(agent_name.as_str()),
+            );
+            if !sample.tags.is_empty() {
+                fields.insert(
+                    payload_fields::TAGS.to_string(),
+                    Value::from(sample.tags.clone()),
+                );
+            }
+            if let Some(language) = &sample.language {
+                fields.insert(
+                    payload_fields::LANGUAGE.to_string(),
+                    Value::from(language.as_str()),
+                );
+            }
+            if let Some(model_name) = &vector.embedding_model_name {
+                fields.insert(
+                    EMBEDDING_MODEL_FIELD.to_string(),
+                    Value::from(model_name.as_str()),
+                );
+            }
+            fields.insert(payload_fields::CREATED_AT.to_string(), Value::from(now));
+
+            let planned_point = PlannedPoint {
+                id: id.clone(),
+                json_path: data_model.json_path.clone(),
+                point: VectorPoint {
+                    id: id.clone(),
+                    vector: vector.embedding.embedding.clone(),
+                    payload,
+                },
+            };
+            if let Some(duplicate) = planned.insert(id.clone(), planned_point) {
+                warn!(
+                    "Sample {} is in {:?} and {:?}, the last one wins",
+                    id, duplicate.json_path, data_model.json_path
+                );
+            }
+        }
+
+        let mut diff = CollectionDiff {
+            agent_type,
+            collection_name: agent_name,
+            added: vec![],
+            updated: vec![],
+            deleted: vec![],
+            unchanged: vec![],
+            orphaned: vec![],
+        };
+
+        let live_points: BTreeMap<String, VectorPoint> = live_points
+            .into_iter()
+            .map(|point| (point.id.clone(), point))
+            .collect();
+
+        for (id, planned_point) in planned.iter() {
+            match live_points.get(id) {
+                None => diff.added.push(planned_point.clone()),
+                Some(live_point) => {
+                    let payload = &planned_point.point.payload;
+                    let created_at = live_point.payload.0.get(payload_fields::CREATED_AT);
+                    let same = live_point.payload.text() == payload.text()
+                        && DATASET_FIELDS
+                            .iter()
+                            .all(|field| live_point.payload.0.get(*field) == payload.0.get(*field))
+                        && created_at.is_some();
+                    if same {
+                        diff.unchanged.push(id.clone());
+                    } else {
+                        let mut planned_point = planned_point.clone();
+                        if let Some(created_at) = created_at {
+                            planned_point
+                                .point
+                                .payload
+                                .0
+                                .insert(payload_fields::CREATED_AT.to_string(), created_at.clone());
+                        }
+                        diff.updated.push(planned_point);
+                    }
+                }
+            }
+        }
+
+        for id in live_points.keys() {
+            if planned.contains_key(id) {
+                continue;
+            }
+            if delete_ids.contains(id) {
+                diff.deleted.push(id.clone());
+            } else {
+                diff.orphaned.push(id.clone());
+            }
+        }
+
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+
+    pub fn print(&self) {
+        info!(
+            "Collection {}: {} to add, {} to update, {} to delete, {} unchanged, {} orphaned",
+            self.collection_name,
+            self.added.len(),
+            self.updated.len(),
+            self.deleted.len(),
+            self.unchanged.len(),
+            self.orphaned.len()
+        );
+        for planned_point in self.added.iter() {
+            info!("  + {} {:?}", planned_point.id, planned_point.json_path);
+        }
+        for planned_point in self.updated.iter() {
+            info!("  ~ {} {:?}", planned_point.id, planned_point.json_path);
+        }
+        for id in self.deleted.iter() {
+            info!("  - {}", id);
+        }
+        for id in self.orphaned.iter() {
+            info!("  ? {} (orphaned)", id);
+        }
+    }
+
+    /// Executes the diff: deletes, then upserts the added and updated points,
+    /// the store splits them into batches
+    pub async fn apply(&self, vector_store: &dyn VectorStore) -> anyhow::Result<()> {
+        if !self.deleted.is_empty() {
+            vector_store
+                .delete(&self.collection_name, self.deleted.clone())
+                .await?;
+        }
+
+        let points: Vec<VectorPoint> = self
+            .added
+            .iter()
+            .chain(self.updated.iter())
+            .map(|planned_point| planned_point.point.clone())
+            .collect();
+        vector_store.upsert(&self.collection_name, points).await
+    }
+}
+
+/// Diffs of every agent the dataset has
+pub async fn plan_migration(
+    plans: &[MigrationPlan],
+    ai_db: &NervoAiDb,
+) -> anyhow::Result<Vec<CollectionDiff>> {
+    let mut diffs = vec![];
+    for plan in plans {
+        let collection_name = NervoAgentType::get_name(plan.agent_type);
+        let live_points = ai_db.read_collection(&collection_name).await?;
+        diffs.push(CollectionDiff::build(
+            plan.agent_type,
+            &plan.data_models,
+            live_points,
+        )?);
+    }
+    Ok(diffs)
+}
+
+/// What an applied migration has done
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationReport {
+    /// Unix time in seconds
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub applied: Vec<CollectionDiff>,
+    /// The migration stops on the first failed collection
+    pub error: Option<String>,
+}
+
+impl MigrationReport {
+    pub fn start() -> Self {
+        MigrationReport {
+            started_at: unix_now(),
+            finished_at: None,
+            applied: vec![],
+            error: None,
+        }
+    }
+
+    pub fn save(&mut self, report_path: &Path) -> anyhow::Result<()> {
+        self.finished_at = Some(unix_now());
+        let writer = BufWriter::new(File::create(report_path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        info!("Migration report: {:?}", report_path);
+        Ok(())
+    }
+}
+
+/// Applies the diffs one by one and writes the report, even if a diff fails
+pub async fn apply_migration(
+    diffs: Vec<CollectionDiff>,
+    vector_store: &dyn VectorStore,
+    report_path: &Path,
+) -> anyhow::Result<()> {
+    let mut report = MigrationReport::start();
+    for diff in diffs {
+        if let Err(err) = apply_diff(&diff, vector_store).await {
+            report.error = Some(format!("{}: {}", diff.collection_name, err));
+            report.save(report_path)?;
+            return Err(err);
+        }
+        report.applied.push(diff);
+    }
+    report.save(report_path)
+}
+
+async fn apply_diff(diff: &CollectionDiff, vector_store: &dyn VectorStore) -> anyhow::Result<()> {
+    if vector_store
+        .collection_exists(&diff.collection_name)
+        .await?
+    {
+        // Collections created before the filters get their indexes here
+        vector_store
+            .create_payload_indexes(&diff.collection_name, &PayloadIndex::filter_fields())
+            .await?;
+    } else if let Some(planned_point) = diff.added.first() {
+        // The bots read a new collection through the alias, so re-embedding switches it at once
+        let collections = vector_store.list_collections().await?;
+        let collection_name = next_version(&diff.collection_name, &collections);
+        info!(
+            "Create collection {} behind the alias {}",
+            collection_name, diff.collection_name
+        );
+        let params = CollectionParams::new(planned_point.point.vector.len());
+        vector_store
+            .create_collection(&collection_name, &params)
+            .await?;
+        vector_store
+            .switch_alias(&diff.collection_name, &collection_name)
+            .await?;
+    }
+
+    if diff.is_empty() {
+        info!("Collection {} is up to date", diff.collection_name);
+        return Ok(());
+    }
+    diff.apply(vector_store).await
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::migration_diff::{apply_migration, CollectionDiff};
+    use crate::models::migration_model::{DataSample, MigrationModel, VectorData};
+    use crate::models::migration_path_model::MigrationMetaData;
+    use async_openai::types::Embedding;
+    use nervo_bot_core::ai::memory_vector_store::InMemoryVectorStore;
+    use nervo_bot_core::ai::vector_store::{
+        payload_fields, VectorPayload, VectorPoint, VectorStore,
+    };
+    use nervo_sdk::agent_type::AgentType;
+    use serde_json::Value;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn data_model(id: &str, text: &str, delete: &[&str]) -> MigrationMetaData {
+        let sample = |id: &str| DataSample {
+            id: Some(id.to_string()),
+            text: text.to_string(),
+            vector: None,
+            source: Some("faq.md".to_string()),
+            tags: vec![],
+            language: None,
+        };
+        MigrationMetaData {
+            json_path: PathBuf::from(format!("{}.json", id)),
+            migration_model: MigrationModel {
+                delete: delete.iter().map(|id| sample(id)).collect(),
+                create: DataSample {
+                    vector: Some(VectorData {
+                        embedding_model_name: None,
+                        embedding: Embedding {
+                            index: 0,
+                            object: "embedding".to_string(),
+                            embedding: vec![1.0, 0.0],
+                        },
+                    }),
+                    ..sample(id)
+                },
+            },
+        }
+    }
+
+    fn live_point(id: &str, text: &str) -> VectorPoint {
+        let mut payload = VectorPayload::from_text(text);
+        payload
+            .0
+            .insert(payload_fields::SOURCE.to_string(), Value::from("faq.md"));
+        payload.0.insert(
+            payload_fields::AGENT_TYPE.to_string(),
+            Value::from("nervoznyak"),
+        );
+        payload
+            .0
+            .insert(payload_fields::CREATED_AT.to_string(), Value::from(1));
+        VectorPoint {
+            id: id.to_string(),
+            vector: vec![1.0, 0.0],
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_diff_sorts_points_out() -> anyhow::Result<()> {
+        let data_models = vec![
+            data_model("a", "new sample", &[]),
+            data_model("b", "same sample", &[]),
+            data_model("c", "edited sample", &["d"]),
+        ];
+        let live_points = vec![
+            live_point("b", "same sample"),
+            live_point("c", "old sample"),
+            live_point("d", "deleted sample"),
+            live_point("e", "manual sample"),
+        ];
+
+        let diff = CollectionDiff::build(AgentType::Nervoznyak, &data_models, live_points)?;
+        let ids = |points: &[crate::migration_diff::PlannedPoint]| -> Vec<String> {
+            points.iter().map(|point| point.id.clone()).collect()
+        };
+        assert_eq!(ids(&diff.added), vec!["a"]);
+        assert_eq!(ids(&diff.updated), vec!["c"]);
+        assert_eq!(diff.unchanged, vec!["b"]);
+        assert_eq!(diff.deleted, vec!["d"]);
+        assert_eq!(diff.orphaned, vec!["e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_never_deletes_recreated_samples() -> anyhow::Result<()> {
+        let data_models = vec![data_model("a", "sample", &["a"])];
+        let diff = CollectionDiff::build(
+            AgentType::Nervoznyak,
+            &data_models,
+            vec![live_point("a", "sample")],
+        )?;
+        assert!(diff.is_empty());
+        assert_eq!(diff.unchanged, vec!["a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_backfills_filter_fields() -> anyhow::Result<()> {
+        let mut data_models = vec![
+            data_model("a", "sample", &[]),
+            data_model("b", "sample", &[]),
+        ];
+        data_models[1].migration_model.create.tags = vec!["router".to_string()];
+        let mut old_point = live_point("a", "sample");
+        old_point.payload.0.remove(payload_fields::AGENT_TYPE);
+        old_point.payload.0.remove(payload_fields::CREATED_AT);
+
+        let diff = CollectionDiff::build(
+            AgentType::Nervoznyak,
+            &data_models,
+            vec![old_point, live_point("b", "sample")],
+        )?;
+        assert_eq!(diff.updated.len(), 2);
+        let payload = &diff.updated[0].point.payload.0;
+        assert_eq!(payload[payload_fields::AGENT_TYPE], "nervoznyak");
+        assert!(payload[payload_fields::CREATED_AT].as_u64() > Some(1));
+        // The time the point was added is kept
+        let payload = &diff.updated[1].point.payload.0;
+        assert_eq!(payload[payload_fields::TAGS], Value::from(vec!["router"]));
+        assert_eq!(payload[payload_fields::CREATED_AT], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_updates_reembedded_samples() -> anyhow::Result<()> {
+        let mut data_models = vec![data_model("a", "sample", &[])];
+        if let Some(vector) = data_models[0].migration_model.create.vector.as_mut() {
+            vector.embedding_model_name = Some("embedding-v2".to_string());
+        }
+        let mut live_point = live_point("a", "sample");
+        live_point
+            .payload
+            .0
+            .insert("embedding_model".to_string(), Value::from("embedding-v1"));
+
+        let diff = CollectionDiff::build(AgentType::Nervoznyak, &data_models, vec![live_point])?;
+        assert_eq!(diff.updated.len(), 1);
+        assert_eq!(
+            diff.updated[0].point.payload.0["embedding_model"],
+            "embedding-v2"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_executes_diff_and_writes_report() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        store
+            .upsert(
+                "nervoznyak",
+                vec![live_point("d", "deleted"), live_point("e", "manual")],
+            )
+            .await?;
+
+        let data_models = vec![data_model("a", "new sample", &["d"])];
+        let live_points = vec![live_point("d", "deleted"), live_point("e", "manual")];
+        let diff = CollectionDiff::build(AgentType::Nervoznyak, &data_models, live_points)?;
+
+        let report_path = std::env::temp_dir().join(format!("report_{}.json", Uuid::new_v4()));
+        apply_migration(vec![diff], &store, &report_path).await?;
+
+        let ids = vec!["a".to_string(), "d".to_string(), "e".to_string()];
+        let points = store.get("nervoznyak", ids).await?;
+        let ids: Vec<&str> = points.iter().map(|point| point.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "e"]);
+
+        let report: Value = serde_json::from_str(&std::fs::read_to_string(&report_path)?)?;
+        assert_eq!(report["applied"][0]["added"][0]["id"], "a");
+        assert_eq!(report["applied"][0]["orphaned"][0], "e");
+        std::fs::remove_file(report_path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_creates_collection_behind_alias() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        let data_models = vec![data_model("a", "new sample", &[])];
+        let diff = CollectionDiff::build(AgentType::Nervoznyak, &data_models, vec![])?;
+
+        let report_path = std::env::temp_dir().join(format!("report_{}.json", Uuid::new_v4()));
+        apply_migration(vec![diff], &store, &report_path).await?;
+
+        assert_eq!(store.list_collections().await?, vec!["nervoznyak_v1"]);
+        let aliases = store.list_aliases().await?;
+        assert_eq!(aliases[0].alias, "nervoznyak");
+        assert_eq!(aliases[0].collection_name, "nervoznyak_v1");
+        let points = store.get("nervoznyak", vec!["a".to_string()]).await?;
+        assert_eq!(points.len(), 1);
+        std::fs::remove_file(report_path)?;
+        Ok(())
+    }
+}
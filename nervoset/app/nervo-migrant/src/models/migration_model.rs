@@ -19,6 +19,14 @@ pub struct DataSample {
     pub text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub vector: Option<VectorData>,
+    /// Document the sample was ingested from, the json path is the source otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The search layers may filter the samples by tags and language
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -0,0 +1,335 @@
+use crate::migration_diff::CollectionDiff;
+use crate::models::migration_model::VectorData;
+use crate::models::migration_path_model::MigrationPlan;
+use crate::save_updated_model_to_json;
+use anyhow::bail;
+use nervo_bot_core::ai::ai_db::NervoAiDb;
+use nervo_bot_core::ai::nervo_llm::NervoLlm;
+use nervo_bot_core::ai::vector_store::{CollectionParams, VectorPoint};
+use nervo_sdk::agent_type::NervoAgentType;
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+/// Versioned collections are named `<alias>_v<version>`
+const VERSION_SEPARATOR: &str = "_v";
+
+/// Indexes of the samples embedded by another model than `model_name`, or not embedded at all
+pub fn stale_samples(plan: &MigrationPlan, model_name: &str) -> Vec<usize> {
+    plan.data_models
+        .iter()
+        .enumerate()
+        .filter(|(_, data_model)| {
+            data_model
+                .migration_model
+                .create
+                .vector
+                .as_ref()
+                .is_none_or(|vector| vector.embedding_model_name.as_deref() != Some(model_name))
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Re-embeds the stale samples of the dataset and saves them, returns their number
+pub async fn reembed_dataset(
+    nervo_llm: &NervoLlm,
+    plan: &mut MigrationPlan,
+    batch_size: usize,
+) -> anyhow::Result<usize> {
+    let model_name = nervo_llm.embedding_model_name().to_string();
+    let stale = stale_samples(plan, &model_name);
+
+    for batch in stale.chunks(batch_size.max(1)) {
+        let texts = batch
+            .iter()
+            .map(|index| plan.data_models[*index].migration_model.create.text.clone())
+            .collect();
+        let embeddings = nervo_llm.embeddings(texts).await?;
+
+        for (index, embedding) in batch.iter().zip(embeddings) {
+            let data_model = &mut plan.data_models[*index];
+            data_model.migration_model.create.vector = Some(VectorData {
+                embedding_model_name: Some(model_name.clone()),
+                embedding,
+            });
+            save_updated_model_to_json(data_model, &data_model.migration_model)?;
+        }
+        info!(
+            "Re-embedded {} samples of {:?}",
+            batch.len(),
+            plan.agent_type
+        );
+    }
+
+    Ok(stale.len())
+}
+
+/// Moves the collection named as the agent behind the agent alias: copies it
+/// to a new version, deletes it and points the alias to the copy. The agent misses
+/// its collection between the delete and the switch, so run it once per agent
+/// while the bots are stopped. Rerun it if the switch fails, the copy is kept.
+/// Returns the collection name the alias points to.
+pub async fn cutover(ai_db: &NervoAiDb, alias: &str) -> anyhow::Result<String> {
+    let vector_store = ai_db.vector_store.as_ref();
+    let aliases = vector_store.list_aliases().await?;
+    if let Some(existing) = aliases.iter().find(|existing| existing.alias == alias) {
+        bail!(
+            "Alias {} points to {} already",
+            alias,
+            existing.collection_name
+        );
+    }
+
+    let collections = vector_store.list_collections().await?;
+    let collection_name = if collections.iter().any(|name| name == alias) {
+        let collection_name = next_version(alias, &collections);
+        let points = ai_db.read_collection(alias).await?;
+        info!(
+            "Copy {} points of {} to {}",
+            points.len(),
+            alias,
+            collection_name
+        );
+        let Some(point) = points.first() else {
+            bail!("Collection {} is empty, delete it instead", alias);
+        };
+        vector_store
+            .create_collection(&collection_name, &CollectionParams::new(point.vector.len()))
+            .await?;
+        vector_store.upsert(&collection_name, points).await?;
+        vector_store.delete_collection(alias).await?;
+        collection_name
+    } else {
+        // the copy of an interrupted cutover
+        let Some(collection_name) = last_version(alias, &collections) else {
+            bail!("Collection {} doesn't exist", alias);
+        };
+        collection_name
+    };
+
+    vector_store.switch_alias(alias, &collection_name).await?;
+    info!("Alias {} points to {}", alias, collection_name);
+    Ok(collection_name)
+}
+
+/// Fills a new version of the agent collection with the dataset and the re-embedded
+/// orphaned points, then points the agent alias to it. Returns the new collection name.
+/// The previous versions are kept for a rollback. A collection named as the agent
+/// needs the [`cutover`] first.
+pub async fn switch_collection(
+    ai_db: &NervoAiDb,
+    plan: &MigrationPlan,
+    batch_size: usize,
+) -> anyhow::Result<String> {
+    let vector_store = ai_db.vector_store.as_ref();
+    let alias = NervoAgentType::get_name(plan.agent_type);
+    let collections = vector_store.list_collections().await?;
+    if collections.contains(&alias) {
+        bail!(
+            "Collection {} is not behind an alias, run `nervo-migrant collections --cutover {}`",
+            alias,
+            alias
+        );
+    }
+    let live_points = ai_db.read_collection(&alias).await?;
+    let collection_name = next_version(&alias, &collections);
+    info!("Build collection {} for {}", collection_name, alias);
+
+    let mut diff = CollectionDiff::build(plan.agent_type, &plan.data_models, vec![])?;
+    diff.collection_name = collection_name.clone();
+    if let Some(planned_point) = diff.added.first() {
+        let params = CollectionParams::new(planned_point.point.vector.len());
+        vector_store
+            .create_collection(&collection_name, &params)
+            .await?;
+    }
+    diff.apply(vector_store).await?;
+
+    let dataset_ids: HashSet<&str> = diff.added.iter().map(|point| point.id.as_str()).collect();
+    let orphaned: Vec<VectorPoint> = live_points
+        .into_iter()
+        .filter(|point| !dataset_ids.contains(point.id.as_str()))
+        .collect();
+    for batch in orphaned.chunks(batch_size.max(1)) {
+        let (points, texts): (Vec<&VectorPoint>, Vec<String>) = batch
+            .iter()
+            .filter_map(|point| Some((point, point.payload.text()?.to_string())))
+            .unzip();
+        if points.len() < batch.len() {
+            warn!(
+                "{} orphaned points have no text and are dropped",
+                batch.len() - points.len()
+            );
+        }
+
+        let embeddings = ai_db.nervo_llm.embeddings(texts).await?;
+        let points = points
+            .into_iter()
+            .zip(embeddings)
+            .map(|(point, embedding)| VectorPoint {
+                vector: embedding.embedding,
+                ..point.clone()
+            })
+            .collect();
+        vector_store.upsert(&collection_name, points).await?;
+    }
+
+    vector_store.switch_alias(&alias, &collection_name).await?;
+    info!("Alias {} points to {}", alias, collection_name);
+    Ok(collection_name)
+}
+
+/// `<alias>_v<n + 1>` for the highest existing version `n`
+pub fn next_version(alias: &str, collections: &[String]) -> String {
+    let version = last_version_number(alias, collections).unwrap_or_default() + 1;
+    format!("{}{}{}", alias, VERSION_SEPARATOR, version)
+}
+
+/// `<alias>_v<n>` for the highest existing version `n`
+fn last_version(alias: &str, collections: &[String]) -> Option<String> {
+    let version = last_version_number(alias, collections)?;
+    Some(format!("{}{}{}", alias, VERSION_SEPARATOR, version))
+}
+
+fn last_version_number(alias: &str, collections: &[String]) -> Option<u32> {
+    let prefix = format!("{}{}", alias, VERSION_SEPARATOR);
+    collections
+        .iter()
+        .filter_map(|name| name.strip_prefix(&prefix)?.parse::<u32>().ok())
+        .max()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::migration_model::{DataSample, MigrationModel, VectorData};
+    use crate::models::migration_path_model::{MigrationMetaData, MigrationPlan};
+    use crate::reembed::{
+        cutover, last_version, next_version, reembed_dataset, stale_samples, switch_collection,
+    };
+    use async_openai::types::Embedding;
+    use nervo_bot_core::ai::ai_db::NervoAiDb;
+    use nervo_bot_core::ai::fake_llm_provider::{FakeLlmProvider, FAKE_EMBEDDING_SIZE};
+    use nervo_bot_core::ai::llm_provider::LlmProviderType;
+    use nervo_bot_core::ai::memory_vector_store::InMemoryVectorStore;
+    use nervo_bot_core::ai::nervo_llm::{AudioParams, EmbeddingParams, NervoLlm, NervoLlmConfig};
+    use nervo_bot_core::ai::vector_store::{VectorPayload, VectorPoint};
+    use nervo_sdk::agent_type::AgentType;
+    use std::path::Path;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn fake_ai_db() -> NervoAiDb {
+        let llm_config = NervoLlmConfig {
+            api_key: String::new(),
+            model_name: "fake-model".to_string(),
+            embedding_model_name: "fake-embedding-v2".to_string(),
+            max_tokens: 100,
+            temperature: 0.0,
+            provider: LlmProviderType::OpenAi,
+            api_base: None,
+            embeddings: EmbeddingParams::default(),
+            audio: AudioParams::default(),
+        };
+        let nervo_llm = NervoLlm::with_provider(llm_config, Arc::new(FakeLlmProvider::default()));
+        NervoAiDb::with_store(Arc::new(InMemoryVectorStore::default()), nervo_llm)
+    }
+
+    fn data_model(dir: &Path, id: &str, model_name: &str) -> MigrationMetaData {
+        MigrationMetaData {
+            json_path: dir.join(format!("{}.json", id)),
+            migration_model: MigrationModel {
+                delete: vec![],
+                create: DataSample {
+                    id: Some(id.to_string()),
+                    text: format!("sample {}", id),
+                    vector: Some(VectorData {
+                        embedding_model_name: Some(model_name.to_string()),
+                        embedding: Embedding {
+                            index: 0,
+                            object: "embedding".to_string(),
+                            embedding: vec![1.0, 0.0],
+                        },
+                    }),
+                    source: None,
+                    tags: vec![],
+                    language: None,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_next_version() {
+        let collections = vec![
+            "kevin_v2".to_string(),
+            "nervoznyak_v3".to_string(),
+            "nervoznyak_vx".to_string(),
+        ];
+        assert_eq!(next_version("nervoznyak", &collections), "nervoznyak_v4");
+        assert_eq!(next_version("kevin", &[]), "kevin_v1");
+        assert_eq!(
+            last_version("nervoznyak", &collections),
+            Some("nervoznyak_v3".to_string())
+        );
+        assert_eq!(last_version("kevin", &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_reembed_and_switch_collection() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nervo_reembed_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let ai_db = fake_ai_db();
+
+        let mut plan = MigrationPlan {
+            agent_type: AgentType::Nervoznyak,
+            data_models: vec![
+                data_model(&dir, "a", "fake-embedding-v2"),
+                data_model(&dir, "b", "fake-embedding-v1"),
+            ],
+        };
+        assert_eq!(stale_samples(&plan, "fake-embedding-v2"), vec![1]);
+        plan.data_models[0]
+            .migration_model
+            .create
+            .vector
+            .as_mut()
+            .unwrap()
+            .embedding
+            .embedding = vec![0.0; FAKE_EMBEDDING_SIZE];
+
+        assert_eq!(reembed_dataset(&ai_db.nervo_llm, &mut plan, 10).await?, 1);
+        assert!(stale_samples(&plan, "fake-embedding-v2").is_empty());
+        assert!(dir.join("b.json").exists());
+
+        let old_point = VectorPoint {
+            id: "orphan".to_string(),
+            vector: vec![1.0, 0.0],
+            payload: VectorPayload::from_text("manual sample"),
+        };
+        ai_db
+            .vector_store
+            .upsert("nervoznyak", vec![old_point])
+            .await?;
+
+        assert!(switch_collection(&ai_db, &plan, 10).await.is_err());
+        assert_eq!(cutover(&ai_db, "nervoznyak").await?, "nervoznyak_v1");
+        assert!(cutover(&ai_db, "nervoznyak").await.is_err());
+        let original = ai_db.read_collection("nervoznyak").await?;
+        assert_eq!(original.len(), 1);
+        assert_eq!(original[0].id, "orphan");
+
+        let collection_name = switch_collection(&ai_db, &plan, 10).await?;
+        assert_eq!(collection_name, "nervoznyak_v2");
+
+        let points = ai_db.read_collection("nervoznyak").await?;
+        let ids: Vec<&str> = points.iter().map(|point| point.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "orphan"]);
+        assert!(points
+            .iter()
+            .all(|point| point.vector.len() == FAKE_EMBEDDING_SIZE));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+-- Users, their ids in external resources (i.e. TELEGRAM) and roles.
+-- The tables existed before the migrations were introduced, hence IF NOT EXISTS.
+CREATE TABLE IF NOT EXISTS user (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT UNIQUE,
+    info TEXT
+);
+
+CREATE TABLE IF NOT EXISTS user_external_ids (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id integer NOT NULL,
+    external_resource_code TEXT NOT NULL,
+    external_resource_id TEXT NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
+
+CREATE INDEX IF NOT EXISTS user_external_ids_resource
+    ON user_external_ids (external_resource_code, external_resource_id);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id integer,
+    role TEXT NOT NULL,
+    dt_from TEXT NOT NULL,
+    dt_to TEXT,
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
+
+-- Conversation of a user with an agent in a chat
+CREATE TABLE chats (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_type TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    UNIQUE (agent_type, chat_id, user_id)
+);
+
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER NOT NULL,
+    sender_id INTEGER,
+    role TEXT NOT NULL,
+    persistence TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY(chat_id) REFERENCES chats(id)
+);
+
+CREATE INDEX messages_chat ON messages (chat_id, id);
+
+-- Messages of a chat which are used as the history context, the oldest rows are dropped first
+CREATE TABLE context_windows (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    FOREIGN KEY(chat_id) REFERENCES chats(id),
+    FOREIGN KEY(message_id) REFERENCES messages(id)
+);
+
+CREATE INDEX context_windows_chat ON context_windows (chat_id, id);
+
+-- Legacy `table_*` tables which have been imported into the tables above
+CREATE TABLE legacy_table_imports (
+    table_name TEXT PRIMARY KEY,
+    imported_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
@@ -0,0 +1,2 @@
+-- Language the bot talks to the user in, i.e. "english"
+ALTER TABLE user ADD COLUMN language TEXT;
@@ -0,0 +1,8 @@
+-- Translations of the agent replies by sha256 of the text and the target language code
+CREATE TABLE IF NOT EXISTS translations (
+    content_hash TEXT NOT NULL,
+    language TEXT NOT NULL,
+    translation TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (content_hash, language)
+);
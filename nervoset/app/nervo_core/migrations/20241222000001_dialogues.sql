@@ -0,0 +1,10 @@
+-- Short-term dialogue cache of the memory agents, `interaction` is the json of UserInteraction
+CREATE TABLE IF NOT EXISTS dialogue_interactions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_type TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    interaction TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS dialogue_interactions_chat_idx ON dialogue_interactions (agent_type, chat_id, id);
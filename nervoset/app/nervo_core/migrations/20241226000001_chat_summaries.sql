@@ -0,0 +1,9 @@
+-- Rolling summary of the chat history which is too old to be kept in the context verbatim
+CREATE TABLE IF NOT EXISTS chat_summaries (
+    chat_id INTEGER PRIMARY KEY,
+    summary TEXT NOT NULL,
+    -- The last message folded into the summary
+    last_message_id INTEGER NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY(chat_id) REFERENCES chats(id)
+);
@@ -0,0 +1,2 @@
+-- Json array of the knowledge base chunks the assistant message cites, NULL if it cites nothing
+ALTER TABLE messages ADD COLUMN sources TEXT;
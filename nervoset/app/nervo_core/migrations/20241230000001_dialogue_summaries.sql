@@ -0,0 +1,10 @@
+-- Rolling summary of the dialogue interactions which don't fit into the prompt anymore
+CREATE TABLE IF NOT EXISTS dialogue_summaries (
+    agent_type TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    summary TEXT NOT NULL,
+    -- The last interaction folded into the summary
+    last_interaction_id INTEGER NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (agent_type, chat_id)
+);
@@ -1,45 +1,204 @@
+use crate::ai::memory_vector_store::InMemoryVectorStore;
 use crate::ai::nervo_llm::NervoLlm;
 use crate::ai::qdrant_db::QdrantDb;
-use crate::config::common::QdrantParams;
+use crate::ai::sqlite_vector_store::SqliteVectorStore;
+use crate::ai::vector_store::{
+    PayloadFilter, SearchQuery, VectorHit, VectorPayload, VectorPoint, VectorStore, VectorStoreType,
+};
+use crate::config::jarvis::JarvisConfig;
+use anyhow::bail;
 use anyhow::Result;
 use async_openai::types::Embedding;
-use qdrant_client::qdrant::SearchResponse;
+use nervo_sdk::agent_type::{AgentType, NervoAgentType};
+use nervo_sdk::utils::cryptography::UuidGenerator;
+use std::sync::Arc;
 use tracing::log::info;
+use uuid::Uuid;
 
 pub struct NervoAiDb {
-    pub qdrant: QdrantDb,
+    pub vector_store: Arc<dyn VectorStore>,
     pub nervo_llm: NervoLlm,
 }
 
 impl NervoAiDb {
-    pub fn build(config: &QdrantParams, nervo_llm: NervoLlm) -> Result<Self> {
-        let qdrant = QdrantDb::try_from(config, nervo_llm.clone())?;
-        Ok(NervoAiDb { qdrant, nervo_llm })
+    pub fn build(config: &JarvisConfig, nervo_llm: NervoLlm) -> Result<Self> {
+        let vector_store: Arc<dyn VectorStore> = match config.vector_store {
+            VectorStoreType::Qdrant => Arc::new(QdrantDb::try_from(&config.qdrant)?),
+            VectorStoreType::Memory => Arc::new(InMemoryVectorStore::default()),
+            VectorStoreType::Sqlite => {
+                Arc::new(SqliteVectorStore::try_init(config.database.clone())?)
+            }
+        };
+        info!("Vector store: {:?}", config.vector_store);
+
+        Ok(NervoAiDb::with_store(vector_store, nervo_llm))
+    }
+
+    pub fn with_store(vector_store: Arc<dyn VectorStore>, nervo_llm: NervoLlm) -> Self {
+        NervoAiDb {
+            vector_store,
+            nervo_llm,
+        }
     }
 }
 
 impl NervoAiDb {
+    pub async fn save_text(&self, collection_name: &str, text: &str) -> Result<()> {
+        let Some(embedding) = self.nervo_llm.text_to_embeddings(text).await? else {
+            bail!("No embedding data found.");
+        };
+
+        self.save(collection_name, text, embedding).await
+    }
+
+    pub async fn save(
+        &self,
+        collection_name: &str,
+        text: &str,
+        embedding: Embedding,
+    ) -> Result<()> {
+        self.save_embedded(collection_name, VectorPayload::from_text(text), embedding)
+            .await
+    }
+
+    /// Saves the payload with the ready embedding of its `text` field
+    pub async fn save_embedded(
+        &self,
+        collection_name: &str,
+        payload: VectorPayload,
+        embedding: Embedding,
+    ) -> Result<()> {
+        let Some(text) = payload.text() else {
+            bail!("Payload has no text");
+        };
+        let point = VectorPoint {
+            id: UuidGenerator::from(text).to_string(),
+            vector: embedding.embedding,
+            payload,
+        };
+
+        self.vector_store.upsert(collection_name, vec![point]).await
+    }
+
+    /// Saves the payload with the embedding of its `text` field
+    pub async fn save_payload(&self, collection_name: &str, payload: VectorPayload) -> Result<()> {
+        let Some(text) = payload.text() else {
+            bail!("Payload has no text to embed");
+        };
+        let Some(embedding) = self.nervo_llm.text_to_embeddings(text).await? else {
+            bail!("No embedding data found.");
+        };
+
+        let point = VectorPoint {
+            id: UuidGenerator::from(text).to_string(),
+            vector: embedding.embedding,
+            payload,
+        };
+        self.vector_store.upsert(collection_name, vec![point]).await
+    }
+
     pub async fn text_search(
         &self,
         collection_name: &str,
         search_text: String,
         vectors_limit: u64,
-    ) -> Result<SearchResponse> {
-        info!("Starting QDrant db search...");
-        self.qdrant
-            .text_search(collection_name, search_text, vectors_limit)
-            .await
+    ) -> Result<Vec<VectorHit>> {
+        self.query_text(
+            collection_name,
+            search_text,
+            vectors_limit,
+            false,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Vector search among the points that pass the filter,
+    /// `hybrid` fuses it with the keyword search by the same text.
+    /// `min_score` applies to the vector hits before the fusion
+    pub async fn query_text(
+        &self,
+        collection_name: &str,
+        search_text: String,
+        limit: u64,
+        hybrid: bool,
+        filter: Option<PayloadFilter>,
+        min_score: Option<f32>,
+    ) -> Result<Vec<VectorHit>> {
+        info!("Starting vector db search...");
+        let Some(embedding) = self.nervo_llm.text_to_embeddings(&search_text).await? else {
+            bail!("No embedding data found.");
+        };
+
+        let query = SearchQuery {
+            vector: embedding.embedding,
+            keywords: hybrid.then_some(search_text),
+            filter,
+            min_score,
+            limit,
+        };
+        self.vector_store.query(collection_name, query).await
     }
 
     pub async fn vector_search(
         &self,
         collection_name: &str,
-        embedding: Embedding,
+        embedding: Vec<f32>,
         limit: u64,
-    ) -> Result<SearchResponse> {
-        info!("Starting QDrant db search...");
-        self.qdrant
-            .vector_search(collection_name, embedding.embedding, limit)
+    ) -> Result<Vec<VectorHit>> {
+        info!("Starting vector db search...");
+        self.vector_store
+            .search(collection_name, embedding, limit)
+            .await
+    }
+
+    /// All the points of the collection
+    pub async fn read_collection(&self, collection_name: &str) -> Result<Vec<VectorPoint>> {
+        self.vector_store.scroll_all(collection_name).await
+    }
+
+    pub async fn find_by_id(&self, agent_type: AgentType, id: Uuid) -> Result<Option<VectorPoint>> {
+        let collection_name = NervoAgentType::get_name(agent_type);
+        let points = self
+            .vector_store
+            .get(&collection_name, vec![id.to_string()])
+            .await?;
+
+        Ok(points.into_iter().next())
+    }
+
+    pub async fn delete_by_id(&self, agent_type: AgentType, id: Uuid) -> Result<()> {
+        let collection_name = NervoAgentType::get_name(agent_type);
+        self.vector_store
+            .delete(&collection_name, vec![id.to_string()])
             .await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::ai::ai_db::NervoAiDb;
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::memory_vector_store::InMemoryVectorStore;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_text_search_finds_saved_text() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::default());
+        let ai_db = NervoAiDb::with_store(Arc::new(InMemoryVectorStore::default()), nervo_llm);
+
+        ai_db.save_text("facts", "cats like warm milk").await?;
+        ai_db
+            .save_text("facts", "rust has no garbage collector")
+            .await?;
+
+        let hits = ai_db
+            .text_search("facts", "what do cats like".to_string(), 1)
+            .await?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].payload.text(), Some("cats like warm milk"));
+        Ok(())
+    }
+}
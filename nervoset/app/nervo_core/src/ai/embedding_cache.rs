@@ -0,0 +1,79 @@
+use nervo_sdk::utils::cryptography::Sha256Generator;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Embeddings by the digest of the model name and the text.
+/// When the cache is full the oldest embeddings are evicted first.
+#[derive(Debug, Default)]
+pub struct EmbeddingCache {
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    vectors: HashMap<String, Vec<f32>>,
+    order: VecDeque<String>,
+}
+
+impl EmbeddingCache {
+    /// Zero capacity turns the cache off
+    pub fn new(capacity: usize) -> Self {
+        EmbeddingCache {
+            capacity,
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    pub fn get(&self, model_name: &str, text: &str) -> Option<Vec<f32>> {
+        let entries = self
+            .entries
+            .lock()
+            .expect("Embedding cache lock is poisoned");
+        entries.vectors.get(&cache_key(model_name, text)).cloned()
+    }
+
+    pub fn insert(&self, model_name: &str, text: &str, vector: Vec<f32>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = cache_key(model_name, text);
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("Embedding cache lock is poisoned");
+        if entries.vectors.insert(key.clone(), vector).is_some() {
+            return;
+        }
+        entries.order.push_back(key);
+
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.vectors.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn cache_key(model_name: &str, text: &str) -> String {
+    let content = format!("{}\n{}", model_name, text);
+    Sha256Generator::digest_hex_str(content.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::embedding_cache::EmbeddingCache;
+
+    #[test]
+    fn test_cache_evicts_oldest() {
+        let cache = EmbeddingCache::new(2);
+        cache.insert("model", "a", vec![1.0]);
+        cache.insert("model", "b", vec![2.0]);
+        cache.insert("model", "c", vec![3.0]);
+
+        assert_eq!(cache.get("model", "a"), None);
+        assert_eq!(cache.get("model", "c"), Some(vec![3.0]));
+        assert_eq!(cache.get("other-model", "c"), None);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::ai::llm_provider::{LlmProvider, LlmTextStream, RateLimitError};
+use anyhow::Result;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestUserMessageContent,
+    CreateChatCompletionRequest, CreateChatCompletionResponse, CreateEmbeddingRequest,
+    CreateEmbeddingResponse, Embedding, EmbeddingInput, EmbeddingUsage,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream;
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const FAKE_EMBEDDING_SIZE: usize = 64;
+
+/// Deterministic in-process provider for tests.
+/// Chat replies are taken from the queue, or the last user message is echoed back.
+/// Embeddings are hashed bags of words, so texts sharing words are close to each other.
+#[derive(Debug, Default)]
+pub struct FakeLlmProvider {
+    replies: Mutex<VecDeque<String>>,
+    flagged_words: Vec<String>,
+    /// The next embedding requests that fail with the rate limit
+    rate_limits: AtomicUsize,
+    embedding_requests: AtomicUsize,
+}
+
+impl FakeLlmProvider {
+    pub fn with_replies(replies: Vec<&str>) -> Self {
+        FakeLlmProvider {
+            replies: Mutex::new(replies.into_iter().map(String::from).collect()),
+            ..FakeLlmProvider::default()
+        }
+    }
+
+    pub fn with_rate_limits(self, rate_limits: usize) -> Self {
+        self.rate_limits.store(rate_limits, Ordering::SeqCst);
+        self
+    }
+
+    /// Embedding requests received so far, the rate limited ones included
+    pub fn embedding_requests(&self) -> usize {
+        self.embedding_requests.load(Ordering::SeqCst)
+    }
+
+    pub fn with_flagged_words(mut self, flagged_words: Vec<&str>) -> Self {
+        self.flagged_words = flagged_words.into_iter().map(String::from).collect();
+        self
+    }
+
+    fn next_reply(&self, request: &CreateChatCompletionRequest) -> String {
+        let mut replies = self.replies.lock().expect("Fake provider lock is poisoned");
+        replies
+            .pop_front()
+            .unwrap_or_else(|| last_user_text(request))
+    }
+
+    pub fn fake_embedding(text: &str) -> Vec<f32> {
+        let mut vector = vec![0f32; FAKE_EMBEDDING_SIZE];
+        for word in text.split_whitespace() {
+            let mut hasher = DefaultHasher::new();
+            word.to_lowercase().hash(&mut hasher);
+            let index = (hasher.finish() % FAKE_EMBEDDING_SIZE as u64) as usize;
+            vector[index] += 1.0;
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+fn last_user_text(request: &CreateChatCompletionRequest) -> String {
+    request
+        .messages
+        .iter()
+        .rev()
+        .find_map(|msg| match msg {
+            ChatCompletionRequestMessage::User(user_msg) => match &user_msg.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => Some(text.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl LlmProvider for FakeLlmProvider {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse> {
+        let reply = self.next_reply(&request);
+        let response = json!({
+            "id": "fake-completion",
+            "object": "chat.completion",
+            "created": 0,
+            "model": request.model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": reply},
+                "finish_reason": "stop"
+            }]
+        });
+        Ok(serde_json::from_value(response)?)
+    }
+
+    async fn chat_stream(&self, request: CreateChatCompletionRequest) -> Result<LlmTextStream> {
+        let reply = self.next_reply(&request);
+        let deltas: Vec<Result<String>> = reply
+            .split_inclusive(' ')
+            .map(|delta| Ok(delta.to_string()))
+            .collect();
+        Ok(stream::iter(deltas).boxed())
+    }
+
+    async fn embeddings(&self, request: CreateEmbeddingRequest) -> Result<CreateEmbeddingResponse> {
+        self.embedding_requests.fetch_add(1, Ordering::SeqCst);
+        let rate_limited = self
+            .rate_limits
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if rate_limited {
+            let retry_after = Some(Duration::from_millis(1));
+            return Err(RateLimitError { retry_after }.into());
+        }
+
+        let texts = match request.input {
+            EmbeddingInput::String(text) => vec![text],
+            EmbeddingInput::StringArray(texts) => texts,
+            _ => vec![],
+        };
+
+        let data = texts
+            .iter()
+            .enumerate()
+            .map(|(index, text)| Embedding {
+                index: index as u32,
+                object: "embedding".to_string(),
+                embedding: FakeLlmProvider::fake_embedding(text),
+            })
+            .collect();
+
+        Ok(CreateEmbeddingResponse {
+            object: "list".to_string(),
+            model: request.model,
+            data,
+            usage: EmbeddingUsage {
+                prompt_tokens: 0,
+                total_tokens: 0,
+            },
+        })
+    }
+
+    async fn is_flagged(&self, text: &str) -> Result<bool> {
+        Ok(self.flagged_words.iter().any(|word| text.contains(word)))
+    }
+
+    async fn transcribe(&self, file_path: &str) -> Result<String> {
+        Ok(format!("Transcription of {}", file_path))
+    }
+
+    async fn speech(&self, text: &str) -> Result<Bytes> {
+        Ok(Bytes::from(text.to_string()))
+    }
+}
@@ -0,0 +1,162 @@
+use crate::ai::vector_store::{VectorHit, VectorPoint};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Name of the sparse vector of the Qdrant collections
+pub const SPARSE_VECTOR_NAME: &str = "bm25";
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+/// Qdrant weighs the terms by IDF itself, but it doesn't know the average document length
+const AVERAGE_DOCUMENT_TOKENS: f32 = 256.0;
+
+/// Lowercased words and numbers, so product codes like `XR-200` become `xr` and `200`
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|ch: char| !ch.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+/// Keyword vector with the hashed tokens as the indices
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SparseVector {
+    pub indices: Vec<u32>,
+    pub values: Vec<f32>,
+}
+
+impl SparseVector {
+    /// BM25 term frequency weights of the document
+    pub fn document(text: &str) -> Self {
+        let tokens = tokenize(text);
+        let length_norm = 1.0 - B + B * tokens.len() as f32 / AVERAGE_DOCUMENT_TOKENS;
+
+        let mut frequencies: HashMap<u32, f32> = HashMap::new();
+        for token in &tokens {
+            *frequencies.entry(token_index(token)).or_default() += 1.0;
+        }
+        Self::from_weights(frequencies.into_iter().map(|(index, frequency)| {
+            let weight = frequency * (K1 + 1.0) / (frequency + K1 * length_norm);
+            (index, weight)
+        }))
+    }
+
+    /// Every query term weighs the same
+    pub fn query(text: &str) -> Self {
+        let indices: HashSet<u32> = tokenize(text)
+            .iter()
+            .map(|token| token_index(token))
+            .collect();
+        Self::from_weights(indices.into_iter().map(|index| (index, 1.0)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    fn from_weights(weights: impl Iterator<Item = (u32, f32)>) -> Self {
+        let mut weights: Vec<(u32, f32)> = weights.collect();
+        weights.sort_by_key(|(index, _)| *index);
+        let (indices, values) = weights.into_iter().unzip();
+        SparseVector { indices, values }
+    }
+}
+
+/// FNV-1a, the indices are stored in the collections, so the hash must not change between releases
+fn token_index(token: &str) -> u32 {
+    token.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Scores the `text` of the points by the query, points without the query terms are left out
+pub fn bm25_rank(points: &[VectorPoint], query: &str, limit: u64) -> Vec<VectorHit> {
+    let query_tokens: HashSet<String> = tokenize(query).into_iter().collect();
+    let documents: Vec<Vec<String>> = points
+        .iter()
+        .map(|point| tokenize(point.payload.text().unwrap_or_default()))
+        .collect();
+    if documents.is_empty() || query_tokens.is_empty() {
+        return vec![];
+    }
+
+    let average_length =
+        documents.iter().map(Vec::len).sum::<usize>() as f32 / documents.len() as f32;
+    let documents_count = documents.len() as f32;
+    let idf: HashMap<&String, f32> = query_tokens
+        .iter()
+        .map(|token| {
+            let frequency = documents
+                .iter()
+                .filter(|document| document.contains(token))
+                .count() as f32;
+            let idf = ((documents_count - frequency + 0.5) / (frequency + 0.5) + 1.0).ln();
+            (token, idf)
+        })
+        .collect();
+
+    let mut hits: Vec<VectorHit> = points
+        .iter()
+        .zip(&documents)
+        .filter_map(|(point, document)| {
+            let length_norm = 1.0 - B + B * document.len() as f32 / average_length.max(1.0);
+            let score: f32 = idf
+                .iter()
+                .map(|(token, idf)| {
+                    let frequency = document.iter().filter(|word| word == token).count() as f32;
+                    idf * frequency * (K1 + 1.0) / (frequency + K1 * length_norm)
+                })
+                .sum();
+            (score > 0.0).then(|| VectorHit {
+                id: point.id.clone(),
+                score,
+                payload: point.payload.clone(),
+                vector: Some(point.vector.clone()),
+            })
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    hits.truncate(limit as usize);
+    hits
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::keyword_search::{bm25_rank, tokenize, SparseVector};
+    use crate::ai::vector_store::{VectorPayload, VectorPoint};
+
+    #[test]
+    fn test_bm25_finds_exact_codes() {
+        assert_eq!(
+            tokenize("Router XR-200, v2!"),
+            vec!["router", "xr", "200", "v2"]
+        );
+
+        let points: Vec<VectorPoint> = [
+            "the router supports mesh networks",
+            "XR-200 router firmware update",
+            "how to reset the modem",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(id, text)| VectorPoint {
+            id: id.to_string(),
+            vector: vec![1.0],
+            payload: VectorPayload::from_text(text),
+        })
+        .collect();
+
+        let hits = bm25_rank(&points, "firmware for xr-200", 10);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, "1");
+
+        let query = SparseVector::query("xr 200 xr");
+        assert_eq!(query.indices.len(), 2);
+        let document = SparseVector::document("XR-200 router firmware update");
+        assert!(query
+            .indices
+            .iter()
+            .all(|index| document.indices.contains(index)));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::ai::nervo_llm::AudioParams;
+use anyhow::Result;
+use anyhow::{anyhow, bail};
+use async_openai::config::{Config, OpenAIConfig};
+use async_openai::types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateEmbeddingRequest, CreateEmbeddingResponse, CreateModerationRequest, ModerationInput,
+};
+use async_openai::Client;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use openai_dive::v1::api::Client as DiveClient;
+use openai_dive::v1::resources::audio::{
+    AudioOutputFormat, AudioSpeechParameters, AudioSpeechResponseFormat, AudioTranscriptionFile,
+    AudioTranscriptionParameters, AudioVoice,
+};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+use std::fmt::Debug;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::info;
+
+const OPEN_AI_TRANSCRIPTION_MODEL: &str = "whisper-1";
+const OPEN_AI_SPEECH_MODEL: &str = "tts-1";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Long enough for a full chat completion of a slow local model
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Which backend serves the LLM requests of [`crate::ai::nervo_llm::NervoLlm`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmProviderType {
+    #[default]
+    OpenAi,
+    /// OpenAI-compatible self-hosted endpoint (Ollama, llama.cpp server)
+    Local,
+}
+
+/// The provider has replied with 429, `retry_after` is the delay it asked for
+#[derive(Debug, Error)]
+#[error("LLM provider rate limit, retry after {retry_after:?}")]
+pub struct RateLimitError {
+    pub retry_after: Option<Duration>,
+}
+
+/// Text deltas of a streamed chat completion, in the order they were generated
+pub type LlmTextStream = BoxStream<'static, Result<String>>;
+
+/// Everything Nervo asks from a language model backend
+#[async_trait]
+pub trait LlmProvider: Debug + Send + Sync {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse>;
+
+    async fn chat_stream(&self, request: CreateChatCompletionRequest) -> Result<LlmTextStream>;
+
+    /// Embeds every input of the request, fails with [`RateLimitError`] on 429
+    async fn embeddings(&self, request: CreateEmbeddingRequest) -> Result<CreateEmbeddingResponse>;
+
+    /// Returns `true` if the text has been flagged by the provider
+    async fn is_flagged(&self, text: &str) -> Result<bool>;
+
+    async fn transcribe(&self, file_path: &str) -> Result<String>;
+
+    async fn speech(&self, text: &str) -> Result<Bytes>;
+}
+
+#[derive(Clone, Debug)]
+pub struct OpenAiProvider {
+    client: Client<OpenAIConfig>,
+    http_client: reqwest::Client,
+    audio_client: DiveClient,
+    transcription_model: String,
+    speech_model: String,
+}
+
+impl OpenAiProvider {
+    pub fn new(config: OpenAIConfig, api_key: &str, audio: &AudioParams) -> Result<Self> {
+        let http_client = http_client()?;
+        Ok(OpenAiProvider {
+            client: Client::with_config(config).with_http_client(http_client.clone()),
+            http_client,
+            audio_client: DiveClient::new(api_key.to_string()),
+            transcription_model: audio
+                .transcription_model_name
+                .clone()
+                .unwrap_or_else(|| OPEN_AI_TRANSCRIPTION_MODEL.to_string()),
+            speech_model: audio
+                .speech_model_name
+                .clone()
+                .unwrap_or_else(|| OPEN_AI_SPEECH_MODEL.to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse> {
+        Ok(self.client.chat().create(request).await?)
+    }
+
+    async fn chat_stream(&self, request: CreateChatCompletionRequest) -> Result<LlmTextStream> {
+        let stream = self.client.chat().create_stream(request).await?;
+        Ok(completion_deltas(stream))
+    }
+
+    async fn embeddings(&self, request: CreateEmbeddingRequest) -> Result<CreateEmbeddingResponse> {
+        post_embeddings(&self.http_client, self.client.config(), request).await
+    }
+
+    async fn is_flagged(&self, text: &str) -> Result<bool> {
+        let request = CreateModerationRequest {
+            input: ModerationInput::from(text),
+            model: None,
+        };
+
+        let response = self.client.moderations().create(request).await?;
+        Ok(response.results.iter().any(|property| property.flagged))
+    }
+
+    async fn transcribe(&self, file_path: &str) -> Result<String> {
+        transcribe_with(&self.audio_client, &self.transcription_model, file_path).await
+    }
+
+    async fn speech(&self, text: &str) -> Result<Bytes> {
+        speech_with(&self.audio_client, &self.speech_model, text).await
+    }
+}
+
+/// Talks to a self-hosted server through its OpenAI-compatible api.
+/// Such servers have no moderation endpoint, so nothing gets flagged,
+/// and audio works only when its models are configured.
+#[derive(Clone, Debug)]
+pub struct LocalLlmProvider {
+    client: Client<OpenAIConfig>,
+    http_client: reqwest::Client,
+    audio_client: DiveClient,
+    audio: AudioParams,
+}
+
+impl LocalLlmProvider {
+    pub fn new(api_base: &str, api_key: &str, audio: &AudioParams) -> Result<Self> {
+        let config = OpenAIConfig::new()
+            .with_api_base(api_base)
+            .with_api_key(api_key);
+
+        let mut audio_client = DiveClient::new(api_key.to_string());
+        audio_client.base_url = api_base.to_string();
+
+        let http_client = http_client()?;
+        Ok(LocalLlmProvider {
+            client: Client::with_config(config).with_http_client(http_client.clone()),
+            http_client,
+            audio_client,
+            audio: audio.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for LocalLlmProvider {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse> {
+        Ok(self.client.chat().create(request).await?)
+    }
+
+    async fn chat_stream(&self, request: CreateChatCompletionRequest) -> Result<LlmTextStream> {
+        let stream = self.client.chat().create_stream(request).await?;
+        Ok(completion_deltas(stream))
+    }
+
+    async fn embeddings(&self, request: CreateEmbeddingRequest) -> Result<CreateEmbeddingResponse> {
+        post_embeddings(&self.http_client, self.client.config(), request).await
+    }
+
+    async fn is_flagged(&self, _text: &str) -> Result<bool> {
+        info!("Local LLM provider has no moderation, skipping");
+        Ok(false)
+    }
+
+    async fn transcribe(&self, file_path: &str) -> Result<String> {
+        let Some(model) = &self.audio.transcription_model_name else {
+            bail!("Local LLM provider requires audio.transcription_model_name");
+        };
+        transcribe_with(&self.audio_client, model, file_path).await
+    }
+
+    async fn speech(&self, text: &str) -> Result<Bytes> {
+        let Some(model) = &self.audio.speech_model_name else {
+            bail!("Local LLM provider requires audio.speech_model_name");
+        };
+        speech_with(&self.audio_client, model, text).await
+    }
+}
+
+fn http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()?)
+}
+
+// async-openai retries the rate limits on its own and hides the `Retry-After` header,
+// so the embeddings go through plain http
+async fn post_embeddings(
+    http_client: &reqwest::Client,
+    config: &OpenAIConfig,
+    request: CreateEmbeddingRequest,
+) -> Result<CreateEmbeddingResponse> {
+    let response = http_client
+        .post(config.url("/embeddings"))
+        .headers(config.headers())
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&request)?)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = retry_after(response.headers());
+        return Err(RateLimitError { retry_after }.into());
+    }
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        bail!("Embeddings request failed with {}: {}", status, body);
+    }
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+/// OpenAI sends the precise `retry-after-ms` along with the standard `Retry-After` seconds
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(millis) = header("retry-after-ms").and_then(|ms| ms.parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(millis.max(0.0) / 1000.0));
+    }
+    header(RETRY_AFTER.as_str())
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .map(|secs| Duration::from_secs_f64(secs.max(0.0)))
+}
+
+fn completion_deltas(stream: ChatCompletionResponseStream) -> LlmTextStream {
+    stream
+        .filter_map(|chunk| async move {
+            match chunk {
+                Ok(response) => response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.delta.content)
+                    .filter(|delta| !delta.is_empty())
+                    .map(Ok),
+                Err(err) => Some(Err(anyhow!(err))),
+            }
+        })
+        .boxed()
+}
+
+// Async OpenAi library is not working with audio, we have to use the openai_dive library
+async fn transcribe_with(client: &DiveClient, model: &str, file_path: &str) -> Result<String> {
+    let parameters = AudioTranscriptionParameters {
+        file: AudioTranscriptionFile::File(file_path.to_string()),
+        model: model.to_string(),
+        language: None,
+        prompt: None,
+        response_format: Some(AudioOutputFormat::Text),
+        temperature: None,
+        timestamp_granularities: None,
+    };
+
+    client
+        .audio()
+        .create_transcription(parameters)
+        .await
+        .map_err(|err| anyhow!(err).context("Can't transcribe audio file to text"))
+}
+
+async fn speech_with(client: &DiveClient, model: &str, text: &str) -> Result<Bytes> {
+    let parameters = AudioSpeechParameters {
+        model: model.to_string(),
+        input: text.to_string(),
+        voice: AudioVoice::Onyx,
+        response_format: Some(AudioSpeechResponseFormat::Mp3),
+        speed: Some(1.0),
+    };
+
+    let audio = client
+        .audio()
+        .create_speech(parameters)
+        .await
+        .map_err(|err| anyhow!("ERROR: {:?}", err))?;
+    Ok(audio.bytes)
+}
@@ -0,0 +1,400 @@
+use crate::ai::vector_store::{
+    page_of, query_points, CollectionAlias, CollectionParams, ScrollPage, SearchQuery, VectorHit,
+    VectorPayload, VectorPoint, VectorStore,
+};
+use anyhow::bail;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+type Collection = HashMap<String, VectorPoint>;
+
+/// Keeps all the collections in memory and searches through them point by point.
+/// Good enough for tests and small single-user deployments.
+#[derive(Debug, Default)]
+pub struct InMemoryVectorStore {
+    collections: RwLock<HashMap<String, Collection>>,
+    /// Collection name by alias
+    aliases: RwLock<HashMap<String, String>>,
+}
+
+impl InMemoryVectorStore {
+    fn resolve(&self, collection_name: &str) -> String {
+        let aliases = self.aliases.read().expect("Vector store lock is poisoned");
+        aliases
+            .get(collection_name)
+            .cloned()
+            .unwrap_or_else(|| collection_name.to_string())
+    }
+}
+
+#[async_trait]
+impl VectorStore for InMemoryVectorStore {
+    async fn collection_exists(&self, collection_name: &str) -> Result<bool> {
+        let collection_name = self.resolve(collection_name);
+        let collections = self
+            .collections
+            .read()
+            .expect("Vector store lock is poisoned");
+        Ok(collections.contains_key(&collection_name))
+    }
+
+    async fn create_collection(
+        &self,
+        collection_name: &str,
+        _params: &CollectionParams,
+    ) -> Result<()> {
+        let collection_name = self.resolve(collection_name);
+        let mut collections = self
+            .collections
+            .write()
+            .expect("Vector store lock is poisoned");
+        collections.entry(collection_name).or_default();
+        Ok(())
+    }
+
+    async fn upsert(&self, collection_name: &str, points: Vec<VectorPoint>) -> Result<()> {
+        let collection_name = self.resolve(collection_name);
+        let mut collections = self
+            .collections
+            .write()
+            .expect("Vector store lock is poisoned");
+        let collection = collections.entry(collection_name.clone()).or_default();
+
+        for point in points {
+            let existing_size = collection.values().next().map(|point| point.vector.len());
+            if let Some(size) = existing_size {
+                if size != point.vector.len() {
+                    bail!(
+                        "Vector size {} doesn't match collection {} size {}",
+                        point.vector.len(),
+                        collection_name,
+                        size
+                    );
+                }
+            }
+            collection.insert(point.id.clone(), point);
+        }
+        Ok(())
+    }
+
+    async fn query(&self, collection_name: &str, query: SearchQuery) -> Result<Vec<VectorHit>> {
+        let collection_name = self.resolve(collection_name);
+        let collections = self
+            .collections
+            .read()
+            .expect("Vector store lock is poisoned");
+        let Some(collection) = collections.get(&collection_name) else {
+            return Ok(vec![]);
+        };
+
+        Ok(query_points(collection.values().cloned().collect(), &query))
+    }
+
+    async fn get(&self, collection_name: &str, ids: Vec<String>) -> Result<Vec<VectorPoint>> {
+        let collection_name = self.resolve(collection_name);
+        let collections = self
+            .collections
+            .read()
+            .expect("Vector store lock is poisoned");
+        let Some(collection) = collections.get(&collection_name) else {
+            return Ok(vec![]);
+        };
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| collection.get(id).cloned())
+            .collect())
+    }
+
+    async fn delete(&self, collection_name: &str, ids: Vec<String>) -> Result<()> {
+        let collection_name = self.resolve(collection_name);
+        let mut collections = self
+            .collections
+            .write()
+            .expect("Vector store lock is poisoned");
+        if let Some(collection) = collections.get_mut(&collection_name) {
+            for id in ids {
+                collection.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_payload(
+        &self,
+        collection_name: &str,
+        id: String,
+        fields: VectorPayload,
+    ) -> Result<()> {
+        let collection_name = self.resolve(collection_name);
+        let mut collections = self
+            .collections
+            .write()
+            .expect("Vector store lock is poisoned");
+        let point = collections
+            .get_mut(&collection_name)
+            .and_then(|collection| collection.get_mut(&id));
+        if let Some(point) = point {
+            point.payload.0.extend(fields.0);
+        }
+        Ok(())
+    }
+
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        offset: Option<String>,
+        limit: u64,
+    ) -> Result<ScrollPage> {
+        let collection_name = self.resolve(collection_name);
+        let collections = self
+            .collections
+            .read()
+            .expect("Vector store lock is poisoned");
+        let points = collections
+            .get(&collection_name)
+            .map(|collection| collection.values().cloned().collect())
+            .unwrap_or_default();
+
+        Ok(page_of(points, offset, limit))
+    }
+
+    async fn delete_collection(&self, collection_name: &str) -> Result<()> {
+        let mut collections = self
+            .collections
+            .write()
+            .expect("Vector store lock is poisoned");
+        collections.remove(collection_name);
+        Ok(())
+    }
+
+    async fn list_collections(&self) -> Result<Vec<String>> {
+        let collections = self
+            .collections
+            .read()
+            .expect("Vector store lock is poisoned");
+        Ok(collections.keys().cloned().collect())
+    }
+
+    async fn switch_alias(&self, alias: &str, collection_name: &str) -> Result<()> {
+        let collections = self
+            .collections
+            .read()
+            .expect("Vector store lock is poisoned");
+        if !collections.contains_key(collection_name) {
+            bail!("Collection {} doesn't exist", collection_name);
+        }
+        if collections.contains_key(alias) {
+            bail!("Collection {} is not behind an alias", alias);
+        }
+
+        let mut aliases = self.aliases.write().expect("Vector store lock is poisoned");
+        aliases.insert(alias.to_string(), collection_name.to_string());
+        Ok(())
+    }
+
+    async fn list_aliases(&self) -> Result<Vec<CollectionAlias>> {
+        let aliases = self.aliases.read().expect("Vector store lock is poisoned");
+        Ok(aliases
+            .iter()
+            .map(|(alias, collection_name)| CollectionAlias {
+                alias: alias.clone(),
+                collection_name: collection_name.clone(),
+            })
+            .collect())
+    }
+
+    async fn delete_alias(&self, alias: &str) -> Result<()> {
+        let mut aliases = self.aliases.write().expect("Vector store lock is poisoned");
+        aliases.remove(alias);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::memory_vector_store::InMemoryVectorStore;
+    use crate::ai::vector_store::{
+        CollectionParams, PayloadFilter, SearchQuery, VectorPayload, VectorPoint, VectorStore,
+    };
+    use serde_json::json;
+
+    fn point(id: &str, vector: Vec<f32>) -> VectorPoint {
+        VectorPoint {
+            id: id.to_string(),
+            vector,
+            payload: VectorPayload::from_text(id),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_returns_closest_points_first() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        store
+            .upsert(
+                "test",
+                vec![
+                    point("x", vec![1.0, 0.0]),
+                    point("y", vec![0.0, 1.0]),
+                    point("xy", vec![1.0, 1.0]),
+                ],
+            )
+            .await?;
+
+        let hits = store.search("test", vec![1.0, 0.1], 2).await?;
+        let texts: Vec<&str> = hits.iter().filter_map(|hit| hit.payload.text()).collect();
+        assert_eq!(texts, vec!["x", "xy"]);
+        assert!(hits[0].score > hits[1].score);
+
+        let missing = store.search("unknown", vec![1.0, 0.0], 2).await?;
+        assert!(missing.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upsert_get_and_delete() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        store
+            .upsert("test", vec![point("x", vec![1.0, 0.0])])
+            .await?;
+        assert!(store.collection_exists("test").await?);
+
+        let wrong_size = store.upsert("test", vec![point("z", vec![1.0])]).await;
+        assert!(wrong_size.is_err());
+
+        let found = store.get("test", vec!["x".to_string()]).await?;
+        assert_eq!(found, vec![point("x", vec![1.0, 0.0])]);
+
+        store.delete("test", vec!["x".to_string()]).await?;
+        assert!(store.get("test", vec!["x".to_string()]).await?.is_empty());
+
+        let fields = VectorPayload::from_text("deleted");
+        store.set_payload("test", "x".to_string(), fields).await?;
+        assert!(store.get("test", vec!["x".to_string()]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scroll_and_delete_collection() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        let points = ["c", "a", "b"]
+            .iter()
+            .map(|id| point(id, vec![1.0, 0.0]))
+            .collect();
+        store.upsert("test", points).await?;
+
+        let first_page = store.scroll("test", None, 2).await?;
+        let ids: Vec<&str> = first_page.points.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(first_page.next_offset.as_deref(), Some("c"));
+
+        let last_page = store.scroll("test", first_page.next_offset, 2).await?;
+        assert_eq!(last_page.points.len(), 1);
+        assert_eq!(last_page.next_offset, None);
+
+        store.delete_collection("test").await?;
+        assert!(!store.collection_exists("test").await?);
+        assert!(store.scroll("test", None, 2).await?.points.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_switch_alias() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        store
+            .upsert("docs", vec![point("old", vec![1.0, 0.0])])
+            .await?;
+        store
+            .upsert("docs_v1", vec![point("new", vec![1.0, 0.0, 0.0])])
+            .await?;
+
+        assert!(store.switch_alias("docs", "docs_v1").await.is_err());
+        store.delete_collection("docs").await?;
+        store.switch_alias("docs", "docs_v1").await?;
+        let page = store.scroll("docs", None, 10).await?;
+        let ids: Vec<&str> = page.points.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec!["new"]);
+        assert_eq!(store.list_collections().await?, vec!["docs_v1".to_string()]);
+
+        assert!(store.switch_alias("docs", "missing").await.is_err());
+
+        let aliases = store.list_aliases().await?;
+        assert_eq!(aliases[0].collection_name, "docs_v1");
+        store.delete_alias("docs").await?;
+        assert!(store.scroll("docs", None, 10).await?.points.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_collection_and_scroll_all() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        store
+            .create_collection("test", &CollectionParams::new(2))
+            .await?;
+        assert!(store.collection_exists("test").await?);
+        assert!(store.scroll_all("test").await?.is_empty());
+
+        let points: Vec<VectorPoint> = (0..250)
+            .map(|index| point(&format!("{:03}", index), vec![1.0, 0.0]))
+            .collect();
+        store.upsert("test", points).await?;
+        assert_eq!(store.scroll_all("test").await?.len(), 250);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_query_with_filter() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::default();
+        let documents = [
+            ("a", vec![1.0, 0.0], "how to update the router", "en"),
+            ("b", vec![0.6, 0.8], "XR-200 firmware notes", "en"),
+            ("c", vec![0.6, 0.8], "XR-200 firmware notes", "ru"),
+        ];
+        let points = documents
+            .into_iter()
+            .map(|(id, vector, text, language)| {
+                let mut point = point(id, vector);
+                point.payload = VectorPayload::from_text(text);
+                point
+                    .payload
+                    .0
+                    .insert("language".to_string(), json!(language));
+                point
+            })
+            .collect();
+        store.upsert("test", points).await?;
+
+        let filter: PayloadFilter = serde_json::from_value(json!({"language": "en"}))?;
+        let dense = SearchQuery {
+            vector: vec![1.0, 0.0],
+            filter: Some(filter),
+            limit: 3,
+            ..SearchQuery::default()
+        };
+        let hits = store.query("test", dense.clone()).await?;
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+
+        let hybrid = SearchQuery {
+            keywords: Some("XR-200".to_string()),
+            ..dense
+        };
+        let hits = store.query("test", hybrid.clone()).await?;
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, vec!["b", "a"]);
+        assert!(hits[0].score <= 1.0 && hits[0].score > hits[1].score);
+
+        // The vector hits below min_score are dropped, the keyword ones are kept
+        let strict = SearchQuery {
+            keywords: Some("router".to_string()),
+            min_score: Some(0.9),
+            ..hybrid
+        };
+        let hits = store.query("test", strict).await?;
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, vec!["a"]);
+        Ok(())
+    }
+}
@@ -1,3 +1,11 @@
 pub mod ai_db;
+pub mod embedding_cache;
+pub mod fake_llm_provider;
+pub mod keyword_search;
+pub mod llm_provider;
+pub mod memory_vector_store;
 pub mod nervo_llm;
-mod qdrant_db;
+pub mod qdrant_db;
+pub mod reranker;
+pub mod sqlite_vector_store;
+pub mod vector_store;
@@ -1,54 +1,163 @@
-use anyhow::bail;
+use crate::ai::embedding_cache::EmbeddingCache;
+use crate::ai::llm_provider::{
+    LlmProvider, LlmProviderType, LlmTextStream, LocalLlmProvider, OpenAiProvider, RateLimitError,
+};
 use anyhow::Result;
+use anyhow::{anyhow, bail};
 use async_openai::config::OpenAIConfig;
 use async_openai::types::CreateEmbeddingRequestArgs;
-use async_openai::types::CreateEmbeddingResponse;
-use async_openai::types::CreateModerationRequest;
-use async_openai::types::CreateTranscriptionRequest;
-use async_openai::types::ModerationInput;
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
-    ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestAssistantMessage, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestUserMessageContent,
 };
 use async_openai::types::{
     ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestSystemMessageArgs,
     ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessageArgs,
-    CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    ResponseFormat, ResponseFormatJsonSchema, Stop,
 };
 use async_openai::types::{ChatCompletionRequestUserMessage, Embedding};
-use async_openai::Client;
-use serde_derive::Deserialize;
-use tracing::info;
+use async_openai::types::{CreateEmbeddingRequest, CreateEmbeddingResponse};
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
 
 use nervo_sdk::api::spec::{LlmChat, LlmMessage, LlmMessageContent, LlmMessageRole};
 
+/// How many times [`NervoLlm::complete_json`] asks LLM before giving up
+const JSON_REPLY_ATTEMPTS: usize = 3;
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct NervoLlmConfig {
+    #[serde(default)]
     pub api_key: String,
     pub model_name: String,
     pub embedding_model_name: String,
     pub max_tokens: u16,
     pub temperature: f32,
+    #[serde(default)]
+    pub provider: LlmProviderType,
+    /// Base url of an OpenAI-compatible server, i.e. `http://localhost:11434/v1` for Ollama
+    #[serde(default)]
+    pub api_base: Option<String>,
+    #[serde(default)]
+    pub embeddings: EmbeddingParams,
+    #[serde(default)]
+    pub audio: AudioParams,
+}
+
+/// Speech models of the provider, OpenAI ones are used when they are not set.
+/// Self-hosted servers seldom serve audio, so the local provider needs them set explicitly.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AudioParams {
+    pub transcription_model_name: Option<String>,
+    pub speech_model_name: Option<String>,
+}
+
+/// How [`NervoLlm::embeddings`] talks to the provider
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingParams {
+    /// Texts sent in one request
+    pub batch_size: usize,
+    /// Requests in flight at once
+    pub concurrency: usize,
+    /// Retries of a rate limited request
+    pub max_retries: u32,
+    /// Delay before the first retry, it doubles with every next one
+    /// unless the provider sends `Retry-After`
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Embeddings kept in memory, 0 turns the cache off
+    pub cache_size: usize,
+}
+
+impl Default for EmbeddingParams {
+    fn default() -> Self {
+        EmbeddingParams {
+            batch_size: 100,
+            concurrency: 4,
+            max_retries: 6,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            cache_size: 10_000,
+        }
+    }
 }
 
 impl NervoLlmConfig {
     pub fn open_ai_config(&self) -> OpenAIConfig {
         let cfg = OpenAIConfig::new();
+        let cfg = match &self.api_base {
+            Some(api_base) => cfg.with_api_base(api_base),
+            None => cfg,
+        };
         cfg.with_api_key(self.api_key.clone())
     }
+
+    pub fn build_provider(&self) -> Result<Arc<dyn LlmProvider>> {
+        let provider: Arc<dyn LlmProvider> = match self.provider {
+            LlmProviderType::OpenAi => Arc::new(OpenAiProvider::new(
+                self.open_ai_config(),
+                &self.api_key,
+                &self.audio,
+            )?),
+            LlmProviderType::Local => {
+                let Some(api_base) = &self.api_base else {
+                    bail!("Local LLM provider requires api_base");
+                };
+                Arc::new(LocalLlmProvider::new(api_base, &self.api_key, &self.audio)?)
+            }
+        };
+        Ok(provider)
+    }
+}
+
+/// Per-call overrides of the [`NervoLlmConfig`] defaults
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LlmRequestOptions {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// i.e. `{"type": "json_object"}`
+    pub response_format: Option<ResponseFormat>,
+    pub seed: Option<i64>,
 }
 
 #[derive(Clone, Debug)]
 pub struct NervoLlm {
     llm_config: NervoLlmConfig,
-    client: Client<OpenAIConfig>,
+    provider: Arc<dyn LlmProvider>,
+    embedding_cache: Arc<EmbeddingCache>,
 }
 
-impl From<NervoLlmConfig> for NervoLlm {
-    fn from(llm_config: NervoLlmConfig) -> Self {
+impl TryFrom<NervoLlmConfig> for NervoLlm {
+    type Error = anyhow::Error;
+
+    fn try_from(llm_config: NervoLlmConfig) -> Result<Self> {
+        let provider = llm_config.build_provider()?;
+        Ok(NervoLlm::with_provider(llm_config, provider))
+    }
+}
+
+impl NervoLlm {
+    pub fn with_provider(llm_config: NervoLlmConfig, provider: Arc<dyn LlmProvider>) -> Self {
+        let embedding_cache = Arc::new(EmbeddingCache::new(llm_config.embeddings.cache_size));
         NervoLlm {
-            llm_config: llm_config.clone(),
-            client: Client::with_config(llm_config.open_ai_config()),
+            llm_config,
+            provider,
+            embedding_cache,
         }
     }
 }
@@ -57,51 +166,153 @@ impl NervoLlm {
     pub fn model_name(&self) -> &str {
         self.llm_config.model_name.as_str()
     }
-    pub fn api_key(&self) -> &str {
-        self.llm_config.api_key.as_str()
+
+    pub fn embedding_model_name(&self) -> &str {
+        self.llm_config.embedding_model_name.as_str()
     }
 }
 
 impl NervoLlm {
     pub async fn embedding(&self, text: &str) -> Result<CreateEmbeddingResponse> {
-        let response = {
-            let embedding = CreateEmbeddingRequestArgs::default()
-                .model(self.llm_config.embedding_model_name.clone())
-                .input(text)
-                .build()?;
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(self.llm_config.embedding_model_name.clone())
+            .input(text)
+            .build()?;
 
-            self.client.embeddings().create(embedding).await?
-        };
+        self.request_embeddings(request).await
+    }
+
+    /// Embeddings of the texts in their order. The texts missing in the cache are sent
+    /// `batch_size` per request, up to `concurrency` requests at once.
+    pub async fn embeddings(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+        let params = &self.llm_config.embeddings;
+        let model_name = self.embedding_model_name();
+
+        let mut vectors: HashMap<String, Vec<f32>> = HashMap::new();
+        let mut missing: Vec<String> = vec![];
+        let mut seen: HashSet<&String> = HashSet::new();
+        for text in texts.iter().filter(|text| seen.insert(*text)) {
+            match self.embedding_cache.get(model_name, text) {
+                Some(vector) => {
+                    vectors.insert(text.clone(), vector);
+                }
+                None => missing.push(text.clone()),
+            }
+        }
 
-        Ok(response)
+        if !missing.is_empty() {
+            info!(
+                "Embedding {} texts, {} are cached",
+                missing.len(),
+                vectors.len()
+            );
+        }
+        let batches: Vec<Vec<String>> = missing
+            .chunks(params.batch_size.max(1))
+            .map(|batch| batch.to_vec())
+            .collect();
+        // Every finished batch goes to the cache, so a failed run resumes where it stopped
+        let mut embedded = stream::iter(batches)
+            .map(|batch| self.embed_batch(batch))
+            .buffer_unordered(params.concurrency.max(1));
+        while let Some(batch) = embedded.next().await {
+            for (text, vector) in batch? {
+                self.embedding_cache
+                    .insert(model_name, &text, vector.clone());
+                vectors.insert(text, vector);
+            }
+        }
+
+        texts
+            .iter()
+            .enumerate()
+            .map(|(index, text)| {
+                let vector = vectors
+                    .get(text)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("No embedding for the text {}", index))?;
+                Ok(Embedding {
+                    index: index as u32,
+                    object: "embedding".to_string(),
+                    embedding: vector,
+                })
+            })
+            .collect()
     }
 
-    pub async fn embeddings(&self, texts: Vec<String>) -> Result<Vec<CreateEmbeddingResponse>> {
-        let mut embeddings: Vec<CreateEmbeddingResponse> = Vec::new();
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<(String, Vec<f32>)>> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(self.llm_config.embedding_model_name.clone())
+            .input(texts.clone())
+            .build()?;
 
-        for text in &texts {
-            let vectorized_keyword = self.embedding(text).await?;
-            embeddings.push(vectorized_keyword);
+        let mut data = self.request_embeddings(request).await?.data;
+        if data.len() != texts.len() {
+            bail!(
+                "{} embeddings are received for {} texts",
+                data.len(),
+                texts.len()
+            );
         }
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(texts
+            .into_iter()
+            .zip(data.into_iter().map(|embedding| embedding.embedding))
+            .collect())
+    }
 
-        Ok(embeddings)
+    /// Sends the request, waits and repeats it while the provider is rate limited
+    async fn request_embeddings(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> Result<CreateEmbeddingResponse> {
+        let params = &self.llm_config.embeddings;
+        let max_backoff = Duration::from_millis(params.max_backoff_ms);
+        let mut backoff = Duration::from_millis(params.initial_backoff_ms).min(max_backoff);
+        let mut retries = 0;
+
+        loop {
+            let err = match self.provider.embeddings(request.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            let Some(rate_limit) = err.downcast_ref::<RateLimitError>() else {
+                return Err(err);
+            };
+            if retries >= params.max_retries {
+                return Err(err.context(format!("Rate limited after {} retries", retries)));
+            }
+
+            let delay = rate_limit.retry_after.unwrap_or(backoff);
+            retries += 1;
+            warn!(
+                "Embeddings are rate limited, retry {} in {:?}",
+                retries, delay
+            );
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(max_backoff);
+        }
     }
 
     pub async fn text_to_embeddings(&self, text: &str) -> Result<Option<Embedding>> {
-        let embedding = self.embedding(text).await?;
-        Ok(embedding.data.first().cloned())
+        let embeddings = self.embeddings(vec![text.to_string()]).await?;
+        Ok(embeddings.into_iter().next())
     }
 }
 
 impl NervoLlm {
-    pub async fn send_msg_batch(&self, chat: LlmChat) -> Result<String> {
+    pub async fn send_msg_batch(
+        &self,
+        chat: LlmChat,
+        options: &LlmRequestOptions,
+    ) -> Result<String> {
         let mut messages = vec![];
         for msg in chat.messages {
             let gpt_msg = ChatCompletionRequestMessage::transform_to(msg)?;
             messages.push(gpt_msg);
         }
 
-        let chat_response = self.create_chat(messages).await?;
+        let chat_response = self.create_chat(messages, options).await?;
 
         let maybe_reply = chat_response
             .choices
@@ -116,38 +327,54 @@ impl NervoLlm {
         Ok(reply.0)
     }
 
-    pub async fn send_msg(&self, message: LlmMessage, chat_id: u64) -> Result<String> {
+    pub async fn send_msg_batch_stream(
+        &self,
+        chat: LlmChat,
+        options: &LlmRequestOptions,
+    ) -> Result<LlmTextStream> {
+        let mut messages = vec![];
+        for msg in chat.messages {
+            let gpt_msg = ChatCompletionRequestMessage::transform_to(msg)?;
+            messages.push(gpt_msg);
+        }
+
+        self.create_chat_stream(messages, options).await
+    }
+
+    pub async fn send_msg(
+        &self,
+        message: LlmMessage,
+        chat_id: u64,
+        options: &LlmRequestOptions,
+    ) -> Result<String> {
         let chat = LlmChat {
             chat_id: Some(chat_id),
             messages: vec![message],
         };
-        let llm_response_text = self.send_msg_batch(chat).await?;
+        let llm_response_text = self.send_msg_batch(chat, options).await?;
         Ok(llm_response_text)
     }
 
     pub async fn moderate(&self, text: &str) -> Result<bool> {
-        let request = CreateModerationRequest {
-            input: ModerationInput::from(text),
-            model: None,
-        };
+        let is_flagged = self.provider.is_flagged(text).await?;
+        let is_passed = !is_flagged && (text.len() < 10000);
+        info!("Moderation is passed: {:?}", is_passed);
+        Ok(is_passed)
+    }
 
-        let response = self.client.moderations().create(request).await?;
-        info!(
-            "Moderation is passed: {:?}",
-            !response.results.iter().any(|property| property.flagged) && (text.len() < 10000)
-        );
-        Ok(!response.results.iter().any(|property| property.flagged) && (text.len() < 10000))
+    pub async fn voice_transcription(&self, file_path: &str) -> Result<String> {
+        self.provider.transcribe(file_path).await
     }
 
-    pub async fn voice_transcription(&self, request: CreateTranscriptionRequest) -> Result<String> {
-        let response = self.client.audio().transcribe(request).await?;
-        Ok(response.text)
+    pub async fn create_speech(&self, text: &str) -> Result<Bytes> {
+        self.provider.speech(text).await
     }
 
     pub(crate) async fn raw_llm_processing(
         &self,
         system_role: &str,
         request: &str,
+        options: &LlmRequestOptions,
     ) -> Result<String> {
         let messages = vec![
             ChatCompletionRequestSystemMessageArgs::default()
@@ -160,10 +387,7 @@ impl NervoLlm {
                 .into(),
         ];
 
-        // TODO: Check config if it's 4096u32
-        // TODO: Check config if it's "gpt-4o"
-        // TODO: Check config if it's 0.4
-        let response = self.create_chat(messages).await?;
+        let response = self.create_chat(messages, options).await?;
 
         if let Some(choice) = response.choices.get(0) {
             let content = choice.message.content.clone().unwrap_or_else(|| {
@@ -177,21 +401,169 @@ impl NervoLlm {
         }
     }
 
+    /// Asks LLM for a JSON reply described by the schema of `T`.
+    /// Replies which don't match the schema are sent back to LLM along with the parsing error.
+    pub async fn complete_json<T: DeserializeOwned + JsonSchema>(
+        &self,
+        system_role: &str,
+        request: &str,
+        options: &LlmRequestOptions,
+    ) -> Result<T> {
+        let schema = serde_json::to_value(schemars::schema_for!(T))?;
+        let system_role = format!(
+            "{}\n\nReply with JSON only, it must match the JSON schema:\n{}",
+            system_role, schema
+        );
+
+        let mut options = options.clone();
+        if options.response_format.is_none() {
+            options.response_format = Some(ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: None,
+                    name: json_schema_name(&T::schema_name()),
+                    schema: Some(schema),
+                    strict: Some(false),
+                },
+            });
+        }
+
+        let mut messages: Vec<ChatCompletionRequestMessage> = vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(system_role)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(request)
+                .build()?
+                .into(),
+        ];
+
+        let mut last_error = anyhow!("No reply from LLM");
+        for attempt in 1..=JSON_REPLY_ATTEMPTS {
+            let response = self.create_chat(messages.clone(), &options).await?;
+            let reply = response
+                .choices
+                .first()
+                .and_then(|choice| choice.message.content.clone())
+                .unwrap_or_default();
+
+            match parse_json_reply::<T>(&reply) {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    info!("Invalid JSON reply, attempt {}: {}", attempt, err);
+                    messages.push(
+                        ChatCompletionRequestAssistantMessageArgs::default()
+                            .content(reply)
+                            .build()?
+                            .into(),
+                    );
+                    messages.push(
+                        ChatCompletionRequestUserMessageArgs::default()
+                            .content(format!(
+                                "The reply doesn't match the JSON schema: {}. \
+                                Reply with the corrected JSON only.",
+                                err
+                            ))
+                            .build()?
+                            .into(),
+                    );
+                    last_error = err;
+                }
+            }
+        }
+
+        Err(last_error.context(format!(
+            "No valid JSON reply after {} attempts",
+            JSON_REPLY_ATTEMPTS
+        )))
+    }
+
     pub async fn create_chat(
         &self,
         messages: Vec<ChatCompletionRequestMessage>,
+        options: &LlmRequestOptions,
     ) -> Result<CreateChatCompletionResponse> {
-        let request = CreateChatCompletionRequestArgs::default()
-            .max_tokens(self.llm_config.max_tokens)
-            .model(self.llm_config.model_name.clone())
-            .temperature(self.llm_config.temperature)
-            .messages(messages)
-            .build()?;
+        let request = self.chat_request(messages, options, false)?;
+        self.provider.chat(request).await
+    }
 
-        Ok(self.client.chat().create(request).await?)
+    pub async fn create_chat_stream(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        options: &LlmRequestOptions,
+    ) -> Result<LlmTextStream> {
+        let request = self.chat_request(messages, options, true)?;
+        self.provider.chat_stream(request).await
+    }
+
+    /// Options of the call take precedence over the config defaults
+    fn chat_request(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        options: &LlmRequestOptions,
+        stream: bool,
+    ) -> Result<CreateChatCompletionRequest> {
+        let model = options
+            .model
+            .clone()
+            .unwrap_or_else(|| self.llm_config.model_name.clone());
+        let max_tokens = options
+            .max_tokens
+            .unwrap_or(self.llm_config.max_tokens as u32);
+        let temperature = options.temperature.unwrap_or(self.llm_config.temperature);
+
+        let mut request_args = CreateChatCompletionRequestArgs::default();
+        request_args
+            .max_tokens(max_tokens)
+            .model(model)
+            .temperature(temperature)
+            .messages(messages);
+
+        if stream {
+            request_args.stream(true);
+        }
+        if !options.stop.is_empty() {
+            request_args.stop(Stop::StringArray(options.stop.clone()));
+        }
+        if let Some(response_format) = &options.response_format {
+            request_args.response_format(response_format.clone());
+        }
+        if let Some(seed) = options.seed {
+            request_args.seed(seed);
+        }
+
+        Ok(request_args.build()?)
     }
 }
 
+/// Parses the reply, ignoring a markdown code fence around the JSON
+fn parse_json_reply<T: DeserializeOwned>(reply: &str) -> Result<T> {
+    let reply = reply.trim();
+    let json = match reply.strip_prefix("```") {
+        Some(fenced) => fenced
+            .trim_start_matches("json")
+            .trim_end()
+            .trim_end_matches("```"),
+        None => reply,
+    };
+    Ok(serde_json::from_str(json.trim())?)
+}
+
+/// Structured output names may contain only latin letters, digits, `_` and `-`
+fn json_schema_name(schema_name: &str) -> String {
+    schema_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect()
+}
+
 pub trait TransformTo<T>: Sized {
     type Error;
 
@@ -234,3 +606,186 @@ impl TransformTo<LlmMessage> for ChatCompletionRequestMessage {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::ai::fake_llm_provider::{FakeLlmProvider, FAKE_EMBEDDING_SIZE};
+    use crate::ai::llm_provider::LlmProviderType;
+    use crate::ai::nervo_llm::{
+        AudioParams, EmbeddingParams, LlmRequestOptions, NervoLlm, NervoLlmConfig,
+    };
+    use async_openai::types::{ResponseFormat, Stop};
+    use nervo_sdk::api::spec::{
+        LlmChat, LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence,
+        LlmMessageRole,
+    };
+    use schemars::JsonSchema;
+    use serde_derive::Deserialize;
+    use std::sync::Arc;
+
+    fn fake_llm_config() -> NervoLlmConfig {
+        NervoLlmConfig {
+            api_key: String::new(),
+            model_name: "fake-model".to_string(),
+            embedding_model_name: "fake-embedding".to_string(),
+            max_tokens: 100,
+            temperature: 0.0,
+            provider: LlmProviderType::OpenAi,
+            api_base: None,
+            embeddings: EmbeddingParams::default(),
+            audio: AudioParams::default(),
+        }
+    }
+
+    pub(crate) fn fake_llm(provider: FakeLlmProvider) -> NervoLlm {
+        NervoLlm::with_provider(fake_llm_config(), Arc::new(provider))
+    }
+
+    fn user_message(text: &str) -> LlmMessage {
+        LlmMessage {
+            meta_info: LlmMessageMetaInfo {
+                sender_id: Some(1),
+                role: LlmMessageRole::User,
+                persistence: LlmMessagePersistence::Temporal,
+            },
+            content: LlmMessageContent::from(text),
+            sources: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_msg_batch_with_fake_provider() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec!["first"]));
+        let chat = || LlmChat {
+            chat_id: None,
+            messages: vec![user_message("echo me")],
+        };
+
+        let options = LlmRequestOptions::default();
+        assert_eq!(nervo_llm.send_msg_batch(chat(), &options).await?, "first");
+        assert_eq!(nervo_llm.send_msg_batch(chat(), &options).await?, "echo me");
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_request_options_override_config() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::default());
+
+        let default_request =
+            nervo_llm.chat_request(vec![], &LlmRequestOptions::default(), false)?;
+        assert_eq!(default_request.model, "fake-model");
+        assert_eq!(default_request.max_tokens, Some(100));
+        assert_eq!(default_request.temperature, Some(0.0));
+        assert_eq!(default_request.stop, None);
+
+        let options = LlmRequestOptions {
+            model: Some("cheap-model".to_string()),
+            temperature: Some(0.7),
+            max_tokens: Some(10),
+            stop: vec!["END".to_string()],
+            response_format: Some(ResponseFormat::JsonObject),
+            seed: Some(42),
+        };
+        let request = nervo_llm.chat_request(vec![], &options, true)?;
+        assert_eq!(request.model, "cheap-model");
+        assert_eq!(request.max_tokens, Some(10));
+        assert_eq!(request.temperature, Some(0.7));
+        assert_eq!(
+            request.stop,
+            Some(Stop::StringArray(vec!["END".to_string()]))
+        );
+        assert_eq!(request.response_format, Some(ResponseFormat::JsonObject));
+        assert_eq!(request.seed, Some(42));
+        assert_eq!(request.stream, Some(true));
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    struct Keywords {
+        keywords: Vec<String>,
+    }
+
+    #[tokio::test]
+    async fn test_complete_json_retries_invalid_reply() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec![
+            r#"{"words": ["car"]}"#,
+            "```json\n{\"keywords\": [\"car\", \"service\"]}\n```",
+        ]));
+
+        let reply: Keywords = nervo_llm
+            .complete_json("Find keywords", "My car", &LlmRequestOptions::default())
+            .await?;
+        assert_eq!(reply.keywords, vec!["car", "service"]);
+
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec!["no", "no", "no"]));
+        let reply = nervo_llm
+            .complete_json::<Keywords>("Find keywords", "My car", &LlmRequestOptions::default())
+            .await;
+        assert!(reply.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_moderate_with_fake_provider() -> anyhow::Result<()> {
+        let provider = FakeLlmProvider::default().with_flagged_words(vec!["forbidden"]);
+        let nervo_llm = fake_llm(provider);
+
+        assert!(nervo_llm.moderate("hello").await?);
+        assert!(!nervo_llm.moderate("forbidden word").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embedding_with_fake_provider() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::default());
+
+        let embedding = nervo_llm.text_to_embeddings("hello world").await?.unwrap();
+        let same_embedding = nervo_llm.text_to_embeddings("hello world").await?.unwrap();
+
+        assert_eq!(embedding.embedding.len(), FAKE_EMBEDDING_SIZE);
+        assert_eq!(embedding.embedding, same_embedding.embedding);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_are_batched_and_cached() -> anyhow::Result<()> {
+        let provider = Arc::new(FakeLlmProvider::default());
+        let mut llm_config = fake_llm_config();
+        llm_config.embeddings.batch_size = 2;
+        let nervo_llm = NervoLlm::with_provider(llm_config, provider.clone());
+
+        let texts = ["a", "b", "a", "c"].map(String::from).to_vec();
+        let embeddings = nervo_llm.embeddings(texts).await?;
+        assert_eq!(provider.embedding_requests(), 2);
+        assert_eq!(embeddings.len(), 4);
+        assert_eq!(embeddings[0].embedding, embeddings[2].embedding);
+        assert_eq!(
+            embeddings[3].embedding,
+            FakeLlmProvider::fake_embedding("c")
+        );
+
+        nervo_llm.text_to_embeddings("b").await?;
+        assert_eq!(provider.embedding_requests(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_retry_rate_limits() -> anyhow::Result<()> {
+        let provider = Arc::new(FakeLlmProvider::default().with_rate_limits(2));
+        let mut llm_config = fake_llm_config();
+        llm_config.embeddings.max_retries = 2;
+        let nervo_llm = NervoLlm::with_provider(llm_config.clone(), provider.clone());
+
+        let embeddings = nervo_llm.embeddings(vec!["hello".to_string()]).await?;
+        assert_eq!(embeddings.len(), 1);
+        assert_eq!(provider.embedding_requests(), 3);
+
+        let provider = Arc::new(FakeLlmProvider::default().with_rate_limits(3));
+        let nervo_llm = NervoLlm::with_provider(llm_config, provider);
+        assert!(nervo_llm
+            .embeddings(vec!["hello".to_string()])
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -1,172 +1,609 @@
-use crate::ai::nervo_llm::NervoLlm;
+use crate::ai::keyword_search::{SparseVector, SPARSE_VECTOR_NAME};
+use crate::ai::vector_store::{
+    fuse_rankings, payload_fields, CollectionAlias, CollectionParams, PayloadFilter, PayloadIndex,
+    PayloadIndexKind, ScrollPage, SearchQuery, VectorDistance, VectorHit, VectorPayload,
+    VectorPoint, VectorQuantization, VectorStore, HYBRID_CANDIDATES_FACTOR,
+};
 use crate::config::common::QdrantParams;
-use anyhow::bail;
-use anyhow::Result;
-use async_openai::types::Embedding;
-use nervo_sdk::agent_type::{AgentType, NervoAgentType};
-use nervo_sdk::utils::cryptography::UuidGenerator;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+#[allow(deprecated)]
+use qdrant_client::client::QdrantClient;
+use qdrant_client::qdrant::alias_operations::Action;
+use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::quantization_config::Quantization;
+use qdrant_client::qdrant::vectors::VectorsOptions;
 use qdrant_client::qdrant::vectors_config::Config;
 use qdrant_client::qdrant::{
-    CreateCollection, DeletePointsBuilder, Distance, GetPointsBuilder, GetResponse, PointStruct,
-    SearchParamsBuilder, SearchPointsBuilder, UpsertPointsBuilder,
+    AliasOperations, BinaryQuantization, ChangeAliases, Condition, CreateAlias, CreateCollection,
+    CreateFieldIndexCollectionBuilder, DeleteAlias, DeletePointsBuilder, Distance, FieldType,
+    Filter, GetPointsBuilder, HnswConfigDiff, Modifier, NamedVectors, PointId, PointStruct,
+    PointsIdsList, QuantizationConfig, QuantizationType, QueryPointsBuilder, Range, RetrievedPoint,
+    ScalarQuantization, ScoredPoint, ScrollPointsBuilder, SearchParamsBuilder, SearchPointsBuilder,
+    SetPayloadPointsBuilder, SparseVectorConfig, SparseVectorParams, UpsertPointsBuilder, Value,
+    Vector, VectorInput, Vectors,
 };
-use qdrant_client::qdrant::{SearchResponse, VectorParams, VectorsConfig};
+use qdrant_client::qdrant::{VectorParams, VectorsConfig};
 use qdrant_client::Payload;
 use qdrant_client::Qdrant;
-use serde_json::json;
-use tracing::info;
-use uuid::Uuid;
+use serde_json::Map;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+/// Name of the unnamed dense vector among the named ones
+const DENSE_VECTOR_NAME: &str = "";
 
 pub struct QdrantDb {
     pub qdrant_client: Qdrant,
-    pub nervo_llm: NervoLlm,
+    /// `Qdrant` sends one alias action per request, the alias switch needs two in one
+    #[allow(deprecated)]
+    alias_client: QdrantClient,
+    upsert_batch_size: usize,
+    /// Whether the collection has the keyword vectors, by collection name.
+    /// Collections created before the hybrid search have only the dense ones.
+    /// A collection gets here once it has been created or looked up, and leaves
+    /// when it is deleted through this store, so an entry may outlive a collection
+    /// deleted elsewhere.
+    keyword_indexed: RwLock<HashMap<String, bool>>,
 }
 
 impl QdrantDb {
-    pub fn try_from(config: &QdrantParams, nervo_llm: NervoLlm) -> Result<Self> {
+    pub fn try_from(config: &QdrantParams) -> Result<Self> {
         let qdrant_client = Qdrant::from_url(config.server_url.as_str())
             .api_key(config.api_key.clone())
             .build()?;
 
+        #[allow(deprecated)]
+        let alias_client = QdrantClient::from_url(config.server_url.as_str())
+            .with_api_key(config.api_key.clone())
+            .build()?;
+
         Ok(QdrantDb {
             qdrant_client,
-            nervo_llm,
+            alias_client,
+            upsert_batch_size: config.upsert_batch_size.max(1),
+            keyword_indexed: RwLock::new(HashMap::new()),
         })
     }
 }
 
 impl QdrantDb {
-    pub async fn save_text(&self, collection_name: &str, text: &str) -> Result<()> {
-        let maybe_vec_data = self.nervo_llm.text_to_embeddings(text).await?;
+    async fn create_collection_if_missing(
+        &self,
+        collection_name: &str,
+        vector_size: usize,
+    ) -> Result<()> {
+        let known = self
+            .keyword_indexed
+            .read()
+            .expect("Qdrant collections lock is poisoned")
+            .contains_key(collection_name);
+        if known {
+            return Ok(());
+        }
 
-        self.save(collection_name.to_string(), text, maybe_vec_data.unwrap())
+        self.create_collection(collection_name, &CollectionParams::new(vector_size))
             .await
     }
 
-    pub async fn save(
+    async fn keyword_index_of(&self, collection_name: &str) -> Result<bool> {
+        let cached = self
+            .keyword_indexed
+            .read()
+            .expect("Qdrant collections lock is poisoned")
+            .get(collection_name)
+            .copied();
+        if let Some(keyword_indexed) = cached {
+            return Ok(keyword_indexed);
+        }
+
+        let collection_info = self.qdrant_client.collection_info(collection_name).await?;
+        let keyword_indexed = collection_info
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.sparse_vectors_config)
+            .is_some_and(|sparse_config| sparse_config.map.contains_key(SPARSE_VECTOR_NAME));
+        if !keyword_indexed {
+            warn!(
+                "Collection {} has no keyword vectors, its hybrid search is vector only. \
+                Rebuild it with `nervo-migrant reembed`",
+                collection_name
+            );
+        }
+
+        self.keyword_indexed
+            .write()
+            .expect("Qdrant collections lock is poisoned")
+            .insert(collection_name.to_string(), keyword_indexed);
+        Ok(keyword_indexed)
+    }
+
+    async fn keyword_search(
         &self,
-        collection_name: String,
-        text: &str,
-        embedding: Embedding,
-    ) -> Result<()> {
-        let col_exists = self
-            .qdrant_client
-            .collection_exists(&collection_name)
-            .await?;
+        collection_name: &str,
+        keywords: &str,
+        filter: Option<Filter>,
+        limit: u64,
+    ) -> Result<Vec<VectorHit>> {
+        let sparse_vector = SparseVector::query(keywords);
+        if sparse_vector.is_empty() || !self.keyword_index_of(collection_name).await? {
+            return Ok(vec![]);
+        }
+
+        let mut request = QueryPointsBuilder::new(collection_name)
+            .query(VectorInput::new_sparse(
+                sparse_vector.indices,
+                sparse_vector.values,
+            ))
+            .using(SPARSE_VECTOR_NAME)
+            .limit(limit)
+            .with_payload(true);
+        if let Some(filter) = filter {
+            request = request.filter(filter);
+        }
+        let query_result = self.qdrant_client.query(request).await?;
+        Ok(query_result.result.into_iter().map(to_vector_hit).collect())
+    }
+}
+
+impl From<&PayloadFilter> for Filter {
+    fn from(filter: &PayloadFilter) -> Self {
+        let mut conditions = vec![];
+        let text_conditions = [
+            (payload_fields::AGENT_TYPE, &filter.agent),
+            (payload_fields::LANGUAGE, &filter.language),
+            (payload_fields::SOURCE, &filter.source),
+        ];
+        for (field, value) in text_conditions {
+            if let Some(value) = value {
+                conditions.push(Condition::matches(field, value.clone()));
+            }
+        }
+        if !filter.tags.is_empty() {
+            conditions.push(Condition::matches(
+                payload_fields::TAGS,
+                filter.tags.clone(),
+            ));
+        }
 
-        if !col_exists {
-            let details = CreateCollection {
-                collection_name: collection_name.clone(),
-                vectors_config: Some(VectorsConfig {
-                    config: Some(Config::Params(VectorParams {
-                        size: embedding.embedding.len() as u64,
-                        distance: Distance::Cosine.into(),
-                        ..Default::default()
-                    })),
-                }),
+        let (from, to) = filter.created_at_range();
+        if from.is_some() || to.is_some() {
+            let range = Range {
+                gte: from.map(|from| from as f64),
+                lte: to.map(|to| to as f64),
                 ..Default::default()
             };
-
-            self.qdrant_client.create_collection(details).await?;
+            conditions.push(Condition::range(payload_fields::CREATED_AT, range));
         }
 
-        let points = {
-            // Generate a UUID
-            let point_id = UuidGenerator::from(text).to_string();
+        Filter::must(conditions)
+    }
+}
 
-            let payload: Payload = json!({"text": text}).try_into()?;
-            let point = PointStruct::new(point_id, embedding.embedding.clone(), payload);
-            vec![point]
+fn collection_details(collection_name: &str, params: &CollectionParams) -> CreateCollection {
+    let distance = match params.distance {
+        VectorDistance::Cosine => Distance::Cosine,
+        VectorDistance::Dot => Distance::Dot,
+        VectorDistance::Euclid => Distance::Euclid,
+    };
+    let quantization = params.quantization.map(|quantization| {
+        let quantization = match quantization {
+            VectorQuantization::Scalar => Quantization::Scalar(ScalarQuantization {
+                r#type: QuantizationType::Int8.into(),
+                always_ram: Some(true),
+                ..Default::default()
+            }),
+            VectorQuantization::Binary => Quantization::Binary(BinaryQuantization {
+                always_ram: Some(true),
+            }),
         };
+        QuantizationConfig {
+            quantization: Some(quantization),
+        }
+    });
 
+    CreateCollection {
+        collection_name: collection_name.to_string(),
+        vectors_config: Some(VectorsConfig {
+            config: Some(Config::Params(VectorParams {
+                size: params.vector_size as u64,
+                distance: distance.into(),
+                ..Default::default()
+            })),
+        }),
+        sparse_vectors_config: Some(SparseVectorConfig {
+            map: HashMap::from([(
+                SPARSE_VECTOR_NAME.to_string(),
+                SparseVectorParams {
+                    modifier: Some(Modifier::Idf.into()),
+                    ..Default::default()
+                },
+            )]),
+        }),
+        hnsw_config: params.hnsw.map(|hnsw| HnswConfigDiff {
+            m: Some(hnsw.m),
+            ef_construct: Some(hnsw.ef_construct),
+            ..Default::default()
+        }),
+        quantization_config: quantization,
+        ..Default::default()
+    }
+}
+
+/// Qdrant applies the actions of one request atomically,
+/// so the readers of the alias never miss it
+fn alias_actions(alias: &str, collection_name: &str, alias_exists: bool) -> Vec<AliasOperations> {
+    let mut actions = vec![];
+    if alias_exists {
+        actions.push(Action::DeleteAlias(DeleteAlias {
+            alias_name: alias.to_string(),
+        }));
+    }
+    actions.push(Action::CreateAlias(CreateAlias {
+        collection_name: collection_name.to_string(),
+        alias_name: alias.to_string(),
+    }));
+    actions.into_iter().map(AliasOperations::from).collect()
+}
+
+fn point_id_to_string(point_id: Option<PointId>) -> String {
+    match point_id.and_then(|id| id.point_id_options) {
+        Some(PointIdOptions::Uuid(uuid)) => uuid,
+        Some(PointIdOptions::Num(num)) => num.to_string(),
+        None => String::new(),
+    }
+}
+
+fn to_point_ids(ids: Vec<String>) -> Vec<PointId> {
+    ids.into_iter().map(PointId::from).collect()
+}
+
+fn to_vector_payload(payload: HashMap<String, Value>) -> VectorPayload {
+    let payload: Map<String, serde_json::Value> = payload
+        .into_iter()
+        .map(|(key, value)| (key, value.into_json()))
+        .collect();
+    VectorPayload(payload)
+}
+
+fn to_vector_point(point: RetrievedPoint) -> VectorPoint {
+    VectorPoint {
+        id: point_id_to_string(point.id),
+        vector: to_vector(point.vectors).unwrap_or_default(),
+        payload: to_vector_payload(point.payload),
+    }
+}
+
+fn to_vector_hit(point: ScoredPoint) -> VectorHit {
+    VectorHit {
+        id: point_id_to_string(point.id),
+        score: point.score,
+        payload: to_vector_payload(point.payload),
+        vector: to_vector(point.vectors),
+    }
+}
+
+fn to_vector(vectors: Option<Vectors>) -> Option<Vec<f32>> {
+    match vectors?.vectors_options? {
+        VectorsOptions::Vector(vector) => Some(vector.data),
+        VectorsOptions::Vectors(named) => named
+            .vectors
+            .get(DENSE_VECTOR_NAME)
+            .map(|vector| vector.data.clone()),
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantDb {
+    async fn collection_exists(&self, collection_name: &str) -> Result<bool> {
+        Ok(self
+            .qdrant_client
+            .collection_exists(collection_name)
+            .await?)
+    }
+
+    async fn create_collection(
+        &self,
+        collection_name: &str,
+        params: &CollectionParams,
+    ) -> Result<()> {
+        if self.collection_exists(collection_name).await? {
+            self.keyword_index_of(collection_name).await?;
+            return Ok(());
+        }
+
+        info!("Create collection {}", collection_name);
         self.qdrant_client
-            .upsert_points(UpsertPointsBuilder::new(collection_name, points))
+            .create_collection(collection_details(collection_name, params))
+            .await?;
+        self.create_payload_indexes(collection_name, &params.payload_indexes)
             .await?;
+
+        self.keyword_indexed
+            .write()
+            .expect("Qdrant collections lock is poisoned")
+            .insert(collection_name.to_string(), true);
         Ok(())
     }
 
-    pub async fn vector_search(
+    async fn create_payload_indexes(
         &self,
         collection_name: &str,
-        embedding_vec: Vec<f32>,
-        limit: u64,
-    ) -> Result<SearchResponse> {
-        let builder = SearchPointsBuilder::new(collection_name, embedding_vec, limit)
+        indexes: &[PayloadIndex],
+    ) -> Result<()> {
+        for index in indexes {
+            let field_type = match index.kind {
+                PayloadIndexKind::Keyword => FieldType::Keyword,
+                PayloadIndexKind::Integer => FieldType::Integer,
+                PayloadIndexKind::Float => FieldType::Float,
+                PayloadIndexKind::Text => FieldType::Text,
+            };
+            let request =
+                CreateFieldIndexCollectionBuilder::new(collection_name, &index.field, field_type);
+            self.qdrant_client.create_field_index(request).await?;
+        }
+        Ok(())
+    }
+
+    async fn is_keyword_indexed(&self, collection_name: &str) -> Result<bool> {
+        self.keyword_index_of(collection_name).await
+    }
+
+    async fn upsert(&self, collection_name: &str, points: Vec<VectorPoint>) -> Result<()> {
+        let Some(first_point) = points.first() else {
+            return Ok(());
+        };
+        self.create_collection_if_missing(collection_name, first_point.vector.len())
+            .await?;
+        let keyword_indexed = self.keyword_index_of(collection_name).await?;
+
+        let points = points
+            .into_iter()
+            .map(|point| {
+                let sparse_vector =
+                    SparseVector::document(point.payload.text().unwrap_or_default());
+                let vectors: Vectors = if keyword_indexed && !sparse_vector.is_empty() {
+                    NamedVectors::default()
+                        .add_vector(DENSE_VECTOR_NAME, point.vector)
+                        .add_vector(
+                            SPARSE_VECTOR_NAME,
+                            Vector::new_sparse(sparse_vector.indices, sparse_vector.values),
+                        )
+                        .into()
+                } else {
+                    point.vector.into()
+                };
+                let payload = Payload::from(point.payload.0);
+                PointStruct::new(point.id, vectors, payload)
+            })
+            .collect::<Vec<PointStruct>>();
+
+        for batch in points.chunks(self.upsert_batch_size) {
+            self.qdrant_client
+                .upsert_points(UpsertPointsBuilder::new(collection_name, batch.to_vec()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn query(&self, collection_name: &str, query: SearchQuery) -> Result<Vec<VectorHit>> {
+        let filter = query.filter.as_ref().map(Filter::from);
+        let candidates = match query.keywords {
+            Some(_) => query.limit * HYBRID_CANDIDATES_FACTOR,
+            None => query.limit,
+        };
+
+        let mut builder = SearchPointsBuilder::new(collection_name, query.vector, candidates)
             .with_payload(true)
             .params(SearchParamsBuilder::default().exact(true));
-
+        if let Some(filter) = filter.clone() {
+            builder = builder.filter(filter);
+        }
+        if let Some(min_score) = query.min_score {
+            builder = builder.score_threshold(min_score);
+        }
         let search_result = self.qdrant_client.search_points(builder).await?;
+        let vector_hits: Vec<VectorHit> = search_result
+            .result
+            .into_iter()
+            .map(to_vector_hit)
+            .collect();
+
+        let Some(keywords) = query.keywords else {
+            return Ok(vector_hits);
+        };
+        let keyword_hits = self
+            .keyword_search(collection_name, &keywords, filter, candidates)
+            .await?;
+        Ok(fuse_rankings(vec![vector_hits, keyword_hits], query.limit))
+    }
+
+    async fn get(&self, collection_name: &str, ids: Vec<String>) -> Result<Vec<VectorPoint>> {
+        if !self.collection_exists(collection_name).await? {
+            return Ok(vec![]);
+        }
 
-        Ok(search_result)
+        let query = GetPointsBuilder::new(collection_name, to_point_ids(ids))
+            .with_payload(true)
+            .with_vectors(true);
+        let get_result = self.qdrant_client.get_points(query).await?;
+
+        let points = get_result.result.into_iter().map(to_vector_point).collect();
+        Ok(points)
     }
 
-    pub async fn text_search(
+    async fn delete(&self, collection_name: &str, ids: Vec<String>) -> Result<()> {
+        if !self.collection_exists(collection_name).await? {
+            return Ok(());
+        }
+
+        let delete_request = DeletePointsBuilder::new(collection_name).points(PointsIdsList {
+            ids: to_point_ids(ids),
+        });
+        self.qdrant_client.delete_points(delete_request).await?;
+        Ok(())
+    }
+    async fn set_payload(
         &self,
         collection_name: &str,
-        text: String,
-        search_vectors_limit: u64,
-    ) -> Result<SearchResponse> {
-        let maybe_embedding = self.nervo_llm.text_to_embeddings(&text).await?;
-
-        match maybe_embedding {
-            None => {
-                bail!("No embedding data found.");
-            }
-            Some(embedding) => {
-                self.vector_search(&collection_name, embedding.embedding, search_vectors_limit)
-                    .await
-            }
-        }
+        id: String,
+        fields: VectorPayload,
+    ) -> Result<()> {
+        let request = SetPayloadPointsBuilder::new(collection_name, Payload::from(fields.0))
+            .points_selector(PointsIdsList {
+                ids: to_point_ids(vec![id]),
+            })
+            .wait(true);
+        self.qdrant_client.set_payload(request).await?;
+        Ok(())
     }
 
-    pub async fn find_by_id(&self, agent_type: AgentType, id: Uuid) -> Result<GetResponse> {
-        let collection_name = NervoAgentType::get_name(agent_type);
-        let col_exists = self
-            .qdrant_client
-            .collection_exists(&collection_name)
-            .await?;
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        offset: Option<String>,
+        limit: u64,
+    ) -> Result<ScrollPage> {
+        if !self.collection_exists(collection_name).await? {
+            return Ok(ScrollPage::default());
+        }
 
-        if col_exists {
-            let col_name = NervoAgentType::get_name(agent_type);
-            let query = GetPointsBuilder::new(col_name, vec![id.to_string().into()]);
+        let mut request = ScrollPointsBuilder::new(collection_name)
+            .limit(limit as u32)
+            .with_payload(true)
+            .with_vectors(true);
+        if let Some(offset) = offset {
+            request = request.offset(PointId::from(offset));
+        }
+        let scroll_result = self.qdrant_client.scroll(request).await?;
 
-            let search_result = self.qdrant_client.get_points(query).await?;
+        Ok(ScrollPage {
+            points: scroll_result
+                .result
+                .into_iter()
+                .map(to_vector_point)
+                .collect(),
+            next_offset: scroll_result
+                .next_page_offset
+                .map(|offset| point_id_to_string(Some(offset))),
+        })
+    }
 
-            Ok(search_result)
-        } else {
-            Ok(GetResponse::default())
+    async fn delete_collection(&self, collection_name: &str) -> Result<()> {
+        if self.collection_exists(collection_name).await? {
+            self.qdrant_client
+                .delete_collection(collection_name)
+                .await?;
         }
+        self.keyword_indexed
+            .write()
+            .expect("Qdrant collections lock is poisoned")
+            .remove(collection_name);
+        Ok(())
     }
 
-    pub async fn delete_by_id(&self, agent_type: AgentType, id: Uuid) -> Result<DeleteResult> {
-        let col_name = NervoAgentType::get_name(agent_type);
-        let search_result = self.find_by_id(agent_type, id).await?;
+    async fn list_collections(&self) -> Result<Vec<String>> {
+        let collections = self.qdrant_client.list_collections().await?;
+        Ok(collections
+            .collections
+            .into_iter()
+            .map(|collection| collection.name)
+            .collect())
+    }
 
-        if search_result.result.is_empty() {
-            info!("No matching points found.");
-            return Ok(DeleteResult::Success);
+    async fn switch_alias(&self, alias: &str, collection_name: &str) -> Result<()> {
+        let collections = self.list_collections().await?;
+        if collections.iter().any(|name| name == alias) {
+            bail!(
+                "Collection {} is not behind an alias, run `nervo-migrant collections --cutover {}`",
+                alias,
+                alias
+            );
         }
 
-        let Some(point_id) = search_result.result[0].id.clone() else {
-            info!("Couldn't find any points");
-            return Ok(DeleteResult::Success);
+        let alias_exists = self
+            .list_aliases()
+            .await?
+            .iter()
+            .any(|existing| existing.alias == alias);
+        let change_aliases = ChangeAliases {
+            actions: alias_actions(alias, collection_name, alias_exists),
+            timeout: None,
         };
+        #[allow(deprecated)]
+        self.alias_client.update_aliases(change_aliases).await?;
+        self.keyword_indexed
+            .write()
+            .expect("Qdrant collections lock is poisoned")
+            .remove(alias);
+        Ok(())
+    }
 
-        let delete_request = DeletePointsBuilder::new(col_name)
-            .points(vec![point_id])
-            .build();
-
-        let delete_response = self.qdrant_client.delete_points(delete_request).await?;
+    async fn list_aliases(&self) -> Result<Vec<CollectionAlias>> {
+        let aliases = self.qdrant_client.list_aliases().await?;
+        Ok(aliases
+            .aliases
+            .into_iter()
+            .map(|alias| CollectionAlias {
+                alias: alias.alias_name,
+                collection_name: alias.collection_name,
+            })
+            .collect())
+    }
 
-        if delete_response.result.is_some() {
-            Ok(DeleteResult::Success)
-        } else {
-            Ok(DeleteResult::Fail)
-        }
+    async fn delete_alias(&self, alias: &str) -> Result<()> {
+        self.qdrant_client.delete_alias(alias).await?;
+        self.keyword_indexed
+            .write()
+            .expect("Qdrant collections lock is poisoned")
+            .remove(alias);
+        Ok(())
     }
 }
 
-pub enum DeleteResult {
-    Success,
-    Fail,
+#[cfg(test)]
+mod test {
+    use crate::ai::qdrant_db::{alias_actions, collection_details};
+    use crate::ai::vector_store::{CollectionParams, HnswParams, VectorQuantization};
+    use qdrant_client::qdrant::alias_operations::Action;
+    use qdrant_client::qdrant::quantization_config::Quantization;
+    use qdrant_client::qdrant::vectors_config::Config;
+
+    #[test]
+    fn test_alias_actions() {
+        let actions = alias_actions("docs", "docs_v2", true);
+        assert!(matches!(
+            actions[0].action,
+            Some(Action::DeleteAlias(ref delete)) if delete.alias_name == "docs"
+        ));
+        assert!(matches!(
+            actions[1].action,
+            Some(Action::CreateAlias(ref create)) if create.collection_name == "docs_v2"
+        ));
+        assert_eq!(alias_actions("docs", "docs_v1", false).len(), 1);
+    }
+
+    #[test]
+    fn test_collection_details() {
+        let params = CollectionParams {
+            hnsw: Some(HnswParams {
+                m: 32,
+                ef_construct: 200,
+            }),
+            quantization: Some(VectorQuantization::Scalar),
+            ..CollectionParams::new(1536)
+        };
+        let details = collection_details("docs", &params);
+
+        let Some(Config::Params(vector_params)) = details.vectors_config.unwrap().config else {
+            panic!("No vector params");
+        };
+        assert_eq!(vector_params.size, 1536);
+        assert_eq!(details.hnsw_config.unwrap().m, Some(32));
+        let quantization = details.quantization_config.unwrap().quantization;
+        assert!(matches!(quantization, Some(Quantization::Scalar(_))));
+        assert!(details.sparse_vectors_config.is_some());
+    }
 }
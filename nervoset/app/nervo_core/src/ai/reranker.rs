@@ -0,0 +1,259 @@
+use crate::ai::keyword_search::tokenize;
+use crate::ai::nervo_llm::{LlmRequestOptions, NervoLlm};
+use crate::ai::vector_store::{cosine_similarity, VectorHit};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+
+const LLM_JUDGE_SYSTEM_ROLE: &str = "You are provided with the user request and the numbered \
+    text chunks found in the knowledge base. Rate how useful every chunk is to answer the request, \
+    from 0 (unrelated) to 1 (answers it). Reply with the scores in the order of the chunks.";
+
+/// How the found chunks are rescored against the request
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum RerankerConfig {
+    /// Keeps the search scores
+    #[default]
+    Score,
+    /// LLM rates every chunk from 0 to 1
+    #[serde(rename_all = "camelCase")]
+    LlmJudge { system_role_text: Option<String> },
+    /// Cross-encoder served with the `/rerank` API of text-embeddings-inference
+    CrossEncoder { url: String },
+}
+
+#[async_trait]
+pub trait Reranker: Send + Sync {
+    /// The hits with the new scores, the highest first
+    async fn rerank(&self, query: &str, hits: Vec<VectorHit>) -> Result<Vec<VectorHit>>;
+}
+
+pub fn build_reranker(config: &RerankerConfig, nervo_llm: &NervoLlm) -> Box<dyn Reranker> {
+    match config {
+        RerankerConfig::Score => Box::new(ScoreReranker),
+        RerankerConfig::LlmJudge { system_role_text } => Box::new(LlmJudgeReranker {
+            nervo_llm: nervo_llm.clone(),
+            system_role: system_role_text
+                .clone()
+                .unwrap_or_else(|| LLM_JUDGE_SYSTEM_ROLE.to_string()),
+        }),
+        RerankerConfig::CrossEncoder { url } => Box::new(CrossEncoderReranker {
+            client: reqwest::Client::new(),
+            url: url.clone(),
+        }),
+    }
+}
+
+pub struct ScoreReranker;
+
+#[async_trait]
+impl Reranker for ScoreReranker {
+    async fn rerank(&self, _query: &str, hits: Vec<VectorHit>) -> Result<Vec<VectorHit>> {
+        Ok(sorted_by_score(hits))
+    }
+}
+
+pub struct LlmJudgeReranker {
+    nervo_llm: NervoLlm,
+    system_role: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RelevanceScores {
+    scores: Vec<f32>,
+}
+
+#[async_trait]
+impl Reranker for LlmJudgeReranker {
+    async fn rerank(&self, query: &str, hits: Vec<VectorHit>) -> Result<Vec<VectorHit>> {
+        if hits.is_empty() {
+            return Ok(hits);
+        }
+
+        let chunks = hits
+            .iter()
+            .enumerate()
+            .map(|(index, hit)| format!("[{}] {}", index + 1, hit.payload.text().unwrap_or("")))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let request = format!("Request: {}\nChunks:\n{}", query, chunks);
+        let options = LlmRequestOptions {
+            temperature: Some(0.0),
+            ..LlmRequestOptions::default()
+        };
+        let relevance: RelevanceScores = self
+            .nervo_llm
+            .complete_json(&self.system_role, &request, &options)
+            .await?;
+        info!("LLM relevance scores: {:?}", relevance.scores);
+
+        // Chunks the judge didn't rate are treated as unrelated
+        let hits = hits
+            .into_iter()
+            .enumerate()
+            .map(|(index, hit)| VectorHit {
+                score: relevance.scores.get(index).copied().unwrap_or(0.0),
+                ..hit
+            })
+            .collect();
+        Ok(sorted_by_score(hits))
+    }
+}
+
+pub struct CrossEncoderReranker {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CrossEncoderRequest<'a> {
+    query: &'a str,
+    texts: Vec<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrossEncoderScore {
+    index: usize,
+    score: f32,
+}
+
+#[async_trait]
+impl Reranker for CrossEncoderReranker {
+    async fn rerank(&self, query: &str, hits: Vec<VectorHit>) -> Result<Vec<VectorHit>> {
+        if hits.is_empty() {
+            return Ok(hits);
+        }
+
+        let request = CrossEncoderRequest {
+            query,
+            texts: hits
+                .iter()
+                .map(|hit| hit.payload.text().unwrap_or(""))
+                .collect(),
+        };
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Cross-encoder replied with {}", response.status());
+        }
+        let scores: Vec<CrossEncoderScore> = serde_json::from_slice(&response.bytes().await?)?;
+
+        let mut hits: Vec<Option<VectorHit>> = hits.into_iter().map(Some).collect();
+        let reranked = scores
+            .into_iter()
+            .filter_map(|score| {
+                let hit = hits.get_mut(score.index)?.take()?;
+                Some(VectorHit {
+                    score: score.score,
+                    ..hit
+                })
+            })
+            .collect();
+        Ok(sorted_by_score(reranked))
+    }
+}
+
+fn sorted_by_score(mut hits: Vec<VectorHit>) -> Vec<VectorHit> {
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits
+}
+
+/// Maximal marginal relevance: picks `top_k` hits one by one, trading the score of the hit
+/// for its similarity to the hits already picked. `lambda` 1 keeps the score order,
+/// lower values push near-duplicates down.
+pub fn mmr(hits: Vec<VectorHit>, lambda: f32, top_k: usize) -> Vec<VectorHit> {
+    let mut candidates = hits;
+    let mut selected: Vec<VectorHit> = vec![];
+    while selected.len() < top_k && !candidates.is_empty() {
+        let marginal_relevance = |hit: &VectorHit| {
+            let redundancy = selected
+                .iter()
+                .map(|picked| similarity(hit, picked))
+                .fold(0.0, f32::max);
+            lambda * hit.score - (1.0 - lambda) * redundancy
+        };
+
+        let best = candidates
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| marginal_relevance(a).total_cmp(&marginal_relevance(b)))
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        selected.push(candidates.remove(best));
+    }
+    selected
+}
+
+/// Cosine similarity of the vectors, or the word overlap if the store didn't return them
+fn similarity(left: &VectorHit, right: &VectorHit) -> f32 {
+    if let (Some(left), Some(right)) = (&left.vector, &right.vector) {
+        return cosine_similarity(left, right);
+    }
+
+    let words = |hit: &VectorHit| -> HashSet<String> {
+        tokenize(hit.payload.text().unwrap_or_default())
+            .into_iter()
+            .collect()
+    };
+    let (left, right) = (words(left), words(right));
+    let union = left.union(&right).count();
+    if union == 0 {
+        return 0.0;
+    }
+    left.intersection(&right).count() as f32 / union as f32
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::ai::reranker::{build_reranker, mmr, RerankerConfig};
+    use crate::ai::vector_store::{VectorHit, VectorPayload};
+
+    fn hit(id: &str, score: f32, text: &str) -> VectorHit {
+        VectorHit {
+            id: id.to_string(),
+            score,
+            payload: VectorPayload::from_text(text),
+            vector: None,
+        }
+    }
+
+    #[test]
+    fn test_mmr_pushes_duplicates_down() {
+        let hits = vec![
+            hit("a", 0.9, "the office is open from 9 to 18"),
+            hit("b", 0.89, "the office is open from 9 to 18 on weekdays"),
+            hit("c", 0.7, "parking is free for the visitors"),
+        ];
+
+        let ids = |hits: Vec<VectorHit>| hits.into_iter().map(|hit| hit.id).collect::<Vec<_>>();
+        assert_eq!(ids(mmr(hits.clone(), 1.0, 2)), vec!["a", "b"]);
+        assert_eq!(ids(mmr(hits, 0.5, 2)), vec!["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_llm_judge_rescores_hits() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec![
+            r#"{"scores": [0.1, 0.8]}"#,
+        ]));
+        let config: RerankerConfig = serde_json::from_str(r#"{"kind": "llmJudge"}"#)?;
+        let reranker = build_reranker(&config, &nervo_llm);
+
+        let hits = vec![hit("a", 0.9, "cats"), hit("b", 0.5, "dogs")];
+        let reranked = reranker.rerank("tell me about dogs", hits).await?;
+        assert_eq!(reranked[0].id, "b");
+        assert_eq!(reranked[0].score, 0.8);
+        assert_eq!(reranked[1].score, 0.1);
+        Ok(())
+    }
+}
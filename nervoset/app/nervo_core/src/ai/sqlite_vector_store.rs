@@ -0,0 +1,268 @@
+use crate::ai::vector_store::{
+    query_points, ScrollPage, SearchQuery, VectorHit, VectorPayload, VectorPoint, VectorStore,
+};
+use crate::config::common::DatabaseParams;
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteRow,
+};
+use sqlx::Row;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+const MAX_CONNECTIONS: u32 = 5;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps vectors in the `vector_points` table of the local sqlite database.
+/// Search loads the whole collection and compares the vectors one by one,
+/// so it suits collections of a few thousand points.
+pub struct SqliteVectorStore {
+    pool: SqlitePool,
+    /// The table is created by the first request
+    schema: OnceCell<()>,
+}
+
+impl SqliteVectorStore {
+    pub fn try_init(db_params: DatabaseParams) -> Result<Self> {
+        let connect_options = SqliteConnectOptions::from_str(db_params.url.as_str())?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect_lazy_with(connect_options);
+
+        Ok(Self {
+            pool,
+            schema: OnceCell::new(),
+        })
+    }
+
+    async fn pool(&self) -> Result<&SqlitePool> {
+        self.schema
+            .get_or_try_init(|| async {
+                let query = "CREATE TABLE IF NOT EXISTS vector_points (
+                        collection_name TEXT NOT NULL,
+                        id TEXT NOT NULL,
+                        vector TEXT NOT NULL,
+                        payload TEXT NOT NULL,
+                        PRIMARY KEY (collection_name, id)
+                    )";
+                sqlx::query(query).execute(&self.pool).await.map(|_| ())
+            })
+            .await?;
+        Ok(&self.pool)
+    }
+}
+
+fn point_from_row(row: SqliteRow) -> Result<VectorPoint> {
+    let vector_json: String = row.try_get("vector")?;
+    let payload_json: String = row.try_get("payload")?;
+
+    Ok(VectorPoint {
+        id: row.try_get("id")?,
+        vector: serde_json::from_str(&vector_json)?,
+        payload: serde_json::from_str(&payload_json)?,
+    })
+}
+
+#[async_trait]
+impl VectorStore for SqliteVectorStore {
+    async fn collection_exists(&self, collection_name: &str) -> Result<bool> {
+        let pool = self.pool().await?;
+        let exists: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM vector_points WHERE collection_name = ?)",
+        )
+        .bind(collection_name)
+        .fetch_one(pool)
+        .await?;
+        Ok(exists)
+    }
+
+    async fn upsert(&self, collection_name: &str, points: Vec<VectorPoint>) -> Result<()> {
+        let mut tx = self.pool().await?.begin().await?;
+        for point in points {
+            sqlx::query(
+                "INSERT OR REPLACE INTO vector_points (collection_name, id, vector, payload) \
+                VALUES (?, ?, ?, ?)",
+            )
+            .bind(collection_name)
+            .bind(&point.id)
+            .bind(serde_json::to_string(&point.vector)?)
+            .bind(serde_json::to_string(&point.payload)?)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn query(&self, collection_name: &str, query: SearchQuery) -> Result<Vec<VectorHit>> {
+        let pool = self.pool().await?;
+        let rows =
+            sqlx::query("SELECT id, vector, payload FROM vector_points WHERE collection_name = ?")
+                .bind(collection_name)
+                .fetch_all(pool)
+                .await?;
+        info!("Searching through {} sqlite vectors", rows.len());
+
+        let points = rows
+            .into_iter()
+            .map(point_from_row)
+            .collect::<Result<Vec<VectorPoint>>>()?;
+        Ok(query_points(points, &query))
+    }
+
+    async fn get(&self, collection_name: &str, ids: Vec<String>) -> Result<Vec<VectorPoint>> {
+        let pool = self.pool().await?;
+        let mut points = vec![];
+        for id in ids {
+            let maybe_row = sqlx::query(
+                "SELECT id, vector, payload FROM vector_points WHERE collection_name = ? AND id = ?",
+            )
+            .bind(collection_name)
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+            if let Some(row) = maybe_row {
+                points.push(point_from_row(row)?);
+            }
+        }
+        Ok(points)
+    }
+
+    async fn delete(&self, collection_name: &str, ids: Vec<String>) -> Result<()> {
+        let mut tx = self.pool().await?.begin().await?;
+        for id in ids {
+            sqlx::query("DELETE FROM vector_points WHERE collection_name = ? AND id = ?")
+                .bind(collection_name)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_payload(
+        &self,
+        collection_name: &str,
+        id: String,
+        fields: VectorPayload,
+    ) -> Result<()> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            "UPDATE vector_points SET payload = json_patch(payload, ?) \
+            WHERE collection_name = ? AND id = ?",
+        )
+        .bind(serde_json::to_string(&fields)?)
+        .bind(collection_name)
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        offset: Option<String>,
+        limit: u64,
+    ) -> Result<ScrollPage> {
+        let pool = self.pool().await?;
+        // One extra row tells where the next page starts
+        let rows = sqlx::query(
+            "SELECT id, vector, payload FROM vector_points \
+            WHERE collection_name = ? AND id >= ? ORDER BY id LIMIT ?",
+        )
+        .bind(collection_name)
+        .bind(offset.unwrap_or_default())
+        .bind(limit as i64 + 1)
+        .fetch_all(pool)
+        .await?;
+
+        let mut points = rows
+            .into_iter()
+            .map(point_from_row)
+            .collect::<Result<Vec<VectorPoint>>>()?;
+        let next_offset = points.get(limit as usize).map(|point| point.id.clone());
+        points.truncate(limit as usize);
+        Ok(ScrollPage {
+            points,
+            next_offset,
+        })
+    }
+
+    async fn delete_collection(&self, collection_name: &str) -> Result<()> {
+        let pool = self.pool().await?;
+        sqlx::query("DELETE FROM vector_points WHERE collection_name = ?")
+            .bind(collection_name)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_collections(&self) -> Result<Vec<String>> {
+        let pool = self.pool().await?;
+        let collections = sqlx::query_scalar("SELECT DISTINCT collection_name FROM vector_points")
+            .fetch_all(pool)
+            .await?;
+        Ok(collections)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::sqlite_vector_store::SqliteVectorStore;
+    use crate::ai::vector_store::{VectorPayload, VectorPoint, VectorStore};
+    use crate::config::common::DatabaseParams;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_points_survive_reconnect() -> anyhow::Result<()> {
+        let db_path = std::env::temp_dir().join(format!("nervo_vectors_{}.db", Uuid::new_v4()));
+        let db_params = DatabaseParams {
+            url: format!("sqlite://{}", db_path.display()),
+        };
+
+        let store = SqliteVectorStore::try_init(db_params.clone())?;
+        let point = VectorPoint {
+            id: "x".to_string(),
+            vector: vec![1.0, 0.0],
+            payload: VectorPayload::from_text("hello"),
+        };
+        store.upsert("test", vec![point.clone()]).await?;
+
+        let store = SqliteVectorStore::try_init(db_params)?;
+        assert!(store.collection_exists("test").await?);
+        let hits = store.search("test", vec![1.0, 0.0], 3).await?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].payload.text(), Some("hello"));
+        assert_eq!(
+            store.get("test", vec!["x".to_string()]).await?,
+            vec![point.clone()]
+        );
+
+        let mut fields = VectorPayload::default();
+        fields.0.insert("recall_count".to_string(), 1.into());
+        store.set_payload("test", "x".to_string(), fields).await?;
+        let updated = store.get("test", vec!["x".to_string()]).await?;
+        assert_eq!(updated[0].payload.text(), Some("hello"));
+        assert_eq!(updated[0].payload.0["recall_count"], 1);
+        assert_eq!(updated[0].vector, point.vector);
+
+        let page = store.scroll("test", None, 10).await?;
+        assert_eq!(page.points.len(), 1);
+        assert_eq!(page.next_offset, None);
+
+        store.delete("test", vec!["x".to_string()]).await?;
+        assert!(!store.collection_exists("test").await?);
+
+        std::fs::remove_file(db_path)?;
+        Ok(())
+    }
+}
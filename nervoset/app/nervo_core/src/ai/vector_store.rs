@@ -0,0 +1,467 @@
+use crate::ai::keyword_search::bm25_rank;
+use anyhow::bail;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveTime};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Hybrid search fuses this many times more candidates of each kind than it returns
+pub const HYBRID_CANDIDATES_FACTOR: u64 = 4;
+/// Reciprocal rank fusion constant, it damps the weight of the top ranks
+const RRF_K: f32 = 60.0;
+const SCROLL_PAGE_SIZE: u64 = 100;
+
+/// Payload fields the filters work with
+pub mod payload_fields {
+    pub const AGENT_TYPE: &str = "agent_type";
+    pub const TAGS: &str = "tags";
+    pub const LANGUAGE: &str = "language";
+    /// The document the point was made of
+    pub const SOURCE: &str = "source";
+    /// Unix time in seconds
+    pub const CREATED_AT: &str = "created_at";
+}
+
+/// Which backend keeps the vectors of [`crate::ai::ai_db::NervoAiDb`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorStoreType {
+    #[default]
+    Qdrant,
+    /// Brute-force search in the process memory, nothing survives a restart
+    Memory,
+    /// Brute-force search over vectors kept in the local sqlite database
+    Sqlite,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VectorDistance {
+    #[default]
+    Cosine,
+    Dot,
+    Euclid,
+}
+
+/// Graph index settings, the higher the values the better the recall and the slower the build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HnswParams {
+    /// Edges per node
+    pub m: u64,
+    /// Neighbours considered while building the index
+    pub ef_construct: u64,
+}
+
+/// Compressed copy of the vectors kept in memory, the originals are used for rescoring
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VectorQuantization {
+    /// One byte per dimension
+    Scalar,
+    /// One bit per dimension, for the large embedding models
+    Binary,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadIndexKind {
+    Keyword,
+    Integer,
+    Float,
+    /// Full-text index
+    Text,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PayloadIndex {
+    pub field: String,
+    pub kind: PayloadIndexKind,
+}
+
+/// Settings of a new collection. The stores that search point by point
+/// use cosine similarity and ignore everything but the vector size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionParams {
+    pub vector_size: usize,
+    pub distance: VectorDistance,
+    /// The defaults of the store if not set
+    pub hnsw: Option<HnswParams>,
+    pub quantization: Option<VectorQuantization>,
+    pub payload_indexes: Vec<PayloadIndex>,
+}
+
+impl CollectionParams {
+    /// Cosine collection with the fields of [`PayloadFilter`] indexed
+    pub fn new(vector_size: usize) -> Self {
+        CollectionParams {
+            vector_size,
+            distance: VectorDistance::Cosine,
+            hnsw: None,
+            quantization: None,
+            payload_indexes: PayloadIndex::filter_fields(),
+        }
+    }
+}
+
+impl PayloadIndex {
+    /// Indexes of the fields [`PayloadFilter`] filters by
+    pub fn filter_fields() -> Vec<PayloadIndex> {
+        let index = |field: &str, kind| PayloadIndex {
+            field: field.to_string(),
+            kind,
+        };
+        vec![
+            index(payload_fields::AGENT_TYPE, PayloadIndexKind::Keyword),
+            index(payload_fields::TAGS, PayloadIndexKind::Keyword),
+            index(payload_fields::LANGUAGE, PayloadIndexKind::Keyword),
+            index(payload_fields::SOURCE, PayloadIndexKind::Keyword),
+            index(payload_fields::CREATED_AT, PayloadIndexKind::Integer),
+        ]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CollectionAlias {
+    pub alias: String,
+    pub collection_name: String,
+}
+
+/// Json object stored next to a vector
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorPayload(pub Map<String, Value>);
+
+impl VectorPayload {
+    pub fn from_text(text: &str) -> Self {
+        let mut payload = Map::new();
+        payload.insert("text".to_string(), Value::from(text));
+        VectorPayload(payload)
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.0.get("text").and_then(|text| text.as_str())
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.0
+            .get(payload_fields::SOURCE)
+            .and_then(|source| source.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VectorPoint {
+    pub id: String,
+    pub vector: Vec<f32>,
+    pub payload: VectorPayload,
+}
+
+/// A point found by a similarity search, the higher the score the closer the point
+#[derive(Clone, Debug, PartialEq)]
+pub struct VectorHit {
+    pub id: String,
+    pub score: f32,
+    pub payload: VectorPayload,
+    /// Filled only if the store returns vectors along with the search results
+    pub vector: Option<Vec<f32>>,
+}
+
+/// Conditions on the payload fields, every set condition must hold.
+/// Points without the field don't pass the condition on it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadFilter {
+    pub agent: Option<String>,
+    /// Any of the tags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub language: Option<String>,
+    pub source: Option<String>,
+    /// Inclusive, i.e. `2024-12-01`
+    pub date_from: Option<NaiveDate>,
+    /// Inclusive
+    pub date_to: Option<NaiveDate>,
+}
+
+impl PayloadFilter {
+    /// Bounds of `created_at` in unix seconds, both inclusive
+    pub fn created_at_range(&self) -> (Option<i64>, Option<i64>) {
+        let from = self
+            .date_from
+            .map(|date| date.and_time(NaiveTime::MIN).and_utc().timestamp());
+        let to = self.date_to.and_then(|date| {
+            let next_day = date.succ_opt()?;
+            Some(next_day.and_time(NaiveTime::MIN).and_utc().timestamp() - 1)
+        });
+        (from, to)
+    }
+
+    pub fn matches(&self, payload: &VectorPayload) -> bool {
+        let text_field = |field: &str| payload.0.get(field).and_then(Value::as_str);
+        let equals = |field: &str, expected: &Option<String>| {
+            expected
+                .as_ref()
+                .is_none_or(|expected| text_field(field) == Some(expected.as_str()))
+        };
+
+        let has_tag = self.tags.is_empty()
+            || match payload.0.get(payload_fields::TAGS) {
+                Some(Value::Array(tags)) => tags
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|tag| self.tags.iter().any(|expected| expected == tag)),
+                Some(Value::String(tag)) => self.tags.contains(tag),
+                _ => false,
+            };
+
+        let created_at = payload
+            .0
+            .get(payload_fields::CREATED_AT)
+            .and_then(Value::as_i64);
+        let in_range = match self.created_at_range() {
+            (None, None) => true,
+            (from, to) => created_at.is_some_and(|created_at| {
+                from.is_none_or(|from| created_at >= from) && to.is_none_or(|to| created_at <= to)
+            }),
+        };
+
+        equals(payload_fields::AGENT_TYPE, &self.agent)
+            && equals(payload_fields::LANGUAGE, &self.language)
+            && equals(payload_fields::SOURCE, &self.source)
+            && has_tag
+            && in_range
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub vector: Vec<f32>,
+    /// Text for the keyword search, its results are fused with the vector search ones
+    pub keywords: Option<String>,
+    pub filter: Option<PayloadFilter>,
+    /// Vector hits scored lower are dropped before the fusion with the keyword ones,
+    /// the fused scores are ranks and can't be compared with it
+    pub min_score: Option<f32>,
+    pub limit: u64,
+}
+
+/// A page of the collection points in id order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScrollPage {
+    pub points: Vec<VectorPoint>,
+    /// Id of the first point of the next page, `None` on the last page
+    pub next_offset: Option<String>,
+}
+
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    async fn collection_exists(&self, collection_name: &str) -> Result<bool>;
+
+    /// Creates the collection if it is missing. The stores without the collection settings
+    /// create the collections on the first write.
+    async fn create_collection(
+        &self,
+        _collection_name: &str,
+        _params: &CollectionParams,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Indexes the payload fields of an existing collection, the fields indexed already
+    /// keep their indexes. The stores that filter point by point need no indexes
+    async fn create_payload_indexes(
+        &self,
+        _collection_name: &str,
+        _indexes: &[PayloadIndex],
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the keyword search works on the collection. The stores that search
+    /// point by point index the keywords on the fly
+    async fn is_keyword_indexed(&self, _collection_name: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Inserts or replaces the points, the collection gets created on the first write
+    async fn upsert(&self, collection_name: &str, points: Vec<VectorPoint>) -> Result<()>;
+
+    /// Cosine similarity search
+    async fn search(
+        &self,
+        collection_name: &str,
+        vector: Vec<f32>,
+        limit: u64,
+    ) -> Result<Vec<VectorHit>> {
+        let query = SearchQuery {
+            vector,
+            limit,
+            ..SearchQuery::default()
+        };
+        self.query(collection_name, query).await
+    }
+
+    /// Cosine similarity search among the points that pass the filter,
+    /// fused with the keyword search if the query has keywords
+    async fn query(&self, collection_name: &str, query: SearchQuery) -> Result<Vec<VectorHit>>;
+
+    async fn get(&self, collection_name: &str, ids: Vec<String>) -> Result<Vec<VectorPoint>>;
+
+    async fn delete(&self, collection_name: &str, ids: Vec<String>) -> Result<()>;
+
+    /// Overwrites the fields in the payload of the point, the other fields and the vector
+    /// are kept. Unlike [`VectorStore::upsert`] it never brings back a deleted point
+    async fn set_payload(
+        &self,
+        collection_name: &str,
+        id: String,
+        fields: VectorPayload,
+    ) -> Result<()>;
+
+    /// Reads the collection page by page, starting from the `offset` id
+    async fn scroll(
+        &self,
+        collection_name: &str,
+        offset: Option<String>,
+        limit: u64,
+    ) -> Result<ScrollPage>;
+
+    /// All the points of the collection with their payloads
+    async fn scroll_all(&self, collection_name: &str) -> Result<Vec<VectorPoint>> {
+        let mut points = vec![];
+        let mut offset = None;
+        loop {
+            let page = self
+                .scroll(collection_name, offset, SCROLL_PAGE_SIZE)
+                .await?;
+            points.extend(page.points);
+
+            match page.next_offset {
+                Some(next_offset) => offset = Some(next_offset),
+                None => return Ok(points),
+            }
+        }
+    }
+
+    async fn delete_collection(&self, collection_name: &str) -> Result<()>;
+
+    async fn list_collections(&self) -> Result<Vec<String>>;
+
+    /// Points the alias to the collection in one step, the requests to the alias go to the
+    /// collection. Fails if a collection is named as the alias, it has to be moved
+    /// behind the alias first.
+    async fn switch_alias(&self, alias: &str, collection_name: &str) -> Result<()> {
+        bail!(
+            "The store has no aliases, {} can't point to {}",
+            alias,
+            collection_name
+        )
+    }
+
+    async fn list_aliases(&self) -> Result<Vec<CollectionAlias>> {
+        Ok(vec![])
+    }
+
+    async fn delete_alias(&self, alias: &str) -> Result<()> {
+        bail!("The store has no aliases, {} can't be deleted", alias)
+    }
+}
+
+/// Splits the points sorted by id into the page starting from `offset`
+pub fn page_of(mut points: Vec<VectorPoint>, offset: Option<String>, limit: u64) -> ScrollPage {
+    points.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut points: Vec<VectorPoint> = points
+        .into_iter()
+        .filter(|point| offset.as_ref().is_none_or(|offset| &point.id >= offset))
+        .collect();
+
+    let next_offset = points.get(limit as usize).map(|point| point.id.clone());
+    points.truncate(limit as usize);
+    ScrollPage {
+        points,
+        next_offset,
+    }
+}
+
+pub fn cosine_similarity(left: &[f32], right: &[f32]) -> f32 {
+    let dot: f32 = left.iter().zip(right).map(|(l, r)| l * r).sum();
+    let left_norm = left.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let right_norm = right.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if left_norm == 0.0 || right_norm == 0.0 {
+        0.0
+    } else {
+        dot / (left_norm * right_norm)
+    }
+}
+
+/// Scores every point against the vector and keeps the `limit` closest ones
+pub fn rank_points(
+    points: impl IntoIterator<Item = VectorPoint>,
+    vector: &[f32],
+    limit: u64,
+) -> Vec<VectorHit> {
+    let mut hits: Vec<VectorHit> = points
+        .into_iter()
+        .map(|point| VectorHit {
+            score: cosine_similarity(&point.vector, vector),
+            id: point.id,
+            payload: point.payload,
+            vector: Some(point.vector),
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    hits.truncate(limit as usize);
+    hits
+}
+
+/// Runs the query point by point, for the stores without a search index
+pub fn query_points(points: Vec<VectorPoint>, query: &SearchQuery) -> Vec<VectorHit> {
+    let points: Vec<VectorPoint> = points
+        .into_iter()
+        .filter(|point| {
+            query
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(&point.payload))
+        })
+        .collect();
+
+    let min_score = query.min_score.unwrap_or(f32::MIN);
+    let Some(keywords) = &query.keywords else {
+        let mut hits = rank_points(points, &query.vector, query.limit);
+        hits.retain(|hit| hit.score >= min_score);
+        return hits;
+    };
+    let candidates = query.limit * HYBRID_CANDIDATES_FACTOR;
+    let keyword_hits = bm25_rank(&points, keywords, candidates);
+    let mut vector_hits = rank_points(points, &query.vector, candidates);
+    vector_hits.retain(|hit| hit.score >= min_score);
+    fuse_rankings(vec![vector_hits, keyword_hits], query.limit)
+}
+
+/// Reciprocal rank fusion of the rankings. The scores are scaled so the point
+/// ranked first by every ranking gets 1, the point missing from a ranking gets nothing for it.
+pub fn fuse_rankings(rankings: Vec<Vec<VectorHit>>, limit: u64) -> Vec<VectorHit> {
+    let max_score = rankings.len() as f32 / (RRF_K + 1.0);
+    let mut fused: HashMap<String, VectorHit> = HashMap::new();
+    for ranking in rankings {
+        for (rank, hit) in ranking.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0) / max_score;
+            fused
+                .entry(hit.id.clone())
+                .and_modify(|fused_hit| fused_hit.score += score)
+                .or_insert(VectorHit { score, ..hit });
+        }
+    }
+
+    let mut hits: Vec<VectorHit> = fused.into_values().collect();
+    hits.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    hits.truncate(limit as usize);
+    hits
+}
@@ -17,6 +17,18 @@ pub struct NervoConfig {
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppsConfig {
     pub jarvis: JarvisConfig,
+    #[serde(default)]
+    pub server: ServerParams,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServerParams {
+    /// Grants access to the memory of every user and signs the tokens of the users.
+    /// The memory endpoints are closed without it
+    pub api_key: Option<String>,
+    /// Origins allowed to call the memory endpoints from a browser
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -101,6 +113,15 @@ impl TelegramUserAgentClient {
 pub struct QdrantParams {
     pub server_url: String,
     pub api_key: Option<String>,
+    /// Points sent in one upsert request
+    #[serde(default = "QdrantParams::default_upsert_batch_size")]
+    pub upsert_batch_size: usize,
+}
+
+impl QdrantParams {
+    fn default_upsert_batch_size() -> usize {
+        256
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
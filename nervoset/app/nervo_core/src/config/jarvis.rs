@@ -1,21 +1,35 @@
 use crate::ai::ai_db::NervoAiDb;
 use crate::ai::nervo_llm::{NervoLlm, NervoLlmConfig};
+use crate::ai::vector_store::VectorStoreType;
 use crate::config::common::{DatabaseParams, QdrantParams};
 use crate::context::main_handler::UserContextMainHandler;
+use crate::context::memory_consolidation::MemoryConsolidationConfig;
 use crate::db::local_db::LocalDb;
 use crate::models::feature_toggle::FeatureToggle;
+use crate::telegram::chat_sessions::ChatSessions;
 use crate::utils::ai_utils::RESOURCES_DIR;
 use crate::utils::localisation_parser::LocalisationManager;
 use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use tokio::fs;
-use tokio::sync::RwLock;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct JarvisConfig {
     pub llm: NervoLlmConfig,
     pub qdrant: QdrantParams,
     pub database: DatabaseParams,
+    #[serde(default)]
+    pub vector_store: VectorStoreType,
+    /// Max size of the short-term dialogue cache by agent name, 20 by default
+    #[serde(default)]
+    pub dialogue_max_size: HashMap<String, usize>,
+    /// Token budget of the short-term dialogue in the prompt by agent name, 3000 by default.
+    /// Older interactions are summarized
+    #[serde(default)]
+    pub dialogue_token_limit: HashMap<String, usize>,
+    #[serde(default)]
+    pub memory_consolidation: MemoryConsolidationConfig,
 }
 
 /// Application state
@@ -24,28 +38,36 @@ pub struct JarvisAppState {
     pub nervo_ai_db: NervoAiDb,
     pub local_db: LocalDb,
     pub nervo_config: JarvisConfig,
-    pub localisation_manager: RwLock<LocalisationManager>,
+    pub localisation_manager: LocalisationManager,
     pub user_context: UserContextMainHandler,
     pub feature_toggle: Option<FeatureToggle>,
+    pub chat_sessions: ChatSessions,
 }
 
 impl TryFrom<JarvisConfig> for JarvisAppState {
     type Error = anyhow::Error;
 
     fn try_from(nervo_config: JarvisConfig) -> Result<Self, Self::Error> {
-        let nervo_llm = NervoLlm::from(nervo_config.llm.clone());
-        let nervo_ai_db = NervoAiDb::build(&nervo_config.qdrant, nervo_llm.clone())?;
+        let nervo_llm = NervoLlm::try_from(nervo_config.llm.clone())?;
+        let nervo_ai_db = NervoAiDb::build(&nervo_config, nervo_llm.clone())?;
         let local_db = LocalDb::try_init(nervo_config.database.clone())?;
-        let localisation_manager = LocalisationManager::build(nervo_llm.clone())?;
+        let localisation_manager = LocalisationManager::build(nervo_llm.clone(), local_db.clone())?;
+        let user_context = UserContextMainHandler::new(
+            local_db.clone(),
+            nervo_llm.clone(),
+            nervo_config.dialogue_max_size.clone(),
+            nervo_config.dialogue_token_limit.clone(),
+        );
 
         Ok(Self {
             nervo_llm,
             nervo_ai_db,
             local_db,
             nervo_config,
-            localisation_manager: RwLock::new(localisation_manager),
-            user_context: UserContextMainHandler::new(),
+            localisation_manager,
+            user_context,
             feature_toggle: None,
+            chat_sessions: ChatSessions::default(),
         })
     }
 }
@@ -53,10 +75,16 @@ impl TryFrom<JarvisConfig> for JarvisAppState {
 impl JarvisAppState {
     pub async fn create_from(initial_params: InitialParams) -> anyhow::Result<Self> {
         let nervo_config = initial_params.config;
-        let nervo_llm = NervoLlm::from(nervo_config.llm.clone());
-        let nervo_ai_db = NervoAiDb::build(&nervo_config.qdrant, nervo_llm.clone())?;
+        let nervo_llm = NervoLlm::try_from(nervo_config.llm.clone())?;
+        let nervo_ai_db = NervoAiDb::build(&nervo_config, nervo_llm.clone())?;
         let local_db = LocalDb::try_init(nervo_config.database.clone())?;
-        let localisation_manager = LocalisationManager::build(nervo_llm.clone())?;
+        let localisation_manager = LocalisationManager::build(nervo_llm.clone(), local_db.clone())?;
+        let user_context = UserContextMainHandler::new(
+            local_db.clone(),
+            nervo_llm.clone(),
+            nervo_config.dialogue_max_size.clone(),
+            nervo_config.dialogue_token_limit.clone(),
+        );
 
         let agent_type = initial_params.agent_type;
         let agent_name = NervoAgentType::get_name(agent_type);
@@ -69,9 +97,10 @@ impl JarvisAppState {
             nervo_ai_db,
             local_db,
             nervo_config,
-            localisation_manager: RwLock::new(localisation_manager),
-            user_context: UserContextMainHandler::new(),
+            localisation_manager,
+            user_context,
             feature_toggle: Some(feature_toggle),
+            chat_sessions: ChatSessions::default(),
         })
     }
 }
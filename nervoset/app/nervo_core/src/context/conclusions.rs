@@ -1,12 +1,19 @@
+use crate::ai::nervo_llm::LlmRequestOptions;
+use crate::ai::vector_store::VectorPayload;
 use crate::config::jarvis::JarvisAppState;
+use crate::context::memory_consolidation::record_recall;
+use crate::context::permanent_memory::MemorySource;
 use crate::context::user_context::UserContext;
+use crate::context::user_memory::conclusions_collection;
 use crate::telegram::bot_utils::{get_message_related_points, get_payload};
 use crate::utils::ai_utils::filter_search_result;
 use crate::utils::ai_utils_data::system_role::{RolePathBuilder, RoleType};
 use crate::utils::ai_utils_data::SortingType::Ascending;
 use crate::utils::ai_utils_data::TruncatingType;
 use nervo_sdk::agent_type::AgentType;
-use serde_json::Value;
+use nervo_sdk::api::spec::MemoryKind;
+use schemars::JsonSchema;
+use serde_derive::Deserialize;
 use std::sync::Arc;
 use teloxide::types::ChatId;
 use tracing::info;
@@ -22,13 +29,25 @@ pub struct ContentInsights {
     pub conclusions: Vec<String>,
 }
 
+/// Keywords to look up the known facts about the user
+#[derive(Debug, Deserialize, JsonSchema)]
+struct KeywordsReply {
+    keywords: Vec<String>,
+}
+
+/// New facts about the user, empty if there are none
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ConclusionsReply {
+    conclusions: Vec<String>,
+}
+
 impl ConclusionsService {
     pub async fn new(
-        user_collection_name: String,
+        user_id: u64,
         app_state: Arc<JarvisAppState>,
         agent_type: AgentType,
     ) -> anyhow::Result<ConclusionsService> {
-        let user_conclusions_collection_name = format!("{}_conclusions", user_collection_name);
+        let user_conclusions_collection_name = conclusions_collection(user_id);
 
         Ok(ConclusionsService {
             user_conclusions_collection_name,
@@ -42,18 +61,9 @@ impl ConclusionsService {
         timestamped_user_raw_request: &str,
     ) -> anyhow::Result<ContentInsights> {
         info!("Searching conclusions for keywords");
-        let conclusions_json = self
-            .get_conclusions_for_user_message(&timestamped_user_raw_request)
+        let keywords = self
+            .get_keywords_for_user_message(timestamped_user_raw_request)
             .await?;
-
-        let instructions_value: Value = serde_json::from_str(&conclusions_json)?;
-        let keywords: Vec<String> = match instructions_value.get("keywords") {
-            Some(Value::Array(keyword_array)) => keyword_array
-                .iter()
-                .filter_map(|kw| kw.as_str().map(|s| s.to_string()))
-                .collect(),
-            _ => Vec::new(),
-        };
         info!("{} keywords was found", keywords.len());
 
         let path_builder = RolePathBuilder {
@@ -65,13 +75,20 @@ impl ConclusionsService {
 
         let mut all_possible_conclusions = Vec::new();
         for keyword in &keywords {
-            all_possible_conclusions = get_message_related_points(
+            let hits = get_message_related_points(
                 keyword,
                 self.user_conclusions_collection_name.as_str(),
                 system_role_to_clear_request.as_str(),
                 self.app_state.clone(),
             )
             .await?;
+            record_recall(
+                &self.app_state.nervo_ai_db,
+                self.user_conclusions_collection_name.as_str(),
+                &hits,
+            )
+            .await;
+            all_possible_conclusions = get_payload(hits)?;
         }
 
         Ok(ContentInsights {
@@ -80,33 +97,34 @@ impl ConclusionsService {
         })
     }
 
-    async fn get_conclusions_for_user_message(
+    async fn get_keywords_for_user_message(
         &self,
         timestamped_user_raw_request: &str,
-    ) -> anyhow::Result<String> {
-        info!("Create conclusions for user message by llm");
+    ) -> anyhow::Result<Vec<String>> {
+        info!("Create keywords for user message by llm");
 
-        let conclusion_system_role = {
+        let keywords_system_role = {
             let role_path_builder = RolePathBuilder {
                 agent_type: self.agent_type.clone(),
-                role_type: RoleType::ConclusionsPreprocessing,
+                role_type: RoleType::SearchKeywords,
             };
 
             role_path_builder.resource_path_content()?
         };
 
-        let keywords_json = self
+        let reply: KeywordsReply = self
             .app_state
             .clone()
             .nervo_llm
-            .raw_llm_processing(
-                conclusion_system_role.as_str(),
+            .complete_json(
+                keywords_system_role.as_str(),
                 timestamped_user_raw_request,
+                &LlmRequestOptions::default(),
             )
             .await?;
 
-        info!("{} => conclusions was found", keywords_json);
-        Ok(keywords_json)
+        info!("{:?} => keywords was found", reply.keywords);
+        Ok(reply.keywords)
     }
 
     pub async fn set_conclusion(
@@ -114,9 +132,12 @@ impl ConclusionsService {
         user_raw_request: &str,
         user_context: &UserContext,
         timestamp: &str,
-        chat_id: ChatId,
+        source: &MemorySource,
     ) -> anyhow::Result<()> {
-        if let Some(prev_response) = user_context.last_llm_response(&chat_id) {
+        let prev_response = user_context
+            .last_llm_response(self.agent_type, &ChatId(source.chat_id))
+            .await?;
+        if let Some(prev_response) = prev_response {
             let conclusions_keywords_for_struct = self
                 .generate_new_conclusions_list(prev_response.as_str(), user_raw_request)
                 .await?;
@@ -128,17 +149,30 @@ impl ConclusionsService {
                 return Ok(());
             }
 
-            for conclusion in &conclusions_keywords_for_struct {
-                let payload = self.get_conclusion_payload(conclusion).await?;
-                if payload.contains(&"No data found.".to_string()) {
+            let conclusion_vectors = self
+                .app_state
+                .nervo_llm
+                .embeddings(conclusions_keywords_for_struct.clone())
+                .await?;
+            for (conclusion, conclusion_vector) in conclusions_keywords_for_struct
+                .iter()
+                .zip(conclusion_vectors)
+            {
+                let payload = self
+                    .get_conclusion_payload(conclusion_vector.embedding)
+                    .await?;
+                if payload.is_empty() {
                     let timestamped_conclusion = format!("{}: {}", timestamp, conclusion);
+                    let payload = source.payload(
+                        MemoryKind::Conclusion,
+                        &timestamped_conclusion,
+                        self.app_state.nervo_llm.embedding_model_name(),
+                    );
                     self.app_state
-                        .clone()
                         .nervo_ai_db
-                        .qdrant
-                        .save_text(
+                        .save_payload(
                             self.user_conclusions_collection_name.as_str(),
-                            &timestamped_conclusion,
+                            VectorPayload::try_from(&payload)?,
                         )
                         .await?;
 
@@ -165,29 +199,24 @@ impl ConclusionsService {
 
         let role_path_builder = RolePathBuilder {
             agent_type: self.agent_type.clone(),
-            role_type: RoleType::SearchKeywords,
+            role_type: RoleType::ConclusionsPreprocessing,
         };
 
         let conclusion_system_role = role_path_builder.resource_path_content()?;
 
-        let conclusions_list_str = self
+        let reply: ConclusionsReply = self
             .app_state
             .clone()
             .nervo_llm
-            .raw_llm_processing(conclusion_system_role.as_str(), conclusion_message.as_str())
+            .complete_json(
+                conclusion_system_role.as_str(),
+                conclusion_message.as_str(),
+                &LlmRequestOptions::default(),
+            )
             .await?;
-
-        let conclusions_list_json: Value = serde_json::from_str(&conclusions_list_str)?;
         info!("Conclusions list is ready");
 
-        let conclusions_keywords_for_struct: Vec<String> =
-            match conclusions_list_json.get("conclusions") {
-                Some(Value::Array(keyword_array)) => keyword_array
-                    .iter()
-                    .filter_map(|kw| kw.as_str().map(|s| s.to_string()))
-                    .collect(),
-                _ => Vec::new(),
-            };
+        let conclusions_keywords_for_struct = reply.conclusions;
 
         info!(
             "Conclusions keywords for searching in user's db: {:?}",
@@ -197,27 +226,21 @@ impl ConclusionsService {
         Ok(conclusions_keywords_for_struct)
     }
 
-    async fn get_conclusion_payload(&self, conclusion: &String) -> anyhow::Result<Vec<String>> {
-        let conclusion_vector = self
-            .app_state
-            .clone()
-            .nervo_llm
-            .embedding(conclusion)
-            .await?;
-
+    async fn get_conclusion_payload(
+        &self,
+        conclusion_vector: Vec<f32>,
+    ) -> anyhow::Result<Vec<String>> {
         let search_result = self
             .app_state
             .clone()
             .nervo_ai_db
-            .qdrant
             .vector_search(
                 self.user_conclusions_collection_name.as_str(),
-                conclusion_vector.data.into_iter().next().unwrap().embedding,
+                conclusion_vector,
                 1,
             )
             .await?;
 
-        let search_result = search_result.result;
         let filtered_search_result =
             filter_search_result(search_result, Ascending, TruncatingType::None, 0.7)?;
         let payload = get_payload(filtered_search_result)?;
@@ -1,13 +1,15 @@
 use serde_derive::{Deserialize, Serialize};
 use std::collections::VecDeque;
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct MaxSize(pub usize);
 
 #[derive(Clone)]
 pub struct Dialogue {
     messages: VecDeque<UserInteraction>,
     max_size: MaxSize,
+    /// Summary of the interactions which are not kept anymore
+    summary: Option<String>,
 }
 
 impl Dialogue {
@@ -15,10 +17,34 @@ impl Dialogue {
         Dialogue {
             messages: VecDeque::new(),
             max_size,
+            summary: None,
         }
     }
 
+    /// Restores the dialogue, only the last `max_size` interactions are kept
+    pub fn with_interactions(
+        max_size: MaxSize,
+        summary: Option<String>,
+        interactions: Vec<UserInteraction>,
+    ) -> Dialogue {
+        let mut dialogue = Dialogue::new(max_size);
+        dialogue.summary = summary;
+        for interaction in interactions {
+            dialogue.push(interaction);
+        }
+        dialogue
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
     pub fn to_string(&self) -> String {
+        self.turns().join("\n\n")
+    }
+
+    /// Interactions as the prompt lines, the oldest first
+    pub fn turns(&self) -> Vec<String> {
         self.messages
             .iter()
             .map(|interaction| {
@@ -31,22 +57,14 @@ impl Dialogue {
                     timestamp, user_request, llm_response
                 )
             })
-            .collect::<Vec<_>>()
-            .join("\n\n")
+            .collect()
     }
 
-    pub fn add_user_interaction(
-        &mut self,
-        user_request: String,
-        llm_response: String,
-        timestamp: String,
-    ) {
-        let entry = UserInteraction {
-            timestamp,
-            user_request,
-            llm_response,
-        };
+    pub fn add_interaction(&mut self, entry: UserInteraction) {
+        self.push(entry);
+    }
 
+    fn push(&mut self, entry: UserInteraction) {
         self.messages.push_back(entry);
 
         if self.messages.len() > self.max_size.0 {
@@ -60,8 +78,18 @@ impl Dialogue {
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
-struct UserInteraction {
+pub struct UserInteraction {
     timestamp: String,
     user_request: String,
     llm_response: String,
 }
+
+impl UserInteraction {
+    pub fn new(timestamp: String, user_request: String, llm_response: String) -> Self {
+        UserInteraction {
+            timestamp,
+            user_request,
+            llm_response,
+        }
+    }
+}
@@ -1,11 +1,15 @@
+use crate::ai::nervo_llm::{LlmRequestOptions, NervoLlm};
+use crate::ai::vector_store::VectorHit;
 use crate::config::jarvis::JarvisAppState;
 use crate::context::conclusions::ConclusionsService;
-use crate::context::permanent_memory::MemoryCell;
+use crate::context::permanent_memory::{MemoryCell, MemorySource};
 use crate::context::user_context::UserContext;
+use crate::db::local_db::LocalDb;
+use crate::telegram::bot_utils::get_payload;
 use crate::utils::ai_utils_data::system_role::{RolePathBuilder, RoleType};
 use crate::utils::date_time_utils::get_time_stamp;
 use nervo_sdk::agent_type::AgentType;
-use qdrant_client::qdrant::SearchResponse;
+use std::collections::HashMap;
 use std::sync::Arc;
 use teloxide::prelude::*;
 use tracing::log::info;
@@ -15,11 +19,26 @@ pub struct UserContextMainHandler {
 }
 
 impl UserContextMainHandler {
-    pub fn new() -> Self {
+    pub fn new(
+        local_db: LocalDb,
+        nervo_llm: NervoLlm,
+        dialogue_max_size: HashMap<String, usize>,
+        dialogue_token_limit: HashMap<String, usize>,
+    ) -> Self {
         Self {
-            user_context: UserContext::default(),
+            user_context: UserContext::new(
+                local_db,
+                nervo_llm,
+                dialogue_max_size,
+                dialogue_token_limit,
+            ),
         }
     }
+
+    pub async fn forget_dialogues_of_user(&self, user_id: u64) -> anyhow::Result<()> {
+        self.user_context.clear_dialogues_of_user(user_id).await
+    }
+
     pub async fn use_memory_in_conversation(
         &self,
         msg: &Message,
@@ -30,11 +49,16 @@ impl UserContextMainHandler {
         let timestamp = get_time_stamp();
         let user_raw_request = msg.text().unwrap_or("Empty request");
         let timestamped_user_raw_request = format!("[{}] {}]", timestamp, user_raw_request);
-        let user_id = msg.clone().from.map(|user| user.id.0).unwrap_or(0) as i64;
-        let user_collection_name = user_id.to_string();
+        let user_id = msg.clone().from.map(|user| user.id.0).unwrap_or(0);
+        let source = MemorySource {
+            user_id,
+            chat_id: msg.chat.id.0,
+            agent_type,
+            message_ids: vec![msg.id.0],
+        };
 
         let conclusions_service =
-            ConclusionsService::new(user_collection_name, app_state.clone(), agent_type).await?;
+            ConclusionsService::new(user_id, app_state.clone(), agent_type).await?;
 
         let content_insights = conclusions_service
             .search_conclusions_by_user_request(&timestamped_user_raw_request)
@@ -59,7 +83,6 @@ impl UserContextMainHandler {
 
         let qdrant_data_for_user_request = app_state
             .nervo_ai_db
-            .qdrant
             .vector_search(
                 conclusions_service
                     .user_conclusions_collection_name
@@ -87,7 +110,7 @@ impl UserContextMainHandler {
             conclusions_service
                 .user_conclusions_collection_name
                 .as_str(),
-            msg,
+            &source,
             &conclusions_service,
         )
         .await?;
@@ -101,12 +124,13 @@ impl UserContextMainHandler {
         user_raw_request: &str,
         llm_request_response: &str,
         user_collection_name: &str,
-        msg: &Message,
+        source: &MemorySource,
         conclusions_service: &ConclusionsService,
     ) -> anyhow::Result<()> {
         info!("Updating memory");
         MemoryCell::create_memory_cell(
             conclusions_service.app_state.clone(),
+            source,
             timestamp,
             user_raw_request,
             llm_request_response,
@@ -115,21 +139,19 @@ impl UserContextMainHandler {
         .await?;
 
         conclusions_service
-            .set_conclusion(
-                &user_raw_request,
-                &self.user_context,
-                &timestamp,
-                msg.chat.id,
-            )
+            .set_conclusion(&user_raw_request, &self.user_context, &timestamp, source)
             .await?;
 
         let timestamped_user_raw_request = format!("[{}] {}]", timestamp, user_raw_request);
-        self.user_context.add_user_interaction_to_dialogue(
-            timestamped_user_raw_request.as_str(),
-            &msg.chat.id,
-            llm_request_response,
-            String::from(timestamp),
-        );
+        self.user_context
+            .add_user_interaction_to_dialogue(
+                conclusions_service.agent_type,
+                timestamped_user_raw_request.as_str(),
+                &ChatId(source.chat_id),
+                llm_request_response,
+                String::from(timestamp),
+            )
+            .await?;
         info!("Updating memory is ok");
         Ok(())
     }
@@ -139,10 +161,13 @@ impl UserContextMainHandler {
         msg: &Message,
         timestamped_user_raw_request: &str,
         conclusions: Vec<String>,
-        qdrant_data_for_user_request: SearchResponse,
+        qdrant_data_for_user_request: Vec<VectorHit>,
         conclusions_service: &ConclusionsService,
     ) -> anyhow::Result<String> {
-        let current_dialogue_cache = self.user_context.get_dialogue_string(&msg.chat.id);
+        let current_dialogue_cache = self
+            .user_context
+            .get_dialogue_string(conclusions_service.agent_type, &msg.chat.id)
+            .await?;
         let llm_request_message = format!(
             "Текущий запрос пользователя: {}\
         \nКраткосрочный кэш сообщений: {}\
@@ -151,7 +176,7 @@ impl UserContextMainHandler {
             timestamped_user_raw_request,
             current_dialogue_cache,
             conclusions,
-            &qdrant_data_for_user_request
+            get_payload(qdrant_data_for_user_request)?
         );
         info!("llm_request_message: {:?}", llm_request_message);
 
@@ -165,7 +190,11 @@ impl UserContextMainHandler {
         let llm_request_response = conclusions_service
             .app_state
             .nervo_llm
-            .raw_llm_processing(system_role.as_str(), llm_request_message.as_str())
+            .raw_llm_processing(
+                system_role.as_str(),
+                llm_request_message.as_str(),
+                &LlmRequestOptions::default(),
+            )
             .await?;
         info!("llm_request_response: {:?}", llm_request_response);
 
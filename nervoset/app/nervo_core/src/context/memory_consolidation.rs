@@ -0,0 +1,392 @@
+use crate::ai::ai_db::NervoAiDb;
+use crate::ai::nervo_llm::LlmRequestOptions;
+use crate::ai::vector_store::{cosine_similarity, VectorHit, VectorPayload, VectorPoint};
+use crate::config::jarvis::JarvisAppState;
+use crate::context::permanent_memory::MemoryPayload;
+use crate::context::user_memory::split_timestamp;
+use crate::utils::date_time_utils::get_time_stamp;
+use anyhow::Result;
+use nervo_sdk::api::spec::MemoryKind;
+use nervo_sdk::utils::cryptography::UuidGenerator;
+use schemars::JsonSchema;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+const CONCLUSIONS_SUFFIX: &str = "_conclusions";
+const SECONDS_IN_DAY: i64 = 24 * 60 * 60;
+
+const MERGE_SYSTEM_ROLE: &str = "You are provided with the facts about the user, one per line, \
+    the newest first, every fact starts with the date it was learned. The facts are about the same. \
+    Merge them into a single short fact without the date. If the facts contradict each other, \
+    keep the newest one.";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemoryConsolidationConfig {
+    /// Conclusions closer than this are merged into one
+    pub similarity_threshold: f32,
+    /// Conclusions not created or recalled for this many days...
+    pub decay_after_days: i64,
+    /// ...and recalled fewer times than this are forgotten
+    pub min_recall_count: u32,
+    /// How often jarvis of Kevin runs the consolidation, 0 turns the schedule off
+    pub interval_hours: u64,
+}
+
+impl Default for MemoryConsolidationConfig {
+    fn default() -> Self {
+        MemoryConsolidationConfig {
+            similarity_threshold: 0.85,
+            decay_after_days: 90,
+            min_recall_count: 2,
+            interval_hours: 24,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConsolidationReport {
+    pub users: usize,
+    /// Conclusions replaced by the merged ones
+    pub merged: usize,
+    /// Conclusions forgotten as rarely recalled
+    pub decayed: usize,
+}
+
+/// The merged fact, no date
+#[derive(Debug, Deserialize, JsonSchema)]
+struct MergedConclusion {
+    conclusion: String,
+}
+
+struct Conclusion {
+    id: String,
+    vector: Vec<f32>,
+    payload: MemoryPayload,
+}
+
+/// Merges similar conclusions of the users and forgets the ones which are rarely recalled.
+/// Conclusions saved as plain texts before the payloads got structured are left as is.
+pub struct MemoryConsolidation<'a> {
+    ai_db: &'a NervoAiDb,
+    config: &'a MemoryConsolidationConfig,
+}
+
+impl<'a> MemoryConsolidation<'a> {
+    pub fn new(ai_db: &'a NervoAiDb, config: &'a MemoryConsolidationConfig) -> Self {
+        MemoryConsolidation { ai_db, config }
+    }
+
+    /// Consolidates the conclusions of every user
+    pub async fn run(&self) -> Result<ConsolidationReport> {
+        let mut report = ConsolidationReport::default();
+        for collection_name in self.ai_db.vector_store.list_collections().await? {
+            let is_user_collection = collection_name
+                .strip_suffix(CONCLUSIONS_SUFFIX)
+                .is_some_and(|user_id| user_id.parse::<u64>().is_ok());
+            if !is_user_collection {
+                continue;
+            }
+
+            let user_report = self.consolidate(&collection_name).await?;
+            report.users += 1;
+            report.merged += user_report.merged;
+            report.decayed += user_report.decayed;
+        }
+        info!("Memory consolidation: {:?}", report);
+        Ok(report)
+    }
+
+    pub async fn consolidate(&self, collection_name: &str) -> Result<ConsolidationReport> {
+        let now = chrono::Utc::now().timestamp();
+        let mut conclusions: Vec<Conclusion> = self
+            .ai_db
+            .read_collection(collection_name)
+            .await?
+            .into_iter()
+            .filter_map(
+                |VectorPoint {
+                     id,
+                     vector,
+                     payload,
+                 }| {
+                    let payload = MemoryPayload::from_vector_payload(&payload)?;
+                    (payload.kind == MemoryKind::Conclusion).then_some(Conclusion {
+                        id,
+                        vector,
+                        payload,
+                    })
+                },
+            )
+            .collect();
+
+        let mut report = ConsolidationReport {
+            users: 1,
+            ..ConsolidationReport::default()
+        };
+
+        let decayed_ids: Vec<String> = conclusions
+            .iter()
+            .filter(|conclusion| self.is_decayed(&conclusion.payload, now))
+            .map(|conclusion| conclusion.id.clone())
+            .collect();
+        if !decayed_ids.is_empty() {
+            report.decayed = decayed_ids.len();
+            conclusions.retain(|conclusion| !decayed_ids.contains(&conclusion.id));
+            self.ai_db
+                .vector_store
+                .delete(collection_name, decayed_ids)
+                .await?;
+        }
+
+        for cluster in self.clusters(conclusions) {
+            if cluster.len() < 2 {
+                continue;
+            }
+            report.merged += cluster.len();
+            self.merge(collection_name, cluster).await?;
+        }
+
+        info!(
+            "Conclusions of {} are consolidated: {:?}",
+            collection_name, report
+        );
+        Ok(report)
+    }
+
+    fn is_decayed(&self, payload: &MemoryPayload, now: i64) -> bool {
+        let last_used_at = payload.last_recalled_at.unwrap_or(payload.created_at);
+        let days_unused = (now - last_used_at) / SECONDS_IN_DAY;
+        days_unused >= self.config.decay_after_days
+            && payload.recall_count < self.config.min_recall_count
+    }
+
+    /// Every cluster is the newest unclustered conclusion with the ones close to it, newest first
+    fn clusters(&self, mut conclusions: Vec<Conclusion>) -> Vec<Vec<Conclusion>> {
+        conclusions.sort_by_key(|conclusion| std::cmp::Reverse(conclusion.payload.created_at));
+
+        let mut clusters = vec![];
+        while !conclusions.is_empty() {
+            let newest = conclusions.remove(0);
+            let (similar, rest): (Vec<_>, Vec<_>) =
+                conclusions.into_iter().partition(|conclusion| {
+                    cosine_similarity(&newest.vector, &conclusion.vector)
+                        >= self.config.similarity_threshold
+                });
+            conclusions = rest;
+
+            let mut cluster = vec![newest];
+            cluster.extend(similar);
+            clusters.push(cluster);
+        }
+        clusters
+    }
+
+    /// Replaces the cluster with one conclusion, it keeps the date and the source of the newest one
+    async fn merge(&self, collection_name: &str, cluster: Vec<Conclusion>) -> Result<()> {
+        let facts = cluster
+            .iter()
+            .map(|conclusion| conclusion.payload.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let options = LlmRequestOptions {
+            temperature: Some(0.2),
+            ..LlmRequestOptions::default()
+        };
+        let reply: MergedConclusion = self
+            .ai_db
+            .nervo_llm
+            .complete_json(MERGE_SYSTEM_ROLE, &facts, &options)
+            .await?;
+
+        let newest = &cluster[0].payload;
+        let timestamp = split_timestamp(&newest.text)
+            .0
+            .unwrap_or_else(get_time_stamp);
+        let mut merged = newest.clone();
+        merged.text = format!("{}: {}", timestamp, reply.conclusion.trim());
+        merged.embedding_model = self.ai_db.nervo_llm.embedding_model_name().to_string();
+        merged.recall_count = cluster
+            .iter()
+            .map(|conclusion| conclusion.payload.recall_count)
+            .sum();
+        merged.last_recalled_at = cluster
+            .iter()
+            .filter_map(|conclusion| conclusion.payload.last_recalled_at)
+            .max();
+        merged.source_message_ids = cluster
+            .iter()
+            .flat_map(|conclusion| conclusion.payload.source_message_ids.clone())
+            .collect();
+        merged.source_message_ids.sort();
+        merged.source_message_ids.dedup();
+
+        info!(
+            "{} conclusions are merged into: {}",
+            cluster.len(),
+            merged.text
+        );
+        self.ai_db
+            .save_payload(collection_name, VectorPayload::try_from(&merged)?)
+            .await?;
+
+        let merged_id = UuidGenerator::from(merged.text.as_str()).to_string();
+        let ids = cluster
+            .into_iter()
+            .map(|conclusion| conclusion.id)
+            .filter(|id| id != &merged_id)
+            .collect();
+        self.ai_db.vector_store.delete(collection_name, ids).await
+    }
+}
+
+/// Counts the recall of the found conclusions, rarely recalled ones decay.
+/// Called on the way to the reply, so a failure is only logged
+pub async fn record_recall(ai_db: &NervoAiDb, collection_name: &str, hits: &[VectorHit]) {
+    if let Err(err) = update_recall(ai_db, collection_name, hits).await {
+        error!("Recall of the conclusions isn't recorded: {:?}", err);
+    }
+}
+
+async fn update_recall(ai_db: &NervoAiDb, collection_name: &str, hits: &[VectorHit]) -> Result<()> {
+    let ids = hits.iter().map(|hit| hit.id.clone()).collect();
+    let now = chrono::Utc::now().timestamp();
+
+    // Only the recall fields are written, so a conclusion merged away meanwhile stays deleted
+    for point in ai_db.vector_store.get(collection_name, ids).await? {
+        let Some(payload) = MemoryPayload::from_vector_payload(&point.payload) else {
+            continue;
+        };
+        let mut fields = VectorPayload::default();
+        let recall_count = Value::from(payload.recall_count + 1);
+        fields.0.insert("recall_count".to_string(), recall_count);
+        fields
+            .0
+            .insert("last_recalled_at".to_string(), Value::from(now));
+        ai_db
+            .vector_store
+            .set_payload(collection_name, point.id, fields)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Runs the consolidation every `interval_hours` of the config
+pub async fn schedule(app_state: Arc<JarvisAppState>) {
+    let config = &app_state.nervo_config.memory_consolidation;
+    if config.interval_hours == 0 {
+        info!("Memory consolidation schedule is off");
+        return;
+    }
+
+    // The first run waits for the period too, so restarts don't trigger the consolidation
+    let period = Duration::from_secs(config.interval_hours * 60 * 60);
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let consolidation = MemoryConsolidation::new(&app_state.nervo_ai_db, config);
+        if let Err(err) = consolidation.run().await {
+            error!("Memory consolidation failed: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::ai_db::NervoAiDb;
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::memory_vector_store::InMemoryVectorStore;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::ai::vector_store::VectorPayload;
+    use crate::context::memory_consolidation::{
+        record_recall, MemoryConsolidation, MemoryConsolidationConfig,
+    };
+    use crate::context::permanent_memory::{MemoryPayload, MemorySource};
+    use crate::context::user_memory::{conclusions_collection, UserMemory};
+    use nervo_sdk::agent_type::AgentType;
+    use nervo_sdk::api::spec::MemoryKind;
+    use std::sync::Arc;
+
+    fn conclusion(text: &str, days_ago: i64, message_id: i32) -> MemoryPayload {
+        let source = MemorySource {
+            user_id: 7,
+            chat_id: 7,
+            agent_type: AgentType::Kevin,
+            message_ids: vec![message_id],
+        };
+        let mut payload = source.payload(MemoryKind::Conclusion, text, "fake-embedding");
+        payload.created_at -= days_ago * 24 * 60 * 60;
+        payload
+    }
+
+    #[tokio::test]
+    async fn test_merge_and_decay() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec![
+            r#"{"conclusion": "lives in Berlin"}"#,
+        ]));
+        let ai_db = NervoAiDb::with_store(Arc::new(InMemoryVectorStore::default()), nervo_llm);
+        let collection_name = conclusions_collection(7);
+        let conclusions = [
+            conclusion(
+                "2024-12-01 10:00:00 (Sunday): the user lives in Paris",
+                20,
+                1,
+            ),
+            conclusion(
+                "2024-12-20 10:00:00 (Friday): the user lives in Berlin",
+                1,
+                2,
+            ),
+            conclusion("2024-09-01 10:00:00 (Sunday): likes black cats", 100, 3),
+            conclusion(
+                "2024-09-02 10:00:00 (Monday): plays chess on weekends",
+                100,
+                4,
+            ),
+        ];
+        for payload in &conclusions {
+            ai_db
+                .save_payload(&collection_name, VectorPayload::try_from(payload)?)
+                .await?;
+        }
+        let chess = ai_db
+            .text_search(&collection_name, conclusions[3].text.clone(), 1)
+            .await?;
+        record_recall(&ai_db, &collection_name, &chess).await;
+        record_recall(&ai_db, &collection_name, &chess).await;
+
+        let config = MemoryConsolidationConfig {
+            similarity_threshold: 0.6,
+            ..MemoryConsolidationConfig::default()
+        };
+        let report = MemoryConsolidation::new(&ai_db, &config).run().await?;
+        assert_eq!(report.users, 1);
+        assert_eq!(report.merged, 2);
+        assert_eq!(report.decayed, 1);
+
+        let texts: Vec<String> = UserMemory::new(&ai_db, 7)
+            .conclusions()
+            .await?
+            .into_iter()
+            .map(|record| record.text)
+            .collect();
+        assert_eq!(texts.len(), 2);
+        assert!(texts.contains(&"lives in Berlin".to_string()));
+        assert!(texts.contains(&"plays chess on weekends".to_string()));
+
+        let merged = ai_db
+            .read_collection(&collection_name)
+            .await?
+            .into_iter()
+            .filter_map(|point| MemoryPayload::from_vector_payload(&point.payload))
+            .find(|payload| payload.text.ends_with("lives in Berlin"))
+            .unwrap();
+        assert!(merged.text.starts_with("2024-12-20"));
+        assert_eq!(merged.source_message_ids, vec![1, 2]);
+        Ok(())
+    }
+}
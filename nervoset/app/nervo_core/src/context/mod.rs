@@ -2,5 +2,7 @@ pub mod conclusions;
 pub mod dialogue_state;
 pub mod keywords;
 pub mod main_handler;
+pub mod memory_consolidation;
 pub mod permanent_memory;
 pub mod user_context;
+pub mod user_memory;
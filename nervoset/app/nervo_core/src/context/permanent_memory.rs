@@ -1,31 +1,153 @@
+use crate::ai::vector_store::VectorPayload;
 use crate::config::jarvis::JarvisAppState;
+use anyhow::bail;
+use nervo_sdk::agent_type::AgentType;
+use nervo_sdk::api::spec::MemoryKind;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::sync::Arc;
 use tracing::log::info;
 
+/// Where a memory comes from
+#[derive(Clone, Debug)]
+pub struct MemorySource {
+    pub user_id: u64,
+    pub chat_id: i64,
+    pub agent_type: AgentType,
+    /// Telegram ids of the messages the memory is made of
+    pub message_ids: Vec<i32>,
+}
+
+impl MemorySource {
+    pub fn payload(&self, kind: MemoryKind, text: &str, embedding_model: &str) -> MemoryPayload {
+        MemoryPayload {
+            text: text.to_string(),
+            user_id: self.user_id,
+            chat_id: self.chat_id,
+            agent_type: self.agent_type,
+            kind,
+            created_at: chrono::Utc::now().timestamp(),
+            source_message_ids: self.message_ids.clone(),
+            embedding_model: embedding_model.to_string(),
+            recall_count: 0,
+            last_recalled_at: None,
+        }
+    }
+}
+
+/// Payload of the memory points, so the memories can be filtered by the fields
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MemoryPayload {
+    /// The embedded text
+    pub text: String,
+    pub user_id: u64,
+    pub chat_id: i64,
+    pub agent_type: AgentType,
+    pub kind: MemoryKind,
+    /// Unix time in seconds
+    pub created_at: i64,
+    pub source_message_ids: Vec<i32>,
+    pub embedding_model: String,
+    /// How many times the memory was found for a reply
+    #[serde(default)]
+    pub recall_count: u32,
+    /// Unix time in seconds, `None` if the memory was never recalled
+    #[serde(default)]
+    pub last_recalled_at: Option<i64>,
+}
+
+impl MemoryPayload {
+    /// `None` for the memories saved as plain texts before the payloads got structured
+    pub fn from_vector_payload(payload: &VectorPayload) -> Option<Self> {
+        serde_json::from_value(Value::Object(payload.0.clone())).ok()
+    }
+}
+
+impl TryFrom<&MemoryPayload> for VectorPayload {
+    type Error = anyhow::Error;
+
+    fn try_from(payload: &MemoryPayload) -> Result<Self, Self::Error> {
+        match serde_json::to_value(payload)? {
+            Value::Object(fields) => Ok(VectorPayload(fields)),
+            _ => bail!("Memory payload isn't an object"),
+        }
+    }
+}
+
 pub struct MemoryCell {}
 
 impl MemoryCell {
     pub async fn create_memory_cell(
         app_state: Arc<JarvisAppState>,
+        source: &MemorySource,
         timestamp: &str,
         user_request: &str,
         llm_request_response: &str,
         collection_name: &str,
     ) -> anyhow::Result<()> {
-        let memory_cell = format!(
-            "{{{}: [{{\"role\": \"user\", \"content\": {:?}}}, {{\"role\": \"Leo (You)\", \"content\": {:?}}}]}}",
-            timestamp,
-            user_request,
-            llm_request_response
-        );
+        let memory_cell = json!({
+            "timestamp": timestamp,
+            "messages": [
+                {"role": "user", "content": user_request},
+                {"role": "Leo (You)", "content": llm_request_response},
+            ],
+        })
+        .to_string();
         info!("memory cell check: {}", memory_cell);
 
+        let payload = source.payload(
+            MemoryKind::Interaction,
+            &memory_cell,
+            app_state.nervo_llm.embedding_model_name(),
+        );
         app_state
             .nervo_ai_db
-            .qdrant
-            .save_text(collection_name, memory_cell.as_str())
+            .save_payload(collection_name, VectorPayload::try_from(&payload)?)
             .await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::ai::vector_store::VectorPayload;
+    use crate::context::permanent_memory::{MemoryPayload, MemorySource};
+    use nervo_sdk::agent_type::AgentType;
+    use nervo_sdk::api::spec::MemoryKind;
+
+    #[test]
+    fn test_memory_payload_fields() -> anyhow::Result<()> {
+        let source = MemorySource {
+            user_id: 7,
+            chat_id: -42,
+            agent_type: AgentType::Kevin,
+            message_ids: vec![3],
+        };
+        let payload = source.payload(MemoryKind::Conclusion, "likes cats", "embedding-3");
+        let vector_payload = VectorPayload::try_from(&payload)?;
+
+        assert_eq!(vector_payload.text(), Some("likes cats"));
+        assert_eq!(vector_payload.0["kind"], "conclusion");
+        assert_eq!(vector_payload.0["agent_type"], "kevin");
+        assert_eq!(
+            MemoryPayload::from_vector_payload(&vector_payload),
+            Some(payload)
+        );
+        assert_eq!(
+            MemoryPayload::from_vector_payload(&VectorPayload::from_text("legacy")),
+            None
+        );
+
+        let cell = source.payload(MemoryKind::Interaction, "hi", "embedding-3");
+        let mut renamed_cell = VectorPayload::try_from(&cell)?;
+        renamed_cell
+            .0
+            .insert("kind".to_string(), "memoryCell".into());
+        assert_eq!(
+            MemoryPayload::from_vector_payload(&renamed_cell),
+            Some(cell)
+        );
+        Ok(())
+    }
+}
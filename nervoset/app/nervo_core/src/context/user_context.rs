@@ -1,71 +1,383 @@
-use crate::context::dialogue_state::{Dialogue, MaxSize};
+use crate::ai::nervo_llm::NervoLlm;
+use crate::context::dialogue_state::{Dialogue, MaxSize, UserInteraction};
+use crate::db::local_db::{DialogueSummary, LocalDb};
+use crate::utils::context_builder::{
+    count_tokens, split_by_budget, summarize, ConversationHistory,
+};
+use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use teloxide::prelude::ChatId;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info};
 
-#[derive(Default)]
+const DEFAULT_DIALOGUE_MAX_SIZE: MaxSize = MaxSize(20);
+const DEFAULT_DIALOGUE_TOKEN_LIMIT: usize = 3000;
+
+/// Short-term dialogues of the chats. They are kept in LocalDb, loaded on the first access
+/// to the chat and written through on every interaction. The lock is never held across
+/// LocalDb calls, so the chats don't wait for each other's disk I/O.
+/// The interactions which don't fit the dialogue anymore are folded into its summary
+/// in the background after the reply.
+#[derive(Clone)]
 pub struct UserContext {
-    context: Arc<RwLock<HashMap<ChatId, Dialogue>>>,
+    context: Arc<RwLock<Dialogues>>,
+    /// Folds of a chat run one at a time, so they don't summarize the same interactions twice
+    folding: Arc<std::sync::Mutex<FoldLocks>>,
+    local_db: LocalDb,
+    nervo_llm: NervoLlm,
+    /// Max size of the dialogue by agent name
+    dialogue_max_size: HashMap<String, usize>,
+    /// Token budget of the dialogue in the prompt by agent name
+    dialogue_token_limit: HashMap<String, usize>,
+}
+
+/// Fold lock by agent name and chat
+type FoldLocks = HashMap<(String, ChatId), Arc<Mutex<()>>>;
+
+#[derive(Default)]
+struct Dialogues {
+    dialogues: HashMap<(String, ChatId), Dialogue>,
+    /// Bumped by every change, a dialogue loaded while it changed may miss the change
+    generation: u64,
 }
 
 impl UserContext {
-    pub fn new() -> UserContext {
+    pub fn new(
+        local_db: LocalDb,
+        nervo_llm: NervoLlm,
+        dialogue_max_size: HashMap<String, usize>,
+        dialogue_token_limit: HashMap<String, usize>,
+    ) -> UserContext {
         UserContext {
-            context: Arc::new(RwLock::new(HashMap::new())),
+            context: Arc::new(RwLock::new(Dialogues::default())),
+            folding: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            local_db,
+            nervo_llm,
+            dialogue_max_size,
+            dialogue_token_limit,
         }
     }
 
-    pub fn add_user_interaction_to_dialogue(
+    /// The interaction is written to LocalDb first, so a failed write leaves the dialogue as is
+    pub async fn add_user_interaction_to_dialogue(
         &self,
+        agent_type: AgentType,
         user_request: &str,
         chat_id: &ChatId,
         llm_response: &str,
         timestamp: String,
-    ) {
-        let mut dialogue = if let Some(dialogue) = self.get_dialogue(chat_id) {
-            dialogue
-        } else {
-            Dialogue::new(MaxSize(20))
-        };
+    ) -> anyhow::Result<()> {
+        let agent = NervoAgentType::get_name(agent_type);
 
-        dialogue.add_user_interaction(
+        let interaction = UserInteraction::new(
+            timestamp,
             user_request.to_string(),
             llm_response.to_string(),
-            timestamp,
         );
+        self.local_db
+            .append_to_dialogue(&agent, chat_id.0, &serde_json::to_string(&interaction)?)
+            .await?;
+
+        // A dialogue which is not loaded yet gets the interaction from LocalDb
+        {
+            let mut context = self.context.write().await;
+            context.generation += 1;
+            if let Some(dialogue) = context.dialogues.get_mut(&(agent.clone(), *chat_id)) {
+                dialogue.add_interaction(interaction);
+            }
+        }
+
+        // A fold of the chat which is running already covers the interaction or the next
+        // one does, so the folds don't queue up
+        let Ok(folding) = self.fold_lock(&agent, chat_id).try_lock_owned() else {
+            return Ok(());
+        };
+        let user_context = self.clone();
+        let chat_id = *chat_id;
+        tokio::spawn(async move {
+            let _folding = folding;
+            if let Err(err) = user_context.fold_unlocked(agent_type, &chat_id).await {
+                error!(
+                    "Can't summarize the dialogue of the chat {}: {}",
+                    chat_id, err
+                );
+            }
+        });
+        Ok(())
+    }
+
+    pub async fn clear_dialogue(
+        &self,
+        agent_type: AgentType,
+        chat_id: &ChatId,
+    ) -> anyhow::Result<()> {
+        let agent = NervoAgentType::get_name(agent_type);
+        self.local_db.clear_dialogue(&agent, chat_id.0).await?;
 
-        self.set_dialogue_state(&chat_id, dialogue)
+        let mut context = self.context.write().await;
+        context.generation += 1;
+        context.dialogues.remove(&(agent, *chat_id));
+        Ok(())
     }
 
-    fn set_dialogue_state(&self, chat_id: &ChatId, dialogue_state: Dialogue) {
-        let mut context = self
-            .context
-            .write()
-            .expect("Couldn't capture thread for writing");
-        context.insert(*chat_id, dialogue_state);
+    /// Deletes every dialogue of the user, see [`LocalDb::clear_dialogues_of_user`]
+    pub async fn clear_dialogues_of_user(&self, user_id: u64) -> anyhow::Result<()> {
+        self.local_db.clear_dialogues_of_user(user_id).await?;
+
+        // The loaded dialogues don't know their users, they are loaded again on demand
+        let mut context = self.context.write().await;
+        context.generation += 1;
+        context.dialogues.clear();
+        Ok(())
+    }
+
+    /// The summary of the older interactions and the recent ones that fit the token limit
+    pub async fn get_dialogue_string(
+        &self,
+        agent_type: AgentType,
+        chat_id: &ChatId,
+    ) -> anyhow::Result<String> {
+        let agent = NervoAgentType::get_name(agent_type);
+        let dialogue = self.get_dialogue(agent_type, chat_id).await?;
+        let history = ConversationHistory {
+            summary: dialogue.summary().map(str::to_string),
+            turns: dialogue.turns(),
+        };
+        history.render(Some(self.token_limit(&agent)))
+    }
+
+    pub async fn last_llm_response(
+        &self,
+        agent_type: AgentType,
+        chat_id: &ChatId,
+    ) -> anyhow::Result<Option<String>> {
+        let dialogue = self.get_dialogue(agent_type, chat_id).await?;
+        Ok(dialogue.last_llm_response())
+    }
+
+    /// Folds the interactions into the summary of the dialogue once it is beyond the max size
+    /// or the token limit. It is folded down to the half of them, so the summary is updated
+    /// every few interactions rather than on every one. The last interaction is always kept
+    pub async fn fold_dialogue(
+        &self,
+        agent_type: AgentType,
+        chat_id: &ChatId,
+    ) -> anyhow::Result<()> {
+        let agent = NervoAgentType::get_name(agent_type);
+        let _folding = self.fold_lock(&agent, chat_id).lock_owned().await;
+        self.fold_unlocked(agent_type, chat_id).await
+    }
+
+    /// Call it holding the fold lock of the chat
+    async fn fold_unlocked(&self, agent_type: AgentType, chat_id: &ChatId) -> anyhow::Result<()> {
+        let agent = NervoAgentType::get_name(agent_type);
+
+        let summary = self.local_db.dialogue_summary(&agent, chat_id.0).await?;
+        let interactions = self
+            .local_db
+            .read_dialogue(&agent, chat_id.0, usize::MAX)
+            .await?;
+        let turns = interactions
+            .iter()
+            .map(|(_, interaction)| serde_json::from_str::<UserInteraction>(interaction))
+            .collect::<Result<Vec<_>, _>>()?;
+        let turns = Dialogue::with_interactions(MaxSize(turns.len()), None, turns).turns();
+
+        let summary_tokens = summary
+            .as_ref()
+            .map_or(0, |summary| count_tokens(&summary.summary));
+        let budget = self.token_limit(&agent).saturating_sub(summary_tokens);
+        let max_size = self.max_size(&agent).0;
+        let (over_budget, _) = split_by_budget(&turns, budget);
+        if turns.len() <= max_size && over_budget.is_empty() {
+            return Ok(());
+        }
+
+        let (older, _) = split_by_budget(&turns, budget / 2);
+        let beyond_half_size = turns.len().saturating_sub(max_size / 2);
+        let folded = older
+            .len()
+            .max(beyond_half_size)
+            .min(turns.len().saturating_sub(1));
+        if folded == 0 {
+            return Ok(());
+        }
+
+        let folded_summary = DialogueSummary {
+            summary: summarize(
+                &self.nervo_llm,
+                summary.as_ref().map(|summary| summary.summary.as_str()),
+                &turns[..folded],
+            )
+            .await?,
+            last_interaction_id: interactions[folded - 1].0,
+        };
+        self.local_db
+            .fold_dialogue(&agent, chat_id.0, &folded_summary)
+            .await?;
+        info!(
+            "Folded {} interactions of the chat {} into the summary",
+            folded, chat_id
+        );
+
+        // The dialogue is loaded again with the new summary
+        let mut context = self.context.write().await;
+        context.generation += 1;
+        context.dialogues.remove(&(agent, *chat_id));
+        Ok(())
     }
 
-    pub fn get_dialogue_string(&self, chat_id: &ChatId) -> String {
-        let dialogue = self.get_dialogue(chat_id);
-        let dialogue_string = match dialogue {
-            None => "".to_string(),
-            Some(dialogue) => dialogue.to_string(),
+    async fn get_dialogue(
+        &self,
+        agent_type: AgentType,
+        chat_id: &ChatId,
+    ) -> anyhow::Result<Dialogue> {
+        let agent = NervoAgentType::get_name(agent_type);
+        let key = (agent.clone(), *chat_id);
+        let generation = {
+            let context = self.context.read().await;
+            if let Some(dialogue) = context.dialogues.get(&key) {
+                return Ok(dialogue.clone());
+            }
+            context.generation
         };
-        dialogue_string
+
+        let dialogue = self
+            .load_dialogue(&agent, chat_id, self.max_size(&agent))
+            .await?;
+
+        let mut context = self.context.write().await;
+        if let Some(dialogue) = context.dialogues.get(&key) {
+            return Ok(dialogue.clone());
+        }
+        if context.generation == generation {
+            context.dialogues.insert(key, dialogue.clone());
+        }
+        Ok(dialogue)
+    }
+
+    async fn load_dialogue(
+        &self,
+        agent: &str,
+        chat_id: &ChatId,
+        max_size: MaxSize,
+    ) -> anyhow::Result<Dialogue> {
+        let summary = self.local_db.dialogue_summary(agent, chat_id.0).await?;
+        let interactions = self
+            .local_db
+            .read_dialogue(agent, chat_id.0, max_size.0)
+            .await?
+            .iter()
+            .map(|(_, interaction)| serde_json::from_str::<UserInteraction>(interaction))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Dialogue::with_interactions(
+            max_size,
+            summary.map(|summary| summary.summary),
+            interactions,
+        ))
+    }
+
+    fn fold_lock(&self, agent: &str, chat_id: &ChatId) -> Arc<Mutex<()>> {
+        let mut locks = self.folding.lock().expect("Fold locks are poisoned");
+        locks
+            .entry((agent.to_string(), *chat_id))
+            .or_default()
+            .clone()
+    }
+
+    fn max_size(&self, agent: &str) -> MaxSize {
+        self.dialogue_max_size
+            .get(agent)
+            .map(|max_size| MaxSize(*max_size))
+            .unwrap_or(DEFAULT_DIALOGUE_MAX_SIZE)
     }
 
-    fn get_dialogue(&self, chat_id: &ChatId) -> Option<Dialogue> {
-        let read_guard = self
-            .context
-            .write()
-            .expect("Couldn't capture thread for reading");
-        let dialogue = read_guard.get(chat_id).cloned();
-        dialogue
+    fn token_limit(&self, agent: &str) -> usize {
+        self.dialogue_token_limit
+            .get(agent)
+            .copied()
+            .unwrap_or(DEFAULT_DIALOGUE_TOKEN_LIMIT)
     }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::context::user_context::UserContext;
+    use crate::db::local_db::test::temp_db;
+    use nervo_sdk::agent_type::AgentType;
+    use std::collections::HashMap;
+    use teloxide::prelude::ChatId;
+
+    #[tokio::test]
+    async fn test_dialogue_survives_restart() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec!["asked about one"]));
+        let max_sizes = HashMap::from([("kevin".to_string(), 2)]);
+        let chat_id = ChatId(42);
+
+        let user_context = UserContext::new(
+            local_db.clone(),
+            nervo_llm.clone(),
+            max_sizes.clone(),
+            HashMap::new(),
+        );
+        for (request, response) in [("one", "1"), ("two", "2"), ("three", "3")] {
+            user_context
+                .add_user_interaction_to_dialogue(
+                    AgentType::Kevin,
+                    request,
+                    &chat_id,
+                    response,
+                    "2024-12-22".to_string(),
+                )
+                .await?;
+        }
+        // Waits for the background folds too. The third interaction is beyond the max size,
+        // the dialogue is folded down to the half of it
+        user_context
+            .fold_dialogue(AgentType::Kevin, &chat_id)
+            .await?;
+        assert_eq!(
+            local_db.read_dialogue("kevin", chat_id.0, 10).await?.len(),
+            1
+        );
+
+        let restarted_context =
+            UserContext::new(local_db.clone(), nervo_llm, max_sizes, HashMap::new());
+        let dialogue = restarted_context
+            .get_dialogue_string(AgentType::Kevin, &chat_id)
+            .await?;
+        assert!(dialogue.starts_with("Summary of the earlier conversation: "));
+        assert!(!dialogue.contains("two") && dialogue.contains("three"));
+        assert_eq!(
+            restarted_context
+                .last_llm_response(AgentType::Kevin, &chat_id)
+                .await?
+                .as_deref(),
+            Some("3")
+        );
+        assert_eq!(
+            restarted_context
+                .last_llm_response(AgentType::Nervoznyak, &chat_id)
+                .await?,
+            None
+        );
+
+        restarted_context
+            .clear_dialogue(AgentType::Kevin, &chat_id)
+            .await?;
+        assert_eq!(
+            restarted_context
+                .last_llm_response(AgentType::Kevin, &chat_id)
+                .await?,
+            None
+        );
+        assert_eq!(local_db.dialogue_summary("kevin", chat_id.0).await?, None);
 
-    pub fn last_llm_response(&self, chat_id: &ChatId) -> Option<String> {
-        let dialogue = self.get_dialogue(chat_id);
-        let response = dialogue?.last_llm_response();
-        Some(response?)
+        Ok(())
     }
 }
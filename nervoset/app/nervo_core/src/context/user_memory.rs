@@ -0,0 +1,245 @@
+use crate::ai::ai_db::NervoAiDb;
+use crate::ai::vector_store::{VectorPayload, VectorPoint};
+use crate::context::permanent_memory::MemoryPayload;
+use anyhow::Result;
+use chrono::DateTime;
+use nervo_sdk::api::spec::{MemoryKind, MemoryRecord, UserMemoryExport};
+use tracing::info;
+use uuid::Uuid;
+
+/// Memories closer than this to the text are found by [`UserMemory::matching`]
+const MATCH_SCORE: f32 = 0.6;
+const MATCH_LIMIT: u64 = 3;
+
+/// Collection with the facts about the user and the memory cells, i.e. the requests
+/// of the user along with the replies
+pub fn conclusions_collection(user_id: u64) -> String {
+    format!("{}_conclusions", user_id)
+}
+
+/// What the memory agent keeps about the user, so the user can see and remove it
+pub struct UserMemory<'a> {
+    ai_db: &'a NervoAiDb,
+    user_id: u64,
+}
+
+impl<'a> UserMemory<'a> {
+    pub fn new(ai_db: &'a NervoAiDb, user_id: u64) -> Self {
+        UserMemory { ai_db, user_id }
+    }
+
+    pub async fn conclusions(&self) -> Result<Vec<MemoryRecord>> {
+        let (conclusions, _) = self.records().await?;
+        Ok(conclusions)
+    }
+
+    pub async fn export(&self) -> Result<UserMemoryExport> {
+        let (conclusions, memory_cells) = self.records().await?;
+        Ok(UserMemoryExport {
+            user_id: self.user_id,
+            conclusions,
+            memory_cells,
+        })
+    }
+
+    /// Returns `false` if there is no memory with the id
+    pub async fn delete(&self, id: &str) -> Result<bool> {
+        if Uuid::parse_str(id).is_err() {
+            return Ok(false);
+        }
+
+        let collection_name = self.collection();
+        let ids = vec![id.to_string()];
+        let store = &self.ai_db.vector_store;
+        if store.get(&collection_name, ids.clone()).await?.is_empty() {
+            return Ok(false);
+        }
+        store.delete(&collection_name, ids).await?;
+        Ok(true)
+    }
+
+    /// The memories close to the text by meaning. Nothing is deleted, so the user can
+    /// confirm each of them by id with [`UserMemory::delete`]
+    pub async fn matching(&self, text: &str) -> Result<Vec<MemoryRecord>> {
+        let collection_name = self.collection();
+        if !self
+            .ai_db
+            .vector_store
+            .collection_exists(&collection_name)
+            .await?
+        {
+            return Ok(vec![]);
+        }
+
+        let matching: Vec<MemoryRecord> = self
+            .ai_db
+            .text_search(&collection_name, text.to_string(), MATCH_LIMIT)
+            .await?
+            .into_iter()
+            .filter(|hit| hit.score >= MATCH_SCORE)
+            .map(|hit| memory_record(hit.id, &hit.payload))
+            .collect();
+        info!("{} memories match", matching.len());
+        Ok(matching)
+    }
+
+    pub async fn wipe(&self) -> Result<()> {
+        self.ai_db
+            .vector_store
+            .delete_collection(&self.collection())
+            .await?;
+        info!("Memory of the user {} is wiped", self.user_id);
+        Ok(())
+    }
+
+    /// The conclusions and the memory cells, the oldest first
+    async fn records(&self) -> Result<(Vec<MemoryRecord>, Vec<MemoryRecord>)> {
+        let points = self.ai_db.read_collection(&self.collection()).await?;
+        let mut records: Vec<MemoryRecord> = points
+            .into_iter()
+            .map(|VectorPoint { id, payload, .. }| memory_record(id, &payload))
+            .collect();
+        records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(records
+            .into_iter()
+            .partition(|record| record.kind == MemoryKind::Conclusion))
+    }
+
+    fn collection(&self) -> String {
+        conclusions_collection(self.user_id)
+    }
+}
+
+/// Structured payloads keep the kind and the time in the fields. Memories saved as plain
+/// texts start with the timestamp, i.e. `2024-12-22 10:00:00 (Sunday): ...`, memory cells
+/// among them are additionally wrapped into braces.
+fn memory_record(id: String, payload: &VectorPayload) -> MemoryRecord {
+    if let Some(memory) = MemoryPayload::from_vector_payload(payload) {
+        let text = match memory.kind {
+            MemoryKind::Conclusion => split_timestamp(&memory.text).1,
+            MemoryKind::Interaction => memory.text.as_str(),
+        };
+        return MemoryRecord {
+            id,
+            kind: memory.kind,
+            timestamp: DateTime::from_timestamp(memory.created_at, 0)
+                .map(|created_at| created_at.to_rfc3339()),
+            text: text.to_string(),
+        };
+    }
+
+    let text = payload.text().unwrap_or_default();
+    let (kind, unwrapped) = match text
+        .strip_prefix('{')
+        .and_then(|text| text.strip_suffix('}'))
+    {
+        Some(unwrapped) => (MemoryKind::Interaction, unwrapped),
+        None => (MemoryKind::Conclusion, text),
+    };
+
+    let (timestamp, text) = split_timestamp(unwrapped);
+    MemoryRecord {
+        id,
+        kind,
+        timestamp,
+        text: text.to_string(),
+    }
+}
+
+pub(crate) fn split_timestamp(text: &str) -> (Option<String>, &str) {
+    match text.split_once("): ") {
+        Some((timestamp, text)) => (Some(format!("{})", timestamp)), text),
+        None => (None, text),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::ai_db::NervoAiDb;
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::memory_vector_store::InMemoryVectorStore;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::ai::vector_store::VectorPayload;
+    use crate::context::permanent_memory::MemorySource;
+    use crate::context::user_memory::{conclusions_collection, UserMemory};
+    use nervo_sdk::agent_type::AgentType;
+    use nervo_sdk::api::spec::MemoryKind;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_list_forget_and_wipe() -> anyhow::Result<()> {
+        let nervo_llm = fake_llm(FakeLlmProvider::default());
+        let ai_db = NervoAiDb::with_store(Arc::new(InMemoryVectorStore::default()), nervo_llm);
+        let conclusions = conclusions_collection(7);
+        for fact in ["likes black cats", "lives in Berlin"] {
+            let text = format!("2024-12-22 10:00:00 (Sunday): {}", fact);
+            ai_db.save_text(&conclusions, &text).await?;
+        }
+        ai_db
+            .save_text(
+                &conclusions,
+                "{2024-12-22 10:00:00 (Sunday): [{\"role\": \"user\", \"content\": \"hi\"}]}",
+            )
+            .await?;
+        ai_db
+            .save_text(
+                &conclusions_collection(8),
+                "2024-12-22 10:00:00 (Sunday): other user",
+            )
+            .await?;
+
+        let memory = UserMemory::new(&ai_db, 7);
+        let listed = memory.conclusions().await?;
+        assert_eq!(listed.len(), 2);
+        assert_eq!(
+            listed[0].timestamp.as_deref(),
+            Some("2024-12-22 10:00:00 (Sunday)")
+        );
+
+        let export = memory.export().await?;
+        assert_eq!(export.memory_cells.len(), 1);
+        assert!(export.memory_cells[0].text.starts_with('['));
+
+        let source = MemorySource {
+            user_id: 7,
+            chat_id: 7,
+            agent_type: AgentType::Kevin,
+            message_ids: vec![1],
+        };
+        let payload = source.payload(
+            MemoryKind::Interaction,
+            r#"{"messages":[{"role":"user","content":"hello"}]}"#,
+            "fake-embedding",
+        );
+        ai_db
+            .save_payload(&conclusions, VectorPayload::try_from(&payload)?)
+            .await?;
+        let export = memory.export().await?;
+        let structured = export
+            .memory_cells
+            .iter()
+            .find(|record| record.text.contains("hello"))
+            .unwrap();
+        assert_eq!(structured.kind, MemoryKind::Interaction);
+        assert!(structured.timestamp.as_ref().unwrap().contains('T'));
+
+        let matching = memory
+            .matching("2024-12-22 10:00:00 (Sunday): likes black cats")
+            .await?;
+        assert_eq!(matching.len(), 1);
+        assert_eq!(matching[0].text, "likes black cats");
+        assert_eq!(memory.conclusions().await?.len(), 2);
+        assert!(memory.delete(&matching[0].id).await?);
+
+        let berlin = &memory.conclusions().await?[0];
+        assert!(memory.delete(&berlin.id).await?);
+        assert!(!memory.delete(&berlin.id).await?);
+        assert!(!memory.delete("not an id").await?);
+        assert!(memory.conclusions().await?.is_empty());
+
+        memory.wipe().await?;
+        assert!(memory.export().await?.memory_cells.is_empty());
+        assert_eq!(UserMemory::new(&ai_db, 8).conclusions().await?.len(), 1);
+        Ok(())
+    }
+}
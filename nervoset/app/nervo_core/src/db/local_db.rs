@@ -1,271 +1,1156 @@
 use crate::config::common::DatabaseParams;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::sqlite::SqliteConnection;
-use sqlx::{ConnectOptions, Row};
+use crate::models::user_model::TelegramUser;
+use anyhow::bail;
+use nervo_sdk::api::spec::{
+    LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence, LlmMessageRole,
+};
+use serde_json::Value;
+use sqlx::migrate::Migrator;
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+    SqliteRow, SqliteSynchronous,
+};
+use sqlx::{Row, Sqlite};
+use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
+use std::time::Duration;
 use tracing::info;
 
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+pub const TELEGRAM_RESOURCE_CODE: &str = "TELEGRAM";
+
+const LEGACY_TABLE_PREFIX: &str = "table_";
+const LEGACY_CONTEXT_WINDOW_SUFFIX: &str = "_start_index";
+const LEGACY_USERS_TABLE: &str = "table_all_users_list";
+
+const MAX_CONNECTIONS: u32 = 5;
+/// How long a writer waits for the other writers before failing with "database is locked"
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sqlite database in WAL mode, so readers don't wait for the writer.
+/// Each logical operation runs in a single transaction, which starts with a write
+/// or with [`WriteTransaction`] if it reads first, so concurrent operations are queued
+/// by sqlite instead of interleaving.
+#[derive(Clone)]
 pub struct LocalDb {
-    db_params: DatabaseParams,
+    pool: SqlitePool,
+}
+
+/// Transaction started by `BEGIN IMMEDIATE`, it takes the write lock up front.
+/// A deferred transaction that reads first fails with SQLITE_BUSY when it upgrades
+/// to a write while another writer holds the lock, the busy timeout doesn't help there.
+struct WriteTransaction {
+    conn: PoolConnection<Sqlite>,
+    open: bool,
+}
+
+impl WriteTransaction {
+    async fn begin(pool: &SqlitePool) -> anyhow::Result<Self> {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+        Ok(WriteTransaction { conn, open: true })
+    }
+
+    async fn commit(mut self) -> anyhow::Result<()> {
+        sqlx::query("COMMIT").execute(&mut *self.conn).await?;
+        self.open = false;
+        Ok(())
+    }
+}
+
+impl Deref for WriteTransaction {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl DerefMut for WriteTransaction {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn
+    }
+}
+
+impl Drop for WriteTransaction {
+    fn drop(&mut self) {
+        if self.open {
+            // Sqlite rolls back the transaction of a closed connection
+            self.conn.close_on_drop();
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatSummary {
+    pub summary: String,
+    /// Messages up to this one are folded into the summary
+    pub last_message_id: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialogueSummary {
+    pub summary: String,
+    /// Interactions up to this one are folded into the summary and deleted
+    pub last_interaction_id: i64,
 }
 
 impl LocalDb {
     pub fn try_init(db_params: DatabaseParams) -> anyhow::Result<Self> {
-        Ok(Self { db_params })
+        let connect_options = SqliteConnectOptions::from_str(db_params.url.as_str())?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect_lazy_with(connect_options);
+
+        Ok(Self { pool })
     }
 }
 
 impl LocalDb {
-    async fn connect_db(&self) -> anyhow::Result<SqliteConnection> {
-        let conn = SqliteConnectOptions::from_str(self.db_params.url.as_str())?
-            .create_if_missing(true)
-            .connect()
+    /// Applies the pending migrations, imports the legacy tables and registers the super admins
+    pub async fn init_db(&self) -> anyhow::Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+        info!("DB: migrations are applied");
+
+        self.import_legacy_tables().await?;
+
+        // тут список суперадминов, т.е. разработчиков которым будет доступно всё
+        let super_admins = [
+            ("121178660", "ozatot"),
+            ("124607629", "llio6oh"),
+            ("5964236329", "spacewhaleblues"),
+            ("174703869", "bynull"),
+        ];
+
+        for (tg_id, username) in super_admins {
+            let user_exists: bool =
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM user WHERE username = ?)")
+                    .bind(username)
+                    .fetch_one(&self.pool)
+                    .await?;
+            if user_exists {
+                continue;
+            }
+
+            let mut tx = self.pool.begin().await?;
+            let user_id: i64 =
+                sqlx::query_scalar("INSERT INTO user (username) VALUES (?) RETURNING id")
+                    .bind(username)
+                    .fetch_one(&mut *tx)
+                    .await?;
+            sqlx::query(
+                "INSERT INTO user_external_ids (user_id, external_resource_code, \
+                external_resource_id) VALUES (?, ?, ?)",
+            )
+            .bind(user_id)
+            .bind(TELEGRAM_RESOURCE_CODE)
+            .bind(tg_id)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "INSERT INTO user_roles (user_id, role, dt_from) \
+                VALUES (?, 'SUPERADMIN', datetime('now'))",
+            )
+            .bind(user_id)
+            .execute(&mut *tx)
             .await?;
-        Ok(conn)
+            tx.commit().await?;
+        }
+
+        Ok(())
     }
 
-    pub async fn save_to_local_db<T>(
+    pub async fn get_user_permissions_tg_id(&self, tg_user_id: u64) -> anyhow::Result<Vec<String>> {
+        let roles = sqlx::query_scalar(
+            "SELECT DISTINCT role FROM user_roles ur \
+                LEFT JOIN user_external_ids uei ON uei.user_id=ur.user_id \
+            WHERE external_resource_code = ? AND external_resource_id = ? \
+            AND datetime('now') >= dt_from \
+            AND (dt_to is NULL OR datetime('now') <= dt_to)",
+        )
+        .bind(TELEGRAM_RESOURCE_CODE)
+        .bind(tg_user_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(roles)
+    }
+
+    /// Returns the id of the user known by the external id, the user is created if needed
+    pub async fn register_external_user(
         &self,
-        message: T,
-        table_name: &str,
-        restriction_limit: Option<i64>,
-    ) -> anyhow::Result<()>
-    where
-        T: Serialize + Send + 'static,
-    {
-        self.create_table(table_name).await?;
-        let items_count = self.count_items(table_name).await?;
-
-        match restriction_limit {
-            Some(limit) if limit != 0 && items_count >= limit => {
-                self.overwrite_messages(message, table_name).await?;
-            }
-            _ => {
-                self.insert_message(message, table_name).await?;
-            }
+        resource_code: &str,
+        external_id: &str,
+    ) -> anyhow::Result<i64> {
+        let mut tx = WriteTransaction::begin(&self.pool).await?;
+        let user_id = register_external_user(&mut tx, resource_code, external_id).await?;
+        tx.commit().await?;
+        Ok(user_id)
+    }
+
+    pub async fn user_language(
+        &self,
+        resource_code: &str,
+        external_id: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let language: Option<Option<String>> = sqlx::query_scalar(
+            "SELECT u.language FROM user u \
+                JOIN user_external_ids uei ON uei.user_id = u.id \
+            WHERE uei.external_resource_code = ? AND uei.external_resource_id = ?",
+        )
+        .bind(resource_code)
+        .bind(external_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(language.flatten())
+    }
+
+    /// Without `overwrite` the language is set only if the user has none yet
+    pub async fn set_user_language(
+        &self,
+        resource_code: &str,
+        external_id: &str,
+        language: &str,
+        overwrite: bool,
+    ) -> anyhow::Result<()> {
+        let mut tx = WriteTransaction::begin(&self.pool).await?;
+        let user_id = register_external_user(&mut tx, resource_code, external_id).await?;
+        sqlx::query("UPDATE user SET language = ? WHERE id = ? AND (? OR language IS NULL)")
+            .bind(language)
+            .bind(user_id)
+            .bind(overwrite)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn cached_translation(
+        &self,
+        content_hash: &str,
+        language: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let translation = sqlx::query_scalar(
+            "SELECT translation FROM translations WHERE content_hash = ? AND language = ?",
+        )
+        .bind(content_hash)
+        .bind(language)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(translation)
+    }
+
+    pub async fn cache_translation(
+        &self,
+        content_hash: &str,
+        language: &str,
+        translation: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO translations (content_hash, language, translation) VALUES (?, ?, ?)",
+        )
+        .bind(content_hash)
+        .bind(language)
+        .bind(translation)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the id of the conversation of the user with the agent in the chat
+    pub async fn chat_of(
+        &self,
+        agent_type: &str,
+        chat_id: u64,
+        user_id: u64,
+    ) -> anyhow::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        let chat = chat_of(&mut tx, agent_type, chat_id as i64, user_id as i64).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    /// Returns the id of the saved message. With `context_window_limit` the message is also
+    /// appended to the context window, which keeps only the last `limit` messages.
+    pub async fn save_message(
+        &self,
+        chat: i64,
+        message: &LlmMessage,
+        context_window_limit: Option<i64>,
+    ) -> anyhow::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        let message_id = save_message(&mut tx, chat, message).await?;
+        if let Some(limit) = context_window_limit {
+            append_to_context_window(&mut tx, chat, message_id, limit).await?;
         }
+        tx.commit().await?;
+        Ok(message_id)
+    }
+
+    pub async fn read_messages(&self, chat: i64) -> anyhow::Result<Vec<LlmMessage>> {
+        let rows = sqlx::query(
+            "SELECT sender_id, role, persistence, content, sources FROM messages \
+            WHERE chat_id = ? ORDER BY id",
+        )
+        .bind(chat)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(message_from_row).collect()
+    }
+
+    /// Messages of all the conversations which took place in the external (i.e. Telegram) chat
+    pub async fn read_messages_of_external_chat(
+        &self,
+        chat_id: u64,
+    ) -> anyhow::Result<Vec<LlmMessage>> {
+        let rows = sqlx::query(
+            "SELECT m.sender_id, m.role, m.persistence, m.content, m.sources FROM messages m \
+                JOIN chats c ON c.id = m.chat_id \
+            WHERE c.chat_id = ? ORDER BY m.id",
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(message_from_row).collect()
+    }
+
+    pub async fn read_context_window(&self, chat: i64) -> anyhow::Result<Vec<LlmMessage>> {
+        let rows = sqlx::query(
+            "SELECT m.sender_id, m.role, m.persistence, m.content, m.sources FROM context_windows cw \
+                JOIN messages m ON m.id = cw.message_id \
+            WHERE cw.chat_id = ? ORDER BY cw.id",
+        )
+        .bind(chat)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(message_from_row).collect()
+    }
+
+    /// A page of `limit` persistent messages after the `after_id` one with their ids,
+    /// the oldest first
+    pub async fn read_persistent_messages_after(
+        &self,
+        chat: i64,
+        after_id: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i64, LlmMessage)>> {
+        let rows = sqlx::query(
+            "SELECT id, sender_id, role, persistence, content, sources FROM messages \
+            WHERE chat_id = ? AND id > ? AND persistence = ? ORDER BY id LIMIT ?",
+        )
+        .bind(chat)
+        .bind(after_id)
+        .bind(enum_to_text(&LlmMessagePersistence::Persistent)?)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let id: i64 = row.try_get("id")?;
+                Ok((id, message_from_row(row)?))
+            })
+            .collect()
+    }
 
+    pub async fn chat_summary(&self, chat: i64) -> anyhow::Result<Option<ChatSummary>> {
+        let row =
+            sqlx::query("SELECT summary, last_message_id FROM chat_summaries WHERE chat_id = ?")
+                .bind(chat)
+                .fetch_optional(&self.pool)
+                .await?;
+        row.map(|row| {
+            Ok(ChatSummary {
+                summary: row.try_get("summary")?,
+                last_message_id: row.try_get("last_message_id")?,
+            })
+        })
+        .transpose()
+    }
+
+    /// A summary which folds fewer messages than the saved one is ignored,
+    /// so concurrent summarizations can't roll the summary back
+    pub async fn save_chat_summary(&self, chat: i64, summary: &ChatSummary) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO chat_summaries (chat_id, summary, last_message_id, updated_at) \
+            VALUES (?, ?, ?, datetime('now')) \
+            ON CONFLICT (chat_id) DO UPDATE SET summary = excluded.summary, \
+            last_message_id = excluded.last_message_id, updated_at = excluded.updated_at \
+            WHERE excluded.last_message_id > chat_summaries.last_message_id",
+        )
+        .bind(chat)
+        .bind(&summary.summary)
+        .bind(summary.last_message_id)
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
 
-    pub async fn read_from_local_db<T>(&self, table_name: &str) -> anyhow::Result<Vec<T>>
-    where
-        T: DeserializeOwned,
-    {
-        info!("DB: Create table if needed {}", &table_name);
-        self.create_table(table_name).await?;
-        let query = format!("SELECT message FROM table_{}", table_name);
-        info!("DB: Connect to db");
-        let mut conn = self.connect_db().await?;
-        let rows = sqlx::query(&query).fetch_all(&mut conn).await?;
-        let mut messages = Vec::new();
-        info!("in DB {:?}, we got {:?}", table_name, messages.len());
-        for row in rows {
-            let message_json: String = row.try_get("message")?;
-            let message = serde_json::from_str(&message_json)?;
-            messages.push(message);
+    pub async fn clear_chat(&self, chat: i64) -> anyhow::Result<()> {
+        info!("Clearing chat: {}!", chat);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM chat_summaries WHERE chat_id = ?")
+            .bind(chat)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM context_windows WHERE chat_id = ?")
+            .bind(chat)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM messages WHERE chat_id = ?")
+            .bind(chat)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Deletes the messages and the summaries of every chat of the user
+    pub async fn clear_chats_of_user(&self, user_id: u64) -> anyhow::Result<()> {
+        info!("Clearing chats of the user: {}!", user_id);
+        let mut tx = self.pool.begin().await?;
+        for table in ["chat_summaries", "context_windows", "messages"] {
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE chat_id IN (SELECT id FROM chats WHERE user_id = ?)",
+                table
+            ))
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
         }
-        Ok(messages)
-    }
-
-    async fn create_table(&self, table_name: &str) -> anyhow::Result<()> {
-        let table_exists = self.is_table_exists(table_name).await?;
-        if !table_exists {
-            let query = format!(
-                "CREATE TABLE IF NOT EXISTS table_{} (
-               id INTEGER PRIMARY KEY AUTOINCREMENT,
-               message TEXT,
-               timestamp TEXT
-           )",
-                table_name
-            );
-            let mut conn = self.connect_db().await?;
-            sqlx::query(&query).execute(&mut conn).await?;
-        };
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The last `limit` interactions of the dialogue which are not folded into the summary
+    /// yet with their ids, the oldest first
+    pub async fn read_dialogue(
+        &self,
+        agent_type: &str,
+        chat_id: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i64, String)>> {
+        let mut interactions: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT id, interaction FROM dialogue_interactions \
+            WHERE agent_type = ? AND chat_id = ? ORDER BY id DESC LIMIT ?",
+        )
+        .bind(agent_type)
+        .bind(chat_id)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&self.pool)
+        .await?;
+        interactions.reverse();
+        Ok(interactions)
+    }
 
+    /// The interactions are kept until they are folded into the summary of the dialogue
+    pub async fn append_to_dialogue(
+        &self,
+        agent_type: &str,
+        chat_id: i64,
+        interaction: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO dialogue_interactions (agent_type, chat_id, interaction) VALUES (?, ?, ?)",
+        )
+        .bind(agent_type)
+        .bind(chat_id)
+        .bind(interaction)
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
 
-    async fn count_items(&self, table_name: &str) -> anyhow::Result<i64> {
-        let query = format!("SELECT COUNT(*) FROM table_{}", table_name);
-        let mut conn = self.connect_db().await?;
-        let count: i64 = sqlx::query_scalar(&query).fetch_one(&mut conn).await?;
-        Ok(count)
+    pub async fn dialogue_summary(
+        &self,
+        agent_type: &str,
+        chat_id: i64,
+    ) -> anyhow::Result<Option<DialogueSummary>> {
+        let row: Option<(String, i64)> = sqlx::query_as(
+            "SELECT summary, last_interaction_id FROM dialogue_summaries \
+            WHERE agent_type = ? AND chat_id = ?",
+        )
+        .bind(agent_type)
+        .bind(chat_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(summary, last_interaction_id)| DialogueSummary {
+            summary,
+            last_interaction_id,
+        }))
     }
 
-    async fn overwrite_messages<T>(&self, message: T, table_name: &str) -> anyhow::Result<()>
-    where
-        T: Serialize,
-    {
-        let mut conn = self.connect_db().await?;
-        let sql = format!(
-            "SELECT id FROM table_{} ORDER BY id ASC LIMIT 1",
-            table_name
-        );
-        let oldest_message_id: Option<i64> =
-            sqlx::query_scalar(&sql).fetch_optional(&mut conn).await?;
+    /// Saves the summary and deletes the interactions folded into it. A summary which folds
+    /// fewer interactions than the saved one is ignored
+    pub async fn fold_dialogue(
+        &self,
+        agent_type: &str,
+        chat_id: i64,
+        summary: &DialogueSummary,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO dialogue_summaries (agent_type, chat_id, summary, last_interaction_id, \
+            updated_at) VALUES (?, ?, ?, ?, datetime('now')) \
+            ON CONFLICT (agent_type, chat_id) DO UPDATE SET summary = excluded.summary, \
+            last_interaction_id = excluded.last_interaction_id, updated_at = excluded.updated_at \
+            WHERE excluded.last_interaction_id > dialogue_summaries.last_interaction_id",
+        )
+        .bind(agent_type)
+        .bind(chat_id)
+        .bind(&summary.summary)
+        .bind(summary.last_interaction_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "DELETE FROM dialogue_interactions WHERE agent_type = ? AND chat_id = ? AND id <= ?",
+        )
+        .bind(agent_type)
+        .bind(chat_id)
+        .bind(summary.last_interaction_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
 
-        if let Some(id) = oldest_message_id {
-            sqlx::query(&format!("DELETE FROM table_{} WHERE id = ?", table_name))
-                .bind(id)
-                .execute(&mut conn)
-                .await?;
+    pub async fn clear_dialogue(&self, agent_type: &str, chat_id: i64) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for table in ["dialogue_interactions", "dialogue_summaries"] {
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE agent_type = ? AND chat_id = ?",
+                table
+            ))
+            .bind(agent_type)
+            .bind(chat_id)
+            .execute(&mut *tx)
+            .await?;
         }
+        tx.commit().await?;
+        Ok(())
+    }
 
-        self.insert_message(message, table_name).await?;
+    /// Deletes the dialogues of every agent in the user's private chat and in the chats
+    /// the user has written to. The interactions of a group chat are not attributed
+    /// to the users, so its whole dialogue goes
+    pub async fn clear_dialogues_of_user(&self, user_id: u64) -> anyhow::Result<()> {
+        info!("Clearing dialogues of the user: {}!", user_id);
+        let mut tx = self.pool.begin().await?;
+        for table in ["dialogue_interactions", "dialogue_summaries"] {
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE chat_id = ?                 OR (agent_type, chat_id) IN (SELECT agent_type, chat_id FROM chats WHERE user_id = ?)",
+                table
+            ))
+            .bind(user_id as i64)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
         Ok(())
     }
 
-    async fn insert_message<T>(&self, message: T, table_name: &str) -> anyhow::Result<()>
-    where
-        T: Serialize,
-    {
-        let message_json = serde_json::to_string(&message)?;
-        let mut conn = self.connect_db().await?;
+    /// Copies the rows of the `table_*` tables of the key-value era into the schema tables.
+    /// Every legacy table is imported once and left in place.
+    async fn import_legacy_tables(&self) -> anyhow::Result<()> {
+        let legacy_tables: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE 'table\\_%' ESCAPE '\\' \
+            AND name NOT IN (SELECT table_name FROM legacy_table_imports) ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Context windows refer to the messages, so the message tables go first
+        let (window_tables, other_tables): (Vec<String>, Vec<String>) = legacy_tables
+            .into_iter()
+            .partition(|name| name.ends_with(LEGACY_CONTEXT_WINDOW_SUFFIX));
 
-        let query = format!("INSERT INTO table_{} (message) VALUES (?)", &table_name);
-        sqlx::query(&query)
-            .bind(&message_json)
-            .execute(&mut conn)
+        for table_name in other_tables.into_iter().chain(window_tables) {
+            info!("DB: importing legacy table {}", table_name);
+            let mut tx = WriteTransaction::begin(&self.pool).await?;
+            import_legacy_table(&mut tx, &table_name).await?;
+            sqlx::query("INSERT INTO legacy_table_imports (table_name) VALUES (?)")
+                .bind(&table_name)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads before it writes, so it has to run in a [`WriteTransaction`]
+async fn register_external_user(
+    conn: &mut SqliteConnection,
+    resource_code: &str,
+    external_id: &str,
+) -> anyhow::Result<i64> {
+    let maybe_user_id: Option<i64> = sqlx::query_scalar(
+        "SELECT user_id FROM user_external_ids \
+        WHERE external_resource_code = ? AND external_resource_id = ?",
+    )
+    .bind(resource_code)
+    .bind(external_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(user_id) = maybe_user_id {
+        return Ok(user_id);
+    }
+
+    let user_id: i64 = sqlx::query_scalar("INSERT INTO user DEFAULT VALUES RETURNING id")
+        .fetch_one(&mut *conn)
+        .await?;
+    sqlx::query(
+        "INSERT INTO user_external_ids (user_id, external_resource_code, external_resource_id) \
+        VALUES (?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(resource_code)
+    .bind(external_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(user_id)
+}
+
+async fn chat_of(
+    conn: &mut SqliteConnection,
+    agent_type: &str,
+    chat_id: i64,
+    user_id: i64,
+) -> anyhow::Result<i64> {
+    sqlx::query("INSERT OR IGNORE INTO chats (agent_type, chat_id, user_id) VALUES (?, ?, ?)")
+        .bind(agent_type)
+        .bind(chat_id)
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    let chat: i64 = sqlx::query_scalar(
+        "SELECT id FROM chats WHERE agent_type = ? AND chat_id = ? AND user_id = ?",
+    )
+    .bind(agent_type)
+    .bind(chat_id)
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(chat)
+}
+
+async fn save_message(
+    conn: &mut SqliteConnection,
+    chat: i64,
+    message: &LlmMessage,
+) -> anyhow::Result<i64> {
+    let sources = if message.sources.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(&message.sources)?)
+    };
+    let message_id: i64 = sqlx::query_scalar(
+        "INSERT INTO messages (chat_id, sender_id, role, persistence, content, sources) \
+        VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+    )
+    .bind(chat)
+    .bind(message.meta_info.sender_id.map(|id| id as i64))
+    .bind(enum_to_text(&message.meta_info.role)?)
+    .bind(enum_to_text(&message.meta_info.persistence)?)
+    .bind(&message.content.0)
+    .bind(sources)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(message_id)
+}
+
+async fn append_to_context_window(
+    conn: &mut SqliteConnection,
+    chat: i64,
+    message_id: i64,
+    limit: i64,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO context_windows (chat_id, message_id) VALUES (?, ?)")
+        .bind(chat)
+        .bind(message_id)
+        .execute(&mut *conn)
+        .await?;
+
+    if limit > 0 {
+        sqlx::query(
+            "DELETE FROM context_windows WHERE chat_id = ? AND id NOT IN \
+            (SELECT id FROM context_windows WHERE chat_id = ? ORDER BY id DESC LIMIT ?)",
+        )
+        .bind(chat)
+        .bind(chat)
+        .bind(limit)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn import_legacy_table(conn: &mut SqliteConnection, table_name: &str) -> anyhow::Result<()> {
+    // The table name comes from sqlite_master, it can't be bound as a parameter
+    let rows = sqlx::query(&format!(
+        "SELECT message FROM \"{}\" ORDER BY id",
+        table_name.replace('"', "\"\"")
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    let values = rows
+        .into_iter()
+        .filter_map(|row| row.try_get::<Option<String>, _>("message").ok().flatten())
+        .collect::<Vec<String>>();
+
+    if table_name == LEGACY_USERS_TABLE {
+        for value in values {
+            let user: TelegramUser = serde_json::from_str(&value)?;
+            register_external_user(conn, TELEGRAM_RESOURCE_CODE, &user.id).await?;
+        }
+        return Ok(());
+    }
+
+    let Some(messages_table) = table_name.strip_suffix(LEGACY_CONTEXT_WINDOW_SUFFIX) else {
+        let Some((agent_type, chat_id, user_id)) = parse_legacy_chat_table(table_name) else {
+            info!("DB: skipping unknown legacy table {}", table_name);
+            return Ok(());
+        };
+        let chat = chat_of(conn, &agent_type, chat_id, user_id).await?;
+        for value in values {
+            let message: LlmMessage = serde_json::from_str(&value)?;
+            save_message(conn, chat, &message).await?;
+        }
+        return Ok(());
+    };
+
+    // Legacy windows keep positions of the messages in the message table
+    let Some((agent_type, chat_id, user_id)) = parse_legacy_chat_table(messages_table) else {
+        info!("DB: skipping unknown legacy table {}", table_name);
+        return Ok(());
+    };
+    let chat = chat_of(conn, &agent_type, chat_id, user_id).await?;
+    let message_ids: Vec<i64> =
+        sqlx::query_scalar("SELECT id FROM messages WHERE chat_id = ? ORDER BY id")
+            .bind(chat)
+            .fetch_all(&mut *conn)
             .await?;
+    for value in values {
+        let position: usize = serde_json::from_str(&value)?;
+        if let Some(message_id) = message_ids.get(position) {
+            append_to_context_window(conn, chat, *message_id, 0).await?;
+        }
+    }
+    Ok(())
+}
+
+/// `table_<agent>_<chat id>_<user id>` => (agent, chat id, user id).
+/// The legacy tables have the negative ids of the group chats cast to u64
+fn parse_legacy_chat_table(table_name: &str) -> Option<(String, i64, i64)> {
+    let name = table_name.strip_prefix(LEGACY_TABLE_PREFIX)?;
+    let mut parts = name.rsplitn(3, '_');
+    let user_id = parts.next()?.parse().ok()?;
+    let chat_id = parts.next()?.parse::<u64>().ok()? as i64;
+    let agent_type = parts.next()?;
+
+    Some((agent_type.to_string(), chat_id, user_id))
+}
+
+fn message_from_row(row: SqliteRow) -> anyhow::Result<LlmMessage> {
+    let sender_id: Option<i64> = row.try_get("sender_id")?;
+    let role: String = row.try_get("role")?;
+    let persistence: String = row.try_get("persistence")?;
+    let sources: Option<String> = row.try_get("sources")?;
+
+    Ok(LlmMessage {
+        meta_info: LlmMessageMetaInfo {
+            sender_id: sender_id.map(|id| id as u64),
+            role: enum_from_text::<LlmMessageRole>(role)?,
+            persistence: enum_from_text::<LlmMessagePersistence>(persistence)?,
+        },
+        content: LlmMessageContent(row.try_get("content")?),
+        sources: match sources {
+            Some(sources) => serde_json::from_str(&sources)?,
+            None => vec![],
+        },
+    })
+}
+
+/// Unit enum variants are stored by their serde names
+fn enum_to_text<T: serde::Serialize>(value: &T) -> anyhow::Result<String> {
+    match serde_json::to_value(value)? {
+        Value::String(text) => Ok(text),
+        other => bail!("Not a unit variant: {}", other),
+    }
+}
+
+fn enum_from_text<T: serde::de::DeserializeOwned>(text: String) -> anyhow::Result<T> {
+    Ok(serde_json::from_value(Value::String(text))?)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::config::common::DatabaseParams;
+    use crate::db::local_db::{
+        parse_legacy_chat_table, ChatSummary, DialogueSummary, LocalDb, TELEGRAM_RESOURCE_CODE,
+    };
+    use nervo_sdk::api::spec::{
+        LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence, LlmMessageRole,
+        LlmMessageSource,
+    };
+    use sqlx::sqlite::SqliteConnectOptions;
+    use sqlx::ConnectOptions;
+    use std::ops::Deref;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    /// LocalDb in its own directory, as WAL mode keeps a couple of files next to the database.
+    /// The directory is removed when the guard is dropped, by a failed test too.
+    pub(crate) struct TempDb {
+        local_db: LocalDb,
+        db_dir: PathBuf,
+    }
+
+    impl TempDb {
+        pub(crate) fn db_path(&self) -> PathBuf {
+            self.db_dir.join("local.db")
+        }
+    }
+
+    impl Deref for TempDb {
+        type Target = LocalDb;
+
+        fn deref(&self) -> &Self::Target {
+            &self.local_db
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.db_dir);
+        }
+    }
+
+    pub(crate) fn temp_db() -> TempDb {
+        let db_dir = std::env::temp_dir().join(format!("nervo_local_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&db_dir).unwrap();
+        let db_params = DatabaseParams {
+            url: format!("sqlite://{}", db_dir.join("local.db").display()),
+        };
+        TempDb {
+            local_db: LocalDb::try_init(db_params).unwrap(),
+            db_dir,
+        }
+    }
+
+    fn message(text: &str, role: LlmMessageRole) -> LlmMessage {
+        LlmMessage {
+            meta_info: LlmMessageMetaInfo {
+                sender_id: Some(7),
+                role,
+                persistence: LlmMessagePersistence::Persistent,
+            },
+            content: LlmMessageContent::from(text),
+            sources: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_and_context_window() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+
+        let chat = local_db.chat_of("kevin", 42, 7).await?;
+        assert_eq!(local_db.chat_of("kevin", 42, 7).await?, chat);
+
+        for text in ["one", "two", "three'); DROP TABLE messages; --"] {
+            local_db
+                .save_message(chat, &message(text, LlmMessageRole::User), Some(2))
+                .await?;
+        }
+
+        assert_eq!(local_db.read_messages(chat).await?.len(), 3);
+        assert_eq!(local_db.read_messages_of_external_chat(42).await?.len(), 3);
+        let window: Vec<String> = local_db
+            .read_context_window(chat)
+            .await?
+            .iter()
+            .map(|msg| msg.content.text())
+            .collect();
+        assert_eq!(window, vec!["two", "three'); DROP TABLE messages; --"]);
+
         Ok(())
     }
 
-    pub async fn clear_table(&self, table_name: &str) -> anyhow::Result<()> {
-        if self.is_table_exists(table_name).await? {
-            info!("Clearing table: {}!", table_name);
-            let mut conn = self.connect_db().await?;
-            let query = format!("DELETE FROM table_{}", table_name);
-            sqlx::query(&query).execute(&mut conn).await?;
+    #[tokio::test]
+    async fn test_clear_chats_of_user() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let own_chats = [
+            local_db.chat_of("kevin", 42, 7).await?,
+            local_db.chat_of("nervoznyak", 7, 7).await?,
+        ];
+        let other_chat = local_db.chat_of("kevin", 42, 8).await?;
+
+        for chat in own_chats.into_iter().chain([other_chat]) {
+            let message_id = local_db
+                .save_message(chat, &message("hello", LlmMessageRole::User), Some(2))
+                .await?;
+            let summary = ChatSummary {
+                summary: "greetings".to_string(),
+                last_message_id: message_id,
+            };
+            local_db.save_chat_summary(chat, &summary).await?;
+        }
+
+        local_db.clear_chats_of_user(7).await?;
+        for chat in own_chats {
+            assert!(local_db.read_messages(chat).await?.is_empty());
+            assert!(local_db.read_context_window(chat).await?.is_empty());
+            assert!(local_db.chat_summary(chat).await?.is_none());
         }
+        assert_eq!(local_db.read_messages(other_chat).await?.len(), 1);
+        assert!(local_db.chat_summary(other_chat).await?.is_some());
         Ok(())
     }
 
-    pub async fn get_user_permissions_tg_id(&self, tg_user_id: u64) -> anyhow::Result<Vec<String>> {
-        let mut conn = self.connect_db().await?;
-        let sql = format!(
-            "SELECT DISTINCT role FROM user_roles ur \
-                LEFT JOIN user_external_ids uei ON uei.user_id=ur.user_id \
-            WHERE external_resource_code='TELEGRAM' AND \
-            external_resource_id='{}' \
-            AND datetime('now') >= dt_from \
-            AND (dt_to is NULL OR datetime('now') <= dt_to)",
-            tg_user_id.to_string()
-        );
-        let roles_result = sqlx::query(&sql).fetch_all(&mut conn).await?;
-        let mut result: Vec<String> = Vec::new();
+    #[tokio::test]
+    async fn test_clear_dialogues_of_user() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        local_db.chat_of("nervoznyak", 100, 7).await?;
+        local_db.chat_of("nervoznyak", 200, 8).await?;
+        let summary = DialogueSummary {
+            summary: "greetings".to_string(),
+            last_interaction_id: 0,
+        };
+        // The private chat of the user, a group the user wrote to and another group
+        for (agent, chat_id) in [("kevin", 7), ("nervoznyak", 100), ("nervoznyak", 200)] {
+            local_db.append_to_dialogue(agent, chat_id, "hello").await?;
+            local_db.fold_dialogue(agent, chat_id, &summary).await?;
+        }
 
-        for row in roles_result {
-            result.push(row.get(0));
+        local_db.clear_dialogues_of_user(7).await?;
+        for (agent, chat_id) in [("kevin", 7), ("nervoznyak", 100)] {
+            assert!(local_db.read_dialogue(agent, chat_id, 10).await?.is_empty());
+            assert!(local_db.dialogue_summary(agent, chat_id).await?.is_none());
         }
-        Ok(result)
+        assert_eq!(
+            local_db.read_dialogue("nervoznyak", 200, 10).await?.len(),
+            1
+        );
+        assert!(local_db
+            .dialogue_summary("nervoznyak", 200)
+            .await?
+            .is_some());
+        Ok(())
     }
 
-    pub async fn init_db(&self) -> anyhow::Result<()> {
-        let mut conn = self.connect_db().await?;
-
-        let user_create_table_query = "CREATE TABLE IF NOT EXISTS user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                username TEXT UNIQUE,
-                info TEXT
-            )";
-        sqlx::query(&user_create_table_query)
-            .execute(&mut conn)
+    #[tokio::test]
+    async fn test_message_sources() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let chat = local_db.chat_of("kevin", 42, 7).await?;
+
+        local_db
+            .save_message(chat, &message("Is it open?", LlmMessageRole::User), None)
             .await?;
+        let mut answer = message("It is open [1]", LlmMessageRole::Assistant);
+        answer.sources = vec![LlmMessageSource {
+            number: 1,
+            source: Some("nervoznyak/about.json".to_string()),
+            text: "The office is open".to_string(),
+        }];
+        local_db.save_message(chat, &answer, Some(2)).await?;
+
+        let messages = local_db.read_messages(chat).await?;
+        assert_eq!(messages.last().unwrap().sources, answer.sources);
+        assert!(messages[0].sources.is_empty());
+
+        Ok(())
+    }
 
-        let user_external_ids_create_table_query = "CREATE TABLE
-            IF NOT EXISTS user_external_ids (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id integer NOT NULL,
-                external_resource_code TEXT NOT NULL,
-                external_resource_id TEXT NOT NULL,
-                FOREIGN KEY(user_id) REFERENCES user(id)
-            )";
-        sqlx::query(&user_external_ids_create_table_query)
-            .execute(&mut conn)
+    #[tokio::test]
+    async fn test_super_admins_and_external_users() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+
+        let roles = local_db.get_user_permissions_tg_id(121178660).await?;
+        assert_eq!(roles, vec!["SUPERADMIN"]);
+        let user_id = local_db
+            .register_external_user(TELEGRAM_RESOURCE_CODE, "1' OR '1'='1")
             .await?;
+        assert_eq!(
+            local_db
+                .register_external_user(TELEGRAM_RESOURCE_CODE, "1' OR '1'='1")
+                .await?,
+            user_id
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_user_language() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
 
-        let roles_create_table_query = "CREATE TABLE
-            IF NOT EXISTS user_roles (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id integer,
-                role TEXT NOT NULL,
-                dt_from TEXT NOT NULL,
-                dt_to TEXT,
-                FOREIGN KEY(user_id) REFERENCES user(id)
-            )";
-        sqlx::query(&roles_create_table_query)
-            .execute(&mut conn)
+        let tg_id = "1' OR '1'='1";
+        assert_eq!(
+            local_db
+                .user_language(TELEGRAM_RESOURCE_CODE, tg_id)
+                .await?,
+            None
+        );
+        local_db
+            .set_user_language(TELEGRAM_RESOURCE_CODE, tg_id, "russian", false)
+            .await?;
+        local_db
+            .set_user_language(TELEGRAM_RESOURCE_CODE, tg_id, "english", false)
             .await?;
+        let language = local_db
+            .user_language(TELEGRAM_RESOURCE_CODE, tg_id)
+            .await?;
+        assert_eq!(language.as_deref(), Some("russian"));
+        local_db
+            .set_user_language(TELEGRAM_RESOURCE_CODE, tg_id, "english", true)
+            .await?;
+        let language = local_db
+            .user_language(TELEGRAM_RESOURCE_CODE, tg_id)
+            .await?;
+        assert_eq!(language.as_deref(), Some("english"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_translation_cache() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
 
-        //fill SUPERADMINS
-        struct User {
-            tg_id: String,
-            username: String,
+        assert_eq!(local_db.cached_translation("hash", "en").await?, None);
+        local_db.cache_translation("hash", "en", "Hello").await?;
+        local_db.cache_translation("hash", "en", "Hi").await?;
+        assert_eq!(
+            local_db.cached_translation("hash", "en").await?.as_deref(),
+            Some("Hi")
+        );
+        assert_eq!(local_db.cached_translation("hash", "ru").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_appends_keep_context_window() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let local_db = Arc::new(local_db.clone());
+        let chat = local_db.chat_of("kevin", 42, 7).await?;
+
+        let tasks = (0..20).map(|index| {
+            let local_db = local_db.clone();
+            tokio::spawn(async move {
+                let text = format!("message {}", index);
+                local_db
+                    .save_message(chat, &message(&text, LlmMessageRole::User), Some(5))
+                    .await
+            })
+        });
+        for task in futures::future::join_all(tasks).await {
+            task??;
         }
-        // тут список суперадминов, т.е. разработчиков которым будет доступно всё
-        let super_admins = [
-            User {
-                tg_id: "121178660".to_string(),
-                username: "ozatot".to_string(),
-            },
-            User {
-                tg_id: "124607629".to_string(),
-                username: "llio6oh".to_string(),
-            },
-            User {
-                tg_id: "5964236329".to_string(),
-                username: "spacewhaleblues".to_string(),
-            },
-            User {
-                tg_id: "174703869".to_string(),
-                username: "bynull".to_string(),
-            },
-        ];
 
-        for user in super_admins {
-            let query = format!(
-                "SELECT EXISTS (SELECT 1 FROM user WHERE username = '{}')",
-                user.username
-            );
-
-            let user_exists: bool = sqlx::query_scalar(&query).fetch_one(&mut conn).await?;
-            if !user_exists {
-                let create_user_query =
-                    format!("INSERT INTO user (username) VALUES ('{}')", user.username);
-                sqlx::query(&create_user_query).execute(&mut conn).await?;
-
-                let user_bd_id_query =
-                    format!("SELECT id FROM user WHERE username='{}'", user.username);
-                let user_bd_id: i64 = sqlx::query_scalar(&user_bd_id_query)
-                    .fetch_one(&mut conn)
+        assert_eq!(local_db.read_messages(chat).await?.len(), 20);
+        assert_eq!(local_db.read_context_window(chat).await?.len(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_registrations() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let local_db = Arc::new(local_db.clone());
+
+        let tasks = (0..20).map(|index| {
+            let local_db = local_db.clone();
+            tokio::spawn(async move {
+                let tg_id = (index % 4).to_string();
+                local_db
+                    .set_user_language(TELEGRAM_RESOURCE_CODE, &tg_id, "russian", false)
                     .await?;
+                local_db
+                    .register_external_user(TELEGRAM_RESOURCE_CODE, &tg_id)
+                    .await
+            })
+        });
+        let mut user_ids = vec![];
+        for task in futures::future::join_all(tasks).await {
+            user_ids.push(task??);
+        }
+        user_ids.sort();
+        user_ids.dedup();
+        assert_eq!(user_ids.len(), 4);
 
-                let create_tg_id_query = format!(
-                    "INSERT INTO user_external_ids (user_id, external_resource_code, \
-                    external_resource_id) VALUES ({}, 'TELEGRAM', '{}')",
-                    user_bd_id, user.tg_id
-                );
-                sqlx::query(&create_tg_id_query).execute(&mut conn).await?;
+        Ok(())
+    }
 
-                let create_user_query = format!(
-                    " INSERT INTO user_roles (user_id, role, dt_from) VALUES ({}, 'SUPERADMIN', datetime('now'));", user_bd_id);
-                sqlx::query(&create_user_query).execute(&mut conn).await?;
+    #[tokio::test]
+    async fn test_legacy_tables_are_imported_once() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        let db_path = local_db.db_path();
+        {
+            let mut conn =
+                SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path.display()))?
+                    .create_if_missing(true)
+                    .connect()
+                    .await?;
+            for table in ["table_kevin_42_7", "table_kevin_42_7_start_index"] {
+                sqlx::query(&format!(
+                    "CREATE TABLE {} (id INTEGER PRIMARY KEY AUTOINCREMENT, message TEXT, timestamp TEXT)",
+                    table
+                ))
+                .execute(&mut conn)
+                .await?;
             }
+            for text in ["hi", "hello"] {
+                let json = serde_json::to_string(&message(text, LlmMessageRole::User))?;
+                sqlx::query("INSERT INTO table_kevin_42_7 (message) VALUES (?)")
+                    .bind(json)
+                    .execute(&mut conn)
+                    .await?;
+            }
+            sqlx::query("INSERT INTO table_kevin_42_7_start_index (message) VALUES ('1')")
+                .execute(&mut conn)
+                .await?;
         }
 
+        local_db.init_db().await?;
+        local_db.init_db().await?;
+
+        let chat = local_db.chat_of("kevin", 42, 7).await?;
+        assert_eq!(local_db.read_messages(chat).await?.len(), 2);
+        let window = local_db.read_context_window(chat).await?;
+        assert_eq!(window.len(), 1);
+        assert_eq!(window[0].content.text(), "hello");
+
         Ok(())
     }
 
-    async fn is_table_exists(&self, table_name: &str) -> anyhow::Result<bool> {
-        let query = format!(
-            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'table_{}')",
-            table_name
+    #[test]
+    fn test_parse_legacy_group_chat_table() {
+        let group_chat_id: i64 = -1001234567890;
+        let table_name = format!("table_kevin_{}_7", group_chat_id as u64);
+        assert_eq!(
+            parse_legacy_chat_table(&table_name),
+            Some(("kevin".to_string(), group_chat_id, 7))
+        );
+        assert_eq!(
+            parse_legacy_chat_table("table_nervo_ai_42_7"),
+            Some(("nervo_ai".to_string(), 42, 7))
         );
-        let mut conn = self.connect_db().await?;
-        let table_exists: bool = sqlx::query_scalar(&query).fetch_one(&mut conn).await?;
-        Ok(table_exists)
     }
 }
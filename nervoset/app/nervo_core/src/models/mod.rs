@@ -2,6 +2,6 @@ pub mod feature_toggle;
 pub mod message_transcription_type;
 pub mod nervo_message_model;
 pub mod qdrant_search_layers;
+pub mod rag_pipeline;
 pub mod system_messages;
-pub mod typing_action_model;
 pub mod user_model;
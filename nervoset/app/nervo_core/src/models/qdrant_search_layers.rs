@@ -1,9 +1,23 @@
+use crate::ai::nervo_llm::LlmRequestOptions;
+use crate::ai::reranker::RerankerConfig;
+use crate::ai::vector_store::PayloadFilter;
+use crate::models::rag_pipeline::{
+    default_min_score, default_top_k, variables, BranchStep, ClassifyStep, GenerateStep, LlmPrompt,
+    RagPipeline, RagStep, RagStepKind, RerankStep, RewriteStep, VectorSearchStep,
+};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const SKIP_LABEL: &str = "SKIP";
+const CRAP_SYSTEM_ROLE_FILE: &str = "system_roles/crap.txt";
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct QdrantSearchInfo {
-    pub crap_detecting_layer: QdrantSearchLayer,
+    pub pipeline: Option<RagPipeline>,
+    /// Legacy linear layers, used only when there is no `pipeline`
+    pub crap_detecting_layer: Option<QdrantSearchLayer>,
+    #[serde(default)]
     pub layers: Vec<QdrantSearchLayer>,
     pub info_message_1: String,
     pub info_message_2: String,
@@ -20,20 +34,247 @@ pub struct QdrantSearchLayer {
     pub common_token_limit: u32,
     pub vectors_limit: u64,
     pub layer_for_search: bool,
+    /// Tokens of the history context in the layer prompt
+    #[serde(default)]
+    pub history_token_limit: Option<usize>,
+    /// Fuses the vector search with the keyword search
+    #[serde(default)]
+    pub hybrid: bool,
+    #[serde(default)]
+    pub filter: Option<PayloadFilter>,
+    /// Hits scored at or below this are dropped
+    #[serde(default = "default_min_score")]
+    pub min_score: f32,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Rescores the `vectors_limit` hits before the `top_k` of them are taken
+    #[serde(default)]
+    pub reranker: Option<RerankerConfig>,
+    #[serde(default)]
+    pub mmr_lambda: Option<f32>,
+}
+
+impl QdrantSearchLayer {
+    /// Layer that only sends the system role to LLM, without the vector search
+    pub fn new(system_role_text: String) -> Self {
+        QdrantSearchLayer {
+            index: None,
+            user_role_params: vec![],
+            system_role_text,
+            temperature: 0.2,
+            max_tokens: 4096,
+            common_token_limit: 30000,
+            vectors_limit: 0,
+            layer_for_search: false,
+            history_token_limit: None,
+            hybrid: false,
+            filter: None,
+            min_score: default_min_score(),
+            top_k: default_top_k(),
+            reranker: None,
+            mmr_lambda: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct QdrantUserRoleParameters {
-    pub param_type: QdrantUserRoleTextType,
+    /// Name of the pipeline variable
+    #[serde(alias = "paramType")]
+    pub variable: String,
     pub param_value: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub enum QdrantUserRoleTextType {
-    History,
-    UserPrompt,
-    RephrasedPrompt,
-    DbSearch,
+impl QdrantSearchInfo {
+    /// The configured pipeline, or the one equivalent to the legacy layers
+    pub fn pipeline(&self) -> anyhow::Result<RagPipeline> {
+        let pipeline = match &self.pipeline {
+            Some(pipeline) => pipeline.clone(),
+            None => self.legacy_pipeline(),
+        };
+        pipeline.validate()?;
+        Ok(pipeline)
+    }
+
+    fn legacy_pipeline(&self) -> RagPipeline {
+        let mut steps = vec![];
+
+        if let Some(crap_layer) = &self.crap_detecting_layer {
+            let first_layer_step = self
+                .layers
+                .first()
+                .map(|layer| legacy_first_step_name(layer, 0))
+                .unwrap_or_else(|| "smallTalkAnswer".to_string());
+
+            steps.push(RagStep {
+                name: "crapDetecting".to_string(),
+                next: None,
+                kind: RagStepKind::Classify(ClassifyStep {
+                    prompt: legacy_prompt(crap_layer),
+                    labels: vec![SKIP_LABEL.to_string()],
+                    default_label: "QUESTION".to_string(),
+                    output: "requestKind".to_string(),
+                }),
+            });
+            steps.push(RagStep {
+                name: "crapBranch".to_string(),
+                next: None,
+                kind: RagStepKind::Branch(BranchStep {
+                    variable: "requestKind".to_string(),
+                    cases: HashMap::from([(SKIP_LABEL.to_string(), "smallTalkAnswer".to_string())]),
+                    default: first_layer_step,
+                }),
+            });
+            steps.push(RagStep {
+                name: "smallTalkAnswer".to_string(),
+                next: None,
+                kind: RagStepKind::Generate(GenerateStep {
+                    prompt: LlmPrompt {
+                        system_role_file: Some(CRAP_SYSTEM_ROLE_FILE.to_string()),
+                        user_role_params: vec![QdrantUserRoleParameters {
+                            variable: variables::USER_PROMPT.to_string(),
+                            param_value: "Текущий запрос пользователя: ".to_string(),
+                        }],
+                        ..LlmPrompt::default()
+                    },
+                    temporal: true,
+                }),
+            });
+        }
+
+        let last_index = self.layers.len().saturating_sub(1);
+        for (index, layer) in self.layers.iter().enumerate() {
+            let reranked = layer.reranker.is_some() || layer.mmr_lambda.is_some();
+            if layer.layer_for_search {
+                steps.push(RagStep {
+                    name: legacy_first_step_name(layer, index),
+                    next: None,
+                    kind: RagStepKind::VectorSearch(VectorSearchStep {
+                        query: variables::REPHRASED_PROMPT.to_string(),
+                        collection: None,
+                        vectors_limit: layer.vectors_limit,
+                        min_score: layer.min_score,
+                        top_k: if reranked {
+                            layer.vectors_limit as usize
+                        } else {
+                            layer.top_k
+                        },
+                        token_limit: layer.common_token_limit as usize,
+                        hybrid: layer.hybrid,
+                        filter: layer.filter.clone(),
+                        output: variables::DB_SEARCH.to_string(),
+                    }),
+                });
+            }
+            if layer.layer_for_search && reranked {
+                steps.push(RagStep {
+                    name: format!("layer{}Rerank", index),
+                    next: None,
+                    kind: RagStepKind::Rerank(RerankStep {
+                        input: variables::DB_SEARCH.to_string(),
+                        query: variables::REPHRASED_PROMPT.to_string(),
+                        reranker: layer.reranker.clone().unwrap_or_default(),
+                        min_score: layer.min_score,
+                        top_k: layer.top_k,
+                        mmr_lambda: layer.mmr_lambda,
+                        token_limit: layer.common_token_limit as usize,
+                        output: variables::DB_SEARCH.to_string(),
+                    }),
+                });
+            }
+
+            let kind = if index == last_index {
+                RagStepKind::Generate(GenerateStep {
+                    prompt: legacy_prompt(layer),
+                    temporal: false,
+                })
+            } else {
+                RagStepKind::Rewrite(RewriteStep {
+                    prompt: legacy_prompt(layer),
+                    output: variables::REPHRASED_PROMPT.to_string(),
+                })
+            };
+            steps.push(RagStep {
+                name: format!("layer{}", index),
+                next: None,
+                kind,
+            });
+        }
+
+        RagPipeline { start: None, steps }
+    }
+}
+
+fn legacy_first_step_name(layer: &QdrantSearchLayer, index: usize) -> String {
+    if layer.layer_for_search {
+        format!("layer{}Search", index)
+    } else {
+        format!("layer{}", index)
+    }
+}
+
+fn legacy_prompt(layer: &QdrantSearchLayer) -> LlmPrompt {
+    LlmPrompt {
+        system_role_text: Some(layer.system_role_text.clone()),
+        system_role_file: None,
+        user_role_params: layer.user_role_params.clone(),
+        history_token_limit: layer.history_token_limit,
+        options: LlmRequestOptions::from(layer),
+    }
+}
+
+impl From<&QdrantSearchLayer> for LlmRequestOptions {
+    fn from(layer: &QdrantSearchLayer) -> Self {
+        LlmRequestOptions {
+            temperature: Some(layer.temperature),
+            max_tokens: Some(layer.max_tokens),
+            ..LlmRequestOptions::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::qdrant_search_layers::QdrantSearchInfo;
+    use crate::models::rag_pipeline::RagStepKind;
+
+    #[test]
+    fn test_legacy_layers_become_pipeline() -> anyhow::Result<()> {
+        let layer = r#"{
+            "userRoleParams": [{"paramType": "rephrasedPrompt", "paramValue": "Request: "}],
+            "systemRoleText": "Answer",
+            "temperature": 0.3,
+            "maxTokens": 100,
+            "commonTokenLimit": 1000,
+            "vectorsLimit": 10,
+            "layerForSearch": true,
+            "topK": 4,
+            "mmrLambda": 0.7
+        }"#;
+        let json = format!(
+            r#"{{"crapDetectingLayer": {layer}, "layers": [{layer}], "infoMessage1": "", "infoMessage2": ""}}"#
+        );
+
+        let info: QdrantSearchInfo = serde_json::from_str(&json)?;
+        let pipeline = info.pipeline()?;
+        let step_names: Vec<&str> = pipeline.steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            step_names,
+            vec![
+                "crapDetecting",
+                "crapBranch",
+                "smallTalkAnswer",
+                "layer0Search",
+                "layer0Rerank",
+                "layer0"
+            ]
+        );
+        let RagStepKind::Rerank(rerank) = &pipeline.steps[4].kind else {
+            panic!("Wrong step type");
+        };
+        assert_eq!(rerank.top_k, 4);
+        assert!(matches!(pipeline.steps[5].kind, RagStepKind::Generate(_)));
+        Ok(())
+    }
 }
@@ -0,0 +1,301 @@
+use anyhow::bail;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::ai::nervo_llm::LlmRequestOptions;
+use crate::ai::reranker::RerankerConfig;
+use crate::ai::vector_store::PayloadFilter;
+use crate::models::qdrant_search_layers::QdrantUserRoleParameters;
+use crate::utils::context_builder::DEFAULT_HISTORY_TOKEN_LIMIT;
+
+/// Variables every pipeline starts with
+pub mod variables {
+    pub const USER_PROMPT: &str = "userPrompt";
+    pub const REPHRASED_PROMPT: &str = "rephrasedPrompt";
+    pub const HISTORY: &str = "history";
+    pub const DB_SEARCH: &str = "dbSearch";
+}
+
+/// Graph of steps that turns a user request into the chat for the final answer.
+/// Steps run one after another in the list order, unless a step names its `next` step
+/// or a branch step picks one. The pipeline ends with a `generate` step.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RagPipeline {
+    /// Name of the first step, the first step of the list if not set
+    pub start: Option<String>,
+    pub steps: Vec<RagStep>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RagStep {
+    pub name: String,
+    pub next: Option<String>,
+    #[serde(flatten)]
+    pub kind: RagStepKind,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RagStepKind {
+    /// Asks LLM to rewrite the request, the reply goes to `output`
+    Rewrite(RewriteStep),
+    /// Asks LLM to pick one of the labels, the label goes to `output`
+    Classify(ClassifyStep),
+    /// Jumps to the step registered for the value of the variable
+    Branch(BranchStep),
+    VectorSearch(VectorSearchStep),
+    /// Rescores the hits of a previous search and narrows them down
+    Rerank(RerankStep),
+    /// Builds the chat for the final answer and ends the pipeline
+    Generate(GenerateStep),
+    /// Reshapes a text variable without calling LLM
+    PostProcess(PostProcessStep),
+}
+
+/// System and user roles of a step which talks to LLM.
+/// User role is built of `userRoleParams`, one line per variable.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LlmPrompt {
+    pub system_role_text: Option<String>,
+    /// Path relative to the agent resources directory
+    pub system_role_file: Option<String>,
+    #[serde(default)]
+    pub user_role_params: Vec<QdrantUserRoleParameters>,
+    /// Tokens of the `history` variable in this prompt, the newest turns are kept
+    pub history_token_limit: Option<usize>,
+    /// Model, temperature, max tokens and the rest, config defaults if not set
+    #[serde(flatten)]
+    pub options: LlmRequestOptions,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RewriteStep {
+    #[serde(flatten)]
+    pub prompt: LlmPrompt,
+    pub output: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ClassifyStep {
+    #[serde(flatten)]
+    pub prompt: LlmPrompt,
+    pub labels: Vec<String>,
+    /// Label used when the reply matches none of the labels
+    pub default_label: String,
+    pub output: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BranchStep {
+    pub variable: String,
+    /// Value of the variable => name of the step to jump to
+    pub cases: HashMap<String, String>,
+    pub default: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VectorSearchStep {
+    /// Variable with the search text
+    pub query: String,
+    /// Agent collection if not set
+    pub collection: Option<String>,
+    pub vectors_limit: u64,
+    /// Applies to the cosine scores, before the fusion with the keyword search
+    #[serde(default = "default_min_score")]
+    pub min_score: f32,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    pub token_limit: usize,
+    /// Fuses the vector search with the keyword search by the same text
+    #[serde(default)]
+    pub hybrid: bool,
+    /// Only the points that pass the filter are searched
+    #[serde(default)]
+    pub filter: Option<PayloadFilter>,
+    /// Receives texts of the found points, joined
+    pub output: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RerankStep {
+    /// Output of a vector search or of another rerank step
+    pub input: String,
+    /// Variable with the request the hits are scored against
+    #[serde(default = "default_rerank_query")]
+    pub query: String,
+    #[serde(default)]
+    pub reranker: RerankerConfig,
+    /// Applies to the scores of the reranker
+    #[serde(default = "default_min_score")]
+    pub min_score: f32,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Picks the `top_k` hits by maximal marginal relevance, not by the score only
+    #[serde(default)]
+    pub mmr_lambda: Option<f32>,
+    pub token_limit: usize,
+    pub output: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateStep {
+    #[serde(flatten)]
+    pub prompt: LlmPrompt,
+    /// Temporal answers are not used as the history context later
+    #[serde(default)]
+    pub temporal: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostProcessStep {
+    pub input: String,
+    /// `{{variable}}` placeholders are replaced with the variables, `{{input}}` with the input
+    pub template: Option<String>,
+    #[serde(default)]
+    pub trim: bool,
+    pub token_limit: Option<usize>,
+    pub output: String,
+}
+
+pub(crate) fn default_min_score() -> f32 {
+    0.3
+}
+
+pub(crate) fn default_top_k() -> usize {
+    10
+}
+
+fn default_rerank_query() -> String {
+    variables::REPHRASED_PROMPT.to_string()
+}
+
+impl RagPipeline {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.steps.is_empty() {
+            bail!("Pipeline has no steps");
+        }
+
+        let mut names = HashSet::new();
+        for step in &self.steps {
+            if !names.insert(step.name.as_str()) {
+                bail!("Step name '{}' is used more than once", step.name);
+            }
+        }
+
+        let mut targets: Vec<&String> = self.start.iter().collect();
+        for step in &self.steps {
+            targets.extend(step.next.iter());
+            if let RagStepKind::Branch(branch) = &step.kind {
+                targets.extend(branch.cases.values());
+                targets.push(&branch.default);
+            }
+        }
+        for target in targets {
+            if !names.contains(target.as_str()) {
+                bail!("Unknown pipeline step '{}'", target);
+            }
+        }
+
+        let has_generate_step = self
+            .steps
+            .iter()
+            .any(|step| matches!(step.kind, RagStepKind::Generate(_)));
+        if !has_generate_step {
+            bail!("Pipeline has no generate step");
+        }
+
+        Ok(())
+    }
+
+    /// Budget of the history context: the largest one of the prompts
+    pub fn history_token_limit(&self) -> usize {
+        self.steps
+            .iter()
+            .filter_map(|step| match &step.kind {
+                RagStepKind::Rewrite(rewrite) => Some(&rewrite.prompt),
+                RagStepKind::Classify(classify) => Some(&classify.prompt),
+                RagStepKind::Generate(generate) => Some(&generate.prompt),
+                _ => None,
+            })
+            .filter_map(|prompt| prompt.history_token_limit)
+            .max()
+            .unwrap_or(DEFAULT_HISTORY_TOKEN_LIMIT)
+    }
+
+    pub fn step(&self, name: &str) -> Option<(usize, &RagStep)> {
+        self.steps
+            .iter()
+            .enumerate()
+            .find(|(_, step)| step.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::rag_pipeline::{RagPipeline, RagStepKind};
+
+    #[test]
+    fn test_pipeline_parsing_and_validation() -> anyhow::Result<()> {
+        let json = r#"{
+            "steps": [
+                {
+                    "name": "detect",
+                    "type": "classify",
+                    "systemRoleText": "Say SKIP or QUESTION",
+                    "userRoleParams": [{"paramType": "userPrompt", "paramValue": "Request: "}],
+                    "labels": ["SKIP"],
+                    "defaultLabel": "QUESTION",
+                    "output": "kind"
+                },
+                {
+                    "name": "route",
+                    "type": "branch",
+                    "variable": "kind",
+                    "cases": {"SKIP": "answer"},
+                    "default": "search"
+                },
+                {
+                    "name": "search",
+                    "type": "vectorSearch",
+                    "query": "userPrompt",
+                    "vectorsLimit": 5,
+                    "tokenLimit": 100,
+                    "hybrid": true,
+                    "filter": {"language": "en", "tags": ["faq"], "dateFrom": "2024-12-01"},
+                    "output": "dbSearch"
+                },
+                {"name": "answer", "type": "generate", "temporal": true}
+            ]
+        }"#;
+
+        let pipeline: RagPipeline = serde_json::from_str(json)?;
+        pipeline.validate()?;
+
+        let (index, search) = pipeline.step("search").unwrap();
+        assert_eq!(index, 2);
+        let RagStepKind::VectorSearch(search) = &search.kind else {
+            panic!("Wrong step type");
+        };
+        assert_eq!(search.top_k, 10);
+        assert!(search.hybrid);
+        let filter = search.filter.clone().unwrap();
+        assert_eq!(filter.language.as_deref(), Some("en"));
+        assert_eq!(filter.tags, vec!["faq"]);
+        assert_eq!(filter.created_at_range(), (Some(1733011200), None));
+
+        let mut broken = pipeline.clone();
+        broken.steps[1].next = Some("unknown".to_string());
+        assert!(broken.validate().is_err());
+        Ok(())
+    }
+}
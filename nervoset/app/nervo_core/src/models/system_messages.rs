@@ -1,9 +1,18 @@
 use crate::utils::ai_utils::RESOURCES_DIR;
+use anyhow::Result;
 use nervo_sdk::agent_type::{AgentType, NervoAgentType};
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
+use std::io::ErrorKind;
 use tokio::fs;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+const SYSTEM_MESSAGES_FILE: &str = "system_messages";
+/// Replaced with the language name in the language messages
+pub const LANGUAGE_PLACEHOLDER: &str = "{language}";
+/// Replaced with the forgotten memory
+pub const MEMORY_PLACEHOLDER: &str = "{memory}";
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct SystemMessages {
     pub start: String,
@@ -11,6 +20,51 @@ pub struct SystemMessages {
     pub wait_second: String,
     pub empty_message: String,
     pub cant_get_message: String,
+    pub language: String,
+    pub language_set: String,
+    pub memory_empty: String,
+    pub memory_truncated: String,
+    pub forget_usage: String,
+    pub forgotten: String,
+    pub forget_not_found: String,
+    pub forget_choose: String,
+    pub forgot_everything: String,
+}
+
+impl SystemMessages {
+    /// The catalog of the language, `system_messages.<lang>.json` of the agent.
+    /// Returns `None` if the agent has no catalog for the language
+    pub async fn load_catalog(agent_type: AgentType, language: &str) -> Result<Option<Self>> {
+        let is_valid_code = !language.is_empty()
+            && language
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+        if !is_valid_code {
+            return Ok(None);
+        }
+
+        let file_name = format!("{}.{}.json", SYSTEM_MESSAGES_FILE, language);
+        match Self::load(agent_type, &file_name).await {
+            Ok(catalog) => Ok(Some(catalog)),
+            Err(err) => match err.downcast_ref::<std::io::Error>() {
+                Some(io_err) if io_err.kind() == ErrorKind::NotFound => Ok(None),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// `system_messages.json` of the agent, it gets translated for the languages without a catalog
+    pub async fn load_default(agent_type: AgentType) -> Result<Self> {
+        Self::load(agent_type, &format!("{}.json", SYSTEM_MESSAGES_FILE)).await
+    }
+
+    async fn load(agent_type: AgentType, file_name: &str) -> Result<Self> {
+        let agent = NervoAgentType::get_name(agent_type);
+        let system_msg_file = format!("{}{}/{}", RESOURCES_DIR, agent, file_name);
+
+        let json_string = fs::read_to_string(system_msg_file).await?;
+        Ok(serde_json::from_str(&json_string)?)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -21,34 +75,100 @@ pub enum SystemMessage {
     WaitSecond(AgentType),
     EmptyMessage(AgentType),
     CantGetYourMessage(AgentType),
+    /// The current reply language, has [`LANGUAGE_PLACEHOLDER`]
+    Language(AgentType),
+    /// The reply language has been changed, has [`LANGUAGE_PLACEHOLDER`]
+    LanguageSet(AgentType),
+    /// The memory agent keeps nothing about the user
+    MemoryEmpty(AgentType),
+    /// Closes the memories that don't fit the message
+    MemoryTruncated(AgentType),
+    ForgetUsage(AgentType),
+    /// The memory has been deleted, has [`MEMORY_PLACEHOLDER`]
+    Forgotten(AgentType),
+    ForgetNotFound(AgentType),
+    /// Heads the list of the memories to pick from
+    ForgetChoose(AgentType),
+    ForgotEverything(AgentType),
 }
 
 impl SystemMessage {
-    fn agent_type(&self) -> AgentType {
+    pub fn agent_type(&self) -> AgentType {
         match self {
-            SystemMessage::Start(agent_type) => agent_type.clone(),
-            SystemMessage::Manual(agent_type) => agent_type.clone(),
-            SystemMessage::WaitSecond(agent_type) => agent_type.clone(),
-            SystemMessage::EmptyMessage(agent_type) => agent_type.clone(),
-            &SystemMessage::CantGetYourMessage(agent_type) => agent_type.clone(),
+            SystemMessage::Start(agent_type) => *agent_type,
+            SystemMessage::Manual(agent_type) => *agent_type,
+            SystemMessage::WaitSecond(agent_type) => *agent_type,
+            SystemMessage::EmptyMessage(agent_type) => *agent_type,
+            SystemMessage::CantGetYourMessage(agent_type) => *agent_type,
+            SystemMessage::Language(agent_type) => *agent_type,
+            SystemMessage::LanguageSet(agent_type) => *agent_type,
+            SystemMessage::MemoryEmpty(agent_type) => *agent_type,
+            SystemMessage::MemoryTruncated(agent_type) => *agent_type,
+            SystemMessage::ForgetUsage(agent_type) => *agent_type,
+            SystemMessage::Forgotten(agent_type) => *agent_type,
+            SystemMessage::ForgetNotFound(agent_type) => *agent_type,
+            SystemMessage::ForgetChoose(agent_type) => *agent_type,
+            SystemMessage::ForgotEverything(agent_type) => *agent_type,
         }
     }
 
-    pub async fn as_str(&self) -> anyhow::Result<String> {
-        let agent = NervoAgentType::get_name(self.agent_type());
-        let system_msg_file = format!("{}{}/system_messages.json", RESOURCES_DIR, agent);
+    pub fn text_of(&self, catalog: &SystemMessages) -> String {
+        match self {
+            SystemMessage::Start(_) => catalog.start.clone(),
+            SystemMessage::Manual(_) => catalog.manual.clone(),
+            SystemMessage::WaitSecond(_) => catalog.wait_second.clone(),
+            SystemMessage::EmptyMessage(_) => catalog.empty_message.clone(),
+            SystemMessage::CantGetYourMessage(_) => catalog.cant_get_message.clone(),
+            SystemMessage::Language(_) => catalog.language.clone(),
+            SystemMessage::LanguageSet(_) => catalog.language_set.clone(),
+            SystemMessage::MemoryEmpty(_) => catalog.memory_empty.clone(),
+            SystemMessage::MemoryTruncated(_) => catalog.memory_truncated.clone(),
+            SystemMessage::ForgetUsage(_) => catalog.forget_usage.clone(),
+            SystemMessage::Forgotten(_) => catalog.forgotten.clone(),
+            SystemMessage::ForgetNotFound(_) => catalog.forget_not_found.clone(),
+            SystemMessage::ForgetChoose(_) => catalog.forget_choose.clone(),
+            SystemMessage::ForgotEverything(_) => catalog.forgot_everything.clone(),
+        }
+    }
+}
 
-        let json_string = fs::read_to_string(system_msg_file).await?;
-        let system_messages_models: SystemMessages = serde_json::from_str(&json_string)?;
+#[cfg(test)]
+mod test {
+    use crate::models::system_messages::{
+        SystemMessage, SystemMessages, LANGUAGE_PLACEHOLDER, MEMORY_PLACEHOLDER,
+    };
+    use nervo_sdk::agent_type::AgentType;
 
-        match self {
-            SystemMessage::Start(_) => Ok(system_messages_models.start.clone()),
-            SystemMessage::Manual(_) => Ok(system_messages_models.manual.clone()),
-            SystemMessage::WaitSecond(_) => Ok(system_messages_models.wait_second.clone()),
-            SystemMessage::EmptyMessage(_) => Ok(system_messages_models.empty_message.clone()),
-            &SystemMessage::CantGetYourMessage(_) => {
-                Ok(system_messages_models.cant_get_message.clone())
+    #[tokio::test]
+    async fn test_load_catalogs() -> anyhow::Result<()> {
+        for agent_type in [AgentType::Kevin, AgentType::Nervoznyak] {
+            let mut catalogs = vec![SystemMessages::load_default(agent_type).await?];
+            for language in ["en", "ru"] {
+                let catalog = SystemMessages::load_catalog(agent_type, language).await?;
+                assert!(catalog.is_some(), "No {} catalog", language);
+                catalogs.extend(catalog);
+            }
+            for catalog in catalogs {
+                assert!(catalog.language.contains(LANGUAGE_PLACEHOLDER));
+                assert!(catalog.language_set.contains(LANGUAGE_PLACEHOLDER));
+                assert!(catalog.forgotten.contains(MEMORY_PLACEHOLDER));
             }
         }
+
+        let english = SystemMessages::load_catalog(AgentType::Kevin, "en")
+            .await?
+            .unwrap();
+        assert_eq!(
+            SystemMessage::WaitSecond(AgentType::Kevin).text_of(&english),
+            english.wait_second
+        );
+
+        assert!(SystemMessages::load_catalog(AgentType::Kevin, "xx")
+            .await?
+            .is_none());
+        assert!(SystemMessages::load_catalog(AgentType::Kevin, "../kevin/x")
+            .await?
+            .is_none());
+        Ok(())
     }
 }
@@ -1,8 +0,0 @@
-use serde_derive::{Deserialize, Serialize};
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub enum TypingActionType {
-    Acting,
-    Stopped,
-}
@@ -1,31 +1,31 @@
-use crate::ai::nervo_llm::NervoLlm;
+use crate::ai::nervo_llm::{LlmRequestOptions, NervoLlm};
+use crate::ai::vector_store::VectorHit;
 use crate::config::jarvis::JarvisAppState;
+use crate::db::local_db::TELEGRAM_RESOURCE_CODE;
 use crate::models::message_transcription_type::MessageTranscriptionType;
 use crate::models::nervo_message_model::TelegramMessage;
 use crate::models::qdrant_search_layers::QdrantSearchLayer;
 use crate::models::system_messages::SystemMessage;
-use crate::models::typing_action_model::TypingActionType;
-use crate::models::user_model::TelegramUser;
+use crate::telegram::chat_sessions::TypingGuard;
 use crate::telegram::message_parser::MessageParser;
 use crate::utils::ai_utils::{
-    filter_search_result, formation_system_role_llm_message, llm_conversation
+    filter_search_result, formation_system_role_llm_message, llm_conversation,
+    llm_conversation_stream,
 };
 use crate::utils::ai_utils_data::SortingType::Ascending;
 use crate::utils::ai_utils_data::TruncatingType;
+use crate::utils::localisation_parser::{written_in, UserLang};
 use anyhow::bail;
 use anyhow::Result;
 use async_openai::types::Embedding;
 use chrono::Utc;
+use futures::StreamExt;
 use nervo_sdk::agent_type::AgentType;
-use nervo_sdk::api::spec::{LlmChat, LlmMessageContent, SendMessageRequest, UserLlmMessage};
-use openai_dive::v1::api::Client;
-use openai_dive::v1::resources::audio::{
-    AudioSpeechParameters, AudioSpeechResponseFormat, AudioVoice,
+use nervo_sdk::api::spec::{
+    LlmChat, LlmMessageContent, LlmStreamEvent, SendMessageRequest, UserLlmMessage,
 };
-use qdrant_client::qdrant::vectors::VectorsOptions;
-use qdrant_client::qdrant::ScoredPoint;
 use std::collections::HashSet;
-use std::sync::{Arc, LazyLock};
+use std::sync::Arc;
 use std::time::Duration;
 use teloxide::prelude::ChatId;
 use teloxide::prelude::*;
@@ -33,15 +33,20 @@ use teloxide::types::{
     ChatAction, ChatKind, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MediaKind,
     MessageId, MessageKind, ParseMode, ReplyParameters,
 };
-use teloxide::Bot;
-use tokio::sync::{Mutex, RwLock};
-use tokio::time::sleep;
+use teloxide::{ApiError, Bot, RequestError};
+use tokio::time::Instant;
 use tracing::info;
 
-static LAST_MESSAGE_ID: LazyLock<Arc<Mutex<Option<MessageId>>>> =
-    LazyLock::new(|| Arc::new(Mutex::new(None)));
-static SHOULD_STOP: LazyLock<Arc<RwLock<Option<TypingActionType>>>> =
-    LazyLock::new(|| Arc::new(RwLock::new(None)));
+// Telegram allows about one message edit per second in a chat
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1200);
+/// Characters of the reply needed to tell whether it is in the user's language
+const STREAM_LANGUAGE_PROBE_CHARS: usize = 40;
+/// Telegram rejects longer messages, the length is counted after the markdown escaping
+const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+/// The streamed message stops being edited at this length, it is split when the reply is done
+const STREAM_LIVE_LIMIT: usize = 3500;
+/// The markdown characters [`escape_markdown`] doubles
+const ESCAPED_CHARS: &str = "_[]()~>#+-=|{}.!";
 
 pub async fn start_conversation<'a>(
     app_state: Arc<JarvisAppState>,
@@ -59,25 +64,14 @@ pub async fn start_conversation<'a>(
     let message_text = parser.parse_tg_message_content().await?;
 
     let should_answer_as_reply =
-        should_answer_as_reply(&msg, bot_name, message_text.clone()).await?;
+        should_answer_as_reply(msg, bot_name, message_text.clone()).await?;
 
     // Answer formation
     if should_answer_as_reply {
-        {
-            if let Some(feature_toggle) = &app_state.feature_toggle {
-                if feature_toggle.localization {
-                    {
-                        let mut loc_manager = app_state.localisation_manager.write().await;
-                        loc_manager.detect_language(message_text.as_str()).await?;
-                    }
-                }
-            }
-        }
-
         reply_to_user_message(
             app_state,
-            &bot,
-            &msg,
+            bot,
+            msg,
             user_id,
             message_text,
             agent_type,
@@ -97,25 +91,30 @@ async fn reply_to_user_message<'a>(
     agent_type: AgentType,
     parser: MessageParser<'a>,
 ) -> Result<()> {
+    let language = &user_language(&app_state, msg, Some(message_text.as_str())).await?;
+
     if !parser.is_tg_message_text().await? {
         system_message(
             app_state.clone(),
-            &bot,
-            &msg,
+            bot,
+            msg,
             SystemMessage::WaitSecond(agent_type),
+            language,
         )
         .await?;
     }
 
-    start_typing_action(&bot, &msg, ChatAction::Typing).await;
+    let _typing = start_typing_action(&app_state, bot, msg, ChatAction::Typing);
 
     if message_text.is_empty() {
         info!("Empty message");
+        stop_typing_action(&app_state, msg.chat.id);
         system_message(
             app_state,
-            &bot,
-            &msg,
+            bot,
+            msg,
             SystemMessage::EmptyMessage(agent_type),
+            language,
         )
         .await?;
         return Ok(());
@@ -134,51 +133,33 @@ async fn reply_to_user_message<'a>(
     .await?;
 
     chat_gpt_conversation(
-        &bot,
-        &msg,
+        bot,
+        msg,
         app_state,
         question_msg,
         parser.is_voice,
         !is_moderation_passed,
-        agent_type,
+        language,
     )
     .await?;
 
     Ok(())
 }
 
-async fn start_typing_action(bot: &Bot, msg: &Message, action_type: ChatAction) {
-    tokio::spawn({
-        let bot = bot.clone();
-        let msg = msg.clone();
-        async move {
-            set_typing_action(TypingActionType::Acting).await;
-
-            while let Some(TypingActionType::Acting) = get_typing_action().await {
-                info!("Show typing action...");
-                bot.send_chat_action(msg.chat.id.clone(), action_type)
-                    .await
-                    .ok();
-                sleep(Duration::from_secs(3)).await;
-            }
-
-            info!("Stopped typing action");
-        }
-    });
+fn start_typing_action(
+    app_state: &JarvisAppState,
+    bot: &Bot,
+    msg: &Message,
+    action_type: ChatAction,
+) -> TypingGuard {
+    app_state
+        .chat_sessions
+        .start_typing(bot, msg.chat.id, action_type)
 }
 
-async fn stop_typing_action() {
+fn stop_typing_action(app_state: &JarvisAppState, chat_id: ChatId) {
     info!("Stopping typing action...");
-    set_typing_action(TypingActionType::Stopped).await;
-}
-pub async fn set_typing_action(action: TypingActionType) {
-    let mut typing_action = SHOULD_STOP.write().await;
-    *typing_action = Some(action);
-}
-
-pub async fn get_typing_action() -> Option<TypingActionType> {
-    let typing_action = SHOULD_STOP.read().await;
-    typing_action.clone()
+    app_state.chat_sessions.stop_typing(chat_id);
 }
 
 async fn should_answer_as_reply<'a>(
@@ -256,22 +237,13 @@ pub async fn system_message(
     bot: &Bot,
     msg: &Message,
     message_type: SystemMessage,
+    language: &UserLang,
 ) -> Result<()> {
-    let introduction_msg = message_type.as_str().await?;
-    let reply_parameters = ReplyParameters {
-        message_id: msg.id,
-        chat_id: None,
-        allow_sending_without_reply: None,
-        quote: None,
-        quote_parse_mode: None,
-        quote_entities: None,
-        quote_position: None,
-    };
-
-    let translated_text = {
-        let loc_manager = app_state.localisation_manager.read().await;
-        loc_manager.translate(introduction_msg.as_str()).await?
-    };
+    let reply_parameters = reply_parameters_of(msg);
+    let translated_text = app_state
+        .localisation_manager
+        .system_message(&message_type, language)
+        .await?;
 
     info!("Send system message");
     bot.send_message(msg.chat.id, translated_text)
@@ -281,32 +253,60 @@ pub async fn system_message(
     Ok(())
 }
 
-// Work with User Ids
-async fn save_user_id(app_state: Arc<JarvisAppState>, user_id: String) -> Result<()> {
-    let user_ids = load_user_ids(app_state.clone()).await?;
+/// Language of the message sender: the stored one, otherwise it is taken from Telegram
+/// `language_code` or detected from the text and stored for the next messages
+pub(crate) async fn user_language(
+    app_state: &JarvisAppState,
+    msg: &Message,
+    text: Option<&str>,
+) -> Result<UserLang> {
+    let Some(user) = &msg.from else {
+        return Ok(UserLang::None);
+    };
+    let tg_user_id = user.id.0.to_string();
 
-    let contains_id = user_ids.iter().any(|user| user.id == user_id);
-    if !contains_id {
-        let user = TelegramUser {
-            id: user_id.parse()?,
-        };
-        app_state
-            .local_db
-            .save_to_local_db(user, "all_users_list", None)
-            .await?;
+    let stored_language = app_state
+        .local_db
+        .user_language(TELEGRAM_RESOURCE_CODE, &tg_user_id)
+        .await?;
+    if let Some(language) = stored_language {
+        return Ok(UserLang::from(language.as_str()));
     }
-    Ok(())
+
+    let detecting_enabled = app_state
+        .feature_toggle
+        .as_ref()
+        .is_some_and(|feature_toggle| feature_toggle.localization);
+    let language = match (&user.language_code, text) {
+        (Some(locale), _) => {
+            info!("User's locale is {}", locale);
+            UserLang::from(locale.as_str())
+        }
+        (None, Some(text)) if detecting_enabled => {
+            app_state.localisation_manager.detect_language(text).await?
+        }
+        _ => return Ok(UserLang::None),
+    };
+
+    app_state
+        .local_db
+        .set_user_language(
+            TELEGRAM_RESOURCE_CODE,
+            &tg_user_id,
+            &language.to_string(),
+            false,
+        )
+        .await?;
+    Ok(language)
 }
 
-async fn load_user_ids(app_state: Arc<JarvisAppState>) -> Result<Vec<TelegramUser>> {
-    match app_state
+// Work with User Ids
+async fn save_user_id(app_state: Arc<JarvisAppState>, user_id: String) -> Result<()> {
+    app_state
         .local_db
-        .read_from_local_db("all_users_list")
-        .await
-    {
-        Ok(ids) => Ok(ids),
-        Err(_) => Ok(Vec::new()),
-    }
+        .register_external_user(TELEGRAM_RESOURCE_CODE, &user_id)
+        .await?;
+    Ok(())
 }
 
 pub async fn chat_gpt_conversation<'a>(
@@ -316,10 +316,11 @@ pub async fn chat_gpt_conversation<'a>(
     msg: SendMessageRequest,
     is_voice: bool,
     direct_message: bool,
-    agent_type: AgentType,
+    language: &UserLang,
 ) -> Result<()> {
     info!("Start chat gpt conversation");
     let chat_id = msg.chat_id;
+    let agent_type = msg.agent_type;
 
     let final_response = if direct_message {
         info!(
@@ -334,9 +335,15 @@ pub async fn chat_gpt_conversation<'a>(
                 app_state
                     .clone()
                     .user_context
-                    .use_memory_in_conversation(&message, app_state.clone(), agent_type)
+                    .use_memory_in_conversation(message, app_state.clone(), agent_type)
                     .await?
             }
+            _ if !is_voice => {
+                return stream_and_send_response(
+                    app_state, bot, message, msg, agent_type, language,
+                )
+                .await;
+            }
             _ => llm_conversation(app_state.clone(), msg, agent_type)
                 .await?
                 .content
@@ -348,15 +355,194 @@ pub async fn chat_gpt_conversation<'a>(
         app_state.clone(),
         final_response.as_str(),
         is_voice,
-        &bot,
+        bot,
         chat_id,
-        &message,
+        message,
+        language,
     )
     .await?;
 
     Ok(())
 }
 
+// Text replies in the user's language are sent as soon as the first tokens arrive
+// and then edited in place, the ones that need a translation are sent when it is ready
+async fn stream_and_send_response(
+    app_state: Arc<JarvisAppState>,
+    bot: &Bot,
+    message: &Message,
+    msg: SendMessageRequest,
+    agent_type: AgentType,
+    language: &UserLang,
+) -> Result<()> {
+    let chat_id = ChatId(msg.chat_id as i64);
+    let mut events = llm_conversation_stream(app_state.clone(), msg, agent_type).await?;
+
+    let mut streamed_text = String::new();
+    let mut footnotes = String::new();
+    let mut sent_message_id: Option<MessageId> = None;
+    let mut shown_text = String::new();
+    let mut last_edit = Instant::now();
+    let mut stream_live: Option<bool> = None;
+
+    while let Some(event) = events.next().await {
+        match event {
+            LlmStreamEvent::Delta { text } => {
+                streamed_text.push_str(&text);
+                if stream_live.is_none() {
+                    if streamed_text.chars().count() < STREAM_LANGUAGE_PROBE_CHARS {
+                        continue;
+                    }
+                    stream_live = Some(written_in(&streamed_text, language) == Some(true));
+                }
+                if stream_live != Some(true) || streamed_text.trim().is_empty() {
+                    continue;
+                }
+
+                // Telegram rejects the unchanged and the too long texts
+                if streamed_text.trim_end() == shown_text.trim_end()
+                    || streamed_text.chars().count() > STREAM_LIVE_LIMIT
+                {
+                    continue;
+                }
+
+                match sent_message_id {
+                    None => {
+                        stop_typing_action(&app_state, chat_id);
+                        let sent_message = bot
+                            .send_message(chat_id, streamed_text.clone())
+                            .reply_parameters(reply_parameters_of(message))
+                            .await?;
+                        sent_message_id = Some(sent_message.id);
+                        shown_text = streamed_text.clone();
+                        last_edit = Instant::now();
+                    }
+                    Some(message_id) if last_edit.elapsed() >= STREAM_EDIT_INTERVAL => {
+                        ignore_not_modified(
+                            bot.edit_message_text(chat_id, message_id, streamed_text.clone())
+                                .await,
+                        )?;
+                        shown_text = streamed_text.clone();
+                        last_edit = Instant::now();
+                    }
+                    Some(_) => {}
+                }
+            }
+            LlmStreamEvent::Done {
+                message: llm_message,
+            } => {
+                streamed_text = llm_message.content.text();
+                footnotes = llm_message.footnotes();
+            }
+            LlmStreamEvent::Error { message: error } => {
+                stop_typing_action(&app_state, chat_id);
+                bail!("LLM streaming failed: {}", error);
+            }
+        }
+    }
+
+    let Some(message_id) = sent_message_id else {
+        // Nothing has been shown yet, the reply is short or needs a translation.
+        // The sources stay untranslated, as in the edited message below
+        let translated_text = app_state
+            .localisation_manager
+            .translate(streamed_text.as_str(), language)
+            .await?;
+        let final_text = format!("{}{}", translated_text, footnotes);
+        return send_response(app_state, final_text, false, bot, chat_id.0 as u64, message).await;
+    };
+
+    let translated_text = app_state
+        .localisation_manager
+        .translate(streamed_text.as_str(), language)
+        .await?;
+
+    info!("Finalize streamed message");
+    let keyboard = button_creation(false).await?;
+    // The sources are appended after the translation, they are file names mostly
+    let final_text = format!("{}{}", translated_text, footnotes);
+    // The streamed message gets the first part, the rest follow it
+    let parts = split_message(&final_text, TELEGRAM_MESSAGE_LIMIT);
+    let (first_part, other_parts) = parts.split_first().unwrap_or((&final_text, &[]));
+    let edit = bot
+        .edit_message_text(chat_id, message_id, escape_markdown(first_part))
+        .parse_mode(ParseMode::MarkdownV2);
+    let last_message_id = if other_parts.is_empty() {
+        ignore_not_modified(edit.reply_markup(keyboard).await)?;
+        message_id
+    } else {
+        ignore_not_modified(edit.await)?;
+        send_text_parts(bot, chat_id, other_parts, None, keyboard).await?
+    };
+
+    switch_button_to_message(&app_state, bot, chat_id, Some(last_message_id)).await?;
+    Ok(())
+}
+
+/// Telegram fails the edits which change nothing
+fn ignore_not_modified<T>(result: Result<T, RequestError>) -> Result<()> {
+    match result {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Splits the text into the parts Telegram accepts once escaped, on the line breaks
+/// or the spaces where possible
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut rest = text;
+    loop {
+        let mut length = 0;
+        let mut end = rest.len();
+        for (index, ch) in rest.char_indices() {
+            length += if ESCAPED_CHARS.contains(ch) { 2 } else { 1 };
+            if length > limit {
+                end = index;
+                break;
+            }
+        }
+        if end == rest.len() {
+            parts.push(rest.to_string());
+            return parts;
+        }
+
+        let head = &rest[..end];
+        let split_at = head
+            .rfind('\n')
+            .or_else(|| head.rfind(' '))
+            .filter(|split_at| *split_at > 0)
+            .unwrap_or(end);
+        parts.push(rest[..split_at].to_string());
+        rest = rest[split_at..].trim_start();
+    }
+}
+
+/// Sends the parts one by one, the first replies to the message if it is given
+/// and the last gets the keyboard. Returns the id of the last one
+async fn send_text_parts(
+    bot: &Bot,
+    chat_id: ChatId,
+    parts: &[String],
+    reply_parameters: Option<ReplyParameters>,
+    keyboard: InlineKeyboardMarkup,
+) -> Result<MessageId> {
+    let mut last_message_id = None;
+    for (index, part) in parts.iter().enumerate() {
+        let mut request = bot
+            .send_message(chat_id, escape_markdown(part))
+            .parse_mode(ParseMode::MarkdownV2);
+        if let (0, Some(reply_parameters)) = (index, &reply_parameters) {
+            request = request.reply_parameters(reply_parameters.clone());
+        }
+        if index + 1 == parts.len() {
+            request = request.reply_markup(keyboard.clone());
+        }
+        last_message_id = Some(request.await?.id);
+    }
+    last_message_id.ok_or_else(|| anyhow::anyhow!("No message to send"))
+}
+
 async fn translate_and_send_response(
     app_state: Arc<JarvisAppState>,
     final_response: &str,
@@ -364,37 +550,46 @@ async fn translate_and_send_response(
     bot: &Bot,
     chat_id: u64,
     message: &Message,
+    language: &UserLang,
 ) -> Result<()> {
-    let translated_text = {
-        let loc_manager = app_state.localisation_manager.read().await;
-        loc_manager.translate(final_response).await?
-    };
+    let translated_text = app_state
+        .localisation_manager
+        .translate(final_response, language)
+        .await?;
+    send_response(app_state, translated_text, is_voice, bot, chat_id, message).await
+}
 
+async fn send_response(
+    app_state: Arc<JarvisAppState>,
+    translated_text: String,
+    is_voice: bool,
+    bot: &Bot,
+    chat_id: u64,
+    message: &Message,
+) -> Result<()> {
     info!("Stop typing!");
-    stop_typing_action().await;
+    stop_typing_action(&app_state, ChatId(chat_id as i64));
     let keyboard = button_creation(is_voice).await?;
     let message_id = if is_voice {
-        handle_voice_message(&bot, translated_text, chat_id, app_state, keyboard).await?
+        handle_voice_message(bot, translated_text, chat_id, app_state.clone(), keyboard).await?
     } else {
-        handle_text_message(&bot, translated_text, chat_id, message, keyboard).await?
+        handle_text_message(bot, translated_text, chat_id, message, keyboard).await?
     };
 
-    switch_button_to_message(&bot, chat_id, Some(message_id)).await?;
+    switch_button_to_message(&app_state, bot, ChatId(chat_id as i64), Some(message_id)).await?;
     Ok(())
 }
 
 async fn switch_button_to_message(
+    app_state: &JarvisAppState,
     bot: &Bot,
-    chat_id: u64,
+    chat_id: ChatId,
     message_id: Option<MessageId>,
 ) -> Result<()> {
-    remove_last_message_button(&bot, ChatId(chat_id as i64)).await?;
-    let mut last_msg_lock = LAST_MESSAGE_ID.lock().await;
-    *last_msg_lock = match message_id {
-        None => None,
-        Some(_) => message_id,
-    };
-    Ok(())
+    let last_message_id = app_state
+        .chat_sessions
+        .replace_keyboard_message(chat_id, message_id);
+    remove_message_button(bot, chat_id, last_message_id).await
 }
 
 async fn handle_voice_message(
@@ -423,31 +618,28 @@ async fn handle_text_message(
     keyboard: InlineKeyboardMarkup,
 ) -> Result<MessageId> {
     info!("Handle Text TG message");
-    let reply_parameters = ReplyParameters {
-        message_id: message.id,
-        chat_id: None,
-        allow_sending_without_reply: None,
-        quote: None,
-        quote_parse_mode: None,
-        quote_entities: None,
-        quote_position: None,
-    };
-    let escaped_message = escape_markdown(&user_final_question);
-
-    let sent_message = bot
-        .send_message(ChatId(chat_id as i64), escaped_message)
-        .reply_markup(keyboard)
-        .parse_mode(ParseMode::MarkdownV2)
-        .reply_parameters(reply_parameters)
-        .await?;
+    let reply_parameters = reply_parameters_of(message);
+    let parts = split_message(&user_final_question, TELEGRAM_MESSAGE_LIMIT);
+
+    let message_id = send_text_parts(
+        bot,
+        ChatId(chat_id as i64),
+        &parts,
+        Some(reply_parameters),
+        keyboard,
+    )
+    .await?;
     info!("Successfully sent text answer to user");
-    Ok(sent_message.id)
+    Ok(message_id)
 }
 
-async fn remove_last_message_button<'a>(bot: &Bot, chat_id: ChatId) -> Result<()> {
+async fn remove_message_button(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+) -> Result<()> {
     info!("Need to remove last message button");
-    let last_msg_lock = LAST_MESSAGE_ID.lock().await;
-    if let Some(last_msg_id) = *last_msg_lock {
+    if let Some(last_msg_id) = message_id {
         bot.edit_message_reply_markup(chat_id, last_msg_id)
             .reply_markup(InlineKeyboardMarkup::new(
                 Vec::<Vec<InlineKeyboardButton>>::new(),
@@ -459,20 +651,8 @@ async fn remove_last_message_button<'a>(bot: &Bot, chat_id: ChatId) -> Result<()
 }
 
 async fn create_speech(text: &str, app_state: Arc<JarvisAppState>) -> Result<InputFile> {
-    let client = Client::new(app_state.nervo_llm.api_key().to_string());
-    let parameters = AudioSpeechParameters {
-        model: "tts-1".to_string(),
-        input: text.to_string(),
-        voice: AudioVoice::Onyx,
-        response_format: Some(AudioSpeechResponseFormat::Mp3),
-        speed: Some(1.0),
-    };
-    let response = client.audio().create_speech(parameters).await;
-
-    match response {
-        Ok(audio) => Ok(InputFile::memory(audio.bytes)),
-        Err(err) => bail!("ERROR: {:?}", err),
-    }
+    let audio = app_state.nervo_llm.create_speech(text).await?;
+    Ok(InputFile::memory(audio))
 }
 
 pub async fn transcribe_message(
@@ -483,20 +663,17 @@ pub async fn transcribe_message(
 ) -> Result<()> {
     info!("Transcribe message TTS or STT");
     let mut parser = MessageParser {
-        bot: &bot,
-        msg: &message,
+        bot,
+        msg: message,
         app_state: &app_state,
         is_voice: false,
     };
 
-    match transcription_type {
-        MessageTranscriptionType::Tts => {
-            start_typing_action(&bot, &message, ChatAction::RecordVoice).await
-        }
-        MessageTranscriptionType::Stt => {
-            start_typing_action(&bot, &message, ChatAction::Typing).await
-        }
-    }
+    let chat_action = match transcription_type {
+        MessageTranscriptionType::Tts => ChatAction::RecordVoice,
+        MessageTranscriptionType::Stt => ChatAction::Typing,
+    };
+    let _typing = start_typing_action(&app_state, bot, message, chat_action);
 
     let chat_id = message.chat.id;
     let parsed_voice_to_text = parser.parse_tg_message_content().await?;
@@ -504,7 +681,8 @@ pub async fn transcribe_message(
     match transcription_type {
         MessageTranscriptionType::Tts => {
             info!("Transcription type TTS");
-            let audio_file = create_speech(parsed_voice_to_text.as_str(), app_state).await?;
+            let audio_file =
+                create_speech(parsed_voice_to_text.as_str(), app_state.clone()).await?;
             info!("Audio from Text has been created");
             bot.send_voice(chat_id, audio_file).await?;
         }
@@ -514,12 +692,24 @@ pub async fn transcribe_message(
         }
     }
 
-    switch_button_to_message(&bot, chat_id.0 as u64, None).await?;
-    stop_typing_action().await;
+    switch_button_to_message(&app_state, bot, chat_id, None).await?;
+    stop_typing_action(&app_state, chat_id);
     info!("Transcription is OK");
     Ok(())
 }
 
+fn reply_parameters_of(message: &Message) -> ReplyParameters {
+    ReplyParameters {
+        message_id: message.id,
+        chat_id: None,
+        allow_sending_without_reply: None,
+        quote: None,
+        quote_parse_mode: None,
+        quote_entities: None,
+        quote_position: None,
+    }
+}
+
 fn escape_markdown(text: &str) -> String {
     text.replace('_', "\\_")
         .replace('[', "\\[")
@@ -564,17 +754,9 @@ async fn create_not_moderated_message(text: String, nervo_llm: &NervoLlm) -> Res
         format!("{usr_stmt}, {criteria}. Here is the message: {text}")
     };
 
-    let language_detecting_layer = QdrantSearchLayer {
-        index: None,
-        user_role_params: vec![],
-        system_role_text: system_role_instructions.to_string(),
-        temperature: 0.2,
-        max_tokens: 4096,
-        common_token_limit: 30000,
-        vectors_limit: 0,
-        layer_for_search: false,
-    };
+    let language_detecting_layer = QdrantSearchLayer::new(system_role_instructions);
 
+    let options = LlmRequestOptions::from(&language_detecting_layer);
     let system_role_msg = formation_system_role_llm_message(language_detecting_layer).await?;
 
     info!(
@@ -585,7 +767,7 @@ async fn create_not_moderated_message(text: String, nervo_llm: &NervoLlm) -> Res
         chat_id: None,
         messages: vec![system_role_msg],
     };
-    let llm_response = nervo_llm.send_msg_batch(chat).await?;
+    let llm_response = nervo_llm.send_msg_batch(chat, &options).await?;
     Ok(llm_response)
 }
 
@@ -594,10 +776,14 @@ pub async fn get_message_related_points(
     collection_name: &str,
     system_role_to_clear_request: &str,
     app_state: Arc<JarvisAppState>,
-) -> Result<Vec<String>> {
+) -> Result<Vec<VectorHit>> {
     let clear_user_request = app_state
         .nervo_llm
-        .raw_llm_processing(system_role_to_clear_request, &message)
+        .raw_llm_processing(
+            system_role_to_clear_request,
+            message,
+            &LlmRequestOptions::default(),
+        )
         .await?;
 
     let embeddings_response = app_state
@@ -610,58 +796,46 @@ pub async fn get_message_related_points(
         bail!("No embeddings data found.");
     };
 
-    let unique_points_response =
-        search_unique_points(&collection_name, embeddings, app_state.clone()).await?;
-
-    let payload = get_payload(unique_points_response)?;
-
-    Ok(payload)
+    search_unique_points(collection_name, embeddings, app_state.clone()).await
 }
 
 async fn search_unique_points(
     collection_name: &str,
     embedding: Embedding,
     app_state: Arc<JarvisAppState>,
-) -> Result<Vec<ScoredPoint>> {
-    let vector_search_response_of_user_request = app_state
+) -> Result<Vec<VectorHit>> {
+    let search_result = app_state
         .nervo_ai_db
-        .qdrant
         .vector_search(
-            &collection_name,
+            collection_name,
             embedding.embedding,
             3, // TODO: Read from (where??)
         )
         .await?;
 
-    let search_result = vector_search_response_of_user_request.result;
     let filtered_search_result =
         filter_search_result(search_result, Ascending, TruncatingType::None, 0.3)?;
 
-    let mut final_search_result: Vec<ScoredPoint> = filtered_search_result.clone();
+    let mut final_search_result: Vec<VectorHit> = filtered_search_result.clone();
 
     for point in filtered_search_result {
-        if let Some(vector) = point.vectors {
-            if let Some(VectorsOptions::Vector(vec_f32)) = vector.vectors_options {
-                let result_vector: Vec<f32> = vec_f32.clone().data;
-                let vector_search_response_of_point = app_state
-                    .nervo_ai_db
-                    .qdrant
-                    .vector_search(
-                        &collection_name,
-                        result_vector,
-                        3, // TODO: Read from (where??)
-                    )
-                    .await?;
-                let search_result_of_point = vector_search_response_of_point.result;
-                let mut existing_ids: HashSet<_> = final_search_result
-                    .iter()
-                    .map(|point| format!("{:?}", point.id))
-                    .collect();
-
-                for point in search_result_of_point {
-                    if existing_ids.insert(format!("{:?}", point.id)) {
-                        final_search_result.push(point);
-                    }
+        if let Some(result_vector) = point.vector {
+            let search_result_of_point = app_state
+                .nervo_ai_db
+                .vector_search(
+                    collection_name,
+                    result_vector,
+                    3, // TODO: Read from (where??)
+                )
+                .await?;
+            let mut existing_ids: HashSet<_> = final_search_result
+                .iter()
+                .map(|point| point.id.clone())
+                .collect();
+
+            for point in search_result_of_point {
+                if existing_ids.insert(point.id.clone()) {
+                    final_search_result.push(point);
                 }
             }
         }
@@ -670,13 +844,11 @@ async fn search_unique_points(
     Ok(final_search_result)
 }
 
-pub fn get_payload(points: Vec<ScoredPoint>) -> anyhow::Result<Vec<String>> {
+pub fn get_payload(points: Vec<VectorHit>) -> anyhow::Result<Vec<String>> {
     let mut combined_payloads = Vec::new();
     for point in points {
-        if let Some(payload) = point.payload.get("text") {
-            if let Some(text) = payload.as_str() {
-                combined_payloads.push(text.to_string());
-            }
+        if let Some(text) = point.payload.text() {
+            combined_payloads.push(text.to_string());
         }
     }
 
@@ -703,7 +875,23 @@ pub fn get_payload(points: Vec<ScoredPoint>) -> anyhow::Result<Vec<String>> {
 
 #[cfg(test)]
 mod test {
-    use crate::telegram::bot_utils::{button_creation, escape_markdown};
+    use crate::telegram::bot_utils::{button_creation, escape_markdown, split_message};
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("short", 10), vec!["short"]);
+        assert_eq!(
+            split_message("first line\nsecond line", 15),
+            vec!["first line", "second line"]
+        );
+        assert_eq!(split_message("one two three", 8), vec!["one two", "three"]);
+        // The escaped characters count twice
+        assert_eq!(split_message("a.b.c.d", 6), vec!["a.b.", "c.d"]);
+        assert_eq!(split_message("абвгд", 2), vec!["аб", "вг", "д"]);
+        for part in split_message(&"word. ".repeat(1000), 100) {
+            assert!(escape_markdown(&part).chars().count() <= 100);
+        }
+    }
 
     #[tokio::test]
     async fn test_button_creation_is_voice() -> anyhow::Result<()> {
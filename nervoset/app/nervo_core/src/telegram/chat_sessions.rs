@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::{ChatAction, MessageId};
+use tokio::task::{Id, JoinHandle};
+use tokio::time::sleep;
+use tracing::info;
+
+/// Telegram hides the chat action after 5 seconds, so it is repeated more often
+const TYPING_ACTION_INTERVAL: Duration = Duration::from_secs(3);
+/// Sessions are swept when there are more of them
+const MAX_IDLE_SESSIONS: usize = 1000;
+/// A swept session loses its keyboard message, so its keyboard stays in the chat
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What the bot keeps about a chat between the updates
+struct ChatSession {
+    typing_task: Option<JoinHandle<()>>,
+    /// The last reply with the TTS/STT keyboard
+    keyboard_message_id: Option<MessageId>,
+    last_active: Instant,
+}
+
+impl Default for ChatSession {
+    fn default() -> Self {
+        ChatSession {
+            typing_task: None,
+            keyboard_message_id: None,
+            last_active: Instant::now(),
+        }
+    }
+}
+
+impl ChatSession {
+    fn is_empty(&self) -> bool {
+        self.typing_task.is_none() && self.keyboard_message_id.is_none()
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<ChatId, ChatSession>>>;
+
+/// Per-chat state of the Telegram bot, so concurrent chats don't affect each other.
+/// Sessions with nothing to keep are dropped, the idle ones are swept out.
+#[derive(Default)]
+pub struct ChatSessions {
+    sessions: Sessions,
+}
+
+/// Stops the chat action when dropped, so an early return doesn't leave "typing…" forever
+#[must_use = "the chat action stops when the guard is dropped"]
+pub struct TypingGuard {
+    sessions: Sessions,
+    chat_id: ChatId,
+    task_id: Id,
+}
+
+impl Drop for TypingGuard {
+    fn drop(&mut self) {
+        // A newer chat action of the chat is left running
+        with_session(&self.sessions, self.chat_id, |session| {
+            let is_own_task = session
+                .typing_task
+                .as_ref()
+                .is_some_and(|task| task.id() == self.task_id);
+            if is_own_task {
+                if let Some(typing_task) = session.typing_task.take() {
+                    typing_task.abort();
+                }
+            }
+        });
+    }
+}
+
+fn with_session<R>(
+    sessions: &Sessions,
+    chat_id: ChatId,
+    action: impl FnOnce(&mut ChatSession) -> R,
+) -> R {
+    let mut sessions = sessions.lock().expect("Chat sessions lock is poisoned");
+    let now = Instant::now();
+    let session = sessions.entry(chat_id).or_default();
+    session.last_active = now;
+    let result = action(session);
+
+    if session.is_empty() {
+        sessions.remove(&chat_id);
+    }
+    if sessions.len() > MAX_IDLE_SESSIONS {
+        evict_idle(&mut sessions, now);
+    }
+    result
+}
+
+fn evict_idle(sessions: &mut HashMap<ChatId, ChatSession>, now: Instant) {
+    sessions.retain(|_, session| {
+        let is_typing = session
+            .typing_task
+            .as_ref()
+            .is_some_and(|task| !task.is_finished());
+        is_typing || now.duration_since(session.last_active) < SESSION_IDLE_TIMEOUT
+    });
+}
+
+impl ChatSessions {
+    /// Shows the chat action until [`ChatSessions::stop_typing`] is called for the chat
+    /// or the returned guard is dropped
+    pub fn start_typing(&self, bot: &Bot, chat_id: ChatId, action_type: ChatAction) -> TypingGuard {
+        let bot = bot.clone();
+        let typing_task = tokio::spawn(async move {
+            loop {
+                info!("Show typing action...");
+                bot.send_chat_action(chat_id, action_type).await.ok();
+                sleep(TYPING_ACTION_INTERVAL).await;
+            }
+        });
+        let task_id = typing_task.id();
+
+        let previous_task = with_session(&self.sessions, chat_id, |session| {
+            session.typing_task.replace(typing_task)
+        });
+        if let Some(previous_task) = previous_task {
+            previous_task.abort();
+        }
+
+        TypingGuard {
+            sessions: self.sessions.clone(),
+            chat_id,
+            task_id,
+        }
+    }
+
+    pub fn stop_typing(&self, chat_id: ChatId) {
+        let typing_task = with_session(&self.sessions, chat_id, |session| {
+            session.typing_task.take()
+        });
+        if let Some(typing_task) = typing_task {
+            typing_task.abort();
+            info!("Stopped typing action");
+        }
+    }
+
+    pub fn is_typing(&self, chat_id: ChatId) -> bool {
+        with_session(&self.sessions, chat_id, |session| {
+            session
+                .typing_task
+                .as_ref()
+                .is_some_and(|task| !task.is_finished())
+        })
+    }
+
+    /// Remembers the message which carries the keyboard now and returns the previous one
+    pub fn replace_keyboard_message(
+        &self,
+        chat_id: ChatId,
+        message_id: Option<MessageId>,
+    ) -> Option<MessageId> {
+        with_session(&self.sessions, chat_id, |session| {
+            std::mem::replace(&mut session.keyboard_message_id, message_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::telegram::chat_sessions::{evict_idle, ChatSession, ChatSessions};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use teloxide::types::{ChatAction, ChatId, MessageId};
+    use teloxide::Bot;
+
+    #[tokio::test]
+    async fn test_chats_are_isolated() {
+        let sessions = ChatSessions::default();
+        let (first_chat, second_chat) = (ChatId(1), ChatId(2));
+
+        assert_eq!(
+            sessions.replace_keyboard_message(first_chat, Some(MessageId(10))),
+            None
+        );
+        assert_eq!(
+            sessions.replace_keyboard_message(second_chat, Some(MessageId(20))),
+            None
+        );
+        assert_eq!(
+            sessions.replace_keyboard_message(first_chat, None),
+            Some(MessageId(10))
+        );
+
+        let bot = Bot::new("0:test");
+        let _first_typing = sessions.start_typing(&bot, first_chat, ChatAction::Typing);
+        let _second_typing = sessions.start_typing(&bot, second_chat, ChatAction::Typing);
+        sessions.stop_typing(first_chat);
+        assert!(!sessions.is_typing(first_chat));
+        assert!(sessions.is_typing(second_chat));
+        sessions.stop_typing(second_chat);
+    }
+
+    #[tokio::test]
+    async fn test_typing_stops_with_guard() {
+        let sessions = ChatSessions::default();
+        let chat_id = ChatId(1);
+        let bot = Bot::new("0:test");
+
+        let old_typing = sessions.start_typing(&bot, chat_id, ChatAction::Typing);
+        let new_typing = sessions.start_typing(&bot, chat_id, ChatAction::RecordVoice);
+        drop(old_typing);
+        assert!(sessions.is_typing(chat_id));
+
+        drop(new_typing);
+        assert!(!sessions.is_typing(chat_id));
+        assert!(sessions.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_idle_sessions_are_evicted() {
+        let now = Instant::now();
+        let session = |idle_secs| ChatSession {
+            typing_task: None,
+            keyboard_message_id: Some(MessageId(1)),
+            last_active: now - Duration::from_secs(idle_secs),
+        };
+        let mut sessions = HashMap::from([
+            (ChatId(1), session(60)),
+            (ChatId(2), session(2 * 24 * 60 * 60)),
+        ]);
+
+        evict_idle(&mut sessions, now);
+        assert!(sessions.contains_key(&ChatId(1)));
+        assert!(!sessions.contains_key(&ChatId(2)));
+    }
+}
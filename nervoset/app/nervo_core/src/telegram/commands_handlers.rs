@@ -1,13 +1,19 @@
 use crate::config::jarvis::JarvisAppState;
+use crate::context::user_memory::UserMemory;
+use crate::db::local_db::TELEGRAM_RESOURCE_CODE;
 use crate::models::message_transcription_type::MessageTranscriptionType::{Stt, Tts};
-use crate::models::system_messages::SystemMessage;
-use crate::telegram::bot_utils::{start_conversation, system_message, transcribe_message};
+use crate::models::system_messages::{SystemMessage, LANGUAGE_PLACEHOLDER, MEMORY_PLACEHOLDER};
+use crate::telegram::bot_utils::{
+    start_conversation, system_message, transcribe_message, user_language,
+};
 use crate::telegram::message_parser::MessageParser;
+use crate::utils::localisation_parser::UserLang;
 use anyhow::bail;
 use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use std::sync::Arc;
 use teloxide::macros::BotCommands;
 use teloxide::prelude::*;
+use teloxide::types::InputFile;
 use teloxide::Bot;
 use tracing::info;
 
@@ -31,8 +37,23 @@ pub enum JarvisCommands {
     Model,
     Start,
     Manual,
+    #[command(description = "Reply language, i.e. /language en")]
+    Language(String),
+    #[command(description = "What I remember about you")]
+    Memory,
+    #[command(
+        description = "Find memories by meaning or forget one by id, i.e. /forget my address"
+    )]
+    Forget(String),
+    #[command(description = "Forget everything about you")]
+    ForgetAll,
+    #[command(description = "Export what I remember about you as JSON")]
+    ExportMemory,
 }
 
+/// Telegram rejects longer messages
+const MAX_MESSAGE_LENGTH: usize = 4000;
+
 pub async fn owner_command_handler(
     bot: Bot,
     msg: Message,
@@ -59,25 +80,52 @@ pub async fn command_handler(
     agent_type: AgentType,
 ) -> anyhow::Result<()> {
     info!("Command handling");
-    match &msg.from {
-        Some(user) => match &user.language_code {
-            Some(locale) => {
-                let mut loc_manager = app_state.localisation_manager.write().await;
-                info!("User's locale is {}", locale);
-                loc_manager.set_language_as_locale(locale.as_str()).await?;
-            }
-            None => {
-                info!("User has no language code");
-            }
-        },
-        None => {
-            info!("Message doesn't have a sender");
-        }
-    }
+    let language = user_language(&app_state, &msg, None).await?;
 
     match cmd {
         JarvisCommands::Start => {
-            system_message(app_state, &bot, &msg, SystemMessage::Start(agent_type)).await?;
+            system_message(
+                app_state,
+                &bot,
+                &msg,
+                SystemMessage::Start(agent_type),
+                &language,
+            )
+            .await?;
+        }
+        JarvisCommands::Language(new_language) => {
+            let Some(user) = &msg.from else {
+                bail!("Message doesn't have a sender");
+            };
+
+            let new_language = UserLang::from(new_language.as_str());
+            let (message_type, reply_language) = if new_language == UserLang::None {
+                (SystemMessage::Language(agent_type), language)
+            } else {
+                app_state
+                    .local_db
+                    .set_user_language(
+                        TELEGRAM_RESOURCE_CODE,
+                        &user.id.0.to_string(),
+                        &new_language.to_string(),
+                        true,
+                    )
+                    .await?;
+                (SystemMessage::LanguageSet(agent_type), new_language)
+            };
+
+            let reply = app_state
+                .localisation_manager
+                .system_message(&message_type, &reply_language)
+                .await?
+                .replace(LANGUAGE_PLACEHOLDER, &reply_language.to_string());
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        JarvisCommands::Memory
+        | JarvisCommands::Forget(_)
+        | JarvisCommands::ForgetAll
+        | JarvisCommands::ExportMemory => {
+            memory_command(&bot, &msg, cmd, &app_state, agent_type, &language).await?;
         }
         JarvisCommands::Model | JarvisCommands::Manual => {
             if agent_type != AgentType::Kevin {
@@ -90,8 +138,14 @@ pub async fn command_handler(
                         .await?;
                     }
                     JarvisCommands::Manual => {
-                        system_message(app_state, &bot, &msg, SystemMessage::Manual(agent_type))
-                            .await?;
+                        system_message(
+                            app_state,
+                            &bot,
+                            &msg,
+                            SystemMessage::Manual(agent_type),
+                            &language,
+                        )
+                        .await?;
                     }
                     _ => {}
                 }
@@ -105,6 +159,107 @@ pub async fn command_handler(
     Ok(())
 }
 
+/// Lets the user see and remove what the memory agent keeps about them
+async fn memory_command(
+    bot: &Bot,
+    msg: &Message,
+    cmd: JarvisCommands,
+    app_state: &JarvisAppState,
+    agent_type: AgentType,
+    language: &UserLang,
+) -> anyhow::Result<()> {
+    let Some(user) = &msg.from else {
+        bail!("Message doesn't have a sender");
+    };
+    let memory = UserMemory::new(&app_state.nervo_ai_db, user.id.0);
+    let messages = &app_state.localisation_manager;
+
+    let reply = match cmd {
+        JarvisCommands::Memory => {
+            let conclusions = memory.conclusions().await?;
+            if conclusions.is_empty() {
+                messages
+                    .system_message(&SystemMessage::MemoryEmpty(agent_type), language)
+                    .await?
+            } else {
+                let mut reply = String::new();
+                for record in conclusions {
+                    let line = format!(
+                        "{} [{}] {}\n",
+                        record.id,
+                        record.timestamp.unwrap_or_default(),
+                        record.text
+                    );
+                    if reply.len() + line.len() > MAX_MESSAGE_LENGTH {
+                        let truncated = SystemMessage::MemoryTruncated(agent_type);
+                        reply.push_str(&messages.system_message(&truncated, language).await?);
+                        break;
+                    }
+                    reply.push_str(&line);
+                }
+                reply
+            }
+        }
+        JarvisCommands::Forget(target) => {
+            let target = target.trim();
+            if target.is_empty() {
+                messages
+                    .system_message(&SystemMessage::ForgetUsage(agent_type), language)
+                    .await?
+            } else if memory.delete(target).await? {
+                messages
+                    .system_message(&SystemMessage::Forgotten(agent_type), language)
+                    .await?
+                    .replace(MEMORY_PLACEHOLDER, target)
+            } else {
+                // Deleting by meaning could hit the wrong memories, so the user picks them
+                let matching = memory.matching(target).await?;
+                if matching.is_empty() {
+                    messages
+                        .system_message(&SystemMessage::ForgetNotFound(agent_type), language)
+                        .await?
+                } else {
+                    let mut reply = messages
+                        .system_message(&SystemMessage::ForgetChoose(agent_type), language)
+                        .await?;
+                    for record in matching {
+                        let line = format!("\n/forget {}\n{}\n", record.id, record.text);
+                        if reply.len() + line.len() > MAX_MESSAGE_LENGTH {
+                            break;
+                        }
+                        reply.push_str(&line);
+                    }
+                    reply
+                }
+            }
+        }
+        JarvisCommands::ForgetAll => {
+            memory.wipe().await?;
+            app_state.local_db.clear_chats_of_user(user.id.0).await?;
+            app_state
+                .user_context
+                .forget_dialogues_of_user(user.id.0)
+                .await?;
+            messages
+                .system_message(&SystemMessage::ForgotEverything(agent_type), language)
+                .await?
+        }
+        JarvisCommands::ExportMemory => {
+            let export = serde_json::to_vec_pretty(&memory.export().await?)?;
+            bot.send_document(
+                msg.chat.id,
+                InputFile::memory(export).file_name("memory.json"),
+            )
+            .await?;
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
 pub async fn handle_callback_query(
     bot: Bot,
     q: CallbackQuery,
@@ -1,9 +1,5 @@
 use crate::config::jarvis::JarvisAppState;
 use anyhow::{anyhow, bail};
-use openai_dive::v1::api::Client;
-use openai_dive::v1::resources::audio::{
-    AudioOutputFormat, AudioTranscriptionFile, AudioTranscriptionParameters,
-};
 use std::sync::Arc;
 use teloxide::net::Download;
 use teloxide::prelude::{Message, Requester};
@@ -80,18 +76,11 @@ impl<'a> MessageParser<'a> {
 
         if fs::metadata(&file_path).await.is_ok() {
             self.bot.download_file(&file.path, &mut dst).await?;
-            let parameters = AudioTranscriptionParameters {
-                file: AudioTranscriptionFile::File(file_path.to_string()),
-                model: "whisper-1".to_string(),
-                language: None,
-                prompt: None,
-                response_format: Some(AudioOutputFormat::Text),
-                temperature: None,
-                timestamp_granularities: None,
-            };
-
-            let client = Client::new(self.app_state.nervo_llm.api_key().to_string());
-            let response = client.audio().create_transcription(parameters).await;
+            let response = self
+                .app_state
+                .nervo_llm
+                .voice_transcription(file_path.as_str())
+                .await;
 
             fs::remove_file(&file_path).await?;
             drop(dst);
@@ -102,7 +91,7 @@ impl<'a> MessageParser<'a> {
                     info!("Parsing voice to text are success");
                     Ok(text.clone())
                 }
-                Err(err) => Err(anyhow!(err).context("Can't transcribe audio file to text")),
+                Err(err) => Err(err),
             }
         } else {
             let error = anyhow!(format!("File '{}' doesn't exist.", file_path));
@@ -1,4 +1,5 @@
 pub(crate) mod bot_utils;
+pub mod chat_sessions;
 mod commands_handlers;
 pub mod jarvis;
 mod message_parser;
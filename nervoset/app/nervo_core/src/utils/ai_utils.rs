@@ -1,24 +1,45 @@
 use std::sync::Arc;
 
+use crate::ai::llm_provider::LlmTextStream;
+use crate::ai::nervo_llm::LlmRequestOptions;
+use crate::ai::vector_store::VectorHit;
 use crate::config::jarvis::JarvisAppState;
-use crate::models::qdrant_search_layers::{
-    QdrantSearchInfo, QdrantSearchLayer, QdrantUserRoleTextType,
-};
+use crate::models::qdrant_search_layers::{QdrantSearchInfo, QdrantSearchLayer};
 use crate::utils::ai_utils_data::TruncatingType::Truncated;
 use crate::utils::ai_utils_data::{SortingType, TruncatingType};
+use crate::utils::citations::Citations;
+use crate::utils::context_builder::ContextBuilder;
+use crate::utils::rag_engine::RagPipelineEngine;
 use anyhow::bail;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
 use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use nervo_sdk::api::spec::{
     LlmChat, LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence,
-    LlmMessageRole, SendMessageRequest,
+    LlmMessageRole, LlmMessageSource, LlmStreamEvent, SendMessageRequest,
 };
-use qdrant_client::qdrant::value::Kind;
-use qdrant_client::qdrant::ScoredPoint;
 use tiktoken_rs::cl100k_base;
 use tokio::fs;
-use tracing::info;
+use tokio::sync::mpsc;
+use tracing::{error, info};
 
 pub const RESOURCES_DIR: &str = "../resources/agent/";
+const STREAM_CHANNEL_SIZE: usize = 64;
+/// How many persistent messages are kept in the context window, it paces the info messages
+const CONTEXT_WINDOW_SIZE: i64 = 10;
+
+/// Everything that is known before the final answer is requested from LLM
+struct ConversationDraft {
+    final_chat: LlmChat,
+    options: LlmRequestOptions,
+    answer_suffix: String,
+    chat: i64,
+    user_id: u64,
+    persistence: LlmMessagePersistence,
+    /// Chunks the answer may cite
+    citations: Citations,
+    history_token_limit: usize,
+}
 
 //Common entry point for WEB and TG
 pub async fn llm_conversation(
@@ -26,143 +47,196 @@ pub async fn llm_conversation(
     msg_request: SendMessageRequest,
     agent_type: AgentType,
 ) -> anyhow::Result<LlmMessage> {
+    let draft = prepare_conversation(app_state.clone(), msg_request, agent_type).await?;
+
+    let mut llm_response_text = app_state
+        .nervo_llm
+        .send_msg_batch(draft.final_chat.clone(), &draft.options)
+        .await?;
+    llm_response_text.push_str(&draft.answer_suffix);
+
+    finish_conversation(app_state, &draft, &llm_response_text).await
+}
+
+/// Same as [`llm_conversation`], but the final answer is streamed as it is generated.
+/// The stream always ends with either `Done` or `Error` event.
+pub async fn llm_conversation_stream(
+    app_state: Arc<JarvisAppState>,
+    msg_request: SendMessageRequest,
+    agent_type: AgentType,
+) -> anyhow::Result<BoxStream<'static, LlmStreamEvent>> {
+    let draft = prepare_conversation(app_state.clone(), msg_request, agent_type).await?;
+    let deltas = app_state
+        .nervo_llm
+        .send_msg_batch_stream(draft.final_chat.clone(), &draft.options)
+        .await?;
+
+    let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_SIZE);
+    tokio::spawn(async move {
+        let last_event = match stream_answer(app_state, draft, deltas, &sender).await {
+            Ok(message) => LlmStreamEvent::Done { message },
+            Err(err) => LlmStreamEvent::Error {
+                message: err.to_string(),
+            },
+        };
+        let _ = sender.send(last_event).await;
+    });
+
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|event| (event, receiver))
+    });
+    Ok(events.boxed())
+}
+
+async fn stream_answer(
+    app_state: Arc<JarvisAppState>,
+    draft: ConversationDraft,
+    mut deltas: LlmTextStream,
+    sender: &mpsc::Sender<LlmStreamEvent>,
+) -> anyhow::Result<LlmMessage> {
+    let mut llm_response_text = String::new();
+    while let Some(delta) = deltas.next().await {
+        let text = delta?;
+        llm_response_text.push_str(&text);
+        // Keep going even if nobody listens anymore, the answer still goes to the history
+        let _ = sender.send(LlmStreamEvent::Delta { text }).await;
+    }
+
+    if !draft.answer_suffix.is_empty() {
+        llm_response_text.push_str(&draft.answer_suffix);
+        let text = draft.answer_suffix.clone();
+        let _ = sender.send(LlmStreamEvent::Delta { text }).await;
+    }
+
+    finish_conversation(app_state, &draft, &llm_response_text).await
+}
+
+async fn prepare_conversation(
+    app_state: Arc<JarvisAppState>,
+    msg_request: SendMessageRequest,
+    agent_type: AgentType,
+) -> anyhow::Result<ConversationDraft> {
     info!("start LLM layers handling");
     let agent_type_name = NervoAgentType::get_name(agent_type.clone());
-    let message_sender_id_string = msg_request.llm_message.sender_id.to_string();
-    let table_name = format!(
-        "{}_{}_{}",
-        &agent_type_name,
-        msg_request.clone().chat_id,
-        message_sender_id_string.as_str()
-    );
     let msg = msg_request.llm_message;
     let initial_user_content = msg.content.0.as_str();
     let user_id = msg.sender_id;
     let chat_id = msg_request.chat_id;
+    let chat = app_state
+        .local_db
+        .chat_of(&agent_type_name, chat_id, user_id)
+        .await?;
     let layers_info = get_all_search_layers(&agent_type_name).await?;
-    let all_messages: Vec<LlmMessage> = app_state.local_db.read_from_local_db(&table_name).await?;
+    let pipeline = layers_info.pipeline()?;
 
-    let initial_user_request = detecting_crap_request(
-        app_state.clone(),
-        &table_name,
-        &initial_user_content,
+    let context_builder = ContextBuilder {
+        local_db: &app_state.local_db,
+        nervo_llm: &app_state.nervo_llm,
+    };
+    let history_token_limit = pipeline.history_token_limit();
+    let history = context_builder.history(chat, history_token_limit).await?;
+    let engine = RagPipelineEngine {
+        nervo_llm: &app_state.nervo_llm,
+        nervo_ai_db: &app_state.nervo_ai_db,
+        agent_type_name: &agent_type_name,
         chat_id,
-        layers_info.clone().crap_detecting_layer,
-        all_messages.clone(),
-    )
-    .await?;
+        history: &history,
+    };
+    info!("Initial INPUT prompt for LLM: {}", &initial_user_content);
+    let variables =
+        RagPipelineEngine::initial_variables(initial_user_content, &history.render(None)?);
+    let outcome = engine.run(&pipeline, variables).await?;
 
-    if initial_user_request == "SKIP" {
+    if outcome.temporal {
         save_chat_history(
             app_state.clone(),
             user_id,
             &initial_user_content,
-            &table_name,
-            LlmMessagePersistence::Temporal,
-            LlmMessageRole::User,
-        )
-        .await?;
-        let llm_request_content =
-            build_crap_layer_llm_request(agent_type, &initial_user_content).await?;
-        let llm_message_text = get_string_from_llm_response(
-            app_state.clone(),
-            llm_request_content,
-            user_id,
-            chat_id,
+            chat,
             LlmMessagePersistence::Temporal,
             LlmMessageRole::User,
-        )
-        .await?;
-        let llm_response = save_chat_history(
-            app_state,
-            user_id,
-            llm_message_text.as_str(),
-            &table_name,
-            LlmMessagePersistence::Temporal,
-            LlmMessageRole::Assistant,
+            vec![],
         )
         .await?;
 
-        info!(
-            "Final response from LLM w/o RAG: {}",
-            llm_response.content.text()
-        );
-        Ok(llm_response)
+        Ok(ConversationDraft {
+            final_chat: outcome.chat,
+            options: outcome.options,
+            answer_suffix: String::new(),
+            chat,
+            user_id,
+            persistence: LlmMessagePersistence::Temporal,
+            citations: outcome.citations,
+            history_token_limit,
+        })
     } else {
         save_chat_history(
             app_state.clone(),
             user_id,
             &initial_user_content,
-            &table_name,
+            chat,
             LlmMessagePersistence::Persistent,
             LlmMessageRole::User,
+            vec![],
         )
         .await?;
 
-        let messages_count = all_messages.len();
-        let table_name_start_index = format!("{}_start_index", &table_name);
-        app_state
-            .local_db
-            .save_to_local_db(messages_count, &table_name_start_index, Some(10_i64))
-            .await?;
+        let cached_messages = app_state.local_db.read_context_window(chat).await?;
 
-        let llm_response_text = rag_system_processing(
-            app_state.clone(),
-            layers_info,
-            &initial_user_content,
-            &table_name,
-            chat_id,
-            &agent_type_name,
-            all_messages,
-        )
-        .await?;
-
-        let llm_response = save_chat_history(
-            app_state.clone(),
-            user_id,
-            &llm_response_text,
-            &table_name,
-            LlmMessagePersistence::Persistent,
-            LlmMessageRole::Assistant,
-        )
-        .await?;
+        let mut answer_suffix = String::new();
+        info!("cached_messages {:?}", cached_messages.len());
+        if cached_messages.len() % 4 == 0 {
+            answer_suffix.push_str(&layers_info.info_message_1);
+        };
 
-        app_state
-            .local_db
-            .save_to_local_db(messages_count + 1, &table_name_start_index, Some(10_i64))
-            .await?;
+        if cached_messages.len() % 6 == 0 {
+            answer_suffix.push_str(&layers_info.info_message_2);
+        };
 
-        info!(
-            "Final response from LLM with RAG: {}",
-            llm_response.content.text()
-        );
-        Ok(llm_response)
+        Ok(ConversationDraft {
+            final_chat: outcome.chat,
+            options: outcome.options,
+            answer_suffix,
+            chat,
+            user_id,
+            persistence: LlmMessagePersistence::Persistent,
+            citations: outcome.citations,
+            history_token_limit,
+        })
     }
 }
 
-async fn detecting_crap_request(
+async fn finish_conversation(
     app_state: Arc<JarvisAppState>,
-    table_name: &str,
-    initial_user_prompt: &str,
-    chat_id: u64,
-    layer: QdrantSearchLayer,
-    all_saved_messages: Vec<LlmMessage>,
-) -> anyhow::Result<String> {
-    info!("CRAP DETECTION Started");
-
-    let layer_content = create_layer_content(
-        app_state,
-        &table_name,
-        layer,
-        initial_user_prompt,
-        "",
-        "",
-        chat_id,
-        all_saved_messages,
+    draft: &ConversationDraft,
+    llm_response_text: &str,
+) -> anyhow::Result<LlmMessage> {
+    let llm_response = save_chat_history(
+        app_state.clone(),
+        draft.user_id,
+        llm_response_text,
+        draft.chat,
+        draft.persistence,
+        LlmMessageRole::Assistant,
+        draft.citations.cited_in(llm_response_text),
     )
     .await?;
-    info!("Is crap detected: {}", layer_content == "SKIP");
-    Ok(layer_content)
+
+    if matches!(draft.persistence, LlmMessagePersistence::Persistent) {
+        let (chat, token_limit) = (draft.chat, draft.history_token_limit);
+        tokio::spawn(async move {
+            let context_builder = ContextBuilder {
+                local_db: &app_state.local_db,
+                nervo_llm: &app_state.nervo_llm,
+            };
+            if let Err(err) = context_builder.fold_older_messages(chat, token_limit).await {
+                error!("Can't summarize the history of the chat {}: {}", chat, err);
+            }
+        });
+    }
+
+    info!("Final response from LLM: {}", llm_response.content.text());
+    Ok(llm_response)
 }
 
 async fn create_user_message_of(
@@ -170,6 +244,7 @@ async fn create_user_message_of(
     sender_id: u64,
     content: &str,
     role: LlmMessageRole,
+    sources: Vec<LlmMessageSource>,
 ) -> anyhow::Result<LlmMessage> {
     let llm_user_message = LlmMessage {
         meta_info: LlmMessageMetaInfo {
@@ -178,76 +253,32 @@ async fn create_user_message_of(
             persistence: persistence_type,
         },
         content: LlmMessageContent(String::from(content)),
+        sources,
     };
     Ok(llm_user_message)
 }
 
-async fn build_crap_layer_llm_request(
-    agent_type: AgentType,
-    content: &str,
-) -> anyhow::Result<LlmMessageContent> {
-    info!("Start Preparing handled crap Answer");
-    // Prepare System Role and User question to ask a regular LLM
-    let agent_type_name = NervoAgentType::get_name(agent_type);
-    let resource_path = format!(
-        "{}{}/crap_request_system_role.txt",
-        RESOURCES_DIR, agent_type_name
-    );
-    let crap_system_role = std::fs::read_to_string(resource_path)?;
-
-    let user_request_text = format!(
-        "{:?}\nТекущий запрос пользователя: {:?}",
-        crap_system_role, &content
-    );
-    let content = LlmMessageContent::from(user_request_text.as_str());
-    info!("LLMContent of  crap Answer is ready");
-    Ok(content)
-}
-
-async fn get_string_from_llm_response(
-    app_state: Arc<JarvisAppState>,
-    llm_content: LlmMessageContent,
-    sender_id: u64,
-    chat_id: u64,
-    persistence_type: LlmMessagePersistence,
-    role: LlmMessageRole,
-) -> anyhow::Result<String> {
-    info!("Need to get LLM Message from LLM Content");
-    let request_to_llm = LlmMessage {
-        meta_info: LlmMessageMetaInfo {
-            sender_id: Some(sender_id),
-            role,
-            persistence: persistence_type,
-        },
-        content: llm_content,
-    };
-
-    // Asking LLM
-    let llm_response_text = app_state
-        .nervo_llm
-        .send_msg(request_to_llm, chat_id)
-        .await?;
-    info!(
-        "LLM Response WITHOUT RAG handling {:?}",
-        llm_response_text.clone()
-    );
-    Ok(llm_response_text)
-}
-
+/// Persistent messages also become a part of the history context
 async fn save_chat_history(
     app_state: Arc<JarvisAppState>,
     user_id: u64,
     content: &str,
-    table_name: &str,
+    chat: i64,
     persistence_type: LlmMessagePersistence,
     role: LlmMessageRole,
+    sources: Vec<LlmMessageSource>,
 ) -> anyhow::Result<LlmMessage> {
-    info!(" Save to DB to restore chat history, not for history context");
-    let llm_message = create_user_message_of(persistence_type, user_id, content, role).await?;
+    info!(" Save to DB to restore chat history");
+    let llm_message =
+        create_user_message_of(persistence_type, user_id, content, role, sources).await?;
 
+    let context_window_limit = match persistence_type {
+        LlmMessagePersistence::Persistent => Some(CONTEXT_WINDOW_SIZE),
+        LlmMessagePersistence::Temporal => None,
+    };
     app_state
         .local_db
-        .save_to_local_db(llm_message.clone(), &table_name, None)
+        .save_message(chat, &llm_message, context_window_limit)
         .await?;
     Ok(llm_message)
 }
@@ -260,95 +291,12 @@ pub async fn get_all_search_layers(agent_type_name: &str) -> anyhow::Result<Qdra
     );
     let json_string = fs::read_to_string(resource_path).await?;
     let all_layers_data: QdrantSearchInfo = serde_json::from_str(&json_string)?;
-    info!("There are {} layers", all_layers_data.layers.len() + 1);
-    Ok(all_layers_data)
-}
-
-async fn create_layer_content(
-    app_state: Arc<JarvisAppState>,
-    db_table_name: &str,
-    layer: QdrantSearchLayer,
-    initial_user_prompt: &str,
-    llm_rephrased_prompt: &str,
-    search_result_content: &str,
-    chat_id: u64,
-    all_saved_messages: Vec<LlmMessage>,
-) -> anyhow::Result<String> {
     info!(
-        "Create layer request content accor ding to user initial question {}",
-        &initial_user_prompt
+        "There are {} legacy layers, pipeline configured: {}",
+        all_layers_data.layers.len(),
+        all_layers_data.pipeline.is_some()
     );
-    let mut messages: Vec<LlmMessage> = Vec::new();
-    let user_role_msg = formation_user_role_llm_message(
-        app_state.clone(),
-        &db_table_name,
-        all_saved_messages,
-        layer.clone(),
-        initial_user_prompt,
-        llm_rephrased_prompt,
-        search_result_content.to_string(),
-    )
-    .await?;
-
-    let system_role_msg = formation_system_role_llm_message(layer).await?;
-
-    messages.push(system_role_msg);
-    messages.push(user_role_msg);
-
-    let chat: LlmChat = LlmChat {
-        chat_id: Some(chat_id),
-        messages,
-    };
-    info!("Making chat with llm and prepared user and system roles");
-    app_state.nervo_llm.send_msg_batch(chat).await
-}
-
-async fn formation_user_role_llm_message(
-    app_state: Arc<JarvisAppState>,
-    table_name: &str,
-    all_saved_messages: Vec<LlmMessage>,
-    layer: QdrantSearchLayer,
-    prompt: &str,
-    llm_rephrased_prompt: &str,
-    search_result_content: String,
-) -> anyhow::Result<LlmMessage> {
-    info!("Let's start User Role Creation formation");
-
-    let mut user_role_full_text = String::new();
-    for parameter in layer.user_role_params {
-        let value = match parameter.param_type {
-            QdrantUserRoleTextType::History => {
-                let cached_messages = get_last_messages_from_cache(
-                    app_state.clone(),
-                    &table_name,
-                    all_saved_messages.clone(),
-                )
-                .await?;
-                cached_messages
-                    .clone()
-                    .iter()
-                    .map(|msg| msg.content.text())
-                    .collect::<Vec<String>>()
-                    .join("\n")
-            }
-            QdrantUserRoleTextType::UserPrompt => prompt.to_string().clone(),
-            QdrantUserRoleTextType::RephrasedPrompt => llm_rephrased_prompt.to_string(),
-            QdrantUserRoleTextType::DbSearch => search_result_content.clone(),
-        };
-        let part = format!("{:?}{:?}\n", parameter.param_value, value);
-        user_role_full_text.push_str(&part)
-    }
-
-    let user_role_msg = LlmMessage {
-        meta_info: LlmMessageMetaInfo {
-            sender_id: None,
-            role: LlmMessageRole::User,
-            persistence: LlmMessagePersistence::Persistent,
-        },
-        content: LlmMessageContent::from(user_role_full_text.as_str()),
-    };
-    info!("User Role full text: {}", user_role_full_text);
-    Ok(user_role_msg)
+    Ok(all_layers_data)
 }
 
 pub async fn formation_system_role_llm_message(
@@ -363,145 +311,18 @@ pub async fn formation_system_role_llm_message(
             persistence: LlmMessagePersistence::Persistent,
         },
         content: LlmMessageContent::from(system_role_full_text.as_str()),
+        sources: vec![],
     };
     info!("System Role full text: {}", system_role_full_text);
     Ok(system_role_msg)
 }
 
-async fn get_last_messages_from_cache(
-    app_state: Arc<JarvisAppState>,
-    table_name: &str,
-    all_saved_messages: Vec<LlmMessage>,
-) -> anyhow::Result<Vec<LlmMessage>> {
-    info!("Looking for last N messages");
-    let start_index_table_name = format!("{}_start_index", table_name);
-    let context_messages_indexes: Vec<i64> = app_state
-        .local_db
-        .read_from_local_db(&start_index_table_name)
-        .await?;
-
-    let mut cached_messages: Vec<LlmMessage> = vec![];
-    for index in context_messages_indexes {
-        if let Some(content_message) = all_saved_messages.get(index as usize) {
-            cached_messages.push(content_message.clone());
-        }
-    }
-    info!(
-        "We got {} cached messages to build history context",
-        cached_messages.len()
-    );
-    Ok(cached_messages)
-}
-
-async fn rag_system_processing(
-    app_state: Arc<JarvisAppState>,
-    all_layers_info: QdrantSearchInfo,
-    initial_user_prompt: &str,
-    table_name: &str,
-    chat_id: u64,
-    collection_name: &str,
-    all_saved_messages: Vec<LlmMessage>,
-) -> anyhow::Result<String> {
-    info!("Initial INPUT prompt for LLM: {}", &initial_user_prompt);
-    let processing_layers = all_layers_info.layers;
-    let mut llm_rephrased_prompt = String::from(initial_user_prompt);
-    info!("We need to process thru {} layers", processing_layers.len());
-    for processing_layer in processing_layers {
-        let mut search_content = String::new();
-        if processing_layer.layer_for_search {
-            search_content = searching_in_qdrant(
-                app_state.clone(),
-                collection_name,
-                &llm_rephrased_prompt,
-                processing_layer.clone(),
-            )
-            .await?;
-        }
-
-        llm_rephrased_prompt = create_layer_content(
-            app_state.clone(),
-            &table_name,
-            processing_layer,
-            initial_user_prompt,
-            llm_rephrased_prompt.as_str(),
-            search_content.as_str(),
-            chat_id,
-            all_saved_messages.clone(),
-        )
-        .await?;
-    }
-
-    let start_index_table_name = format!("{}_start_index", &table_name);
-    let context_messages_indexes: Vec<i64> = app_state
-        .local_db
-        .read_from_local_db(&start_index_table_name)
-        .await?;
-
-    let mut cached_messages: Vec<LlmMessage> = vec![];
-    for index in context_messages_indexes {
-        if let Some(content_message) = all_saved_messages.get(index as usize) {
-            cached_messages.push(content_message.clone());
-        }
-    }
-
-    info!("cached_messages {:?}", cached_messages.len());
-    if cached_messages.len() % 4 == 0 {
-        llm_rephrased_prompt.push_str(&all_layers_info.info_message_1);
-    };
-
-    if cached_messages.len() % 6 == 0 {
-        llm_rephrased_prompt.push_str(&all_layers_info.info_message_2);
-    };
-
-    Ok(llm_rephrased_prompt)
-}
-
-async fn searching_in_qdrant(
-    app_state: Arc<JarvisAppState>,
-    collection_name: &str,
-    llm_rephrased_prompt: &str,
-    processing_layer: QdrantSearchLayer,
-) -> anyhow::Result<String> {
-    info!("Need to ask QDrant DB to get some info");
-    let mut search_content = String::new();
-
-    let db_search_response = &app_state
-        .nervo_ai_db
-        .text_search(
-            collection_name,
-            llm_rephrased_prompt.to_string(),
-            processing_layer.vectors_limit,
-        )
-        .await?;
-
-    let all_search_results = filter_search_result(
-        db_search_response.result.clone(),
-        SortingType::Descending,
-        Truncated(10),
-        0.3,
-    )?;
-
-    let scores_string = all_search_results
-        .iter()
-        .map(|element| element.score.to_string())
-        .collect::<Vec<String>>()
-        .join(", ");
-
-    info!("All_search_result scores {}", scores_string);
-
-    let concatenated_texts = concatenate_results(all_search_results)?;
-    let token_limit = processing_layer.common_token_limit as usize;
-    let updated_content = update_search_content(token_limit, concatenated_texts)?;
-    search_content.push_str(updated_content.as_str());
-    Ok(search_content)
-}
-
 pub fn filter_search_result(
-    search_results: Vec<ScoredPoint>,
+    search_results: Vec<VectorHit>,
     sorting_type: SortingType,
     truncating: TruncatingType,
     score: f32,
-) -> anyhow::Result<Vec<ScoredPoint>> {
+) -> anyhow::Result<Vec<VectorHit>> {
     // Filtering search_results
     let mut filtered_results: Vec<_> = search_results
         .into_iter()
@@ -529,7 +350,7 @@ pub fn filter_search_result(
 
     match truncating {
         Truncated(value) => {
-            filtered_results.truncate(value as usize);
+            filtered_results.truncate(value);
         }
         TruncatingType::None => {}
     }
@@ -562,26 +383,37 @@ pub fn update_search_content(
     }
 }
 
-fn concatenate_results(all_search_results: Vec<ScoredPoint>) -> anyhow::Result<String> {
+/// A chunk per line, numbered so the answer can cite it: `[1] (source: about.json) text`
+pub fn concatenate_results(
+    all_search_results: Vec<VectorHit>,
+    citations: &mut Citations,
+) -> anyhow::Result<String> {
     info!("Need to concatenate vector");
-    let mut concatenated_texts: String = String::new();
+    let mut chunks = vec![];
 
     for search_result in all_search_results {
-        let Some(Kind::StringValue(text)) = &search_result.payload["text"].kind else {
+        let Some(text) = search_result.payload.text() else {
             bail!("Oooops! Error")
         };
-        concatenated_texts.push_str(text.as_str());
+        let number = citations.number_of(&search_result);
+        let chunk = match search_result.payload.source() {
+            Some(source) => format!("[{}] (source: {}) {}", number, source, text),
+            None => format!("[{}] {}", number, text),
+        };
+        chunks.push(chunk);
     }
     info!("Concatenating are done");
-    Ok(concatenated_texts)
+    Ok(chunks.join("\n"))
 }
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
-
-    use crate::utils::ai_utils::{concatenate_results, update_search_content};
-    use qdrant_client::qdrant::ScoredPoint;
+    use crate::ai::vector_store::{VectorHit, VectorPayload};
+    use crate::utils::ai_utils::{
+        concatenate_results, get_all_search_layers, update_search_content,
+    };
+    use crate::utils::citations::Citations;
+    use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 
     #[test]
     fn test_update_search_content() -> anyhow::Result<()> {
@@ -592,22 +424,23 @@ mod test {
 
     #[test]
     fn test_concatenate_results() -> anyhow::Result<()> {
-        let mut data = HashMap::new();
-        data.insert(
-            "text".to_string(),
-            qdrant_client::qdrant::Value::from("lala-ley".to_string()),
-        );
-        let vector = vec![ScoredPoint {
-            id: None,
-            payload: data,
+        let vector = vec![VectorHit {
+            id: String::new(),
             score: 0.5,
-            version: 0,
-            vectors: None,
-            shard_key: None,
-            order_value: None,
+            payload: VectorPayload::from_text("lala-ley"),
+            vector: None,
         }];
-        let test_result = concatenate_results(vector)?;
-        assert_eq!(test_result, String::from("lala-ley"));
+        let test_result = concatenate_results(vector, &mut Citations::default())?;
+        assert_eq!(test_result, String::from("[1] lala-ley"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_agent_pipeline_is_valid() -> anyhow::Result<()> {
+        let agent_type_name = NervoAgentType::get_name(AgentType::Nervoznyak);
+        let layers_info = get_all_search_layers(&agent_type_name).await?;
+        assert!(layers_info.pipeline.is_some());
+        layers_info.pipeline()?;
         Ok(())
     }
 }
@@ -81,6 +81,6 @@ pub enum SortingType {
 }
 
 pub enum TruncatingType {
-    Truncated(u8),
+    Truncated(usize),
     None,
 }
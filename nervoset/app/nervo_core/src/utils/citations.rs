@@ -0,0 +1,93 @@
+use crate::ai::vector_store::VectorHit;
+use nervo_sdk::api::spec::LlmMessageSource;
+use std::collections::BTreeSet;
+
+/// Chunks handed to LLM during a pipeline run, numbered in the order they were found.
+/// A chunk keeps its number when a rerank step passes it on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Citations {
+    /// Point id and the source of every numbered chunk, the number is the index + 1
+    chunks: Vec<(String, LlmMessageSource)>,
+}
+
+impl Citations {
+    pub fn number_of(&mut self, hit: &VectorHit) -> u32 {
+        if let Some(index) = self.chunks.iter().position(|(id, _)| id == &hit.id) {
+            return index as u32 + 1;
+        }
+
+        let number = self.chunks.len() as u32 + 1;
+        let source = LlmMessageSource {
+            number,
+            source: hit.payload.source().map(str::to_string),
+            text: hit.payload.text().unwrap_or_default().to_string(),
+        };
+        self.chunks.push((hit.id.clone(), source));
+        number
+    }
+
+    /// Sources of the `[1]` or `[1, 2]` markers of the answer, by number.
+    /// Numbers which were never handed to LLM are ignored.
+    pub fn cited_in(&self, answer: &str) -> Vec<LlmMessageSource> {
+        citation_markers(answer)
+            .into_iter()
+            .filter_map(|number| self.chunks.get(number.checked_sub(1)? as usize))
+            .map(|(_, source)| source.clone())
+            .collect()
+    }
+}
+
+fn citation_markers(answer: &str) -> BTreeSet<u32> {
+    let mut numbers = BTreeSet::new();
+    for (start, _) in answer.match_indices('[') {
+        let Some(length) = answer[start + 1..].find(']') else {
+            break;
+        };
+        let marker: Result<Vec<u32>, _> = answer[start + 1..start + 1 + length]
+            .split(',')
+            .map(|number| number.trim().parse::<u32>())
+            .collect();
+        if let Ok(marker) = marker {
+            numbers.extend(marker);
+        }
+    }
+    numbers
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::vector_store::{payload_fields, VectorHit, VectorPayload};
+    use crate::utils::citations::Citations;
+    use serde_json::Value;
+
+    fn hit(id: &str, text: &str, source: Option<&str>) -> VectorHit {
+        let mut payload = VectorPayload::from_text(text);
+        if let Some(source) = source {
+            payload
+                .0
+                .insert(payload_fields::SOURCE.to_string(), Value::from(source));
+        }
+        VectorHit {
+            id: id.to_string(),
+            score: 0.5,
+            payload,
+            vector: None,
+        }
+    }
+
+    #[test]
+    fn test_cited_sources() {
+        let mut citations = Citations::default();
+        let about = hit("a", "we are nervoset", Some("nervoznyak/about.json"));
+        assert_eq!(citations.number_of(&about), 1);
+        assert_eq!(citations.number_of(&hit("b", "office hours", None)), 2);
+        assert_eq!(citations.number_of(&about), 1);
+
+        let sources = citations.cited_in("Nervoset [1] is open [2, 1]. See [link] and [7].");
+        let numbers: Vec<u32> = sources.iter().map(|source| source.number).collect();
+        assert_eq!(numbers, vec![1, 2]);
+        assert_eq!(sources[0].source.as_deref(), Some("nervoznyak/about.json"));
+        assert_eq!(sources[1].footnote(), "[2] office hours");
+        assert!(citations.cited_in("no markers").is_empty());
+    }
+}
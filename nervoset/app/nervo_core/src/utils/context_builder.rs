@@ -0,0 +1,321 @@
+use crate::ai::nervo_llm::{LlmRequestOptions, NervoLlm};
+use crate::db::local_db::{ChatSummary, LocalDb};
+use crate::utils::ai_utils::update_search_content;
+use anyhow::Result;
+use tiktoken_rs::cl100k_base_singleton;
+use tracing::info;
+
+/// History budget of the prompts which don't set their own
+pub const DEFAULT_HISTORY_TOKEN_LIMIT: usize = 2000;
+/// Unsummarized messages are read by pages of this size
+const MESSAGES_PAGE_SIZE: usize = 100;
+/// Older turns are folded into the summary by chunks of this size,
+/// so a chat which has never been summarized doesn't overflow the summarization request
+const SUMMARY_CHUNK_TOKENS: usize = 3000;
+const SUMMARY_MAX_TOKENS: u32 = 500;
+
+const SUMMARY_SYSTEM_ROLE: &str = "You keep a short summary of a conversation between the user \
+    and the assistant. You are provided with the previous summary, possibly empty, and the next \
+    messages of the conversation. Reply with the updated summary only. Keep the facts about the \
+    user, the questions and the answers, drop the small talk.";
+
+pub fn count_tokens(text: &str) -> usize {
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    bpe.encode_with_special_tokens(text).len()
+}
+
+/// Summary of the older turns of the conversation and the recent turns as is, the oldest first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConversationHistory {
+    pub summary: Option<String>,
+    pub turns: Vec<String>,
+}
+
+impl ConversationHistory {
+    /// The summary and as many recent turns as fit into the token limit
+    pub fn render(&self, token_limit: Option<usize>) -> Result<String> {
+        let mut parts = vec![];
+        let mut budget = token_limit.unwrap_or(usize::MAX);
+
+        if let Some(summary) = &self.summary {
+            let summary = format!("Summary of the earlier conversation: {}", summary);
+            let summary = match token_limit {
+                Some(token_limit) => update_search_content(token_limit, summary)?,
+                None => summary,
+            };
+            budget = budget.saturating_sub(count_tokens(&summary));
+            parts.push(summary);
+        }
+
+        let (_, recent) = split_by_budget(&self.turns, budget);
+        parts.extend(recent.iter().cloned());
+        Ok(parts.join("\n"))
+    }
+}
+
+/// Splits the turns into the older ones and the most recent ones that fit into the budget
+pub fn split_by_budget(turns: &[String], token_limit: usize) -> (&[String], &[String]) {
+    let mut tokens = 0;
+    let mut first_recent = turns.len();
+    for (index, turn) in turns.iter().enumerate().rev() {
+        tokens += count_tokens(turn);
+        if tokens > token_limit {
+            break;
+        }
+        first_recent = index;
+    }
+    turns.split_at(first_recent)
+}
+
+/// Folds the turns into the previous summary
+pub async fn summarize(
+    nervo_llm: &NervoLlm,
+    previous: Option<&str>,
+    turns: &[String],
+) -> Result<String> {
+    let request = format!(
+        "Previous summary: {}\nNext messages:\n{}",
+        previous.unwrap_or_default(),
+        turns.join("\n")
+    );
+    let options = LlmRequestOptions {
+        temperature: Some(0.2),
+        max_tokens: Some(SUMMARY_MAX_TOKENS),
+        ..LlmRequestOptions::default()
+    };
+    nervo_llm
+        .raw_llm_processing(SUMMARY_SYSTEM_ROLE, &request, &options)
+        .await
+}
+
+/// Builds the history context of the chat. Recent persistent messages are kept verbatim,
+/// the older ones are folded into the summary of the chat once they don't fit the budget.
+/// Folding calls LLM, so it runs after the reply instead of while the reply is prepared.
+pub struct ContextBuilder<'a> {
+    pub local_db: &'a LocalDb,
+    pub nervo_llm: &'a NervoLlm,
+}
+
+impl ContextBuilder<'_> {
+    /// The summary and the recent turns that fit the budget, the turns which are not folded
+    /// into the summary yet and don't fit are left out
+    pub async fn history(&self, chat: i64, token_limit: usize) -> Result<ConversationHistory> {
+        let summary = self.local_db.chat_summary(chat).await?;
+        let turns = self.unsummarized_turns(chat, summary.as_ref()).await?;
+        let (older, recent) = split_by_budget(&turns, budget_of(token_limit, summary.as_ref()));
+        info!(
+            "History of the chat {}: {} recent turns, {} turns to summarize",
+            chat,
+            recent.len(),
+            older.len()
+        );
+
+        Ok(ConversationHistory {
+            summary: summary.map(|summary| summary.summary),
+            turns: recent.to_vec(),
+        })
+    }
+
+    /// Folds the turns which don't fit the budget into the summary of the chat.
+    /// The summary is saved after every chunk, so a failure keeps the folded chunks
+    pub async fn fold_older_messages(&self, chat: i64, token_limit: usize) -> Result<()> {
+        let mut summary = self.local_db.chat_summary(chat).await?;
+        let messages = self.unsummarized_messages(chat, summary.as_ref()).await?;
+        let turns: Vec<String> = messages.iter().map(|(_, text)| text.clone()).collect();
+        let (older, _) = split_by_budget(&turns, budget_of(token_limit, summary.as_ref()));
+
+        let mut folded = 0;
+        while folded < older.len() {
+            let chunk = summary_chunk(&older[folded..]);
+            folded += chunk.len();
+            let folded_summary = ChatSummary {
+                summary: summarize(
+                    self.nervo_llm,
+                    summary.as_ref().map(|summary| summary.summary.as_str()),
+                    chunk,
+                )
+                .await?,
+                last_message_id: messages[folded - 1].0,
+            };
+            self.local_db
+                .save_chat_summary(chat, &folded_summary)
+                .await?;
+            summary = Some(folded_summary);
+        }
+        if folded > 0 {
+            info!(
+                "Folded {} turns of the chat {} into the summary",
+                folded, chat
+            );
+        }
+        Ok(())
+    }
+
+    async fn unsummarized_turns(
+        &self,
+        chat: i64,
+        summary: Option<&ChatSummary>,
+    ) -> Result<Vec<String>> {
+        let messages = self.unsummarized_messages(chat, summary).await?;
+        Ok(messages.into_iter().map(|(_, text)| text).collect())
+    }
+
+    /// All persistent messages after the summarized ones with their ids, the oldest first
+    async fn unsummarized_messages(
+        &self,
+        chat: i64,
+        summary: Option<&ChatSummary>,
+    ) -> Result<Vec<(i64, String)>> {
+        let mut last_id = summary.map_or(0, |summary| summary.last_message_id);
+        let mut messages = vec![];
+        loop {
+            let page = self
+                .local_db
+                .read_persistent_messages_after(chat, last_id, MESSAGES_PAGE_SIZE)
+                .await?;
+            let page_len = page.len();
+            if let Some((id, _)) = page.last() {
+                last_id = *id;
+            }
+            messages.extend(
+                page.into_iter()
+                    .map(|(id, message)| (id, message.content.text())),
+            );
+            if page_len < MESSAGES_PAGE_SIZE {
+                return Ok(messages);
+            }
+        }
+    }
+}
+
+fn budget_of(token_limit: usize, summary: Option<&ChatSummary>) -> usize {
+    let summary_tokens = summary.map_or(0, |summary| count_tokens(&summary.summary));
+    token_limit.saturating_sub(summary_tokens)
+}
+
+/// The oldest turns that fit into one summarization request, at least one turn
+fn summary_chunk(turns: &[String]) -> &[String] {
+    let mut tokens = 0;
+    let mut len = 0;
+    for turn in turns {
+        tokens += count_tokens(turn);
+        if tokens > SUMMARY_CHUNK_TOKENS && len > 0 {
+            break;
+        }
+        len += 1;
+    }
+    &turns[..len]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::db::local_db::test::temp_db;
+    use crate::utils::context_builder::{count_tokens, ContextBuilder, ConversationHistory};
+    use nervo_sdk::api::spec::{
+        LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence, LlmMessageRole,
+    };
+
+    #[test]
+    fn test_render_keeps_recent_turns() -> anyhow::Result<()> {
+        let history = ConversationHistory {
+            summary: Some("likes cats".to_string()),
+            turns: vec!["one two three".to_string(), "four five".to_string()],
+        };
+        assert_eq!(
+            history.render(None)?,
+            "Summary of the earlier conversation: likes cats\none two three\nfour five"
+        );
+
+        let summary_tokens = count_tokens("Summary of the earlier conversation: likes cats");
+        let rendered = history.render(Some(summary_tokens + count_tokens("four five")))?;
+        assert_eq!(
+            rendered,
+            "Summary of the earlier conversation: likes cats\nfour five"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_older_turns_are_summarized() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let chat = local_db.chat_of("kevin", 42, 7).await?;
+        for (text, persistence) in [
+            ("my cat is called Tom", LlmMessagePersistence::Persistent),
+            ("hi", LlmMessagePersistence::Temporal),
+            ("what is rust", LlmMessagePersistence::Persistent),
+            ("a programming language", LlmMessagePersistence::Persistent),
+        ] {
+            let message = LlmMessage {
+                meta_info: LlmMessageMetaInfo {
+                    sender_id: Some(7),
+                    role: LlmMessageRole::User,
+                    persistence,
+                },
+                content: LlmMessageContent::from(text),
+                sources: vec![],
+            };
+            local_db.save_message(chat, &message, None).await?;
+        }
+
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec!["the cat is Tom"]));
+        let builder = ContextBuilder {
+            local_db: &local_db,
+            nervo_llm: &nervo_llm,
+        };
+        let token_limit = count_tokens("what is rust") + count_tokens("a programming language");
+        // The turns which don't fit are left out until they are folded
+        let history = builder.history(chat, token_limit).await?;
+        assert_eq!(history.summary, None);
+        assert_eq!(
+            history.turns,
+            vec!["what is rust", "a programming language"]
+        );
+
+        builder.fold_older_messages(chat, token_limit).await?;
+        // The summary is kept, the folded turns are not read again
+        let history = builder.history(chat, 1000).await?;
+        assert_eq!(history.summary.as_deref(), Some("the cat is Tom"));
+        assert_eq!(
+            history.turns,
+            vec!["what is rust", "a programming language"]
+        );
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_fold_pages_through_all_messages() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        let chat = local_db.chat_of("kevin", 42, 7).await?;
+        for index in 0..150 {
+            let message = LlmMessage {
+                meta_info: LlmMessageMetaInfo {
+                    sender_id: Some(7),
+                    role: LlmMessageRole::User,
+                    persistence: LlmMessagePersistence::Persistent,
+                },
+                content: LlmMessageContent::from(format!("message {}", index).as_str()),
+                sources: vec![],
+            };
+            local_db.save_message(chat, &message, None).await?;
+        }
+
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec!["many messages"]));
+        let builder = ContextBuilder {
+            local_db: &local_db,
+            nervo_llm: &nervo_llm,
+        };
+        builder
+            .fold_older_messages(chat, count_tokens("message 149"))
+            .await?;
+
+        let history = builder.history(chat, 1000).await?;
+        assert_eq!(history.summary.as_deref(), Some("many messages"));
+        assert_eq!(history.turns, vec!["message 149"]);
+        Ok(())
+    }
+}
@@ -1,40 +1,65 @@
-use crate::ai::nervo_llm::NervoLlm;
+use crate::ai::nervo_llm::{LlmRequestOptions, NervoLlm};
+use crate::db::local_db::LocalDb;
 use crate::models::qdrant_search_layers::QdrantSearchLayer;
+use crate::models::system_messages::{SystemMessage, SystemMessages};
 use crate::utils::ai_utils::formation_system_role_llm_message;
 use anyhow::Result;
+use nervo_sdk::agent_type::NervoAgentType;
 use nervo_sdk::api::spec::LlmChat;
+use nervo_sdk::utils::cryptography::Sha256Generator;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::RwLock;
 use tracing::info;
 
+/// Translates the replies, the language of every user is kept in LocalDb
 pub struct LocalisationManager {
-    pub user_language: UserLang,
     pub nervo_llm: NervoLlm,
+    /// Keeps the translations of the replies
+    local_db: LocalDb,
+    /// System messages by agent name and language code
+    system_messages: RwLock<HashMap<(String, String), SystemMessages>>,
+    metrics: TranslationMetrics,
 }
 
 impl LocalisationManager {
-    pub fn build(nervo_llm: NervoLlm) -> Result<Self> {
+    pub fn build(nervo_llm: NervoLlm, local_db: LocalDb) -> Result<Self> {
         Ok(LocalisationManager {
-            user_language: UserLang::None,
             nervo_llm,
+            local_db,
+            system_messages: RwLock::new(HashMap::new()),
+            metrics: TranslationMetrics::default(),
         })
     }
 }
 
+/// Counters of the reply translations since the start
+#[derive(Default)]
+struct TranslationMetrics {
+    requested: AtomicU64,
+    skipped: AtomicU64,
+    cache_hits: AtomicU64,
+    translated: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TranslationStats {
+    pub requested: u64,
+    /// The reply was already in the user's language
+    pub skipped: u64,
+    pub cache_hits: u64,
+    /// LLM translation actually ran
+    pub translated: u64,
+}
+
 impl LocalisationManager {
-    pub async fn detect_language(&mut self, text: &str) -> Result<()> {
+    pub async fn detect_language(&self, text: &str) -> Result<UserLang> {
         info!("Lang need to be detected! {}", text);
-        let system_role_instructions = format!("You are provided with a text - {}. Determine the language in which this text is written and as a response, return only the language of the provided text, without additional remarks or comments, example: Russian, English.", text);
-        let language_detecting_layer = QdrantSearchLayer {
-            index: None,
-            user_role_params: vec![],
-            system_role_text: system_role_instructions.to_string(),
-            temperature: 0.2,
-            max_tokens: 4096,
-            common_token_limit: 30000,
-            vectors_limit: 0,
-            layer_for_search: false,
-        };
+        let system_role_instructions = format!("You are provided with a text - {}. Determine the language in which this text is written and as a response, return only the ISO 639-1 code of the language of the provided text, without additional remarks or comments, example: ru, en.", text);
+        let language_detecting_layer = QdrantSearchLayer::new(system_role_instructions);
 
+        let options = LlmRequestOptions::from(&language_detecting_layer);
         let system_role_msg = formation_system_role_llm_message(language_detecting_layer).await?;
 
         info!("Full detecting role message: {}", system_role_msg.content.0);
@@ -42,39 +67,118 @@ impl LocalisationManager {
             chat_id: None,
             messages: vec![system_role_msg],
         };
-        let llm_response = self.nervo_llm.send_msg_batch(chat).await?;
+        let llm_response = self.nervo_llm.send_msg_batch(chat, &options).await?;
         info!("Lang has been detected! {}", llm_response);
-        self.user_language = UserLang::from(llm_response.to_lowercase().as_ref());
-
-        Ok(())
+        Ok(UserLang::from(llm_response.trim().to_lowercase().as_ref()))
     }
 
-    pub async fn set_language_as_locale(&mut self, locale: &str) -> Result<()> {
-        match self.user_language {
-            UserLang::None => {
-                info!("Set lang according to user locale {}", locale);
-                self.user_language = UserLang::from(locale.to_lowercase().as_ref());
+    /// The message from the catalog of the language. Languages without a catalog
+    /// get the default catalog translated by LLM once
+    pub async fn system_message(
+        &self,
+        message: &SystemMessage,
+        language: &UserLang,
+    ) -> Result<String> {
+        let agent_type = message.agent_type();
+        let key = (NervoAgentType::get_name(agent_type), language.code());
+        if let Some(catalog) = self.system_messages.read().await.get(&key) {
+            return Ok(message.text_of(catalog));
+        }
+
+        let catalog = match SystemMessages::load_catalog(agent_type, &key.1).await? {
+            Some(catalog) => catalog,
+            None => {
+                info!("No system messages catalog for {}, translating", key.1);
+                let default_catalog = SystemMessages::load_default(agent_type).await?;
+                self.translate_catalog(&default_catalog, language).await?
             }
-            _ => {}
+        };
+
+        let text = message.text_of(&catalog);
+        self.system_messages.write().await.insert(key, catalog);
+        Ok(text)
+    }
+
+    async fn translate_catalog(
+        &self,
+        catalog: &SystemMessages,
+        language: &UserLang,
+    ) -> Result<SystemMessages> {
+        let system_role = format!(
+            "You are provided with the interface messages of a chat bot as JSON. Translate every value into {}, keep the keys and the {{placeholders}} as is.",
+            language
+        );
+        let options = LlmRequestOptions {
+            temperature: Some(0.2),
+            ..LlmRequestOptions::default()
+        };
+        self.nervo_llm
+            .complete_json(&system_role, &serde_json::to_string(catalog)?, &options)
+            .await
+    }
+
+    /// Translates the reply into the user's language. Replies which are in the language
+    /// already are returned as is, the translations are cached in LocalDb.
+    pub async fn translate(&self, text: &str, language: &UserLang) -> Result<String> {
+        self.metrics.requested.fetch_add(1, Ordering::Relaxed);
+        let written = written_in(text, language);
+        if written == Some(true) {
+            info!("The reply is in {} already", language);
+            self.metrics.skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(text.to_string());
+        }
+
+        let content_hash = Sha256Generator::digest_hex_str(text.as_bytes());
+        let language_code = language.code();
+        let cached_translation = self
+            .local_db
+            .cached_translation(&content_hash, &language_code)
+            .await?;
+        if let Some(translation) = cached_translation {
+            info!("Translation on {} is taken from the cache", language);
+            self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(translation);
+        }
+
+        // The script can't tell the language, so LLM detects it. The reply is cached
+        // as its own translation, so the detection runs once per reply
+        let translation = if written.is_none() && self.is_in_language(text, language).await? {
+            info!("The reply is in {} already", language);
+            self.metrics.skipped.fetch_add(1, Ordering::Relaxed);
+            text.to_string()
+        } else {
+            self.metrics.translated.fetch_add(1, Ordering::Relaxed);
+            self.translate_with_llm(text, language).await?
+        };
+        self.local_db
+            .cache_translation(&content_hash, &language_code, &translation)
+            .await?;
+        info!("Translation stats: {:?}", self.translation_stats());
+        Ok(translation)
+    }
+
+    pub fn translation_stats(&self) -> TranslationStats {
+        TranslationStats {
+            requested: self.metrics.requested.load(Ordering::Relaxed),
+            skipped: self.metrics.skipped.load(Ordering::Relaxed),
+            cache_hits: self.metrics.cache_hits.load(Ordering::Relaxed),
+            translated: self.metrics.translated.load(Ordering::Relaxed),
         }
-        Ok(())
     }
 
-    pub async fn translate(&self, text: &str) -> Result<String> {
+    /// Detects the language by LLM, the regional variants count as the same language
+    async fn is_in_language(&self, text: &str, language: &UserLang) -> Result<bool> {
+        let detected = self.detect_language(text).await?;
+        Ok(detected.primary_code() == language.primary_code())
+    }
+
+    async fn translate_with_llm(&self, text: &str, language: &UserLang) -> Result<String> {
         info!("Starting translation");
-        let language = self.user_language.to_string();
+        let language = language.to_string();
         let system_role_instructions = format!("You are provided with: the user’s language - {}, as well as: the ready response for the user - {}. Your task: Translate the ready response for the user into the user’s language.", language, text);
-        let translation_layer = QdrantSearchLayer {
-            index: None,
-            user_role_params: vec![],
-            system_role_text: system_role_instructions.to_string(),
-            temperature: 0.2,
-            max_tokens: 4096,
-            common_token_limit: 30000,
-            vectors_limit: 0,
-            layer_for_search: false,
-        };
+        let translation_layer = QdrantSearchLayer::new(system_role_instructions);
 
+        let options = LlmRequestOptions::from(&translation_layer);
         let system_role_msg = formation_system_role_llm_message(translation_layer).await?;
 
         info!(
@@ -85,12 +189,82 @@ impl LocalisationManager {
             chat_id: None,
             messages: vec![system_role_msg],
         };
-        let llm_response = self.nervo_llm.send_msg_batch(chat).await?;
+        let llm_response = self.nervo_llm.send_msg_batch(chat, &options).await?;
         info!("Translated on {} response is {}", language, llm_response);
         Ok(llm_response)
     }
 }
 
+/// Whether the text is in the language, judged by its letters without asking LLM.
+/// Another script means another language, but a script is shared by many languages,
+/// so only plain ASCII passes as English and only the letters no other Cyrillic language
+/// has pass as Russian. `None` if the letters can't tell and LLM has to detect it
+pub fn written_in(text: &str, language: &UserLang) -> Option<bool> {
+    let Some(script) = script(text) else {
+        // Nothing to translate
+        return Some(true);
+    };
+    let mut letters = text.chars().filter(|ch| ch.is_alphabetic());
+
+    match (language, script) {
+        (UserLang::Ru, Script::Cyrillic) => {
+            let letters: Vec<char> = letters.collect();
+            if letters.iter().any(|ch| NON_RUSSIAN_CYRILLIC.contains(ch)) {
+                Some(false)
+            } else if letters.iter().any(|ch| RUSSIAN_ONLY.contains(ch)) {
+                Some(true)
+            } else {
+                None
+            }
+        }
+        (UserLang::En | UserLang::None, Script::Latin) => {
+            letters.all(|ch| ch.is_ascii()).then_some(true)
+        }
+        (UserLang::Ru | UserLang::En | UserLang::None, _) => Some(false),
+        (UserLang::Other(_), _) => None,
+    }
+}
+
+/// Russian letters Ukrainian, Belarusian and Bulgarian don't have all together
+const RUSSIAN_ONLY: [char; 6] = ['ы', 'Ы', 'э', 'Э', 'ё', 'Ё'];
+
+/// Letters of the other Cyrillic languages, i.e. Ukrainian `і`, `ї`, `є`, `ґ`,
+/// Belarusian `ў` and Serbian `ђ`, `ј`, `љ`, `њ`, `ћ`, `џ`
+const NON_RUSSIAN_CYRILLIC: [char; 22] = [
+    'і', 'І', 'ї', 'Ї', 'є', 'Є', 'ґ', 'Ґ', 'ў', 'Ў', 'ђ', 'Ђ', 'ј', 'Ј', 'љ', 'Љ', 'њ', 'Њ', 'ћ',
+    'Ћ', 'џ', 'Џ',
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Script {
+    Cyrillic,
+    Latin,
+    Other,
+}
+
+/// The script of the most of the letters, `None` for the text without letters
+fn script(text: &str) -> Option<Script> {
+    let (mut cyrillic, mut latin, mut other) = (0, 0, 0);
+    for ch in text.chars().filter(|ch| ch.is_alphabetic()) {
+        match ch {
+            '\u{0400}'..='\u{04FF}' => cyrillic += 1,
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => latin += 1,
+            _ => other += 1,
+        }
+    }
+
+    if cyrillic + latin + other == 0 {
+        None
+    } else if cyrillic > latin && cyrillic > other {
+        Some(Script::Cyrillic)
+    } else if latin >= other {
+        Some(Script::Latin)
+    } else {
+        Some(Script::Other)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum UserLang {
     Ru,
     En,
@@ -98,12 +272,57 @@ pub enum UserLang {
     None,
 }
 
+impl UserLang {
+    /// Code of the language in the catalog names, i.e. `system_messages.en.json`
+    pub fn code(&self) -> String {
+        match self {
+            UserLang::Ru => "ru".to_string(),
+            UserLang::En | UserLang::None => "en".to_string(),
+            UserLang::Other(lang) => lang.clone(),
+        }
+    }
+
+    /// The language without the region, i.e. `pt` for `pt-br`
+    pub fn primary_code(&self) -> String {
+        let code = self.code();
+        code.split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+/// Codes of the language names LLM and the older records use
+const LANGUAGE_NAMES: [(&str, &str); 14] = [
+    ("arabic", "ar"),
+    ("chinese", "zh"),
+    ("french", "fr"),
+    ("german", "de"),
+    ("hebrew", "he"),
+    ("italian", "it"),
+    ("japanese", "ja"),
+    ("kazakh", "kk"),
+    ("korean", "ko"),
+    ("polish", "pl"),
+    ("portuguese", "pt"),
+    ("spanish", "es"),
+    ("turkish", "tr"),
+    ("ukrainian", "uk"),
+];
+
+/// Accepts language names and codes, including Telegram locales like `en-US`
 impl From<&str> for UserLang {
     fn from(lang_string: &str) -> Self {
-        match lang_string {
+        let lang_string = lang_string.trim().to_lowercase();
+        let code = lang_string.split(['-', '_']).next().unwrap_or_default();
+        match code {
             "english" | "eng" | "en" => UserLang::En,
             "russian" | "rus" | "ru" => UserLang::Ru,
-            _ => UserLang::Other(lang_string.to_string()),
+            "" => UserLang::None,
+            _ => match LANGUAGE_NAMES.iter().find(|(name, _)| *name == lang_string) {
+                Some((_, code)) => UserLang::Other(code.to_string()),
+                None => UserLang::Other(lang_string),
+            },
         }
     }
 }
@@ -119,3 +338,122 @@ impl Display for UserLang {
         write!(f, "{}", str)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::db::local_db::test::temp_db;
+    use crate::utils::localisation_parser::{
+        script, written_in, LocalisationManager, Script, TranslationStats, UserLang,
+    };
+
+    #[test]
+    fn test_user_lang_from_locale() {
+        assert_eq!(UserLang::from("en-US"), UserLang::En);
+        assert_eq!(UserLang::from("Russian"), UserLang::Ru);
+        assert_eq!(
+            UserLang::from("pt-br"),
+            UserLang::Other("pt-br".to_string())
+        );
+        assert_eq!(
+            UserLang::from(UserLang::Ru.to_string().as_str()),
+            UserLang::Ru
+        );
+        assert_eq!(UserLang::None.code(), "en");
+        assert_eq!(UserLang::from("pt-BR").code(), "pt-br");
+        assert_eq!(
+            UserLang::from("Portuguese"),
+            UserLang::Other("pt".to_string())
+        );
+        assert_eq!(UserLang::from("pt-br").primary_code(), "pt");
+    }
+
+    #[test]
+    fn test_script() {
+        assert_eq!(
+            script("Один момент, сейчас отвечу!"),
+            Some(Script::Cyrillic)
+        );
+        assert_eq!(script("Use `cargo` в терминале"), Some(Script::Cyrillic));
+        assert_eq!(script("One moment, please"), Some(Script::Latin));
+        assert_eq!(script("Um momento, está bem"), Some(Script::Latin));
+        assert_eq!(script("少々お待ちください"), Some(Script::Other));
+        assert_eq!(script("42 + 7 = 49 :)"), None);
+    }
+
+    #[test]
+    fn test_written_in() {
+        assert_eq!(written_in("Вы это уже знаете", &UserLang::Ru), Some(true));
+        assert_eq!(written_in("Один момент", &UserLang::Ru), None);
+        assert_eq!(written_in("Ви це вже знаєте", &UserLang::Ru), Some(false));
+        assert_eq!(written_in("Schönen Tag noch", &UserLang::En), None);
+        assert_eq!(written_in("One moment", &UserLang::Ru), Some(false));
+        assert_eq!(written_in("One moment", &UserLang::None), Some(true));
+        assert_eq!(written_in("42", &UserLang::En), Some(true));
+        assert_eq!(written_in("少々お待ちください", &UserLang::En), Some(false));
+        assert_eq!(written_in("Um momento", &UserLang::from("pt-br")), None);
+    }
+
+    #[tokio::test]
+    async fn test_translate_skips_and_caches() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        // Only the first round reaches LLM: two detections and a translation,
+        // the rest is served by the cache and the script check
+        let llm = fake_llm(FakeLlmProvider::with_replies(vec!["pt", "en", "Olá"]));
+        let manager = LocalisationManager::build(llm, local_db.clone())?;
+        let portuguese = UserLang::from("pt-br");
+
+        for _ in 0..2 {
+            assert_eq!(
+                manager.translate("Um momento", &portuguese).await?,
+                "Um momento"
+            );
+            assert_eq!(manager.translate("Hello", &portuguese).await?, "Olá");
+        }
+        assert_eq!(
+            manager.translate("Привет, как вы?", &UserLang::Ru).await?,
+            "Привет, как вы?"
+        );
+
+        assert_eq!(
+            manager.translation_stats(),
+            TranslationStats {
+                requested: 5,
+                skipped: 2,
+                cache_hits: 2,
+                translated: 1,
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_translate_replies_in_another_language_of_the_script() -> anyhow::Result<()> {
+        let local_db = temp_db();
+        local_db.init_db().await?;
+        // The German reply is detected by LLM, the Ukrainian one by its letters
+        let llm = fake_llm(FakeLlmProvider::with_replies(vec![
+            "de",
+            "Thank you for waiting",
+            "Спасибо за терпение",
+        ]));
+        let manager = LocalisationManager::build(llm, local_db.clone())?;
+
+        assert_eq!(
+            manager
+                .translate("Danke fürs Warten", &UserLang::En)
+                .await?,
+            "Thank you for waiting"
+        );
+        assert_eq!(
+            manager
+                .translate("Дякую за терпіння", &UserLang::Ru)
+                .await?,
+            "Спасибо за терпение"
+        );
+        assert_eq!(manager.translation_stats().translated, 2);
+        Ok(())
+    }
+}
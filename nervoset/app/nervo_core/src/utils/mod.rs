@@ -1,4 +1,7 @@
 pub mod ai_utils;
 pub mod ai_utils_data;
+pub mod citations;
+pub mod context_builder;
 pub mod date_time_utils;
 pub mod localisation_parser;
+pub mod rag_engine;
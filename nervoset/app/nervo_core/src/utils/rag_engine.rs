@@ -0,0 +1,435 @@
+use crate::ai::ai_db::NervoAiDb;
+use crate::ai::nervo_llm::{LlmRequestOptions, NervoLlm};
+use crate::ai::reranker::{build_reranker, mmr};
+use crate::ai::vector_store::VectorHit;
+use crate::models::rag_pipeline::{
+    variables, ClassifyStep, LlmPrompt, PostProcessStep, RagPipeline, RagStepKind, RerankStep,
+    VectorSearchStep,
+};
+use crate::utils::ai_utils::{
+    concatenate_results, filter_search_result, update_search_content, RESOURCES_DIR,
+};
+use crate::utils::ai_utils_data::TruncatingType::Truncated;
+use crate::utils::ai_utils_data::{SortingType, TruncatingType};
+use crate::utils::citations::Citations;
+use crate::utils::context_builder::ConversationHistory;
+use anyhow::{anyhow, bail};
+use nervo_sdk::api::spec::{
+    LlmChat, LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence,
+    LlmMessageRole,
+};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Protects from pipelines that branch in circles
+const MAX_PIPELINE_STEPS: usize = 64;
+
+pub type RagVariables = HashMap<String, String>;
+
+/// Result of a pipeline run: the chat to ask LLM for the final answer
+#[derive(Debug)]
+pub struct RagOutcome {
+    pub chat: LlmChat,
+    pub options: LlmRequestOptions,
+    pub temporal: bool,
+    pub variables: RagVariables,
+    /// Numbered chunks the prompts were given
+    pub citations: Citations,
+}
+
+pub struct RagPipelineEngine<'a> {
+    pub nervo_llm: &'a NervoLlm,
+    pub nervo_ai_db: &'a NervoAiDb,
+    pub agent_type_name: &'a str,
+    pub chat_id: u64,
+    /// Rendered into the `history` variable within the budget of every prompt
+    pub history: &'a ConversationHistory,
+}
+
+impl RagPipelineEngine<'_> {
+    pub fn initial_variables(user_prompt: &str, history: &str) -> RagVariables {
+        HashMap::from([
+            (variables::USER_PROMPT.to_string(), user_prompt.to_string()),
+            (
+                variables::REPHRASED_PROMPT.to_string(),
+                user_prompt.to_string(),
+            ),
+            (variables::HISTORY.to_string(), history.to_string()),
+            (variables::DB_SEARCH.to_string(), String::new()),
+        ])
+    }
+
+    pub async fn run(
+        &self,
+        pipeline: &RagPipeline,
+        mut variables: RagVariables,
+    ) -> anyhow::Result<RagOutcome> {
+        let mut hits: HashMap<String, Vec<VectorHit>> = HashMap::new();
+        let mut citations = Citations::default();
+
+        let mut step_index = match &pipeline.start {
+            Some(start) => pipeline
+                .step(start)
+                .map(|(index, _)| index)
+                .ok_or_else(|| anyhow!("Unknown pipeline step '{}'", start))?,
+            None => 0,
+        };
+
+        for _ in 0..MAX_PIPELINE_STEPS {
+            let Some(step) = pipeline.steps.get(step_index) else {
+                bail!("Pipeline ended without a generate step");
+            };
+            info!("Pipeline step: {}", step.name);
+
+            let mut next_step = step.next.clone();
+            match &step.kind {
+                RagStepKind::Rewrite(rewrite) => {
+                    let reply = self.ask_llm(&rewrite.prompt, &variables).await?;
+                    variables.insert(rewrite.output.clone(), reply);
+                }
+                RagStepKind::Classify(classify) => {
+                    let reply = self.ask_llm(&classify.prompt, &variables).await?;
+                    let label = match_label(classify, &reply);
+                    info!("Request classified as {}", label);
+                    variables.insert(classify.output.clone(), label);
+                }
+                RagStepKind::Branch(branch) => {
+                    let value = variables.get(&branch.variable).cloned().unwrap_or_default();
+                    let target = branch.cases.get(&value).unwrap_or(&branch.default);
+                    next_step = Some(target.clone());
+                }
+                RagStepKind::VectorSearch(search) => {
+                    let found = self
+                        .vector_search(search, &variables, &mut citations)
+                        .await?;
+                    variables.insert(search.output.clone(), found.0);
+                    hits.insert(search.output.clone(), found.1);
+                }
+                RagStepKind::Rerank(rerank) => {
+                    let found = self
+                        .rerank_hits(rerank, &variables, &hits, &mut citations)
+                        .await?;
+                    variables.insert(rerank.output.clone(), found.0);
+                    hits.insert(rerank.output.clone(), found.1);
+                }
+                RagStepKind::PostProcess(post_process) => {
+                    let text = post_process_text(post_process, &variables)?;
+                    variables.insert(post_process.output.clone(), text);
+                }
+                RagStepKind::Generate(generate) => {
+                    let chat = self.layer_chat(&generate.prompt, &variables)?;
+                    return Ok(RagOutcome {
+                        chat,
+                        options: generate.prompt.options.clone(),
+                        temporal: generate.temporal,
+                        variables,
+                        citations,
+                    });
+                }
+            }
+
+            step_index = match next_step {
+                Some(name) => pipeline
+                    .step(&name)
+                    .map(|(index, _)| index)
+                    .ok_or_else(|| anyhow!("Unknown pipeline step '{}'", name))?,
+                None => step_index + 1,
+            };
+        }
+
+        bail!("Pipeline made more than {} steps", MAX_PIPELINE_STEPS)
+    }
+
+    async fn ask_llm(
+        &self,
+        prompt: &LlmPrompt,
+        variables: &RagVariables,
+    ) -> anyhow::Result<String> {
+        let chat = self.layer_chat(prompt, variables)?;
+        info!("Making chat with llm and prepared user and system roles");
+        self.nervo_llm.send_msg_batch(chat, &prompt.options).await
+    }
+
+    fn layer_chat(&self, prompt: &LlmPrompt, variables: &RagVariables) -> anyhow::Result<LlmChat> {
+        let mut messages = vec![];
+
+        if let Some(system_role_text) = self.system_role_text(prompt)? {
+            messages.push(LlmMessage {
+                meta_info: LlmMessageMetaInfo {
+                    sender_id: None,
+                    role: LlmMessageRole::System,
+                    persistence: LlmMessagePersistence::Persistent,
+                },
+                content: LlmMessageContent::from(system_role_text.as_str()),
+                sources: vec![],
+            });
+        }
+
+        let mut user_role_full_text = String::new();
+        for parameter in &prompt.user_role_params {
+            let value = match prompt.history_token_limit {
+                Some(token_limit) if parameter.variable == variables::HISTORY => {
+                    self.history.render(Some(token_limit))?
+                }
+                _ => variables
+                    .get(&parameter.variable)
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            let part = format!("{:?}{:?}\n", parameter.param_value, value);
+            user_role_full_text.push_str(&part)
+        }
+        info!("User Role full text: {}", user_role_full_text);
+
+        messages.push(LlmMessage {
+            meta_info: LlmMessageMetaInfo {
+                sender_id: None,
+                role: LlmMessageRole::User,
+                persistence: LlmMessagePersistence::Persistent,
+            },
+            content: LlmMessageContent::from(user_role_full_text.as_str()),
+            sources: vec![],
+        });
+
+        Ok(LlmChat {
+            chat_id: Some(self.chat_id),
+            messages,
+        })
+    }
+
+    fn system_role_text(&self, prompt: &LlmPrompt) -> anyhow::Result<Option<String>> {
+        if let Some(text) = &prompt.system_role_text {
+            return Ok(Some(text.clone()));
+        }
+
+        match &prompt.system_role_file {
+            Some(file) => {
+                let path = format!("{}{}/{}", RESOURCES_DIR, self.agent_type_name, file);
+                Ok(Some(std::fs::read_to_string(path)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn vector_search(
+        &self,
+        search: &VectorSearchStep,
+        variables: &RagVariables,
+        citations: &mut Citations,
+    ) -> anyhow::Result<(String, Vec<VectorHit>)> {
+        info!("Need to ask vector DB to get some info");
+        let query = variables.get(&search.query).cloned().unwrap_or_default();
+        let collection_name = search.collection.as_deref().unwrap_or(self.agent_type_name);
+
+        let db_search_response = self
+            .nervo_ai_db
+            .query_text(
+                collection_name,
+                query,
+                search.vectors_limit,
+                search.hybrid,
+                search.filter.clone(),
+                Some(search.min_score),
+            )
+            .await?;
+
+        // The store has dropped the vector hits below min_score, the fused scores are ranks
+        let min_score = if search.hybrid {
+            f32::MIN
+        } else {
+            search.min_score
+        };
+        let all_search_results = filter_search_result(
+            db_search_response,
+            SortingType::Descending,
+            Truncated(search.top_k),
+            min_score,
+        )?;
+
+        let scores_string = all_search_results
+            .iter()
+            .map(|element| element.score.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        info!("All_search_result scores {}", scores_string);
+
+        let text = hits_text(&all_search_results, search.token_limit, citations)?;
+        Ok((text, all_search_results))
+    }
+
+    async fn rerank_hits(
+        &self,
+        rerank: &RerankStep,
+        variables: &RagVariables,
+        hits: &HashMap<String, Vec<VectorHit>>,
+        citations: &mut Citations,
+    ) -> anyhow::Result<(String, Vec<VectorHit>)> {
+        let Some(input_hits) = hits.get(&rerank.input) else {
+            bail!("No search results in '{}' to rerank", rerank.input);
+        };
+        let query = variables.get(&rerank.query).cloned().unwrap_or_default();
+
+        let reranker = build_reranker(&rerank.reranker, self.nervo_llm);
+        let rescored = reranker.rerank(&query, input_hits.clone()).await?;
+        let truncating = match rerank.mmr_lambda {
+            Some(_) => TruncatingType::None,
+            None => Truncated(rerank.top_k),
+        };
+        let mut reranked = filter_search_result(
+            rescored,
+            SortingType::Descending,
+            truncating,
+            rerank.min_score,
+        )?;
+        if let Some(lambda) = rerank.mmr_lambda {
+            reranked = mmr(reranked, lambda, rerank.top_k);
+        }
+        info!("Reranked {} hits into {}", input_hits.len(), reranked.len());
+
+        let text = hits_text(&reranked, rerank.token_limit, citations)?;
+        Ok((text, reranked))
+    }
+}
+
+fn match_label(classify: &ClassifyStep, reply: &str) -> String {
+    let reply = reply.trim().trim_matches(|c: char| !c.is_alphanumeric());
+    classify
+        .labels
+        .iter()
+        .find(|label| label.eq_ignore_ascii_case(reply))
+        .unwrap_or(&classify.default_label)
+        .clone()
+}
+
+fn hits_text(
+    hits: &[VectorHit],
+    token_limit: usize,
+    citations: &mut Citations,
+) -> anyhow::Result<String> {
+    let concatenated_texts = concatenate_results(hits.to_vec(), citations)?;
+    update_search_content(token_limit, concatenated_texts)
+}
+
+fn post_process_text(
+    post_process: &PostProcessStep,
+    variables: &RagVariables,
+) -> anyhow::Result<String> {
+    let input = variables
+        .get(&post_process.input)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut text = match &post_process.template {
+        Some(template) => {
+            let mut text = template.replace("{{input}}", &input);
+            for (name, value) in variables {
+                text = text.replace(&format!("{{{{{}}}}}", name), value);
+            }
+            text
+        }
+        None => input,
+    };
+
+    if post_process.trim {
+        text = text.trim().to_string();
+    }
+
+    if let Some(token_limit) = post_process.token_limit {
+        text = update_search_content(token_limit, text)?;
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ai::ai_db::NervoAiDb;
+    use crate::ai::fake_llm_provider::FakeLlmProvider;
+    use crate::ai::memory_vector_store::InMemoryVectorStore;
+    use crate::ai::nervo_llm::test::fake_llm;
+    use crate::models::rag_pipeline::RagPipeline;
+    use crate::utils::context_builder::ConversationHistory;
+    use crate::utils::rag_engine::RagPipelineEngine;
+    use std::sync::Arc;
+
+    const PIPELINE: &str = r#"{
+        "steps": [
+            {
+                "name": "detect",
+                "type": "classify",
+                "systemRoleText": "Say SKIP or QUESTION",
+                "userRoleParams": [{"variable": "userPrompt", "paramValue": "Request: "}],
+                "labels": ["SKIP"],
+                "defaultLabel": "QUESTION",
+                "output": "kind"
+            },
+            {
+                "name": "route",
+                "type": "branch",
+                "variable": "kind",
+                "cases": {"SKIP": "smallTalk"},
+                "default": "search"
+            },
+            {"name": "smallTalk", "type": "generate", "temporal": true},
+            {
+                "name": "search",
+                "type": "vectorSearch",
+                "query": "userPrompt",
+                "vectorsLimit": 5,
+                "minScore": 0.1,
+                "tokenLimit": 100,
+                "output": "dbSearch"
+            },
+            {
+                "name": "shape",
+                "type": "postProcess",
+                "input": "dbSearch",
+                "template": "Facts: {{input}}",
+                "output": "facts"
+            },
+            {
+                "name": "answer",
+                "type": "generate",
+                "userRoleParams": [{"variable": "facts", "paramValue": ""}]
+            }
+        ]
+    }"#;
+
+    async fn run_pipeline(classification: &str) -> anyhow::Result<super::RagOutcome> {
+        let nervo_llm = fake_llm(FakeLlmProvider::with_replies(vec![classification]));
+        let nervo_ai_db =
+            NervoAiDb::with_store(Arc::new(InMemoryVectorStore::default()), nervo_llm.clone());
+        nervo_ai_db.save_text("test", "cats like warm milk").await?;
+
+        let pipeline: RagPipeline = serde_json::from_str(PIPELINE)?;
+        pipeline.validate()?;
+
+        let engine = RagPipelineEngine {
+            nervo_llm: &nervo_llm,
+            nervo_ai_db: &nervo_ai_db,
+            agent_type_name: "test",
+            chat_id: 1,
+            history: &ConversationHistory::default(),
+        };
+        let variables = RagPipelineEngine::initial_variables("what do cats like", "");
+        engine.run(&pipeline, variables).await
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_branches_on_label() -> anyhow::Result<()> {
+        let small_talk = run_pipeline("skip.").await?;
+        assert!(small_talk.temporal);
+        assert_eq!(small_talk.variables["kind"], "SKIP");
+
+        let question = run_pipeline("It is a question").await?;
+        assert!(!question.temporal);
+        assert_eq!(question.variables["kind"], "QUESTION");
+        assert_eq!(
+            question.variables["facts"],
+            "Facts: [1] cats like warm milk"
+        );
+        let user_text = question.chat.messages.last().unwrap().content.text();
+        assert!(user_text.contains("cats like warm milk"));
+        Ok(())
+    }
+}
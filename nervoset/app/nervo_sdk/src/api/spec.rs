@@ -43,6 +43,9 @@ pub struct LlmMessageMetaInfo {
 pub struct LlmMessage {
     pub meta_info: LlmMessageMetaInfo,
     pub content: LlmMessageContent,
+    /// Knowledge base chunks the content cites with the `[number]` markers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<LlmMessageSource>,
 }
 
 impl LlmMessage {
@@ -53,6 +56,73 @@ impl LlmMessage {
             LlmMessageRole::Assistant => String::from("assistant"),
         }
     }
+
+    /// A line per cited source after an empty line, empty if nothing is cited
+    pub fn footnotes(&self) -> String {
+        if self.sources.is_empty() {
+            return String::new();
+        }
+        let lines: Vec<String> = self
+            .sources
+            .iter()
+            .map(|source| source.footnote())
+            .collect();
+        format!("\n\n{}", lines.join("\n"))
+    }
+}
+
+#[wasm_bindgen]
+impl LlmMessage {
+    pub fn text_with_footnotes(&self) -> String {
+        format!("{}{}", self.content.0, self.footnotes())
+    }
+}
+
+/// Retrieved chunk the answer refers to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[wasm_bindgen(getter_with_clone)]
+pub struct LlmMessageSource {
+    /// Number of the `[number]` marker
+    pub number: u32,
+    /// Dataset document the chunk was made of
+    pub source: Option<String>,
+    pub text: String,
+}
+
+const FOOTNOTE_TEXT_CHARS: usize = 80;
+
+#[wasm_bindgen]
+impl LlmMessageSource {
+    /// `[number] source`, or the beginning of the chunk if its source is unknown
+    pub fn footnote(&self) -> String {
+        let title = match &self.source {
+            Some(source) => source.clone(),
+            None if self.text.chars().count() > FOOTNOTE_TEXT_CHARS => {
+                let beginning: String = self.text.chars().take(FOOTNOTE_TEXT_CHARS).collect();
+                format!("{}…", beginning.trim_end())
+            }
+            None => self.text.clone(),
+        };
+        format!("[{}] {}", self.number, title)
+    }
+}
+
+/// Server-sent event of a streamed LLM reply
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum LlmStreamEvent {
+    /// Next piece of the reply text
+    Delta {
+        text: String,
+    },
+    /// The whole reply, as it was saved to the chat history
+    Done {
+        message: LlmMessage,
+    },
+    Error {
+        message: String,
+    },
 }
 
 #[derive(Clone, Debug, Copy, Serialize, Deserialize)]
@@ -128,3 +198,43 @@ pub struct ServerResponse {
     pub action_buttons: Vec<String>,
     pub can_input: bool,
 }
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MemoryKind {
+    /// A fact the agent has concluded about the user
+    Conclusion,
+    /// A request of the user along with the agent reply, a memory cell.
+    /// Payloads saved before the rename keep `memoryCell`
+    #[serde(alias = "memoryCell")]
+    Interaction,
+}
+
+/// Something the agent remembers about the user
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[wasm_bindgen(getter_with_clone)]
+pub struct MemoryRecord {
+    pub id: String,
+    pub kind: MemoryKind,
+    pub timestamp: Option<String>,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[wasm_bindgen(getter_with_clone)]
+pub struct UserMemoryExport {
+    pub user_id: u64,
+    pub conclusions: Vec<MemoryRecord>,
+    pub memory_cells: Vec<MemoryRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[wasm_bindgen(getter_with_clone)]
+pub struct FindMemoryRequest {
+    /// The memories close to the text by meaning are found
+    pub text: String,
+}
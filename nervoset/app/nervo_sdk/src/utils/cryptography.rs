@@ -22,6 +22,11 @@ impl Sha256Generator {
 
         hex::encode(hasher.finalize())
     }
+
+    /// Digest of the data, i.e. to key the caches by content
+    pub fn digest_hex_str(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
 }
 
 pub struct U64Generator {}
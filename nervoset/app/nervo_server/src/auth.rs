@@ -0,0 +1,97 @@
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who may read and change the memory of a user: the backend with the server API key,
+/// or the user with the token signed by it, i.e. `hex(HMAC-SHA256(api_key, user_id))`.
+/// Tokens are sent as `Authorization: Bearer <token>`
+#[derive(Clone)]
+pub struct MemoryAuth {
+    api_key: Option<Arc<str>>,
+}
+
+impl MemoryAuth {
+    pub fn new(api_key: Option<String>) -> Self {
+        if api_key.is_none() {
+            warn!("Server API key is not set, the memory endpoints reject every request");
+        }
+        MemoryAuth {
+            api_key: api_key.map(Arc::from),
+        }
+    }
+
+    fn allows(&self, token: &str, user_id: u64) -> bool {
+        let Some(api_key) = &self.api_key else {
+            return false;
+        };
+        if bool::from(token.as_bytes().ct_eq(api_key.as_bytes())) {
+            return true;
+        }
+
+        let Ok(signature) = hex::decode(token) else {
+            return false;
+        };
+        user_token_mac(api_key, user_id)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+}
+
+fn user_token_mac(api_key: &str, user_id: u64) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(api_key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(user_id.to_string().as_bytes());
+    mac
+}
+
+/// Lets through only the requests made by the user of the path or by the backend
+pub async fn require_memory_access(
+    State(auth): State<MemoryAuth>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let user_id = params
+        .get("user_id")
+        .and_then(|user_id| user_id.parse::<u64>().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if !auth.allows(token, user_id) {
+        warn!("Rejected access to the memory of the user {}", user_id);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::{user_token_mac, MemoryAuth};
+    use hmac::Mac;
+
+    #[test]
+    fn test_memory_auth() {
+        let auth = MemoryAuth::new(Some("server-key".to_string()));
+        let user_token = hex::encode(user_token_mac("server-key", 7).finalize().into_bytes());
+
+        assert!(auth.allows("server-key", 7));
+        assert!(auth.allows(&user_token, 7));
+        assert!(!auth.allows(&user_token, 8));
+        assert!(!auth.allows("other-key", 7));
+        assert!(!MemoryAuth::new(None).allows(&user_token, 7));
+    }
+}
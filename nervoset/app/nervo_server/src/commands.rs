@@ -1,11 +1,15 @@
 use axum::extract::State;
 use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::Json;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use nervo_bot_core::ai::nervo_llm::LlmRequestOptions;
 use nervo_bot_core::config::jarvis::JarvisAppState;
-use nervo_bot_core::utils::ai_utils::llm_conversation;
+use nervo_bot_core::utils::ai_utils::{llm_conversation, llm_conversation_stream};
 use nervo_sdk::api::spec::{
     LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence, LlmMessageRole,
-    SendMessageRequest, ServerResponse, UserAction,
+    LlmStreamEvent, SendMessageRequest, ServerResponse, UserAction,
 };
 use std::sync::Arc;
 use tracing::{error, info};
@@ -33,6 +37,47 @@ pub async fn send_message(
     }
 }
 
+pub type SseStream = BoxStream<'static, Result<Event, axum::Error>>;
+
+pub async fn send_message_stream(
+    State(state): State<Arc<JarvisAppState>>,
+    Json(msg_request): Json<SendMessageRequest>,
+) -> Result<Sse<SseStream>, StatusCode> {
+    let LlmMessageContent(content) = &msg_request.llm_message.content;
+
+    let is_moderation_passed = state
+        .nervo_llm
+        .moderate(content.as_str())
+        .await
+        .map_err(|err| {
+            error!("Error {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    info!("Is moderation passed: {:?}", is_moderation_passed);
+    let events = if is_moderation_passed {
+        let agent_type = msg_request.agent_type;
+        llm_conversation_stream(state, msg_request, agent_type)
+            .await
+            .map_err(|err| {
+                error!("Error {:?}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+    } else {
+        // Moderation reply is short, there is nothing to stream
+        let Json(message) = fail_path_of_moderation(state, msg_request).await?;
+        let delta = LlmStreamEvent::Delta {
+            text: message.content.text(),
+        };
+        stream::iter(vec![delta, LlmStreamEvent::Done { message }]).boxed()
+    };
+
+    let sse_events = events
+        .map(|event| Event::default().json_data(event))
+        .boxed();
+    Ok(Sse::new(sse_events).keep_alive(KeepAlive::default()))
+}
+
 async fn happy_path_of_moderation(
     app_state: Arc<JarvisAppState>,
     msg_request: SendMessageRequest,
@@ -73,12 +118,13 @@ async fn fail_path_of_moderation(
                 persistence: LlmMessagePersistence::Temporal,
             },
             content,
+            sources: vec![],
         }
     };
 
     let reply_text = app_state
         .nervo_llm
-        .send_msg(user_question, msg.chat_id)
+        .send_msg(user_question, msg.chat_id, &LlmRequestOptions::default())
         .await
         .map_err(|err| {
             error!("Error {:?}", err);
@@ -93,6 +139,7 @@ async fn fail_path_of_moderation(
             persistence: LlmMessagePersistence::Temporal,
         },
         content: LlmMessageContent(reply_text),
+        sources: vec![],
     };
 
     Ok(Json(llm_response))
@@ -1,21 +1,27 @@
+mod auth;
 mod commands;
+mod memory;
 mod queries;
 
+use crate::auth::{require_memory_access, MemoryAuth};
 use crate::commands::{
     handle_main_menu, handle_start_button_click, mini_app_initializing, send_message,
+    send_message_stream,
 };
+use crate::memory::{delete_memory, export_memory, find_memory, list_conclusions, wipe_memory};
 use crate::queries::chat;
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Json, Router,
 };
-use http::{StatusCode, Uri};
+use http::{header, HeaderValue, Method, StatusCode, Uri};
 use nervo_bot_core::config::common::NervoConfig;
 use nervo_bot_core::config::jarvis::JarvisAppState;
 use serde_derive::Serialize;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tower_http::cors::CorsLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
 use tracing::{info, Level};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
@@ -38,26 +44,53 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Starting Server...");
 
-    let app_state = {
-        info!("Loading config...");
-        let nervo_config = NervoConfig::load()?;
-        Arc::from(JarvisAppState::try_from(nervo_config.apps.jarvis)?)
-    };
+    info!("Loading config...");
+    let nervo_config = NervoConfig::load()?;
+    let server_params = nervo_config.apps.server;
+    let app_state = Arc::from(JarvisAppState::try_from(nervo_config.apps.jarvis)?);
+    app_state.local_db.init_db().await?;
 
     let cors = CorsLayer::permissive();
 
+    // The memory of a user is given only to the user and to the backend
+    let memory_auth = MemoryAuth::new(server_params.api_key);
+    let allowed_origins = server_params
+        .allowed_origins
+        .iter()
+        .map(|origin| HeaderValue::from_str(origin))
+        .collect::<Result<Vec<_>, _>>()?;
+    let memory_cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::list(allowed_origins))
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]);
+    let memory_routes = Router::new()
+        .route(
+            "/memory/:user_id",
+            get(list_conclusions).delete(wipe_memory),
+        )
+        .route("/memory/:user_id/export", get(export_memory))
+        .route("/memory/:user_id/search", post(find_memory))
+        .route("/memory/:user_id/:memory_id", delete(delete_memory))
+        .route_layer(middleware::from_fn_with_state(
+            memory_auth,
+            require_memory_access,
+        ))
+        .layer(memory_cors);
+
     info!("Creating router...");
     let app = Router::new()
         .route("/chat/:chat_id", get(chat))
         .route("/send_message", post(send_message))
+        .route("/send_message_stream", post(send_message_stream))
         .route(
             "/user_action/mini_app_initializing",
             post(mini_app_initializing),
         ) // TEMPORARY post, will be changed later
         .route("/user_action/start", post(handle_start_button_click)) // TEMPORARY post, will be changed later
         .route("/user_action/main_menu", post(handle_main_menu)) // TEMPORARY post, will be changed later
-        .with_state(app_state)
         .layer(cors)
+        .merge(memory_routes)
+        .with_state(app_state)
         .layer(TraceLayer::new_for_http())
         .fallback(not_found_handler);
 
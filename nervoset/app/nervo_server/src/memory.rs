@@ -0,0 +1,77 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use nervo_bot_core::config::jarvis::JarvisAppState;
+use nervo_bot_core::context::user_memory::UserMemory;
+use nervo_sdk::api::spec::{FindMemoryRequest, MemoryRecord, UserMemoryExport};
+use std::sync::Arc;
+use tracing::error;
+
+fn internal_error(err: anyhow::Error) -> StatusCode {
+    error!("Error {:?}", err);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+pub async fn list_conclusions(
+    Path(user_id): Path<u64>,
+    State(state): State<Arc<JarvisAppState>>,
+) -> Result<Json<Vec<MemoryRecord>>, StatusCode> {
+    let memory = UserMemory::new(&state.nervo_ai_db, user_id);
+    let conclusions = memory.conclusions().await.map_err(internal_error)?;
+    Ok(Json(conclusions))
+}
+
+pub async fn export_memory(
+    Path(user_id): Path<u64>,
+    State(state): State<Arc<JarvisAppState>>,
+) -> Result<Json<UserMemoryExport>, StatusCode> {
+    let memory = UserMemory::new(&state.nervo_ai_db, user_id);
+    let export = memory.export().await.map_err(internal_error)?;
+    Ok(Json(export))
+}
+
+pub async fn delete_memory(
+    Path((user_id, memory_id)): Path<(u64, String)>,
+    State(state): State<Arc<JarvisAppState>>,
+) -> Result<StatusCode, StatusCode> {
+    let memory = UserMemory::new(&state.nervo_ai_db, user_id);
+    let deleted = memory.delete(&memory_id).await.map_err(internal_error)?;
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// The memories close to the text by meaning, the client deletes the confirmed ones by id
+pub async fn find_memory(
+    Path(user_id): Path<u64>,
+    State(state): State<Arc<JarvisAppState>>,
+    Json(request): Json<FindMemoryRequest>,
+) -> Result<Json<Vec<MemoryRecord>>, StatusCode> {
+    let memory = UserMemory::new(&state.nervo_ai_db, user_id);
+    let matching = memory
+        .matching(&request.text)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(matching))
+}
+
+pub async fn wipe_memory(
+    Path(user_id): Path<u64>,
+    State(state): State<Arc<JarvisAppState>>,
+) -> Result<StatusCode, StatusCode> {
+    let memory = UserMemory::new(&state.nervo_ai_db, user_id);
+    memory.wipe().await.map_err(internal_error)?;
+    state
+        .local_db
+        .clear_chats_of_user(user_id)
+        .await
+        .map_err(internal_error)?;
+    state
+        .user_context
+        .forget_dialogues_of_user(user_id)
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -14,7 +14,7 @@ pub async fn chat(
     info!("Read messages from DB");
     let cached_messages: Vec<LlmMessage> = state
         .local_db
-        .read_from_local_db(format!("{}", chat_id).as_str())
+        .read_messages_of_external_chat(chat_id)
         .await
         .map_err(|err| {
             error!("Error {:?}", err);
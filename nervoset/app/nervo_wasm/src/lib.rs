@@ -1,12 +1,16 @@
 use error_stack::ResultExt;
+use futures::StreamExt;
+use js_sys::Function;
 use nervo_sdk::agent_type::{AgentType, NervoAgentType};
 use nervo_sdk::api::spec::{
-    LlmChat, LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessageRole, SendMessageRequest,
-    ServerResponse, UserAction, UserActionType, UserLlmMessage,
+    LlmChat, LlmMessage, LlmMessageContent, LlmMessageMetaInfo, LlmMessagePersistence,
+    LlmMessageRole, LlmMessageSource, LlmStreamEvent, SendMessageRequest, ServerResponse,
+    UserAction, UserActionType, UserLlmMessage,
 };
 use pulldown_cmark::{html, Parser};
 use reqwest::Client;
 use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::JsValue;
 
 use crate::browser::nervo_wasm_store::NervoWasmStore;
 use crate::common::api_url::ApiUrl;
@@ -147,20 +151,7 @@ impl NervoClient {
             .iter()
             .map(|msg| match msg.meta_info.role {
                 LlmMessageRole::User => msg.clone(),
-                _ => {
-                    let markdown_text = msg.content.text();
-                    let html_text = markdown_to_html(&markdown_text);
-                    let content = LlmMessageContent::from(html_text.as_ref());
-
-                    LlmMessage {
-                        meta_info: LlmMessageMetaInfo {
-                            sender_id: msg.meta_info.sender_id,
-                            role: msg.meta_info.role,
-                            persistence: msg.meta_info.persistence,
-                        },
-                        content,
-                    }
-                }
+                _ => to_html_message(msg),
             })
             .collect();
 
@@ -171,25 +162,7 @@ impl NervoClient {
     }
 
     pub async fn send_message(&self, content: String) -> LlmMessage {
-        let chat_id = self
-            .nervo_store
-            .get_or_generate_chat_id()
-            .instrument(nweb_send_msg_span())
-            .await;
-        let user_id = self
-            .nervo_store
-            .get_or_generate_user_id()
-            .instrument(nweb_send_msg_span())
-            .await;
-
-        let json = SendMessageRequest {
-            chat_id,
-            agent_type: self.agent_type,
-            llm_message: UserLlmMessage {
-                sender_id: user_id,
-                content: LlmMessageContent(content),
-            },
-        };
+        let json = self.send_message_request(content).await;
 
         let url = format!("{}/send_message", self.api_url.get_url());
         info!("Send msg url {:?} with json: {:?}", url, json);
@@ -214,18 +187,90 @@ impl NervoClient {
             .unwrap();
 
         info!("Response LlmMessage: {:?}", llm_message_response);
-        let markdown_text = llm_message_response.content.text();
-        let html_text = markdown_to_html(&markdown_text);
-        info!("html_text: {:?}", html_text);
-        let content = LlmMessageContent::from(html_text.as_ref());
+        to_html_message(&llm_message_response)
+    }
+
+    /// Sends the message and calls `on_chunk` with the html of the reply received so far,
+    /// every time the server streams a new piece of it.
+    pub async fn send_message_stream(&self, content: String, on_chunk: Function) -> LlmMessage {
+        let json = self.send_message_request(content).await;
+
+        let url = format!("{}/send_message_stream", self.api_url.get_url());
+        info!("Send msg stream url {:?} with json: {:?}", url, json);
+
+        let response = self
+            .client
+            .post(url.clone())
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", url)
+            .json(&json)
+            .send()
+            .instrument(nweb_send_msg_span())
+            .await
+            .attach_printable_lazy(|| "Failed sending message")
+            .unwrap();
+
+        let mut body = response.bytes_stream();
+        let mut buffer: Vec<u8> = vec![];
+        let mut reply_text = String::new();
+
+        while let Some(bytes) = body.next().instrument(nweb_send_msg_span()).await {
+            let bytes = bytes
+                .attach_printable_lazy(|| "Failed reading message stream")
+                .unwrap();
+            buffer.extend_from_slice(&bytes);
+
+            for event in take_sse_events(&mut buffer) {
+                match event {
+                    LlmStreamEvent::Delta { text } => {
+                        reply_text.push_str(&text);
+                        let html_text = JsValue::from_str(&markdown_to_html(&reply_text));
+                        if let Err(err) = on_chunk.call1(&JsValue::NULL, &html_text) {
+                            error!("Chunk callback failed: {:?}", err);
+                        }
+                    }
+                    LlmStreamEvent::Done { message } => {
+                        info!("Response LlmMessage: {:?}", message);
+                        return to_html_message(&message);
+                    }
+                    LlmStreamEvent::Error { message } => {
+                        error!("Message stream failed: {}", message);
+                    }
+                }
+            }
+        }
 
-        LlmMessage {
+        // The stream has been cut before the final event, show what we have got
+        to_html_message(&LlmMessage {
             meta_info: LlmMessageMetaInfo {
-                sender_id: llm_message_response.meta_info.sender_id,
-                role: llm_message_response.meta_info.role,
-                persistence: llm_message_response.meta_info.persistence,
+                sender_id: None,
+                role: LlmMessageRole::Assistant,
+                persistence: LlmMessagePersistence::Temporal,
+            },
+            content: LlmMessageContent(reply_text),
+            sources: vec![],
+        })
+    }
+
+    async fn send_message_request(&self, content: String) -> SendMessageRequest {
+        let chat_id = self
+            .nervo_store
+            .get_or_generate_chat_id()
+            .instrument(nweb_send_msg_span())
+            .await;
+        let user_id = self
+            .nervo_store
+            .get_or_generate_user_id()
+            .instrument(nweb_send_msg_span())
+            .await;
+
+        SendMessageRequest {
+            chat_id,
+            agent_type: self.agent_type,
+            llm_message: UserLlmMessage {
+                sender_id: user_id,
+                content: LlmMessageContent(content),
             },
-            content,
         }
     }
 
@@ -319,9 +364,105 @@ fn default_error_response(error_message: &str) -> ServerResponse {
     }
 }
 
+fn to_html_message(llm_message: &LlmMessage) -> LlmMessage {
+    let markdown_text = llm_message.content.text();
+    let html_text = sources_html(&markdown_to_html(&markdown_text), &llm_message.sources);
+    info!("html_text: {:?}", html_text);
+
+    LlmMessage {
+        meta_info: LlmMessageMetaInfo {
+            sender_id: llm_message.meta_info.sender_id,
+            role: llm_message.meta_info.role,
+            persistence: llm_message.meta_info.persistence,
+        },
+        content: LlmMessageContent::from(html_text.as_ref()),
+        sources: llm_message.sources.clone(),
+    }
+}
+
+/// Appends the footnotes of the cited sources to the html of the message
+fn sources_html(html_text: &str, sources: &[LlmMessageSource]) -> String {
+    if sources.is_empty() {
+        return html_text.to_string();
+    }
+    let footnotes: String = sources
+        .iter()
+        .map(|source| markdown_to_html(&source.footnote()))
+        .collect();
+    format!("{}<div class=\"sources\">{}</div>", html_text, footnotes)
+}
+
+/// Takes all complete server-sent events out of the buffer, leaving an incomplete tail there.
+/// Network chunks may split a multibyte character, so only whole events get decoded.
+fn take_sse_events(buffer: &mut Vec<u8>) -> Vec<LlmStreamEvent> {
+    let mut events = vec![];
+    while let Some(event_end) = buffer.windows(2).position(|window| window == b"\n\n") {
+        let raw_bytes: Vec<u8> = buffer.drain(..event_end + 2).collect();
+        let raw_event = match String::from_utf8(raw_bytes) {
+            Ok(raw_event) => raw_event,
+            Err(err) => {
+                error!("Stream event is not utf-8: {}", err);
+                continue;
+            }
+        };
+        let data = raw_event
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.trim_start())
+            .collect::<Vec<&str>>()
+            .join("\n");
+
+        if data.is_empty() {
+            // keep-alive comments have no data
+            continue;
+        }
+
+        match serde_json::from_str(&data) {
+            Ok(event) => events.push(event),
+            Err(err) => error!("Unknown stream event {:?}: {}", data, err),
+        }
+    }
+    events
+}
+
 fn markdown_to_html(markdown: &str) -> String {
     let parser = Parser::new(markdown);
     let mut html_output = String::new();
     html::push_html(&mut html_output, parser);
     html_output
 }
+
+#[cfg(test)]
+mod test {
+    use crate::take_sse_events;
+    use nervo_sdk::api::spec::LlmStreamEvent;
+
+    fn delta_text(event: &LlmStreamEvent) -> &str {
+        match event {
+            LlmStreamEvent::Delta { text } => text,
+            _ => panic!("Delta expected, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_take_sse_events_keeps_split_characters() {
+        let stream = "data: {\"type\":\"delta\",\"text\":\"Привет\"}\n\n: keep-alive\n\ndata: {\"type\":\"delta\",\"text\":\"мир\"}\n\n";
+        let bytes = stream.as_bytes();
+        // splits the first "П" in the middle
+        let split_at = stream.find('П').unwrap() + 1;
+
+        let mut buffer = bytes[..split_at].to_vec();
+        assert!(take_sse_events(&mut buffer).is_empty());
+
+        buffer.extend_from_slice(&bytes[split_at..bytes.len() - 1]);
+        let events = take_sse_events(&mut buffer);
+        assert_eq!(events.len(), 1);
+        assert_eq!(delta_text(&events[0]), "Привет");
+
+        buffer.extend_from_slice(&bytes[bytes.len() - 1..]);
+        let events = take_sse_events(&mut buffer);
+        assert_eq!(events.len(), 1);
+        assert_eq!(delta_text(&events[0]), "мир");
+        assert!(buffer.is_empty());
+    }
+}